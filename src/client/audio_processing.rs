@@ -0,0 +1,223 @@
+use log::{info, warn};
+use qkd_camera_common_lib::echo_reference::DEFAULT_ECHO_REFERENCE_ADDRESS;
+use crate::echo_canceller::EchoCanceller;
+use crate::json_client_config::{DEFAULT_ECHO_TAIL_MS, JsonAudioProcessingConfig, max_echo_tail_ms};
+
+/// Levels and gains are measured and changed over blocks of this duration
+const BLOCK_DURATION_MS: u32 = 10;
+/// Lowest noise floor, so that digital silence doesn't open the gate on the faintest sound
+const MIN_NOISE_FLOOR: f32 = 10.0;
+/// How fast the noise floor estimate rises when the level stays above it, per block
+const NOISE_FLOOR_RISE: f32 = 0.002;
+/// Sound below this multiple of the noise floor is considered noise
+const NOISE_GATE_RATIO: f32 = 2.0;
+/// Gain applied to noise, -20 dB
+const NOISE_ATTENUATION: f32 = 0.1;
+const NOISE_GATE_OPEN_SPEED: f32 = 0.5;
+const NOISE_GATE_CLOSE_SPEED: f32 = 0.05;
+/// RMS level the automatic gain control aims at, -20 dBFS
+const AGC_TARGET_LEVEL: f32 = 3277.0;
+/// Quieter blocks are considered silence and don't change the gain, so that noise isn't amplified
+const AGC_SILENCE_LEVEL: f32 = 100.0;
+const AGC_MIN_GAIN: f32 = 0.1;
+const AGC_MAX_GAIN: f32 = 10.0;
+const AGC_INCREASE_SPEED: f32 = 0.01;
+const AGC_DECREASE_SPEED: f32 = 0.1;
+
+/// Processing applied to the captured sound before sending it: echo cancellation, then noise suppression,
+/// then automatic gain control. Each step is enabled in the `audio_processing` configuration.
+#[derive(Default)]
+pub(crate) struct AudioProcessor {
+    channels: usize,
+    block_length: usize,
+    echo_canceller: Option<EchoCanceller>,
+    noise_suppressor: Option<NoiseSuppressor>,
+    automatic_gain_control: Option<AutomaticGainControl>,
+}
+
+impl AudioProcessor {
+    pub(crate) fn new(audio_processing_config: &JsonAudioProcessingConfig, channels: u16, sample_rate: u32) -> Result<Self, String> {
+        let echo_canceller = if audio_processing_config.echo_cancellation {
+            // The capture sample rate may differ from the configured one, which the configured tail was checked against
+            let echo_tail_ms = audio_processing_config.override_default_echo_tail_ms.unwrap_or(DEFAULT_ECHO_TAIL_MS);
+            if echo_tail_ms > max_echo_tail_ms(sample_rate) {
+                warn!("Echo tail shortened from {} ms to {} ms at {} Hz", echo_tail_ms, max_echo_tail_ms(sample_rate), sample_rate);
+            }
+            Some(EchoCanceller::new(
+                audio_processing_config.echo_reference_address.as_deref().unwrap_or(DEFAULT_ECHO_REFERENCE_ADDRESS),
+                echo_tail_ms.min(max_echo_tail_ms(sample_rate)),
+                channels,
+                sample_rate,
+            )?)
+        } else {
+            None
+        };
+        info!("Audio processing: echo cancellation {}, noise suppression {}, automatic gain control {}",
+            audio_processing_config.echo_cancellation, audio_processing_config.noise_suppression, audio_processing_config.automatic_gain_control);
+        Ok(Self {
+            channels: channels as usize,
+            block_length: (sample_rate * BLOCK_DURATION_MS / 1000).max(1) as usize * channels as usize,
+            echo_canceller,
+            noise_suppressor: audio_processing_config.noise_suppression.then(NoiseSuppressor::new),
+            automatic_gain_control: audio_processing_config.automatic_gain_control.then(AutomaticGainControl::new),
+        })
+    }
+
+    /// Process interleaved samples in place
+    pub(crate) fn process(&mut self, samples: &mut [i16]) {
+        if self.echo_canceller.is_none() && self.noise_suppressor.is_none() && self.automatic_gain_control.is_none() {
+            return;
+        }
+        let mut processed_samples: Vec<f32> = samples.iter().map(|sample| *sample as f32).collect();
+        if let Some(echo_canceller) = self.echo_canceller.as_mut() {
+            echo_canceller.process(&mut processed_samples);
+        }
+        for block in processed_samples.chunks_mut(self.block_length) {
+            let frames = block.len() / self.channels;
+            if let Some(noise_suppressor) = self.noise_suppressor.as_mut() {
+                let (previous_gain, gain) = noise_suppressor.update(rms_level(block));
+                apply_gain_ramp(block, self.channels, frames, previous_gain, gain);
+            }
+            if let Some(automatic_gain_control) = self.automatic_gain_control.as_mut() {
+                let (previous_gain, gain) = automatic_gain_control.update(rms_level(block));
+                apply_gain_ramp(block, self.channels, frames, previous_gain, gain);
+            }
+        }
+        for (sample, processed_sample) in samples.iter_mut().zip(processed_samples) {
+            *sample = processed_sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+}
+
+/// Attenuates the sound quieter than the tracked background noise level
+struct NoiseSuppressor {
+    noise_floor: f32,
+    gain: f32,
+}
+
+impl NoiseSuppressor {
+    fn new() -> Self {
+        Self {
+            noise_floor: MIN_NOISE_FLOOR,
+            gain: 1.0,
+        }
+    }
+
+    /// Gains to apply at the start and at the end of a block of this level
+    fn update(&mut self, level: f32) -> (f32, f32) {
+        // The floor follows quieter blocks right away and louder ones slowly, so that speech barely raises it
+        self.noise_floor = if level < self.noise_floor {
+            level
+        } else {
+            self.noise_floor + (level - self.noise_floor) * NOISE_FLOOR_RISE
+        }.max(MIN_NOISE_FLOOR);
+        let target_gain = if level < self.noise_floor * NOISE_GATE_RATIO { NOISE_ATTENUATION } else { 1.0 };
+        let speed = if target_gain > self.gain { NOISE_GATE_OPEN_SPEED } else { NOISE_GATE_CLOSE_SPEED };
+        let previous_gain = self.gain;
+        self.gain += (target_gain - self.gain) * speed;
+        (previous_gain, self.gain)
+    }
+}
+
+/// Brings the speech level towards [AGC_TARGET_LEVEL], lowering the gain faster than raising it to avoid clipping
+struct AutomaticGainControl {
+    gain: f32,
+}
+
+impl AutomaticGainControl {
+    fn new() -> Self {
+        Self { gain: 1.0 }
+    }
+
+    /// Gains to apply at the start and at the end of a block of this level, measured before applying the gain
+    fn update(&mut self, level: f32) -> (f32, f32) {
+        let previous_gain = self.gain;
+        if level > AGC_SILENCE_LEVEL {
+            let target_gain = (AGC_TARGET_LEVEL / level).clamp(AGC_MIN_GAIN, AGC_MAX_GAIN);
+            let speed = if target_gain < self.gain { AGC_DECREASE_SPEED } else { AGC_INCREASE_SPEED };
+            self.gain += (target_gain - self.gain) * speed;
+        }
+        (previous_gain, self.gain)
+    }
+}
+
+fn rms_level(samples: &[f32]) -> f32 {
+    (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len().max(1) as f32).sqrt()
+}
+
+/// Multiply interleaved samples by a gain going linearly from `start_gain` to `end_gain`, so that gain changes don't click
+fn apply_gain_ramp(samples: &mut [f32], channels: usize, frames: usize, start_gain: f32, end_gain: f32) {
+    for (frame_index, frame) in samples.chunks_mut(channels).enumerate() {
+        let gain = start_gain + (end_gain - start_gain) * (frame_index + 1) as f32 / frames.max(1) as f32;
+        for sample in frame {
+            *sample *= gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_gate_closes_on_steady_noise_and_opens_on_speech() {
+        let mut noise_suppressor = NoiseSuppressor::new();
+        // The noise floor starts low, so the noise first goes through
+        assert_eq!(noise_suppressor.update(50.0), (1.0, 1.0));
+        let mut gain = 1.0;
+        for _ in 0..1000 {
+            let (previous_gain, next_gain) = noise_suppressor.update(50.0);
+            assert_eq!(previous_gain, gain);
+            assert!(next_gain <= gain);
+            gain = next_gain;
+        }
+        assert!((gain - NOISE_ATTENUATION).abs() < 0.001);
+        // Speech opens the gate within a few blocks, faster than it closed
+        let (_, gain) = noise_suppressor.update(2000.0);
+        assert!((gain - (NOISE_ATTENUATION + (1.0 - NOISE_ATTENUATION) * NOISE_GATE_OPEN_SPEED)).abs() < 0.001);
+        for _ in 0..10 {
+            noise_suppressor.update(2000.0);
+        }
+        assert!(noise_suppressor.gain > 0.99);
+        // Speech barely raises the noise floor
+        assert!(noise_suppressor.noise_floor < 100.0);
+    }
+
+    #[test]
+    fn noise_floor_follows_quieter_sound_right_away() {
+        let mut noise_suppressor = NoiseSuppressor::new();
+        noise_suppressor.noise_floor = 500.0;
+        noise_suppressor.update(20.0);
+        assert_eq!(noise_suppressor.noise_floor, 20.0);
+        noise_suppressor.update(0.0);
+        assert_eq!(noise_suppressor.noise_floor, MIN_NOISE_FLOOR);
+    }
+
+    #[test]
+    fn gain_control_raises_quiet_speech_slowly_and_lowers_loud_speech_quickly() {
+        let mut automatic_gain_control = AutomaticGainControl::new();
+        // Silence keeps the gain
+        assert_eq!(automatic_gain_control.update(AGC_SILENCE_LEVEL / 2.0), (1.0, 1.0));
+        let mut gain = 1.0;
+        for _ in 0..1000 {
+            let (previous_gain, next_gain) = automatic_gain_control.update(AGC_TARGET_LEVEL / 20.0);
+            assert_eq!(previous_gain, gain);
+            assert!(next_gain > gain && next_gain <= AGC_MAX_GAIN);
+            gain = next_gain;
+        }
+        assert!((gain - AGC_MAX_GAIN).abs() < 0.01);
+        let (_, lowered_gain) = automatic_gain_control.update(AGC_TARGET_LEVEL * 20.0);
+        assert!((lowered_gain - (gain + (AGC_MIN_GAIN - gain) * AGC_DECREASE_SPEED)).abs() < 0.001);
+        for _ in 0..100 {
+            automatic_gain_control.update(AGC_TARGET_LEVEL * 20.0);
+        }
+        assert!((automatic_gain_control.gain - AGC_MIN_GAIN).abs() < 0.01);
+    }
+
+    #[test]
+    fn gain_ramp_reaches_the_end_gain_on_the_last_frame() {
+        let mut samples = vec![100.0; 8];
+        apply_gain_ramp(&mut samples, 2, 4, 0.0, 1.0);
+        assert_eq!(samples, vec![25.0, 25.0, 50.0, 50.0, 75.0, 75.0, 100.0, 100.0]);
+    }
+}
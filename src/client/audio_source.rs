@@ -0,0 +1,97 @@
+use pv_recorder::{PvRecorder, PvRecorderBuilder};
+use log::info;
+use qkd_camera_common_lib::audio_device::AudioDeviceSelector;
+use crate::cpal_microphone::{self, CpalMicrophone};
+
+/// Source of audio chunks, read by the audio capture thread
+pub(crate) trait AudioSource {
+    fn sample_rate(&self) -> u32;
+    fn channels(&self) -> u16 {
+        1
+    }
+    fn start(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+    /// Wait for the next chunk of captured samples, interleaved when there are several channels
+    fn read(&mut self) -> Result<Vec<i16>, String>;
+    fn stop(&mut self) -> Result<(), String>;
+}
+
+impl<A: AudioSource + ?Sized> AudioSource for Box<A> {
+    fn sample_rate(&self) -> u32 {
+        (**self).sample_rate()
+    }
+
+    fn channels(&self) -> u16 {
+        (**self).channels()
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        (**self).start()
+    }
+
+    fn read(&mut self) -> Result<Vec<i16>, String> {
+        (**self).read()
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        (**self).stop()
+    }
+}
+
+/// Records 16 kHz mono only
+impl AudioSource for PvRecorder {
+    fn sample_rate(&self) -> u32 {
+        PvRecorder::sample_rate(self) as u32
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        PvRecorder::start(self).map_err(|e| format!("cannot start audio capture: {}", e))
+    }
+
+    fn read(&mut self) -> Result<Vec<i16>, String> {
+        PvRecorder::read(self).map_err(|e| format!("cannot read audio: {}", e))
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        PvRecorder::stop(self).map_err(|e| format!("cannot stop audio capture: {}", e))
+    }
+}
+
+/// Sample rate of PvRecorder, the only one it records at
+const PV_RECORDER_SAMPLE_RATE: u32 = 16000;
+
+/// Whether capturing with this format needs cpal, PvRecorder only recording 16 kHz mono
+fn needs_cpal(channels: u16, sample_rate: u32) -> bool {
+    channels != 1 || sample_rate != PV_RECORDER_SAMPLE_RATE
+}
+
+/// Names of the PvRecorder microphones, in the order of their indexes
+pub(crate) fn pv_recorder_microphone_names() -> Result<Vec<String>, String> {
+    PvRecorderBuilder::default().get_available_devices().map_err(|e| format!("cannot list microphones: {}", e))
+}
+
+/// Names of the microphones that can record with this format, in the order of their indexes
+pub(crate) fn microphone_names(channels: u16, sample_rate: u32) -> Result<Vec<String>, String> {
+    if needs_cpal(channels, sample_rate) {
+        cpal_microphone::input_device_names()
+    } else {
+        pv_recorder_microphone_names()
+    }
+}
+
+/// Open the selected microphone, or the default one, with PvRecorder for 16 kHz mono and with cpal otherwise
+pub(crate) fn open_microphone(audio_input_device: Option<&AudioDeviceSelector>, channels: u16, sample_rate: u32, frame_length: i32) -> Result<Box<dyn AudioSource + Send>, String> {
+    if needs_cpal(channels, sample_rate) {
+        return CpalMicrophone::open(audio_input_device, channels, sample_rate, frame_length as usize)
+            .map(|microphone| Box::new(microphone) as Box<dyn AudioSource + Send>);
+    }
+    let mut recorder_builder = PvRecorderBuilder::new(frame_length);
+    if let Some(audio_input_device) = audio_input_device {
+        let device_index = audio_input_device.find(&pv_recorder_microphone_names()?)?;
+        recorder_builder.device_index(device_index as i32);
+    }
+    let sound_recorder = recorder_builder.init().map_err(|e| format!("cannot open microphone: {}", e))?;
+    info!("Recording from microphone {}", sound_recorder.selected_device());
+    Ok(Box::new(sound_recorder))
+}
@@ -0,0 +1,188 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use image::{ImageBuffer, Rgb};
+use log::{debug, error};
+use qkd_camera_common_lib::SessionLimits;
+use crate::audio_processing::AudioProcessor;
+use crate::audio_source::AudioSource;
+use crate::capture_clock::CaptureClock;
+use crate::live_settings::LiveSettings;
+use crate::media_pipeline::MediaPipeline;
+use crate::synthetic_sources::{SYNC_SIGNAL_PERIOD_US, SyntheticAudioSource, SyntheticCamera};
+
+/// Absolute sample value above which the beep is considered started
+const BEEP_THRESHOLD: i16 = 4000;
+/// Mean image value above which the flash is considered visible
+const FLASH_THRESHOLD: f64 = 128.0;
+
+/// Capture the synthetic beep and flash through the media pipeline, without connecting to the server,
+/// and print the offset between their timestamps. Returns whether it stays within one video frame.
+pub(crate) fn calibrate(duration: Duration) -> bool {
+    calibrate_with_settings(LiveSettings::default(), duration)
+}
+
+fn calibrate_with_settings(live_settings: LiveSettings, duration: Duration) -> bool {
+    let frame_duration_us = 1_000_000 / live_settings.camera_fps as i64;
+    let capture_clock = CaptureClock::new();
+    let camera = SyntheticCamera::with_clock(capture_clock, live_settings.camera_format, live_settings.camera_fps);
+    let audio_source = SyntheticAudioSource::with_clock(capture_clock);
+    let sample_rate = audio_source.sample_rate() as u64;
+    let media_pipeline = MediaPipeline::start(camera, audio_source, AudioProcessor::default(), capture_clock, Arc::new(RwLock::new(live_settings)), SessionLimits::default());
+
+    let mut sync_signal_detector = SyncSignalDetector::new(sample_rate);
+    let calibration_start = Instant::now();
+    while calibration_start.elapsed() < duration {
+        let audio_chunk = match media_pipeline.next_audio_chunk() {
+            Some(audio_chunk) => audio_chunk,
+            None => {
+                error!("Audio capture stopped");
+                return false;
+            }
+        };
+        sync_signal_detector.add_audio_chunk(audio_chunk.capture_timestamp_us, &audio_chunk.samples);
+        if let Some(encoded_frame) = media_pipeline.newest_encoded_frame() {
+            let image: ImageBuffer<Rgb<u8>, Vec<u8>> = match turbojpeg::decompress_image(&encoded_frame.jpeg) {
+                Ok(image) => image,
+                Err(e) => {
+                    error!("Cannot decode encoded frame: {}", e);
+                    return false;
+                }
+            };
+            let mean_value = image.as_raw().iter().map(|value| *value as f64).sum::<f64>() / image.as_raw().len().max(1) as f64;
+            sync_signal_detector.add_frame(encoded_frame.capture_timestamp_us, mean_value);
+        }
+    }
+    media_pipeline.stop();
+
+    let offsets_us = sync_signal_detector.offsets_us();
+    if offsets_us.is_empty() {
+        println!("No flash matched a beep, {} beeps and {} flashes detected",
+            sync_signal_detector.beep_timestamps_us.len(), sync_signal_detector.flash_timestamps_us.len());
+        return false;
+    }
+    let mean_offset_us = offsets_us.iter().sum::<i64>() / offsets_us.len() as i64;
+    println!("Video capture offset relative to audio, over {} flashes: mean {:.1} ms, min {:.1} ms, max {:.1} ms",
+        offsets_us.len(), mean_offset_us as f64 / 1000.0,
+        *offsets_us.iter().min().unwrap() as f64 / 1000.0, *offsets_us.iter().max().unwrap() as f64 / 1000.0);
+    let aligned = mean_offset_us.abs() <= frame_duration_us;
+    println!("{}", if aligned { "Audio and video are aligned within one frame" } else { "Audio and video are NOT aligned within one frame" });
+    aligned
+}
+
+/// Beep and flash onsets, from the captured samples and frames with their timestamps
+struct SyncSignalDetector {
+    sample_rate: u64,
+    beep_timestamps_us: Vec<u64>,
+    flash_timestamps_us: Vec<u64>,
+    last_loud_sample_timestamp_us: Option<u64>,
+    flash_visible: bool,
+}
+
+impl SyncSignalDetector {
+    fn new(sample_rate: u64) -> Self {
+        Self {
+            sample_rate,
+            beep_timestamps_us: Vec::new(),
+            flash_timestamps_us: Vec::new(),
+            last_loud_sample_timestamp_us: None,
+            flash_visible: false,
+        }
+    }
+
+    /// A beep starts with a loud sample more than half a signal period after the previous loud one
+    fn add_audio_chunk(&mut self, capture_timestamp_us: u64, samples: &[i16]) {
+        for (sample_index, sample) in samples.iter().enumerate() {
+            if sample.unsigned_abs() < BEEP_THRESHOLD.unsigned_abs() {
+                continue;
+            }
+            let sample_timestamp_us = capture_timestamp_us + sample_index as u64 * 1_000_000 / self.sample_rate;
+            if self.last_loud_sample_timestamp_us.is_none_or(|timestamp_us| sample_timestamp_us.abs_diff(timestamp_us) > SYNC_SIGNAL_PERIOD_US / 2) {
+                debug!("Beep at {} us", sample_timestamp_us);
+                self.beep_timestamps_us.push(sample_timestamp_us);
+            }
+            self.last_loud_sample_timestamp_us = Some(sample_timestamp_us);
+        }
+    }
+
+    /// A flash starts with the first bright frame after a dark one
+    fn add_frame(&mut self, capture_timestamp_us: u64, mean_value: f64) {
+        if mean_value > FLASH_THRESHOLD && !self.flash_visible {
+            debug!("Flash at {} us", capture_timestamp_us);
+            self.flash_timestamps_us.push(capture_timestamp_us);
+        }
+        self.flash_visible = mean_value > FLASH_THRESHOLD;
+    }
+
+    /// Offset of each flash relative to the nearest beep, flashes without a beep within half a signal period being ignored
+    fn offsets_us(&self) -> Vec<i64> {
+        self.flash_timestamps_us.iter().filter_map(|flash_timestamp_us| {
+            self.beep_timestamps_us.iter()
+                .map(|beep_timestamp_us| *flash_timestamp_us as i64 - *beep_timestamp_us as i64)
+                .min_by_key(|offset_us| offset_us.abs())
+                .filter(|offset_us| offset_us.unsigned_abs() < SYNC_SIGNAL_PERIOD_US / 2)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u64 = 48_000;
+
+    /// Chunk of silence with a beep of `beep_length` samples starting at `beep_start`
+    fn audio_chunk(length: usize, beep_start: usize, beep_length: usize) -> Vec<i16> {
+        (0..length).map(|index| if (beep_start..beep_start + beep_length).contains(&index) { 10_000 } else { 0 }).collect()
+    }
+
+    #[test]
+    fn beep_onsets_are_detected_once_per_beep() {
+        let mut sync_signal_detector = SyncSignalDetector::new(SAMPLE_RATE);
+        // 480 samples are 10 ms, the beep continuing over the next chunk is the same beep
+        sync_signal_detector.add_audio_chunk(0, &audio_chunk(480, 240, 240));
+        sync_signal_detector.add_audio_chunk(10_000, &audio_chunk(480, 0, 240));
+        sync_signal_detector.add_audio_chunk(SYNC_SIGNAL_PERIOD_US + 5_000, &audio_chunk(480, 0, 240));
+        assert_eq!(sync_signal_detector.beep_timestamps_us, vec![5_000, SYNC_SIGNAL_PERIOD_US + 5_000]);
+    }
+
+    #[test]
+    fn flash_onsets_are_detected_on_dark_to_bright_frames() {
+        let mut sync_signal_detector = SyncSignalDetector::new(SAMPLE_RATE);
+        for (timestamp_us, mean_value) in [(0, 10.0), (33_000, 200.0), (66_000, 210.0), (100_000, 10.0), (133_000, 200.0)] {
+            sync_signal_detector.add_frame(timestamp_us, mean_value);
+        }
+        assert_eq!(sync_signal_detector.flash_timestamps_us, vec![33_000, 133_000]);
+    }
+
+    #[test]
+    fn flashes_are_matched_with_the_nearest_beep() {
+        let mut sync_signal_detector = SyncSignalDetector::new(SAMPLE_RATE);
+        sync_signal_detector.beep_timestamps_us = vec![100_000, 100_000 + SYNC_SIGNAL_PERIOD_US];
+        sync_signal_detector.flash_timestamps_us = vec![
+            110_000,
+            100_000 + SYNC_SIGNAL_PERIOD_US - 20_000,
+            // Too far from any beep
+            100_000 + 3 * SYNC_SIGNAL_PERIOD_US,
+        ];
+        assert_eq!(sync_signal_detector.offsets_us(), vec![10_000, -20_000]);
+    }
+
+    #[test]
+    fn no_offset_without_beep() {
+        let mut sync_signal_detector = SyncSignalDetector::new(SAMPLE_RATE);
+        sync_signal_detector.add_frame(0, 200.0);
+        assert!(sync_signal_detector.offsets_us().is_empty());
+    }
+
+    /// Captures through the whole media pipeline in real time, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn synthetic_sources_are_aligned() {
+        // Small frames, so that unoptimized builds keep up with the camera frame rate
+        let live_settings = LiveSettings {
+            camera_format: Some((160, 120)),
+            ..LiveSettings::default()
+        };
+        assert!(calibrate_with_settings(live_settings, Duration::from_secs(3)));
+    }
+}
@@ -1,7 +1,33 @@
 use image::{ImageBuffer, Rgb};
+use qkd_camera_common_lib::VideoSource;
 use crate::json_client_config::JsonClientConfig;
 
+/// A captured frame, either raw or already compressed by the camera
+pub enum CameraFrame {
+    Rgb(ImageBuffer<Rgb<u8>, Vec<u8>>),
+    /// JPEG frame delivered by an MJPEG camera, forwarded untouched when possible
+    Jpeg { jpeg: Vec<u8>, width: u32, height: u32 },
+}
+
+impl CameraFrame {
+    pub fn into_rgb(self) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, String> {
+        match self {
+            CameraFrame::Rgb(image) => Ok(image),
+            CameraFrame::Jpeg { jpeg, .. } => turbojpeg::decompress_image(&jpeg).map_err(|e| format!("cannot decode MJPEG frame: {}", e)),
+        }
+    }
+}
+
 pub trait Camera {
-    fn new(client_config: &JsonClientConfig) -> Self;
-    fn get_frame(&mut self) -> ImageBuffer<Rgb<u8>, Vec<u8>>;
+    fn new(client_config: &JsonClientConfig) -> Result<Self, String> where Self: Sized;
+    fn get_frame(&mut self) -> Result<CameraFrame, String>;
+    /// Change the capture format during a call, `None` meaning the device current format
+    fn reconfigure(&mut self, camera_format: Option<(u32, u32)>, camera_fps: u32) -> Result<(), String>;
+    /// Switch between the camera and the screen, for sources capturing both
+    fn select_source(&mut self, video_source: VideoSource) -> Result<(), String> {
+        match video_source {
+            VideoSource::Camera => Ok(()),
+            VideoSource::Screen => Err("screen share unavailable".to_string()),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,95 @@
+use std::time::{Duration, Instant};
+
+/// Monotonic clock shared by the audio and video capture, timestamps being microseconds since its start
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CaptureClock {
+    origin: Instant,
+}
+
+impl CaptureClock {
+    pub(crate) fn new() -> Self {
+        Self { origin: Instant::now() }
+    }
+
+    pub(crate) fn timestamp_us(&self, instant: Instant) -> u64 {
+        instant.saturating_duration_since(self.origin).as_micros() as u64
+    }
+
+    pub(crate) fn now_us(&self) -> u64 {
+        self.timestamp_us(Instant::now())
+    }
+
+    pub(crate) fn instant(&self, timestamp_us: u64) -> Instant {
+        self.origin + Duration::from_micros(timestamp_us)
+    }
+}
+
+/// Capture time of the audio chunks, deduced from the number of samples read since the first chunk.
+/// A chunk is fully captured before being read, so each read gives a latest possible start time for the first chunk,
+/// the smallest one is kept, which ignores the delays of reading buffered audio.
+pub(crate) struct AudioTimestamper {
+    sample_rate: u32,
+    samples_read: u64,
+    first_chunk_timestamp_us: Option<u64>,
+}
+
+impl AudioTimestamper {
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            samples_read: 0,
+            first_chunk_timestamp_us: None,
+        }
+    }
+
+    /// Capture time of the first sample of a chunk of `chunk_length` samples, read at `read_timestamp_us`
+    pub(crate) fn timestamp_us(&mut self, read_timestamp_us: u64, chunk_length: usize) -> u64 {
+        let samples_duration_us = |samples: u64| samples * 1_000_000 / self.sample_rate as u64;
+        let samples_read_before_chunk = self.samples_read;
+        self.samples_read += chunk_length as u64;
+        let latest_first_chunk_timestamp_us = read_timestamp_us.saturating_sub(samples_duration_us(self.samples_read));
+        let first_chunk_timestamp_us = self.first_chunk_timestamp_us
+            .map_or(latest_first_chunk_timestamp_us, |timestamp_us| timestamp_us.min(latest_first_chunk_timestamp_us));
+        self.first_chunk_timestamp_us = Some(first_chunk_timestamp_us);
+        first_chunk_timestamp_us + samples_duration_us(samples_read_before_chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_read_on_time_follow_each_other() {
+        let mut audio_timestamper = AudioTimestamper::new(48_000);
+        // 480 samples are 10 ms, each chunk being read once fully captured
+        assert_eq!(audio_timestamper.timestamp_us(110_000, 480), 100_000);
+        assert_eq!(audio_timestamper.timestamp_us(120_000, 480), 110_000);
+        assert_eq!(audio_timestamper.timestamp_us(130_000, 480), 120_000);
+    }
+
+    #[test]
+    fn late_reads_do_not_delay_the_timestamps() {
+        let mut audio_timestamper = AudioTimestamper::new(48_000);
+        assert_eq!(audio_timestamper.timestamp_us(110_000, 480), 100_000);
+        // Buffered chunks read late keep their capture time
+        assert_eq!(audio_timestamper.timestamp_us(150_000, 480), 110_000);
+        assert_eq!(audio_timestamper.timestamp_us(150_000, 480), 120_000);
+    }
+
+    #[test]
+    fn late_first_read_is_corrected_by_the_next_ones() {
+        let mut audio_timestamper = AudioTimestamper::new(48_000);
+        assert_eq!(audio_timestamper.timestamp_us(130_000, 480), 120_000);
+        // The second chunk could not have been captured later, so the first one started at 100 ms at the latest
+        assert_eq!(audio_timestamper.timestamp_us(120_000, 480), 110_000);
+        assert_eq!(audio_timestamper.timestamp_us(130_000, 480), 120_000);
+    }
+
+    #[test]
+    fn timestamps_do_not_underflow_before_the_clock_origin() {
+        let mut audio_timestamper = AudioTimestamper::new(48_000);
+        assert_eq!(audio_timestamper.timestamp_us(5_000, 480), 0);
+        assert_eq!(audio_timestamper.timestamp_us(20_000, 480), 10_000);
+    }
+}
@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use qkd_camera_common_lib::config_loader::ConfigArgs;
+use qkd_camera_common_lib::logging::LoggingArgs;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "QKD video call client, records the camera and microphone and sends them to the server")]
+pub(crate) struct Cli {
+    #[command(flatten)]
+    pub(crate) logging_args: LoggingArgs,
+    #[command(subcommand)]
+    pub(crate) command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Call the server
+    Run {
+        #[command(flatten)]
+        config_args: ConfigArgs,
+        /// Validate the configuration without connecting
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check KME health, key availability and server reachability
+    Diagnose {
+        #[command(flatten)]
+        config_args: ConfigArgs,
+    },
+    /// List capture devices
+    ListDevices,
+    /// List microphones
+    ListAudioDevices,
+    /// Measure the audio/video capture alignment using a synthetic beep and flash, without calling the server
+    Calibrate {
+        /// Measurement duration, in seconds
+        #[arg(long, default_value_t = 10)]
+        duration_secs: u64,
+    },
+    /// Write a configuration template
+    GenConfig {
+        /// Output file, standard output if missing
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Write the JSON schema of the configuration
+    Schema {
+        /// Output file, standard output if missing
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
@@ -0,0 +1,218 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use image::{ImageBuffer, Rgb};
+use image::imageops::{self, FilterType};
+use log::{info, warn};
+use qkd_camera_common_lib::VideoSource;
+use crate::camera::{Camera, CameraFrame};
+use crate::json_client_config::{CompositionLayout, DEFAULT_PIP_SIZE_PERCENT, JsonClientConfig, JsonCompositionSourceConfig, PipCorner};
+use crate::linux_camera::LinuxCamera;
+use crate::screen_capture::ScreenCapture;
+
+/// Space between the insets and the edges of the main image, in percent of its width
+const PIP_MARGIN_PERCENT: u32 = 2;
+
+type RgbImage = ImageBuffer<Rgb<u8>, Vec<u8>>;
+
+/// Composes each frame of the main source with the newest frames of the additional sources configured in `composition`.
+/// Each additional source is captured by its own thread, so that it doesn't slow the main one down. Without additional
+/// sources, the frames of the main source are forwarded untouched.
+pub(crate) struct Compositor<C: Camera> {
+    main_source: C,
+    additional_frames: Vec<Arc<Mutex<Option<RgbImage>>>>,
+    layout: CompositionLayout,
+    pip_corner: PipCorner,
+    pip_size_percent: u32,
+    stop_capturing: Arc<AtomicBool>,
+}
+
+impl<C: Camera> Camera for Compositor<C> {
+    fn new(client_config: &JsonClientConfig) -> Result<Self, String> {
+        let main_source = C::new(client_config)?;
+        let stop_capturing = Arc::new(AtomicBool::new(false));
+        let mut additional_frames = Vec::new();
+        let composition = client_config.composition.as_ref();
+        for source_config in composition.map(|composition| composition.sources.as_slice()).unwrap_or_default() {
+            let (source, description): (Box<dyn Camera + Send>, String) = match source_config {
+                JsonCompositionSourceConfig::Camera { device } => {
+                    let mut camera_config = client_config.clone();
+                    camera_config.override_default_camera_device = Some(device.clone());
+                    camera_config.override_default_format = None;
+                    camera_config.mjpeg_passthrough = false;
+                    let camera = LinuxCamera::new(&camera_config).map_err(|e| format!("cannot open composed camera {}: {}", device, e))?;
+                    (Box::new(camera), format!("camera {}", device))
+                },
+                JsonCompositionSourceConfig::Screen => {
+                    let screen_capture = ScreenCapture::new(client_config).map_err(|e| format!("cannot open composed screen: {}", e))?;
+                    (Box::new(screen_capture), "screen".to_string())
+                },
+            };
+            info!("Composing {} with the main video", description);
+            let newest_frame = Arc::new(Mutex::new(None));
+            let thread_newest_frame = newest_frame.clone();
+            let thread_stop_capturing = stop_capturing.clone();
+            std::thread::spawn(move || capture_additional_source(source, description, thread_newest_frame, thread_stop_capturing));
+            additional_frames.push(newest_frame);
+        }
+        Ok(Self {
+            main_source,
+            additional_frames,
+            layout: composition.map(|composition| composition.layout).unwrap_or_default(),
+            pip_corner: composition.map(|composition| composition.pip_corner).unwrap_or_default(),
+            pip_size_percent: composition.and_then(|composition| composition.override_default_pip_size_percent).unwrap_or(DEFAULT_PIP_SIZE_PERCENT),
+            stop_capturing,
+        })
+    }
+
+    fn get_frame(&mut self) -> Result<CameraFrame, String> {
+        let frame = self.main_source.get_frame()?;
+        if self.additional_frames.is_empty() {
+            return Ok(frame);
+        }
+        let main_image = frame.into_rgb()?;
+        let newest_frames: Vec<_> = self.additional_frames.iter().map(|newest_frame| newest_frame.lock().unwrap()).collect();
+        let additional_images: Vec<&RgbImage> = newest_frames.iter().filter_map(|newest_frame| newest_frame.as_ref()).collect();
+        let composed_image = match self.layout {
+            CompositionLayout::PictureInPicture => picture_in_picture(main_image, &additional_images, self.pip_corner, self.pip_size_percent),
+            CompositionLayout::SideBySide => side_by_side(main_image, &additional_images),
+        };
+        Ok(CameraFrame::Rgb(composed_image))
+    }
+
+    fn reconfigure(&mut self, camera_format: Option<(u32, u32)>, camera_fps: u32) -> Result<(), String> {
+        self.main_source.reconfigure(camera_format, camera_fps)
+    }
+
+    fn select_source(&mut self, video_source: VideoSource) -> Result<(), String> {
+        self.main_source.select_source(video_source)
+    }
+}
+
+impl<C: Camera> Drop for Compositor<C> {
+    fn drop(&mut self) {
+        self.stop_capturing.store(true, Ordering::Relaxed);
+    }
+}
+
+fn capture_additional_source(mut source: Box<dyn Camera + Send>, description: String, newest_frame: Arc<Mutex<Option<RgbImage>>>, stop_capturing: Arc<AtomicBool>) {
+    while !stop_capturing.load(Ordering::Relaxed) {
+        match source.get_frame().and_then(CameraFrame::into_rgb) {
+            Ok(image) => *newest_frame.lock().unwrap() = Some(image),
+            Err(e) => {
+                warn!("Error capturing {}: {}, no longer composed", description, e);
+                *newest_frame.lock().unwrap() = None;
+                return;
+            }
+        }
+    }
+}
+
+/// Draw the insets in a corner of the main image, stacked from the corner towards the opposite edge. Insets that don't
+/// fit are left out.
+fn picture_in_picture(mut main_image: RgbImage, insets: &[&RgbImage], corner: PipCorner, size_percent: u32) -> RgbImage {
+    let (width, height) = main_image.dimensions();
+    let margin = width * PIP_MARGIN_PERCENT / 100;
+    let inset_width = (width * size_percent / 100).max(1);
+    // Distance between the next inset and the top or bottom edge
+    let mut edge_distance = margin;
+    for inset in insets {
+        let inset_height = ((inset.height() as u64 * inset_width as u64 / inset.width().max(1) as u64) as u32).max(1);
+        if edge_distance + inset_height + margin > height {
+            break;
+        }
+        let scaled_inset = imageops::resize(*inset, inset_width, inset_height, FilterType::Triangle);
+        let x = match corner {
+            PipCorner::TopLeft | PipCorner::BottomLeft => margin,
+            PipCorner::TopRight | PipCorner::BottomRight => width - inset_width - margin,
+        };
+        let y = match corner {
+            PipCorner::TopLeft | PipCorner::TopRight => edge_distance,
+            PipCorner::BottomLeft | PipCorner::BottomRight => height - edge_distance - inset_height,
+        };
+        imageops::overlay(&mut main_image, &scaled_inset, x as i64, y as i64);
+        edge_distance += inset_height + margin;
+    }
+    main_image
+}
+
+/// Place the other images right of the main one, scaled to its height
+fn side_by_side(main_image: RgbImage, other_images: &[&RgbImage]) -> RgbImage {
+    let height = main_image.height();
+    let scaled_images: Vec<RgbImage> = other_images.iter().map(|image| {
+        let scaled_width = ((image.width() as u64 * height as u64 / image.height().max(1) as u64) as u32).max(1);
+        imageops::resize(*image, scaled_width, height, FilterType::Triangle)
+    }).collect();
+    let width = main_image.width() + scaled_images.iter().map(|image| image.width()).sum::<u32>();
+    let mut composed_image = ImageBuffer::new(width, height);
+    let mut x = 0;
+    for image in std::iter::once(&main_image).chain(scaled_images.iter()) {
+        imageops::replace(&mut composed_image, image, x as i64, 0);
+        x += image.width();
+    }
+    composed_image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN_COLOR: Rgb<u8> = Rgb([0, 0, 255]);
+    const INSET_COLORS: [Rgb<u8>; 4] = [Rgb([255, 0, 0]), Rgb([0, 255, 0]), Rgb([255, 255, 0]), Rgb([0, 255, 255])];
+
+    /// 200x100 main image, whose insets at 25% are 50x25 with a margin of 4 pixels
+    fn main_image() -> RgbImage {
+        ImageBuffer::from_pixel(200, 100, MAIN_COLOR)
+    }
+
+    fn insets(count: usize) -> Vec<RgbImage> {
+        INSET_COLORS[..count].iter().map(|color| ImageBuffer::from_pixel(100, 50, *color)).collect()
+    }
+
+    fn contains_color(image: &RgbImage, color: Rgb<u8>) -> bool {
+        image.pixels().any(|pixel| *pixel == color)
+    }
+
+    #[test]
+    fn inset_is_drawn_in_the_selected_corner() {
+        let insets = insets(1);
+        let inset_refs: Vec<&RgbImage> = insets.iter().collect();
+        let corner_centers = [
+            (PipCorner::TopLeft, (29, 16)),
+            (PipCorner::TopRight, (171, 16)),
+            (PipCorner::BottomLeft, (29, 83)),
+            (PipCorner::BottomRight, (171, 83)),
+        ];
+        for (corner, (x, y)) in corner_centers {
+            let composed_image = picture_in_picture(main_image(), &inset_refs, corner, 25);
+            assert_eq!(composed_image.dimensions(), (200, 100));
+            assert_eq!(*composed_image.get_pixel(x, y), INSET_COLORS[0], "{:?}", corner);
+            assert_eq!(*composed_image.get_pixel(100, 50), MAIN_COLOR, "{:?}", corner);
+        }
+    }
+
+    #[test]
+    fn insets_are_stacked_and_left_out_when_they_dont_fit() {
+        let insets = insets(4);
+        let inset_refs: Vec<&RgbImage> = insets.iter().collect();
+        let composed_image = picture_in_picture(main_image(), &inset_refs, PipCorner::TopRight, 25);
+        assert_eq!(*composed_image.get_pixel(171, 16), INSET_COLORS[0]);
+        assert_eq!(*composed_image.get_pixel(171, 45), INSET_COLORS[1]);
+        assert_eq!(*composed_image.get_pixel(171, 74), INSET_COLORS[2]);
+        assert!(!contains_color(&composed_image, INSET_COLORS[3]));
+    }
+
+    #[test]
+    fn side_by_side_scales_the_other_images_to_the_main_height() {
+        let other_image = ImageBuffer::from_pixel(50, 50, INSET_COLORS[0]);
+        let composed_image = side_by_side(main_image(), &[&other_image]);
+        assert_eq!(composed_image.dimensions(), (300, 100));
+        assert_eq!(*composed_image.get_pixel(199, 50), MAIN_COLOR);
+        assert_eq!(*composed_image.get_pixel(200, 0), INSET_COLORS[0]);
+        assert_eq!(*composed_image.get_pixel(299, 99), INSET_COLORS[0]);
+    }
+
+    #[test]
+    fn side_by_side_without_other_images_is_the_main_image() {
+        assert_eq!(side_by_side(main_image(), &[]), main_image());
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::mpsc;
+use log::{error, info, warn};
+use qkd_camera_common_lib::audio_device::AudioDeviceSelector;
+use rodio::cpal::{self, FromSample, SampleFormat, SizedSample, StreamConfig, SupportedStreamConfigRange};
+use rodio::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crate::audio_source::AudioSource;
+
+/// Microphone captured through cpal, which unlike PvRecorder can record in stereo and at other sample rates.
+/// The cpal stream can't be moved between threads, so it lives in a thread of its own, controlled through a channel.
+pub(crate) struct CpalMicrophone {
+    sample_receiver: mpsc::Receiver<Vec<i16>>,
+    /// Samples received from cpal but not read yet
+    pending_samples: Vec<i16>,
+    /// Samples per chunk returned by [AudioSource::read], all channels included
+    chunk_length: usize,
+    channels: u16,
+    sample_rate: u32,
+    /// `true` to start the stream, `false` or disconnecting to release it
+    control_sender: mpsc::Sender<bool>,
+}
+
+/// Names of the cpal capture devices, in the order of their indexes
+pub(crate) fn input_device_names() -> Result<Vec<String>, String> {
+    let input_devices = cpal::default_host().input_devices().map_err(|e| format!("cannot list microphones: {}", e))?;
+    Ok(input_devices.map(|input_device| input_device.name().unwrap_or_else(|_| "unknown".to_string())).collect())
+}
+
+impl CpalMicrophone {
+    /// Open the selected microphone, or the default one. Fewer channels or another sample rate are used if the device
+    /// doesn't support the requested ones.
+    pub(crate) fn open(audio_input_device: Option<&AudioDeviceSelector>, channels: u16, sample_rate: u32, frame_length: usize) -> Result<Self, String> {
+        let input_device = match audio_input_device {
+            Some(audio_input_device) => {
+                let device_index = audio_input_device.find(&input_device_names()?)?;
+                cpal::default_host().input_devices()
+                    .map_err(|e| format!("cannot list microphones: {}", e))?
+                    .nth(device_index)
+                    .ok_or_else(|| format!("microphone {} was disconnected", audio_input_device))?
+            },
+            None => cpal::default_host().default_input_device().ok_or("no default microphone")?,
+        };
+        let device_name = input_device.name().unwrap_or_else(|_| "unknown".to_string());
+        let supported_configs: Vec<SupportedStreamConfigRange> = input_device.supported_input_configs()
+            .map_err(|e| format!("cannot query microphone {} formats: {}", device_name, e))?
+            .collect();
+        let supported_config = nearest_supported_config(&supported_configs, channels, sample_rate)
+            .ok_or_else(|| format!("microphone {} has no supported format", device_name))?;
+        let stream_config: StreamConfig = supported_config.config();
+        if (stream_config.channels, stream_config.sample_rate.0) != (channels, sample_rate) {
+            warn!("Microphone {} doesn't support {} channels at {} Hz, using {} channels at {} Hz",
+                device_name, channels, sample_rate, stream_config.channels, stream_config.sample_rate.0);
+        }
+        info!("Recording from microphone {} ({} channels, {} Hz, {})", device_name, stream_config.channels, stream_config.sample_rate.0, supported_config.sample_format());
+
+        let (sample_sender, sample_receiver) = mpsc::channel();
+        let (control_sender, control_receiver) = mpsc::channel();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        let sample_format = supported_config.sample_format();
+        let thread_stream_config = stream_config.clone();
+        std::thread::spawn(move || {
+            let stream = match sample_format {
+                SampleFormat::I16 => build_input_stream::<i16>(&input_device, &thread_stream_config, sample_sender),
+                SampleFormat::I32 => build_input_stream::<i32>(&input_device, &thread_stream_config, sample_sender),
+                SampleFormat::U16 => build_input_stream::<u16>(&input_device, &thread_stream_config, sample_sender),
+                SampleFormat::F32 => build_input_stream::<f32>(&input_device, &thread_stream_config, sample_sender),
+                sample_format => Err(format!("unsupported sample format {}", sample_format)),
+            };
+            let stream = match stream {
+                Ok(stream) => {
+                    let _ = ready_sender.send(Ok(()));
+                    stream
+                },
+                Err(e) => {
+                    let _ = ready_sender.send(Err(e));
+                    return;
+                }
+            };
+            while let Ok(true) = control_receiver.recv() {
+                if let Err(e) = stream.play() {
+                    error!("Cannot start microphone: {}", e);
+                }
+            }
+        });
+        ready_receiver.recv().map_err(|_| "microphone thread stopped".to_string())??;
+
+        Ok(Self {
+            sample_receiver,
+            pending_samples: Vec::new(),
+            chunk_length: frame_length * stream_config.channels as usize,
+            channels: stream_config.channels,
+            sample_rate: stream_config.sample_rate.0,
+            control_sender,
+        })
+    }
+}
+
+/// Supported configuration nearest to the requested one, comparing channel counts first, then sample rates,
+/// 16 bits integer samples being preferred
+fn nearest_supported_config(supported_configs: &[SupportedStreamConfigRange], channels: u16, sample_rate: u32) -> Option<cpal::SupportedStreamConfig> {
+    let nearest_sample_rate = |supported_config: &SupportedStreamConfigRange| {
+        sample_rate.clamp(supported_config.min_sample_rate().0, supported_config.max_sample_rate().0)
+    };
+    supported_configs.iter()
+        .min_by_key(|supported_config| (
+            channels.abs_diff(supported_config.channels()),
+            sample_rate.abs_diff(nearest_sample_rate(supported_config)),
+            supported_config.sample_format() != SampleFormat::I16,
+        ))
+        .map(|supported_config| supported_config.clone().with_sample_rate(cpal::SampleRate(nearest_sample_rate(supported_config))))
+}
+
+fn build_input_stream<T>(input_device: &cpal::Device, stream_config: &StreamConfig, sample_sender: mpsc::Sender<Vec<i16>>) -> Result<cpal::Stream, String>
+where
+    T: SizedSample,
+    i16: FromSample<T>,
+{
+    input_device.build_input_stream(
+        stream_config,
+        move |samples: &[T], _: &cpal::InputCallbackInfo| {
+            let _ = sample_sender.send(samples.iter().map(|sample| i16::from_sample_(*sample)).collect());
+        },
+        |e| error!("Microphone error: {}", e),
+        None,
+    ).map_err(|e| format!("cannot open microphone stream: {}", e))
+}
+
+impl AudioSource for CpalMicrophone {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        self.control_sender.send(true).map_err(|_| "microphone thread stopped".to_string())
+    }
+
+    fn read(&mut self) -> Result<Vec<i16>, String> {
+        while self.pending_samples.len() < self.chunk_length {
+            let mut samples = self.sample_receiver.recv().map_err(|_| "microphone stream stopped".to_string())?;
+            self.pending_samples.append(&mut samples);
+        }
+        Ok(self.pending_samples.drain(..self.chunk_length).collect())
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        let _ = self.control_sender.send(false);
+        Ok(())
+    }
+}
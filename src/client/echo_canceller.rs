@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::{debug, info, warn};
+use qkd_camera_common_lib::echo_reference::{self, EchoReferencePacket, MAX_ECHO_REFERENCE_PACKET_SIZE};
+use qkd_camera_common_lib::resampler::Resampler;
+use crate::json_client_config::MAX_ECHO_FILTER_LENGTH;
+
+/// NLMS adaptation step, between 0 and 2, smaller being slower but more stable
+const ADAPTATION_STEP: f32 = 0.3;
+/// Avoids dividing by zero when the reference is silent
+const REGULARIZATION: f32 = 1e3;
+/// The microphone being louder than this fraction of the reference peak means the local participant is talking
+const DOUBLE_TALK_THRESHOLD: f32 = 0.5;
+/// How long adaptation stays frozen after double talk
+const DOUBLE_TALK_HANGOVER_MS: u32 = 100;
+/// How often the receiving thread checks whether it has to stop
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Removes from the captured sound the echo of the sound played by the server next to the client, with one NLMS adaptive
+/// filter per channel. The played sound is received over UDP, as sent by the server, converted to mono at the capture rate.
+pub(crate) struct EchoCanceller {
+    channels: usize,
+    /// Reference samples received and not used yet, oldest first
+    pending_reference: Arc<Mutex<VecDeque<f32>>>,
+    stop_receiving: Arc<AtomicBool>,
+    /// Last reference samples stored twice, so that the newest `filter_length` ones are always contiguous, newest first
+    reference_history: Vec<f32>,
+    reference_history_position: usize,
+    reference_energy: f32,
+    /// Decreasing absolute values of the reference window, with their position, so that its peak is the first one
+    reference_peaks: VecDeque<(u64, f32)>,
+    reference_position: u64,
+    /// Filter coefficients of each channel
+    filters: Vec<Vec<f32>>,
+    double_talk_hangover_length: usize,
+    double_talk_hangover: usize,
+}
+
+impl EchoCanceller {
+    /// Listen for the played sound on `reference_address`, cancelling echoes up to `tail_ms` after it's played
+    pub(crate) fn new(reference_address: &str, tail_ms: u32, channels: u16, sample_rate: u32) -> Result<Self, String> {
+        let reference_address = echo_reference::resolve_loopback_address(reference_address)?;
+        let socket = UdpSocket::bind(reference_address).map_err(|e| format!("cannot listen for echo reference on {}: {}", reference_address, e))?;
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT)).map_err(|e| format!("cannot configure echo reference socket: {}", e))?;
+        info!("Listening for echo reference on {}", reference_address);
+        let filter_length = (sample_rate as usize * tail_ms as usize / 1000).max(1);
+        if filter_length > MAX_ECHO_FILTER_LENGTH as usize {
+            return Err(format!("echo tail of {} ms is too long at {} Hz, at most {} samples are supported", tail_ms, sample_rate, MAX_ECHO_FILTER_LENGTH));
+        }
+        let echo_canceller = Self::with_filter_length(filter_length, channels, sample_rate);
+        let thread_pending_reference = echo_canceller.pending_reference.clone();
+        let thread_stop_receiving = echo_canceller.stop_receiving.clone();
+        std::thread::spawn(move || receive_reference(socket, sample_rate, filter_length, thread_pending_reference, thread_stop_receiving));
+        Ok(echo_canceller)
+    }
+
+    fn with_filter_length(filter_length: usize, channels: u16, sample_rate: u32) -> Self {
+        Self {
+            channels: channels as usize,
+            pending_reference: Arc::new(Mutex::new(VecDeque::new())),
+            stop_receiving: Arc::new(AtomicBool::new(false)),
+            reference_history: vec![0.0; filter_length * 2],
+            reference_history_position: 0,
+            reference_energy: 0.0,
+            reference_peaks: VecDeque::new(),
+            reference_position: 0,
+            filters: vec![vec![0.0; filter_length]; channels as usize],
+            double_talk_hangover_length: (sample_rate * DOUBLE_TALK_HANGOVER_MS / 1000) as usize,
+            double_talk_hangover: 0,
+        }
+    }
+
+    /// Subtract the estimated echo from interleaved samples, in place
+    pub(crate) fn process(&mut self, samples: &mut [f32]) {
+        let frames = samples.len() / self.channels;
+        let reference: Vec<f32> = {
+            let mut pending_reference = self.pending_reference.lock().unwrap();
+            let available_frames = frames.min(pending_reference.len());
+            pending_reference.drain(..available_frames).chain(std::iter::repeat(0.0)).take(frames).collect()
+        };
+        let filter_length = self.filters[0].len();
+        for (frame, reference_sample) in samples.chunks_exact_mut(self.channels).zip(reference) {
+            let oldest_sample = self.reference_history[self.reference_history_position + filter_length - 1];
+            self.reference_history_position = (self.reference_history_position + filter_length - 1) % filter_length;
+            self.reference_history[self.reference_history_position] = reference_sample;
+            self.reference_history[self.reference_history_position + filter_length] = reference_sample;
+            self.reference_energy = (self.reference_energy + reference_sample * reference_sample - oldest_sample * oldest_sample).max(0.0);
+            let reference_peak = self.update_reference_peak(reference_sample, filter_length);
+            let reference_window = &self.reference_history[self.reference_history_position..self.reference_history_position + filter_length];
+
+            if frame.iter().any(|sample| sample.abs() > DOUBLE_TALK_THRESHOLD * reference_peak) {
+                self.double_talk_hangover = self.double_talk_hangover_length;
+            } else {
+                self.double_talk_hangover = self.double_talk_hangover.saturating_sub(1);
+            }
+            for (sample, filter) in frame.iter_mut().zip(self.filters.iter_mut()) {
+                let echo_estimate: f32 = filter.iter().zip(reference_window).map(|(coefficient, reference)| coefficient * reference).sum();
+                let error = *sample - echo_estimate;
+                if self.double_talk_hangover == 0 {
+                    let step = ADAPTATION_STEP * error / (self.reference_energy + REGULARIZATION);
+                    for (coefficient, reference) in filter.iter_mut().zip(reference_window) {
+                        *coefficient += step * reference;
+                    }
+                }
+                *sample = error;
+            }
+        }
+    }
+
+    /// Add a sample to the reference window and return the window peak, in constant amortized time
+    fn update_reference_peak(&mut self, reference_sample: f32, filter_length: usize) -> f32 {
+        let magnitude = reference_sample.abs();
+        while self.reference_peaks.back().is_some_and(|(_, peak_magnitude)| *peak_magnitude <= magnitude) {
+            self.reference_peaks.pop_back();
+        }
+        self.reference_peaks.push_back((self.reference_position, magnitude));
+        while self.reference_peaks.front().is_some_and(|(position, _)| position + filter_length as u64 <= self.reference_position) {
+            self.reference_peaks.pop_front();
+        }
+        self.reference_position += 1;
+        self.reference_peaks.front().map_or(0.0, |(_, peak_magnitude)| *peak_magnitude)
+    }
+}
+
+impl Drop for EchoCanceller {
+    fn drop(&mut self) {
+        self.stop_receiving.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Receive the played sound, keeping at most `max_pending_length` samples so that the echo stays within the filter
+fn receive_reference(socket: UdpSocket, sample_rate: u32, max_pending_length: usize, pending_reference: Arc<Mutex<VecDeque<f32>>>, stop_receiving: Arc<AtomicBool>) {
+    let mut packet_buffer = vec![0u8; MAX_ECHO_REFERENCE_PACKET_SIZE];
+    let mut resampler: Option<Resampler> = None;
+    while !stop_receiving.load(Ordering::Relaxed) {
+        let packet_size = match socket.recv_from(&mut packet_buffer) {
+            // Only the server running on the same machine sends the played sound
+            Ok((_, source_address)) if !source_address.ip().is_loopback() => {
+                debug!("Echo reference from {} ignored, not a loopback address", source_address);
+                continue;
+            },
+            Ok((packet_size, _)) => packet_size,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(e) => {
+                warn!("Error receiving echo reference, echo cancellation disabled: {}", e);
+                return;
+            }
+        };
+        let packet: EchoReferencePacket = match postcard::from_bytes(&packet_buffer[..packet_size]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Invalid echo reference packet: {}", e);
+                continue;
+            }
+        };
+        if packet.channels == 0 || packet.sample_rate == 0 {
+            debug!("Invalid echo reference format: {} channels at {} Hz", packet.channels, packet.sample_rate);
+            continue;
+        }
+        let mono_samples: Vec<i16> = packet.samples.chunks_exact(packet.channels as usize)
+            .map(|frame| (frame.iter().map(|sample| *sample as i32).sum::<i32>() / frame.len() as i32) as i16)
+            .collect();
+        if !resampler.as_ref().is_some_and(|resampler| resampler.accepts(1, packet.sample_rate)) {
+            resampler = Some(Resampler::new(1, packet.sample_rate, sample_rate));
+        }
+        let mono_samples = resampler.as_mut().unwrap().resample(mono_samples);
+        let mut pending_reference = pending_reference.lock().unwrap();
+        pending_reference.extend(mono_samples.iter().map(|sample| *sample as f32));
+        let excess_length = pending_reference.len().saturating_sub(max_pending_length);
+        pending_reference.drain(..excess_length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic white noise between -8000 and 8000
+    fn noise(length: usize) -> Vec<f32> {
+        let mut state = 12345u32;
+        (0..length).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            ((state >> 16) % 16001) as f32 - 8000.0
+        }).collect()
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|sample| sample * sample).sum()
+    }
+
+    #[test]
+    fn synthetic_echo_is_cancelled() {
+        const ECHO_DELAY: usize = 10;
+        const CHUNK_LENGTH: usize = 160;
+        let mut echo_canceller = EchoCanceller::with_filter_length(32, 1, 16000);
+        let reference = noise(50 * CHUNK_LENGTH);
+        let echo: Vec<f32> = std::iter::repeat_n(0.0, ECHO_DELAY).chain(reference.iter().map(|sample| sample * 0.4)).take(reference.len()).collect();
+        let mut residual_echo = Vec::new();
+        for (reference_chunk, echo_chunk) in reference.chunks(CHUNK_LENGTH).zip(echo.chunks(CHUNK_LENGTH)) {
+            echo_canceller.pending_reference.lock().unwrap().extend(reference_chunk);
+            let mut samples = echo_chunk.to_vec();
+            echo_canceller.process(&mut samples);
+            residual_echo.push(samples);
+        }
+        // Attenuated by more than 30 dB once converged
+        let last_chunk = reference.len() / CHUNK_LENGTH - 1;
+        assert!(energy(&residual_echo[last_chunk]) < energy(&echo[last_chunk * CHUNK_LENGTH..]) / 1000.0);
+    }
+
+    #[test]
+    fn missing_reference_leaves_the_sound_unchanged() {
+        let mut echo_canceller = EchoCanceller::with_filter_length(32, 2, 16000);
+        let captured = noise(320);
+        let mut samples = captured.clone();
+        echo_canceller.process(&mut samples);
+        assert_eq!(samples, captured);
+    }
+
+    #[test]
+    fn reference_peak_follows_the_window() {
+        let mut echo_canceller = EchoCanceller::with_filter_length(3, 1, 16000);
+        let peaks: Vec<f32> = [5.0, -2.0, 1.0, 3.0, 0.0, 0.0, 0.0].iter()
+            .map(|sample| echo_canceller.update_reference_peak(*sample, 3))
+            .collect();
+        assert_eq!(peaks, vec![5.0, 5.0, 5.0, 3.0, 3.0, 3.0, 0.0]);
+    }
+}
@@ -1,28 +1,302 @@
+use std::ops::RangeInclusive;
+use schemars::JsonSchema;
 use serde::Deserialize;
+use qkd_camera_common_lib::audio_device::AudioDeviceSelector;
+use qkd_camera_common_lib::config_validation::{ConfigValidator, ValidateConfig};
+use qkd_camera_common_lib::echo_reference;
+use qkd_camera_common_lib::secret_source::SecretSource;
+use qkd_camera_common_lib::security_policy::SecurityPolicy;
+use crate::audio_source;
 
 pub(crate) const DEFAULT_CAMERA_DEVICE_NAME: &'static str = "/dev/video0";
 pub(crate) const DEFAULT_CAMERA_FPS: u32 = 30;
 /// How many audio frames og length 512 to accumulate before sending them to the server
 pub(crate) const DEFAULT_AUDIO_FRAME_ACCUMULATOR_LENGTH: usize = 2;
+/// How long to wait for the remote participant to accept the call, should be longer than the server prompt timeout
+pub(crate) const DEFAULT_CALL_ANSWER_TIMEOUT_SECS: u64 = 45;
+pub(crate) const DEFAULT_AUDIO_CHANNELS: u16 = 1;
+pub(crate) const DEFAULT_AUDIO_SAMPLE_RATE: u32 = 16000;
+/// How long after being played an echo can still be cancelled
+pub(crate) const DEFAULT_ECHO_TAIL_MS: u32 = 100;
 
-#[derive(Debug, Deserialize)]
+pub(crate) const VIDEO_JPEG_QUALITY_RANGE: RangeInclusive<i32> = 1..=100;
+pub(crate) const CAMERA_FPS_RANGE: RangeInclusive<u32> = 1..=240;
+pub(crate) const CAMERA_WIDTH_RANGE: RangeInclusive<u32> = 1..=7680;
+pub(crate) const CAMERA_HEIGHT_RANGE: RangeInclusive<u32> = 1..=4320;
+pub(crate) const AUDIO_FRAME_ACCUMULATOR_LENGTH_RANGE: RangeInclusive<usize> = 1..=64;
+pub(crate) const AUDIO_CHANNELS_RANGE: RangeInclusive<u16> = 1..=2;
+pub(crate) const AUDIO_SAMPLE_RATE_RANGE: RangeInclusive<u32> = 8000..=192000;
+pub(crate) const ECHO_TAIL_MS_RANGE: RangeInclusive<u32> = 10..=500;
+/// Longest echo cancellation filter, in samples, as each sample is multiplied by each coefficient twice per channel
+pub(crate) const MAX_ECHO_FILTER_LENGTH: u32 = 8000;
+/// X11 screen coordinates and sizes are 16 bits signed, respectively unsigned, integers
+pub(crate) const SCREEN_COORDINATE_RANGE: RangeInclusive<u32> = 0..=i16::MAX as u32;
+pub(crate) const SCREEN_SIZE_RANGE: RangeInclusive<u32> = 1..=u16::MAX as u32;
+pub(crate) const DEFAULT_PIP_SIZE_PERCENT: u32 = 25;
+pub(crate) const PIP_SIZE_PERCENT_RANGE: RangeInclusive<u32> = 10..=50;
+
+/// Longest echo tail that can be cancelled at `sample_rate`, within [ECHO_TAIL_MS_RANGE]
+pub(crate) fn max_echo_tail_ms(sample_rate: u32) -> u32 {
+    (MAX_ECHO_FILTER_LENGTH as u64 * 1000 / sample_rate.max(1) as u64).clamp(*ECHO_TAIL_MS_RANGE.start() as u64, *ECHO_TAIL_MS_RANGE.end() as u64) as u32
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct JsonClientConfig {
     pub(crate) kme_address: String,
     pub(crate) kme_authentication_certificate_path: String,
-    pub(crate) kme_authentication_certificate_password: String,
+    pub(crate) kme_authentication_certificate_password: SecretSource,
     pub(crate) target_sae_host: String,
     pub(crate) target_sae_port: u16,
     pub(crate) target_sae_id: i64,
+    /// SAE ID of this client, announced to the server for access control
+    pub(crate) origin_sae_id: i64,
+    /// DER certificate of this SAE, proving `origin_sae_id` to the servers knowing its fingerprint
+    pub(crate) sae_certificate_path: Option<String>,
+    /// PKCS#8 DER private key of `sae_certificate_path`
+    pub(crate) sae_private_key_path: Option<String>,
     pub(crate) danger_accept_invalid_kme_cert: bool, // TODO audio frame length too for lag ?
+    /// Write TLS secrets to $SSLKEYLOGFILE, for debugging only
+    #[serde(default)]
+    pub(crate) danger_enable_key_log: bool,
+    #[serde(default)]
+    pub(crate) security_policy: SecurityPolicy,
+    /// DER certificate of the server's CA, trusted when falling back to non-QKD TLS
+    pub(crate) fallback_root_certificate_path: Option<String>,
     pub(crate) override_default_format: Option<JsonCameraFormatConfig>,
     pub(crate) override_default_camera_fps: Option<u32>,
     pub(crate) override_default_video_jpeg_quality: Option<i32>,
     pub(crate) override_default_camera_device: Option<String>,
-    pub(crate) override_default_audio_frame_accumulator_length: Option<usize>
+    /// Microphone, by index or name as printed by `list-audio-devices`, the default one if missing
+    pub(crate) audio_input_device: Option<AudioDeviceSelector>,
+    /// 1 for mono or 2 for stereo, other values than mono at 16 kHz recording through cpal instead of PvRecorder
+    pub(crate) override_default_audio_channels: Option<u16>,
+    pub(crate) override_default_audio_sample_rate: Option<u32>,
+    #[serde(default)]
+    pub(crate) audio_processing: JsonAudioProcessingConfig,
+    pub(crate) override_default_audio_frame_accumulator_length: Option<usize>,
+    pub(crate) override_default_call_answer_timeout_secs: Option<u64>,
+    /// Send the JPEG frames of MJPEG cameras without decoding and compressing them again
+    #[serde(default)]
+    pub(crate) mjpeg_passthrough: bool,
+    /// Show the captured video in a window, whose keys mute the sound (M), turn the video off (V), switch between the
+    /// camera and the screen (S) and talk (space)
+    #[serde(default)]
+    pub(crate) preview_window: bool,
+    /// Show the video of the other participants in a grid window when the server relays a conference, their sound
+    /// being played in any case
+    #[serde(default)]
+    pub(crate) participants_window: bool,
+    /// Only send sound while the space key is held in the preview window
+    #[serde(default)]
+    pub(crate) push_to_talk: bool,
+    /// File the chat messages are appended to, not recorded if missing
+    pub(crate) chat_transcript_path: Option<String>,
+    /// Directory the files sent by the server are written to, they are refused if missing
+    pub(crate) download_directory: Option<String>,
+    /// Screen the video can be switched to during a call, screen sharing is unavailable if missing
+    pub(crate) screen_share: Option<JsonScreenShareConfig>,
+    /// Additional sources composed with the camera into each sent frame
+    pub(crate) composition: Option<JsonCompositionConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct JsonCameraFormatConfig {
     pub(crate) width: u32,
     pub(crate) height: u32
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonScreenShareConfig {
+    /// X11 display, eg ":0", $DISPLAY if missing
+    pub(crate) display: Option<String>,
+    /// Captured part of the screen, the whole screen if missing
+    pub(crate) region: Option<JsonScreenRegionConfig>,
+    /// The captured image is downscaled, keeping its aspect ratio, to fit in this format
+    pub(crate) max_format: Option<JsonCameraFormatConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonCompositionConfig {
+    /// Composed with the camera, or with the screen while it's shared, in this order
+    pub(crate) sources: Vec<JsonCompositionSourceConfig>,
+    #[serde(default)]
+    pub(crate) layout: CompositionLayout,
+    #[serde(default)]
+    pub(crate) pip_corner: PipCorner,
+    /// Width of each picture-in-picture inset, in percent of the main image width
+    pub(crate) override_default_pip_size_percent: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum JsonCompositionSourceConfig {
+    /// Another camera, in its default format
+    Camera { device: String },
+    /// The screen configured in `screen_share`
+    Screen,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CompositionLayout {
+    /// The additional sources are small insets stacked in a corner of the main image
+    #[default]
+    PictureInPicture,
+    /// The additional sources are placed right of the main image, scaled to its height
+    SideBySide,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PipCorner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+/// Rectangle of the screen, in pixels from its top left corner
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonScreenRegionConfig {
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+/// Processing of the captured sound, everything being disabled by default
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonAudioProcessingConfig {
+    /// Cancel the echo of the sound played by a server running on the same machine, which must set `echo_reference_address` too
+    #[serde(default)]
+    pub(crate) echo_cancellation: bool,
+    /// Where to receive the played sound from the server, 127.0.0.1:14600 if missing
+    pub(crate) echo_reference_address: Option<String>,
+    pub(crate) override_default_echo_tail_ms: Option<u32>,
+    #[serde(default)]
+    pub(crate) noise_suppression: bool,
+    #[serde(default)]
+    pub(crate) automatic_gain_control: bool,
+}
+
+impl ValidateConfig for JsonClientConfig {
+    fn validate(&self, validator: &mut ConfigValidator) {
+        validator.check(!self.kme_address.is_empty(), "$.kme_address", "must not be empty");
+        validator.check_file_exists("$.kme_authentication_certificate_path", &self.kme_authentication_certificate_path);
+        if let Err(e) = self.kme_authentication_certificate_password.resolve() {
+            validator.problem("$.kme_authentication_certificate_password", e);
+        }
+        validator.check(!self.target_sae_host.is_empty(), "$.target_sae_host", "must not be empty");
+        validator.check(self.target_sae_port != 0, "$.target_sae_port", "must not be 0");
+        validator.check(self.target_sae_id != self.origin_sae_id, "$.target_sae_id", "must be different from origin_sae_id");
+        match (self.sae_certificate_path.as_ref(), self.sae_private_key_path.as_ref()) {
+            (Some(sae_certificate_path), Some(sae_private_key_path)) => {
+                validator.check_file_exists("$.sae_certificate_path", sae_certificate_path);
+                validator.check_file_exists("$.sae_private_key_path", sae_private_key_path);
+            },
+            (None, None) => {},
+            (Some(_), None) => validator.problem("$.sae_private_key_path", "must be set along with sae_certificate_path"),
+            (None, Some(_)) => validator.problem("$.sae_certificate_path", "must be set along with sae_private_key_path"),
+        }
+        if let Some(fallback_root_certificate_path) = self.fallback_root_certificate_path.as_ref() {
+            validator.check_file_exists("$.fallback_root_certificate_path", fallback_root_certificate_path);
+        }
+        if let Some(override_default_format) = self.override_default_format.as_ref() {
+            validator.check_range("$.override_default_format.width", Some(override_default_format.width), *CAMERA_WIDTH_RANGE.start(), *CAMERA_WIDTH_RANGE.end());
+            validator.check_range("$.override_default_format.height", Some(override_default_format.height), *CAMERA_HEIGHT_RANGE.start(), *CAMERA_HEIGHT_RANGE.end());
+        }
+        validator.check_range("$.override_default_camera_fps", self.override_default_camera_fps, *CAMERA_FPS_RANGE.start(), *CAMERA_FPS_RANGE.end());
+        validator.check_range("$.override_default_video_jpeg_quality", self.override_default_video_jpeg_quality, *VIDEO_JPEG_QUALITY_RANGE.start(), *VIDEO_JPEG_QUALITY_RANGE.end());
+        let camera_device = self.override_default_camera_device.as_deref().unwrap_or(DEFAULT_CAMERA_DEVICE_NAME);
+        validator.check_file_exists("$.override_default_camera_device", camera_device);
+        validator.check_range("$.override_default_audio_channels", self.override_default_audio_channels, *AUDIO_CHANNELS_RANGE.start(), *AUDIO_CHANNELS_RANGE.end());
+        validator.check_range("$.override_default_audio_sample_rate", self.override_default_audio_sample_rate, *AUDIO_SAMPLE_RATE_RANGE.start(), *AUDIO_SAMPLE_RATE_RANGE.end());
+        if let Some(audio_input_device) = self.audio_input_device.as_ref() {
+            if let Err(e) = audio_source::microphone_names(self.audio_channels(), self.audio_sample_rate()).and_then(|microphone_names| audio_input_device.find(&microphone_names)) {
+                validator.problem("$.audio_input_device", e);
+            }
+        }
+        if let Some(echo_reference_address) = self.audio_processing.echo_reference_address.as_ref() {
+            if let Err(e) = echo_reference::resolve_loopback_address(echo_reference_address) {
+                validator.problem("$.audio_processing.echo_reference_address", e);
+            }
+        }
+        validator.check_range("$.audio_processing.override_default_echo_tail_ms", self.audio_processing.override_default_echo_tail_ms, *ECHO_TAIL_MS_RANGE.start(), max_echo_tail_ms(self.audio_sample_rate()));
+        validator.check_range("$.override_default_audio_frame_accumulator_length", self.override_default_audio_frame_accumulator_length, *AUDIO_FRAME_ACCUMULATOR_LENGTH_RANGE.start(), *AUDIO_FRAME_ACCUMULATOR_LENGTH_RANGE.end());
+        validator.check_range("$.override_default_call_answer_timeout_secs", self.override_default_call_answer_timeout_secs, 1, 3600);
+        validator.check(!self.push_to_talk || self.preview_window, "$.push_to_talk", "requires preview_window, where the push-to-talk key is held");
+        if let Some(screen_share) = self.screen_share.as_ref() {
+            if let Some(region) = screen_share.region.as_ref() {
+                validator.check_range("$.screen_share.region.x", Some(region.x), *SCREEN_COORDINATE_RANGE.start(), *SCREEN_COORDINATE_RANGE.end());
+                validator.check_range("$.screen_share.region.y", Some(region.y), *SCREEN_COORDINATE_RANGE.start(), *SCREEN_COORDINATE_RANGE.end());
+                validator.check_range("$.screen_share.region.width", Some(region.width), *SCREEN_SIZE_RANGE.start(), *SCREEN_SIZE_RANGE.end());
+                validator.check_range("$.screen_share.region.height", Some(region.height), *SCREEN_SIZE_RANGE.start(), *SCREEN_SIZE_RANGE.end());
+            }
+            if let Some(max_format) = screen_share.max_format.as_ref() {
+                validator.check_range("$.screen_share.max_format.width", Some(max_format.width), *CAMERA_WIDTH_RANGE.start(), *CAMERA_WIDTH_RANGE.end());
+                validator.check_range("$.screen_share.max_format.height", Some(max_format.height), *CAMERA_HEIGHT_RANGE.start(), *CAMERA_HEIGHT_RANGE.end());
+            }
+        }
+        if let Some(composition) = self.composition.as_ref() {
+            validator.check(!composition.sources.is_empty(), "$.composition.sources", "must not be empty");
+            for (source_index, source) in composition.sources.iter().enumerate() {
+                match source {
+                    JsonCompositionSourceConfig::Camera { device } => validator.check_file_exists(&format!("$.composition.sources[{}].camera.device", source_index), device),
+                    JsonCompositionSourceConfig::Screen => validator.check(self.screen_share.is_some(), &format!("$.composition.sources[{}]", source_index), "requires screen_share"),
+                }
+            }
+            validator.check_range("$.composition.override_default_pip_size_percent", composition.override_default_pip_size_percent, *PIP_SIZE_PERCENT_RANGE.start(), *PIP_SIZE_PERCENT_RANGE.end());
+        }
+    }
+}
+impl JsonClientConfig {
+    pub(crate) fn audio_channels(&self) -> u16 {
+        self.override_default_audio_channels.unwrap_or(DEFAULT_AUDIO_CHANNELS)
+    }
+
+    pub(crate) fn audio_sample_rate(&self) -> u32 {
+        self.override_default_audio_sample_rate.unwrap_or(DEFAULT_AUDIO_SAMPLE_RATE)
+    }
+}
+
+/// Configuration template written by the `gen-config` subcommand
+pub(crate) fn config_template() -> serde_json::Value {
+    serde_json::json!({
+        "kme_address": "localhost:13000",
+        "kme_authentication_certificate_path": "data/sae1.pfx",
+        "kme_authentication_certificate_password": "",
+        "target_sae_host": "localhost",
+        "target_sae_port": 14443,
+        "target_sae_id": 3,
+        "origin_sae_id": 1,
+        "danger_accept_invalid_kme_cert": false,
+        "security_policy": "qkd_required",
+        "override_default_format": {
+            "width": 640,
+            "height": 480
+        },
+        "override_default_camera_fps": DEFAULT_CAMERA_FPS,
+        "override_default_video_jpeg_quality": 25,
+        "override_default_camera_device": DEFAULT_CAMERA_DEVICE_NAME,
+        "override_default_audio_channels": DEFAULT_AUDIO_CHANNELS,
+        "override_default_audio_sample_rate": DEFAULT_AUDIO_SAMPLE_RATE,
+        "audio_processing": {
+            "echo_cancellation": false,
+            "noise_suppression": true,
+            "automatic_gain_control": true
+        },
+        "override_default_audio_frame_accumulator_length": DEFAULT_AUDIO_FRAME_ACCUMULATOR_LENGTH,
+        "override_default_call_answer_timeout_secs": DEFAULT_CALL_ANSWER_TIMEOUT_SECS,
+        "mjpeg_passthrough": false,
+        "preview_window": false,
+        "participants_window": false,
+        "push_to_talk": false
+    })
+}
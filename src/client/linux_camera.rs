@@ -1,53 +1,128 @@
-use image::{ImageBuffer, Rgb};
-use v4l::Device;
-use crate::camera::Camera;
-use simple_image_interface::simple_image_interface::SimpleImageInterface;
+use log::{info, warn};
+use v4l::{Device, Format, FourCC};
+use v4l::buffer::Type;
+use v4l::io::mmap::Stream;
+use v4l::io::traits::CaptureStream;
 use v4l::video::Capture;
+use v4l::video::capture::Parameters;
+use crate::camera::{Camera, CameraFrame};
 use crate::json_client_config::{DEFAULT_CAMERA_DEVICE_NAME, DEFAULT_CAMERA_FPS, JsonClientConfig};
+use crate::{pixel_format, v4l_capabilities};
+
+/// Number of buffers shared with the V4L2 driver
+const CAPTURE_BUFFER_COUNT: u32 = 4;
 
 pub(crate) struct LinuxCamera {
-    interface: SimpleImageInterface,
-    webcam_width: u32,
-    webcam_height: u32
+    stream: Option<Stream<'static>>,
+    camera_device: String,
+    /// Forward the JPEG frames of MJPEG cameras instead of decoding them
+    mjpeg_passthrough: bool,
+    /// Format negotiated with the driver, in which the captured frames are
+    format: Format,
 }
 
 impl LinuxCamera {
-    fn get_webcam_format(device_name: &str) -> (u32, u32) {
-        let dev = Device::with_path(device_name);
-        let format = dev.unwrap().format().unwrap();
-        (format.width, format.height)
+    fn pixel_formats(&self) -> [FourCC; 4] {
+        if self.mjpeg_passthrough {
+            pixel_format::PASSTHROUGH_PIXEL_FORMATS
+        } else {
+            pixel_format::SUPPORTED_PIXEL_FORMATS
+        }
     }
-}
 
-impl Camera for LinuxCamera {
-
-    fn new(client_config: &JsonClientConfig) -> Self {
-        let interface: SimpleImageInterface;
-        let (webcam_width, webcam_height) = match client_config.override_default_format.as_ref() {
-            Some(override_default_format) => {
-                (override_default_format.width, override_default_format.height)
+    /// Mode supported by the device nearest to the requested one, the requested one is kept with the current pixel format
+    /// if the device capabilities can't be queried
+    fn supported_mode(&self, device: &Device, width: u32, height: u32, fps: u32) -> Result<(Format, u32), String> {
+        let current_format = device.format().map_err(|e| format!("cannot read camera {} format: {}", self.camera_device, e))?;
+        let nearest_mode = match v4l_capabilities::query_camera_capabilities(&self.camera_device) {
+            Ok(capabilities) => capabilities.nearest_mode(width, height, fps, &self.pixel_formats()),
+            Err(e) => {
+                warn!("Cannot query camera {} capabilities: {}, using {}x{} at {} fps", self.camera_device, e, width, height, fps);
+                return Ok((Format::new(width, height, current_format.fourcc), fps));
+            }
+        };
+        match nearest_mode {
+            Some(mode) => {
+                if (mode.width, mode.height, mode.fps) != (width, height, fps) {
+                    warn!("Camera {} doesn't support {}x{} at {} fps, using nearest mode {}x{} at {} fps ({})",
+                        self.camera_device, width, height, fps, mode.width, mode.height, mode.fps, mode.fourcc);
+                }
+                Ok((Format::new(mode.width, mode.height, mode.fourcc), mode.fps))
             },
+            None => Err(format!("camera {} supports none of the pixel formats {:?}", self.camera_device,
+                self.pixel_formats().iter().map(|pixel_format| pixel_format.to_string()).collect::<Vec<String>>())),
+        }
+    }
+
+    /// Open the camera device with the given format, or its current one if none is given
+    fn open(&mut self, camera_format: Option<(u32, u32)>, camera_fps: u32) -> Result<(), String> {
+        // The device has to be released before being opened again
+        self.stream = None;
+        let device = Device::with_path(&self.camera_device).map_err(|e| format!("cannot open camera {}: {}", self.camera_device, e))?;
+        let (width, height) = match camera_format {
+            Some(camera_format) => camera_format,
             None => {
-                Self::get_webcam_format(DEFAULT_CAMERA_DEVICE_NAME)
+                let current_format = device.format().map_err(|e| format!("cannot read camera {} format: {}", self.camera_device, e))?;
+                (current_format.width, current_format.height)
             }
         };
+        let (requested_format, camera_fps) = self.supported_mode(&device, width, height, camera_fps)?;
+        let format = device.set_format(&requested_format).map_err(|e| format!("cannot set camera {} format: {}", self.camera_device, e))?;
+        if (format.width, format.height, format.fourcc) != (requested_format.width, requested_format.height, requested_format.fourcc) {
+            warn!("Camera {} driver chose {}x{} ({}) instead of {}x{} ({})", self.camera_device, format.width, format.height, format.fourcc,
+                requested_format.width, requested_format.height, requested_format.fourcc);
+        }
+        if let Err(e) = device.set_params(&Parameters::with_fps(camera_fps)) {
+            warn!("Cannot set camera {} frame rate to {} fps: {}", self.camera_device, camera_fps, e);
+        }
+        let stream = Stream::with_buffers(&device, Type::VideoCapture, CAPTURE_BUFFER_COUNT)
+            .map_err(|e| format!("cannot start camera {} capture: {}", self.camera_device, e))?;
+        info!("Camera {} capturing {}x{} ({}) at {} fps", self.camera_device, format.width, format.height, format.fourcc, camera_fps);
+        self.stream = Some(stream);
+        self.format = format;
+        Ok(())
+    }
+}
+
+impl Camera for LinuxCamera {
+
+    fn new(client_config: &JsonClientConfig) -> Result<Self, String> {
+        let camera_format = client_config.override_default_format.as_ref()
+            .map(|override_default_format| (override_default_format.width, override_default_format.height));
         let camera_fps = client_config.override_default_camera_fps.unwrap_or_else(|| DEFAULT_CAMERA_FPS);
         let camera_device = match client_config.override_default_camera_device.as_ref() {
             Some(override_default_camera_device) => override_default_camera_device.as_str(),
             None => DEFAULT_CAMERA_DEVICE_NAME
         };
-        interface = SimpleImageInterface::new_camera(camera_device, webcam_width, webcam_height, camera_fps);
-        Self{
-            interface,
-            webcam_width,
-            webcam_height
-        }
+        let mut camera = Self{
+            stream: None,
+            camera_device: camera_device.to_string(),
+            mjpeg_passthrough: client_config.mjpeg_passthrough,
+            format: Format::new(0, 0, pixel_format::RGB3),
+        };
+        camera.open(camera_format, camera_fps)?;
+        Ok(camera)
     }
 
-    fn get_frame(&mut self) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-        let input_image = self.interface.get_frame().unwrap();
+    fn get_frame(&mut self) -> Result<CameraFrame, String> {
+        let stream = self.stream.as_mut().ok_or_else(|| format!("camera {} is not open", self.camera_device))?;
+        let (buffer, metadata) = stream.next().map_err(|e| format!("cannot capture camera {} frame: {}", self.camera_device, e))?;
+        // Some drivers don't report the used size
+        let frame = match metadata.bytesused as usize {
+            0 => buffer,
+            bytes_used => &buffer[..bytes_used.min(buffer.len())],
+        };
+        if self.mjpeg_passthrough && self.format.fourcc == pixel_format::MJPG {
+            return Ok(CameraFrame::Jpeg {
+                jpeg: frame.to_vec(),
+                width: self.format.width,
+                height: self.format.height,
+            });
+        }
+        pixel_format::convert_to_rgb(frame, &self.format).map(CameraFrame::Rgb)
+    }
 
-        let input_image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_raw(self.webcam_width, self.webcam_height, input_image.to_vec()).unwrap();
-        input_image
+    fn reconfigure(&mut self, camera_format: Option<(u32, u32)>, camera_fps: u32) -> Result<(), String> {
+        self.open(camera_format, camera_fps)
     }
-}
\ No newline at end of file
+}
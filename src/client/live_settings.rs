@@ -0,0 +1,372 @@
+use std::io::BufRead;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+use log::{info, warn};
+use qkd_camera_common_lib::{MediaState, VideoSource};
+use qkd_camera_common_lib::chat::ChatMessage;
+use qkd_camera_common_lib::file_transfer::OutgoingFile;
+use qkd_camera_common_lib::config_loader::ConfigArgs;
+use crate::json_client_config::{self, JsonClientConfig};
+
+/// How often the configuration file modification time is checked
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings that can be changed during a call, without renegotiating the QKD session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LiveSettings {
+    pub(crate) jpeg_quality: i32,
+    pub(crate) camera_fps: u32,
+    /// Camera format, `None` meaning the device default format
+    pub(crate) camera_format: Option<(u32, u32)>,
+    pub(crate) audio_frame_accumulator_length: usize,
+    /// Only send sound while the push-to-talk key is held
+    pub(crate) push_to_talk: bool,
+    /// Call state rather than configuration, kept when the configuration file changes
+    pub(crate) audio_muted: bool,
+    pub(crate) video_enabled: bool,
+    pub(crate) video_source: VideoSource,
+    /// Whether the push-to-talk key is held
+    pub(crate) talking: bool,
+}
+
+/// Settings used when the configuration overrides none of them
+impl Default for LiveSettings {
+    fn default() -> Self {
+        Self {
+            jpeg_quality: crate::DEFAULT_JPEG_COMPRESS_QUALITY,
+            camera_fps: json_client_config::DEFAULT_CAMERA_FPS,
+            camera_format: None,
+            audio_frame_accumulator_length: json_client_config::DEFAULT_AUDIO_FRAME_ACCUMULATOR_LENGTH,
+            push_to_talk: false,
+            audio_muted: false,
+            video_enabled: true,
+            video_source: VideoSource::Camera,
+            talking: false,
+        }
+    }
+}
+
+impl LiveSettings {
+    pub(crate) fn from_config(client_config: &JsonClientConfig) -> Self {
+        Self {
+            jpeg_quality: client_config.override_default_video_jpeg_quality.unwrap_or(crate::DEFAULT_JPEG_COMPRESS_QUALITY),
+            camera_fps: client_config.override_default_camera_fps.unwrap_or(json_client_config::DEFAULT_CAMERA_FPS),
+            camera_format: client_config.override_default_format.as_ref().map(|format| (format.width, format.height)),
+            audio_frame_accumulator_length: client_config.override_default_audio_frame_accumulator_length
+                .unwrap_or(json_client_config::DEFAULT_AUDIO_FRAME_ACCUMULATOR_LENGTH),
+            push_to_talk: client_config.push_to_talk,
+            ..Self::default()
+        }
+    }
+
+    /// What is sent to the server, the sound being muted unless the push-to-talk key is held when enabled
+    pub(crate) fn media_state(&self) -> MediaState {
+        MediaState {
+            audio_muted: self.audio_muted || (self.push_to_talk && !self.talking),
+            video_enabled: self.video_enabled,
+            video_source: self.video_source,
+        }
+    }
+}
+
+/// Something typed in the terminal for the remote participant
+pub(crate) enum PeerCommand {
+    Chat(ChatMessage),
+    SendFile(OutgoingFile),
+}
+
+/// A change of live settings, coming from the configuration file or from a control command
+#[derive(Debug)]
+pub(crate) enum LiveSettingsUpdate {
+    All(LiveSettings),
+    JpegQuality(i32),
+    CameraFps(u32),
+    CameraFormat(Option<(u32, u32)>),
+    AudioFrameAccumulatorLength(usize),
+    AudioMuted(bool),
+    VideoEnabled(bool),
+    ToggleAudioMuted,
+    ToggleVideo,
+    VideoSource(VideoSource),
+    ToggleVideoSource,
+    Talking(bool),
+}
+
+impl LiveSettingsUpdate {
+    pub(crate) fn apply(self, settings: &LiveSettings) -> LiveSettings {
+        let mut new_settings = settings.clone();
+        match self {
+            LiveSettingsUpdate::All(all_settings) => new_settings = LiveSettings {
+                audio_muted: settings.audio_muted,
+                video_enabled: settings.video_enabled,
+                video_source: settings.video_source,
+                talking: settings.talking,
+                ..all_settings
+            },
+            LiveSettingsUpdate::JpegQuality(jpeg_quality) => new_settings.jpeg_quality = jpeg_quality,
+            LiveSettingsUpdate::CameraFps(camera_fps) => new_settings.camera_fps = camera_fps,
+            LiveSettingsUpdate::CameraFormat(camera_format) => new_settings.camera_format = camera_format,
+            LiveSettingsUpdate::AudioFrameAccumulatorLength(length) => new_settings.audio_frame_accumulator_length = length,
+            LiveSettingsUpdate::AudioMuted(audio_muted) => new_settings.audio_muted = audio_muted,
+            LiveSettingsUpdate::VideoEnabled(video_enabled) => new_settings.video_enabled = video_enabled,
+            LiveSettingsUpdate::ToggleAudioMuted => new_settings.audio_muted = !settings.audio_muted,
+            LiveSettingsUpdate::ToggleVideo => new_settings.video_enabled = !settings.video_enabled,
+            LiveSettingsUpdate::VideoSource(video_source) => new_settings.video_source = video_source,
+            LiveSettingsUpdate::ToggleVideoSource => new_settings.video_source = match settings.video_source {
+                VideoSource::Camera => VideoSource::Screen,
+                VideoSource::Screen => VideoSource::Camera,
+            },
+            LiveSettingsUpdate::Talking(talking) => new_settings.talking = talking,
+        }
+        new_settings
+    }
+}
+
+/// Start watching the configuration file and the control commands typed on the standard input, sending their changes
+/// to `update_sender` and the chat messages and files to send to `peer_command_sender`. Changes that cannot be applied
+/// without reconnecting are reported and ignored.
+pub(crate) fn watch_live_settings(config_args: ConfigArgs, client_config: JsonClientConfig, update_sender: mpsc::Sender<LiveSettingsUpdate>, peer_command_sender: mpsc::Sender<PeerCommand>) {
+    let command_update_sender = update_sender.clone();
+    std::thread::spawn(move || watch_config_file(config_args, client_config, update_sender));
+    std::thread::spawn(move || read_control_commands(command_update_sender, peer_command_sender));
+}
+
+fn watch_config_file(config_args: ConfigArgs, initial_config: JsonClientConfig, update_sender: mpsc::Sender<LiveSettingsUpdate>) {
+    let modification_time = |config_args: &ConfigArgs| -> Option<SystemTime> {
+        std::fs::metadata(&config_args.config).and_then(|metadata| metadata.modified()).ok()
+    };
+    let mut last_modification_time = modification_time(&config_args);
+    loop {
+        std::thread::sleep(CONFIG_WATCH_INTERVAL);
+        let current_modification_time = modification_time(&config_args);
+        if current_modification_time == last_modification_time {
+            continue;
+        }
+        last_modification_time = current_modification_time;
+        let new_config: JsonClientConfig = match config_args.load(crate::CONFIG_ENV_PREFIX) {
+            Ok(new_config) => new_config,
+            Err(e) => {
+                warn!("Configuration {} changed but is invalid, ignored:\n{}", config_args.config.display(), e);
+                continue;
+            }
+        };
+        let reconnect_required_fields = reconnect_required_changes(&initial_config, &new_config);
+        if !reconnect_required_fields.is_empty() {
+            warn!("Changing {} requires reconnecting, ignored until the next call", reconnect_required_fields.join(", "));
+        }
+        info!("Configuration {} changed, applying live settings", config_args.config.display());
+        if update_sender.send(LiveSettingsUpdate::All(LiveSettings::from_config(&new_config))).is_err() {
+            return;
+        }
+    }
+}
+
+/// Names of the fields that changed but are only used when connecting
+fn reconnect_required_changes(initial_config: &JsonClientConfig, new_config: &JsonClientConfig) -> Vec<&'static str> {
+    let changes = [
+        ("kme_address", initial_config.kme_address != new_config.kme_address),
+        ("kme_authentication_certificate_path", initial_config.kme_authentication_certificate_path != new_config.kme_authentication_certificate_path),
+        ("kme_authentication_certificate_password", initial_config.kme_authentication_certificate_password != new_config.kme_authentication_certificate_password),
+        ("target_sae_host", initial_config.target_sae_host != new_config.target_sae_host),
+        ("target_sae_port", initial_config.target_sae_port != new_config.target_sae_port),
+        ("target_sae_id", initial_config.target_sae_id != new_config.target_sae_id),
+        ("origin_sae_id", initial_config.origin_sae_id != new_config.origin_sae_id),
+        ("sae_certificate_path", initial_config.sae_certificate_path != new_config.sae_certificate_path),
+        ("sae_private_key_path", initial_config.sae_private_key_path != new_config.sae_private_key_path),
+        ("danger_accept_invalid_kme_cert", initial_config.danger_accept_invalid_kme_cert != new_config.danger_accept_invalid_kme_cert),
+        ("danger_enable_key_log", initial_config.danger_enable_key_log != new_config.danger_enable_key_log),
+        ("security_policy", initial_config.security_policy != new_config.security_policy),
+        ("fallback_root_certificate_path", initial_config.fallback_root_certificate_path != new_config.fallback_root_certificate_path),
+        ("override_default_camera_device", initial_config.override_default_camera_device != new_config.override_default_camera_device),
+        ("audio_input_device", initial_config.audio_input_device != new_config.audio_input_device),
+        ("override_default_audio_channels", initial_config.audio_channels() != new_config.audio_channels()),
+        ("override_default_audio_sample_rate", initial_config.audio_sample_rate() != new_config.audio_sample_rate()),
+        ("audio_processing", initial_config.audio_processing != new_config.audio_processing),
+        ("override_default_call_answer_timeout_secs", initial_config.override_default_call_answer_timeout_secs != new_config.override_default_call_answer_timeout_secs),
+        ("mjpeg_passthrough", initial_config.mjpeg_passthrough != new_config.mjpeg_passthrough),
+        ("preview_window", initial_config.preview_window != new_config.preview_window),
+        ("participants_window", initial_config.participants_window != new_config.participants_window),
+        ("chat_transcript_path", initial_config.chat_transcript_path != new_config.chat_transcript_path),
+        ("download_directory", initial_config.download_directory != new_config.download_directory),
+        ("screen_share", initial_config.screen_share != new_config.screen_share),
+        ("composition", initial_config.composition != new_config.composition),
+    ];
+    changes.iter().filter(|(_, changed)| *changed).map(|(field_name, _)| *field_name).collect()
+}
+
+/// Read control commands from the standard input, eg `quality 50`, `fps 15`, `resolution 640x480`, `audio-frames 4`,
+/// `audio off`, `video on` or `source screen`, chat messages, eg `chat hello`, and files to send, eg `send-file report.pdf`
+fn read_control_commands(update_sender: mpsc::Sender<LiveSettingsUpdate>, peer_command_sender: mpsc::Sender<PeerCommand>) {
+    for command in std::io::stdin().lock().lines() {
+        let command = match command {
+            Ok(command) => command,
+            Err(_) => return,
+        };
+        if command.trim().is_empty() {
+            continue;
+        }
+        if let Some(text) = command.trim_start().strip_prefix("chat ") {
+            match ChatMessage::new(text) {
+                Ok(chat_message) => {
+                    if peer_command_sender.send(PeerCommand::Chat(chat_message)).is_err() {
+                        return;
+                    }
+                },
+                Err(e) => warn!("Chat message not sent: {}", e),
+            }
+            continue;
+        }
+        if let Some(path) = command.trim_start().strip_prefix("send-file ") {
+            match OutgoingFile::open(Path::new(path.trim())) {
+                Ok(outgoing_file) => {
+                    if peer_command_sender.send(PeerCommand::SendFile(outgoing_file)).is_err() {
+                        return;
+                    }
+                },
+                Err(e) => warn!("File not sent: {}", e),
+            }
+            continue;
+        }
+        match parse_control_command(&command) {
+            Ok(update) => {
+                if update_sender.send(update).is_err() {
+                    return;
+                }
+            },
+            Err(e) => warn!("Invalid command \"{}\": {}", command.trim(), e),
+        }
+    }
+}
+
+fn parse_control_command(command: &str) -> Result<LiveSettingsUpdate, String> {
+    let mut words = command.split_whitespace();
+    let (name, value) = match (words.next(), words.next(), words.next()) {
+        (Some(name), Some(value), None) => (name, value),
+        _ => return Err("expected \"<setting> <value>\", settings are quality, fps, resolution, audio-frames, audio, video and source, or \"chat <message>\" and \"send-file <path>\"".to_string()),
+    };
+    match name {
+        "quality" => parse_in_range(value, &json_client_config::VIDEO_JPEG_QUALITY_RANGE).map(LiveSettingsUpdate::JpegQuality),
+        "fps" => parse_in_range(value, &json_client_config::CAMERA_FPS_RANGE).map(LiveSettingsUpdate::CameraFps),
+        "resolution" if value == "default" => Ok(LiveSettingsUpdate::CameraFormat(None)),
+        "resolution" => {
+            let (width, height) = value.split_once('x').ok_or("expected WIDTHxHEIGHT or default")?;
+            let width = parse_in_range(width, &json_client_config::CAMERA_WIDTH_RANGE)?;
+            let height = parse_in_range(height, &json_client_config::CAMERA_HEIGHT_RANGE)?;
+            Ok(LiveSettingsUpdate::CameraFormat(Some((width, height))))
+        },
+        "audio-frames" => parse_in_range(value, &json_client_config::AUDIO_FRAME_ACCUMULATOR_LENGTH_RANGE).map(LiveSettingsUpdate::AudioFrameAccumulatorLength),
+        "audio" => parse_on_off(value).map(|audio_on| LiveSettingsUpdate::AudioMuted(!audio_on)),
+        "video" => parse_on_off(value).map(LiveSettingsUpdate::VideoEnabled),
+        "source" => match value {
+            "camera" => Ok(LiveSettingsUpdate::VideoSource(VideoSource::Camera)),
+            "screen" => Ok(LiveSettingsUpdate::VideoSource(VideoSource::Screen)),
+            _ => Err(format!("{} is neither camera nor screen", value)),
+        },
+        _ => Err(format!("unknown setting {}, settings are quality, fps, resolution, audio-frames, audio, video and source", name)),
+    }
+}
+
+fn parse_on_off(value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("{} is neither on nor off", value)),
+    }
+}
+
+fn parse_in_range<T: FromStr + PartialOrd + std::fmt::Display>(value: &str, range: &RangeInclusive<T>) -> Result<T, String> {
+    let value: T = value.parse().map_err(|_| format!("{} is not a number", value))?;
+    if !range.contains(&value) {
+        return Err(format!("{} is out of range, expected between {} and {}", value, range.start(), range.end()));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> JsonClientConfig {
+        serde_json::from_value(json_client_config::config_template()).unwrap()
+    }
+
+    #[test]
+    fn control_commands_are_parsed() {
+        assert!(matches!(parse_control_command("quality 50"), Ok(LiveSettingsUpdate::JpegQuality(50))));
+        assert!(matches!(parse_control_command("  fps   15 "), Ok(LiveSettingsUpdate::CameraFps(15))));
+        assert!(matches!(parse_control_command("resolution 640x480"), Ok(LiveSettingsUpdate::CameraFormat(Some((640, 480))))));
+        assert!(matches!(parse_control_command("resolution default"), Ok(LiveSettingsUpdate::CameraFormat(None))));
+        assert!(matches!(parse_control_command("audio off"), Ok(LiveSettingsUpdate::AudioMuted(true))));
+        assert!(matches!(parse_control_command("video on"), Ok(LiveSettingsUpdate::VideoEnabled(true))));
+        assert!(matches!(parse_control_command("source screen"), Ok(LiveSettingsUpdate::VideoSource(VideoSource::Screen))));
+    }
+
+    #[test]
+    fn malformed_control_commands_are_rejected() {
+        assert!(parse_control_command("quality").is_err());
+        assert!(parse_control_command("quality 50 60").is_err());
+        assert!(parse_control_command("brightness 50").is_err());
+        assert!(parse_control_command("quality high").is_err());
+        assert!(parse_control_command("resolution 640").is_err());
+        assert!(parse_control_command("audio maybe").is_err());
+        assert!(parse_control_command("source window").is_err());
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        let quality_above_range = json_client_config::VIDEO_JPEG_QUALITY_RANGE.end() + 1;
+        assert!(parse_control_command(&format!("quality {}", quality_above_range)).unwrap_err().contains("out of range"));
+        let fps_above_range = json_client_config::CAMERA_FPS_RANGE.end() + 1;
+        assert!(parse_control_command(&format!("fps {}", fps_above_range)).is_err());
+        assert!(parse_control_command("resolution 0x480").is_err());
+        assert!(parse_control_command("audio-frames 0").is_err());
+    }
+
+    #[test]
+    fn update_changes_only_its_setting() {
+        let settings = LiveSettings::default();
+        let new_settings = LiveSettingsUpdate::JpegQuality(80).apply(&settings);
+        assert_eq!(new_settings, LiveSettings { jpeg_quality: 80, ..settings.clone() });
+        let new_settings = LiveSettingsUpdate::ToggleVideoSource.apply(&new_settings);
+        assert_eq!(new_settings.video_source, VideoSource::Screen);
+        assert!(LiveSettingsUpdate::ToggleAudioMuted.apply(&settings).audio_muted);
+    }
+
+    #[test]
+    fn configuration_update_keeps_the_call_state() {
+        let settings = LiveSettings {
+            audio_muted: true,
+            video_enabled: false,
+            video_source: VideoSource::Screen,
+            talking: true,
+            ..LiveSettings::default()
+        };
+        let configured_settings = LiveSettings {
+            camera_fps: 10,
+            ..LiveSettings::default()
+        };
+        let new_settings = LiveSettingsUpdate::All(configured_settings).apply(&settings);
+        assert_eq!(new_settings, LiveSettings { camera_fps: 10, ..settings });
+    }
+
+    #[test]
+    fn push_to_talk_mutes_until_talking() {
+        let settings = LiveSettings { push_to_talk: true, ..LiveSettings::default() };
+        assert!(settings.media_state().audio_muted);
+        assert!(!LiveSettingsUpdate::Talking(true).apply(&settings).media_state().audio_muted);
+    }
+
+    #[test]
+    fn connection_fields_require_reconnecting() {
+        let initial_config = test_config();
+        let mut new_config = test_config();
+        new_config.override_default_camera_fps = Some(10);
+        assert!(reconnect_required_changes(&initial_config, &new_config).is_empty());
+        new_config.target_sae_id += 1;
+        new_config.preview_window = !new_config.preview_window;
+        assert_eq!(reconnect_required_changes(&initial_config, &new_config), vec!["target_sae_id", "preview_window"]);
+    }
+}
@@ -1,199 +1,565 @@
 mod linux_camera;
 mod camera;
+mod compositor;
+mod screen_capture;
+mod video_sources;
 mod json_client_config;
+mod cli;
+mod live_settings;
+mod media_pipeline;
+mod pixel_format;
+mod v4l_capabilities;
+mod audio_source;
+mod capture_clock;
+mod synthetic_sources;
+mod calibration;
+mod cpal_microphone;
+mod audio_processing;
+mod echo_canceller;
+mod preview;
+mod remote_participants;
 
 use std::fmt::{Debug, Formatter};
-use std::io::{Read, Write};
+use std::io::Write;
 use std::net::TcpStream;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, RwLock};
 use std::vec;
-use rustls::{ClientConnection, DigitallySignedStruct, Error, RootCertStore, SignatureScheme};
+use rustls::{ClientConnection, DigitallySignedStruct, Error, SignatureScheme};
 use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
 use rustls::qkd_config::QkdClientConfig;
 use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
 
-use pv_recorder::{PvRecorder, PvRecorderBuilder};
-use qkd_camera_common_lib::PACKET_CHUNK_SIZE;
+use clap::Parser;
+use log::{debug, error, info, trace, warn};
+use qkd_camera_common_lib::{config_loader, key_log, kme_diagnostics, logging};
+use qkd_camera_common_lib::key_log::DangerousKeyLog;
+use qkd_camera_common_lib::chat::{self, ChatTranscript};
+use qkd_camera_common_lib::config_loader::ConfigArgs;
+use qkd_camera_common_lib::file_transfer::FileTransfers;
+use qkd_camera_common_lib::sae_identity::{SaeCredentials, SaeIdentityProof, SAE_IDENTITY_BINDING_LENGTH, SAE_IDENTITY_EXPORTER_LABEL};
+use qkd_camera_common_lib::security_policy::{self, SecurityMode, SecurityPolicy};
+use qkd_camera_common_lib::session_security::SessionSecurityInfo;
+use qkd_camera_common_lib::tls_connection::{TlsConnection, TlsStream};
+use qkd_camera_common_lib::{ClientMessage, MAX_SERVER_MESSAGE_SIZE, MediaState, PACKET_CHUNK_SIZE, ServerMessage, SessionLimits, SessionRequest, SessionResponse};
+use crate::audio_processing::AudioProcessor;
+use crate::audio_source::AudioSource;
 use crate::camera::Camera;
+use crate::compositor::Compositor;
+use crate::capture_clock::CaptureClock;
+use crate::cli::Command;
 use crate::json_client_config::JsonClientConfig;
+use crate::live_settings::{LiveSettings, PeerCommand};
+use crate::media_pipeline::MediaPipeline;
+use crate::preview::Preview;
+use crate::remote_participants::RemoteParticipants;
+use crate::video_sources::VideoSources;
 
 //const FPS: u32 = 30;
 const DEFAULT_JPEG_COMPRESS_QUALITY: i32 = 25;
 const PV_RECORDER_FRAME_LENGTH: i32 = 512;
+/// Prefix of the environment variables overriding configuration fields
+const CONFIG_ENV_PREFIX: &str = "QKD_CLIENT_";
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <client_config.json>", args[0]);
+    let cli = cli::Cli::parse();
+    if let Err(e) = logging::init_logger(&cli.logging_args) {
+        eprintln!("Error initializing logger: {}", e);
         std::process::exit(1);
     }
 
-    let client_config_str = std::fs::read_to_string(&args[1]).unwrap();
-    let client_config: JsonClientConfig = serde_json::from_str(&client_config_str).unwrap();
+    match cli.command {
+        Command::Run { config_args, dry_run } => {
+            let client_config: JsonClientConfig = load_config_or_exit(&config_args);
+            if dry_run {
+                println!("Configuration {} is valid:\n{:#?}", config_args.config.display(), client_config);
+                return;
+            }
+            if client_config.preview_window || client_config.participants_window {
+                // The preview and participants windows need the main thread for their event loop
+                show_image::run_context(move || run(client_config, config_args));
+            }
+            run(client_config, config_args);
+        },
+        Command::Diagnose { config_args } => {
+            let client_config: JsonClientConfig = load_config_or_exit(&config_args);
+            std::process::exit(if diagnose(&client_config) { 0 } else { 1 });
+        },
+        Command::ListDevices => list_devices(),
+        Command::ListAudioDevices => list_audio_devices(),
+        Command::Calibrate { duration_secs } => {
+            std::process::exit(if calibration::calibrate(std::time::Duration::from_secs(duration_secs)) { 0 } else { 1 });
+        },
+        Command::GenConfig { output } => {
+            let config_template = serde_json::to_string_pretty(&json_client_config::config_template()).unwrap();
+            write_output_or_exit(output, &config_template);
+        },
+        Command::Schema { output } => {
+            write_output_or_exit(output, &config_loader::json_schema::<JsonClientConfig>());
+        }
+    }
+}
+
+fn load_config_or_exit(config_args: &ConfigArgs) -> JsonClientConfig {
+    match config_args.load(CONFIG_ENV_PREFIX) {
+        Ok(client_config) => client_config,
+        Err(e) => {
+            error!("Invalid configuration {}:\n{}", config_args.config.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn write_output_or_exit(output: Option<PathBuf>, content: &str) {
+    if let Err(e) = config_loader::write_output(output, content) {
+        error!("Error writing output: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Print the available cameras, with their supported modes, and microphones
+fn list_devices() {
+    println!("Cameras:");
+    for node in v4l::context::enum_devices() {
+        println!("  {}: {}", node.path().display(), node.name().unwrap_or_else(|| "unknown".to_string()));
+        match v4l_capabilities::query_camera_capabilities(node.path()) {
+            Ok(capabilities) => {
+                for capabilities_line in capabilities.to_string().lines() {
+                    println!("    {}", capabilities_line);
+                }
+            },
+            Err(e) => println!("    capabilities unavailable: {}", e),
+        }
+    }
+    list_audio_devices();
+}
+
+/// Print the available microphones, with the index used to select them in the configuration
+fn list_audio_devices() {
+    println!("Microphones (mono at 16 kHz):");
+    print_microphone_names(audio_source::pv_recorder_microphone_names());
+    println!("Microphones (other channel counts and sample rates):");
+    print_microphone_names(cpal_microphone::input_device_names());
+}
 
-    let jpeg_quality = client_config.override_default_video_jpeg_quality.unwrap_or_else(|| DEFAULT_JPEG_COMPRESS_QUALITY);
-    let audio_frame_accumulator_length = client_config.override_default_audio_frame_accumulator_length.unwrap_or_else(|| json_client_config::DEFAULT_AUDIO_FRAME_ACCUMULATOR_LENGTH);
+fn print_microphone_names(microphone_names: Result<Vec<String>, String>) {
+    match microphone_names {
+        Ok(microphone_names) => {
+            for (microphone_index, microphone_name) in microphone_names.iter().enumerate() {
+                println!("  {}: {}", microphone_index, microphone_name);
+            }
+        },
+        Err(e) => error!("Error listing microphones: {}", e),
+    }
+}
 
+fn run(client_config: JsonClientConfig, config_args: ConfigArgs) {
+    let live_settings = LiveSettings::from_config(&client_config);
+    let (live_settings_sender, live_settings_receiver) = mpsc::channel();
     #[cfg(target_os = "linux")]
-    let mut camera = linux_camera::LinuxCamera::new(&client_config);
+    let camera = match Compositor::<VideoSources<linux_camera::LinuxCamera>>::new(&client_config) {
+        Ok(camera) => camera,
+        Err(e) => {
+            error!("Error opening camera: {}", e);
+            return;
+        }
+    };
     #[cfg(target_os = "windows")]
     compile_error!("Windows is not yet supported");
 
-    let sound_recorder = PvRecorderBuilder::new(PV_RECORDER_FRAME_LENGTH).init().unwrap();
-
-    let mut root_store = RootCertStore::empty();
-    root_store.extend(
-        webpki_roots::TLS_SERVER_ROOTS
-            .iter()
-            .cloned(),
-    );
-    let mut config = rustls::ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_qkd(
-            &QkdClientConfig::new(
-                client_config.kme_address.as_str(),
-                client_config.kme_authentication_certificate_path.as_str(),
-                client_config.kme_authentication_certificate_password.as_str(),
-                client_config.target_sae_id,
-                client_config.danger_accept_invalid_kme_cert
-            )).unwrap();
-        /*.dangerous()
-        .with_custom_certificate_verifier(Arc::new(NoVerifier {}))
-        .with_no_client_auth();*/
-
-    // Allow using SSLKEYLOGFILE.
-    config.key_log = Arc::new(rustls::KeyLogFile::new());
-
-    let server_sae_host = client_config.target_sae_host;
-    let server_sae_port = client_config.target_sae_port;
-    let server_name: ServerName = server_sae_host.clone().try_into().unwrap();
-
-    let mut conn = ClientConnection::new(Arc::new(config), server_name).unwrap();
-    let mut sock = match TcpStream::connect(format!("{}:{}", server_sae_host, server_sae_port)) {
-        Ok(sock) => sock,
+    let mut sound_recorder = match audio_source::open_microphone(client_config.audio_input_device.as_ref(), client_config.audio_channels(), client_config.audio_sample_rate(), PV_RECORDER_FRAME_LENGTH) {
+        Ok(sound_recorder) => sound_recorder,
+        Err(e) => {
+            error!("Error opening microphone: {}", e);
+            return;
+        }
+    };
+    let sound_sample_rate = sound_recorder.sample_rate();
+    let sound_channels = sound_recorder.channels();
+    let audio_processor = match AudioProcessor::new(&client_config.audio_processing, sound_channels, sound_sample_rate) {
+        Ok(audio_processor) => audio_processor,
         Err(e) => {
-            eprintln!("Error connecting to server: {}", e);
+            error!("Error starting audio processing: {}", e);
             return;
         }
     };
-    let mut tls = rustls::Stream::new(&mut conn, &mut sock);
-    tls.conn.complete_io(&mut tls.sock).unwrap();
 
-    sound_recorder.start().unwrap();
+    let (config, security_mode) = match build_tls_config(&client_config) {
+        Ok(config_and_security_mode) => config_and_security_mode,
+        Err(e) => {
+            error!("Error creating TLS configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    match init_audio_capture_sync(&sound_recorder, 100) {
-        Ok(sync_duration) => {
-            println!("Audio capture synchronized in {} ms", sync_duration.as_millis());
+    let sae_credentials = match (client_config.sae_certificate_path.as_ref(), client_config.sae_private_key_path.as_ref()) {
+        (Some(sae_certificate_path), Some(sae_private_key_path)) => match SaeCredentials::from_der_files(sae_certificate_path, sae_private_key_path) {
+            Ok(sae_credentials) => Some(sae_credentials),
+            Err(e) => {
+                error!("Error loading SAE certificate: {}", e);
+                std::process::exit(1);
+            }
         },
-        Err(_) => {
-            println!("Warning: audio capture could be not well synchronized...");
-        }
-    }
+        _ => None,
+    };
 
+    // Allow using SSLKEYLOGFILE, only if explicitly enabled in the configuration.
+    let key_log = key_log::dangerous_key_log(client_config.danger_enable_key_log);
 
-    loop {
-        if /*input_image.is_none() ||*/ !sound_recorder.is_recording() {
-            eprintln!("Error getting frame or sound recorder not recording, disconnecting client...");
+    let (mut conn, mut sock) = match connect_to_server(&client_config, config, key_log.clone()) {
+        Ok(conn_and_sock) => conn_and_sock,
+        // The KME may only fail when the QKD key is requested for the handshake
+        Err(e) if security_mode == SecurityMode::Qkd && client_config.security_policy == SecurityPolicy::QkdPreferred => {
+            warn!("QKD handshake failed ({}), falling back to non-QKD key exchange", e);
+            let fallback_connection = build_fallback_tls_config(&client_config).and_then(|(config, _)| {
+                connect_to_server(&client_config, config, key_log)
+            });
+            match fallback_connection {
+                Ok(conn_and_sock) => conn_and_sock,
+                Err(e) => {
+                    error!("Error connecting to server: {}", e);
+                    return;
+                }
+            }
+        },
+        Err(e) => {
+            error!("Error connecting to server: {}", e);
             return;
         }
-        let sound_frame = (0..audio_frame_accumulator_length).fold(Vec::new(), |mut acc, _| {
-            acc.append(&mut sound_recorder.read().unwrap());
-            acc
-        });
-        let input_image = camera.get_frame();
-        //println!("Sound frame time: {}", start.elapsed().as_millis());
-        //println!("Sound frame size: {}", sound_frame.len());
-        let compressed_image = turbojpeg::compress_image(&input_image, jpeg_quality, turbojpeg::Subsamp::Sub2x2).unwrap();
-        //println!("Compressed size: {}", compressed_image.len());
-        let audio_video_packet = qkd_camera_common_lib::VideoAudioPacket {
-            compressed_image: compressed_image.to_vec(),
-            sound_frame,
-            sound_sample_rate: sound_recorder.sample_rate() as u32,
-        };
-        let packet_to_send = postcard::to_allocvec(&audio_video_packet).unwrap();
-        let packet_size: usize = packet_to_send.len();
-        let nb_chunk: usize = packet_size / PACKET_CHUNK_SIZE + 1;
-        //println!("Packet size: {}: {} chunks", packet_size, nb_chunk);
-        if tls.write_all(&[packet_size.to_be_bytes(), nb_chunk.to_be_bytes(), usize::MAX.to_be_bytes()].concat()).is_err() {
-            eprintln!("Error writing packet size, disconnecting client...");
-            break;
+    };
+    info!("Session key exchange: {} (security policy {:?})", conn.security_mode(), client_config.security_policy);
+    let mut tls = TlsStream::new(&mut conn, &mut sock);
+
+    let sae_identity_proof = match sae_credentials.as_ref().map(|sae_credentials| prove_sae_identity(tls.conn, sae_credentials)).transpose() {
+        Ok(sae_identity_proof) => sae_identity_proof,
+        Err(e) => {
+            error!("Error proving SAE identity: {}", e);
+            return;
         }
-        //println!("{}", tls.conn.wants_read());
-        //println!("{:?}", tls.conn.read_tls(&mut tls.sock));
-        tls.conn.write_tls(&mut tls.sock).unwrap();
-        if tls.flush().is_err() {
-            eprintln!("Error flushing data, disconnecting client...");
-            break;
+    };
+    let session_request = SessionRequest {
+        origin_sae_id: client_config.origin_sae_id,
+        target_sae_id: client_config.target_sae_id,
+        sae_identity_proof,
+    };
+    if let Err(e) = qkd_camera_common_lib::write_message(&mut tls, &session_request) {
+        error!("Error sending session request: {}", e);
+        return;
+    }
+    let call_answer_timeout = client_config.override_default_call_answer_timeout_secs.unwrap_or(json_client_config::DEFAULT_CALL_ANSWER_TIMEOUT_SECS);
+    info!("Calling SAE {}, waiting for answer...", client_config.target_sae_id);
+    tls.sock.set_read_timeout(Some(std::time::Duration::from_secs(call_answer_timeout))).unwrap();
+    let session_response = qkd_camera_common_lib::read_message(&mut tls);
+    tls.sock.set_read_timeout(None).unwrap();
+    let session_limits: SessionLimits = match session_response {
+        Ok(SessionResponse::Accepted(session_limits)) => session_limits,
+        Ok(SessionResponse::Rejected(rejection_reason)) => {
+            warn!("Call rejected by server: {}", rejection_reason);
+            return;
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
+            warn!("Call not answered after {} s", call_answer_timeout);
+            return;
+        },
+        Err(e) => {
+            error!("Error reading session response: {}", e);
+            return;
         }
+    };
 
-        for packet_chunk in packet_to_send.chunks(PACKET_CHUNK_SIZE) {
-            if tls.write_all(packet_chunk).is_err() {
-                eprintln!("Error writing packet chunk, disconnecting client...");
-                break;
+    let mut session_security_info = SessionSecurityInfo::new(tls.conn, &client_config.kme_address, client_config.origin_sae_id, client_config.target_sae_id);
+    info!("Call accepted: {}", session_security_info.summary());
+    info!("{}", session_security_info.log_line("session_start"));
+    // Opened once the call is accepted, to show the security properties of the session in its title
+    let mut preview = if client_config.preview_window {
+        match Preview::open(live_settings_sender.clone(), &session_security_info.summary()) {
+            Ok(preview) => Some(preview),
+            Err(e) => {
+                error!("Error opening preview: {}", e);
+                return;
             }
-            if tls.flush().is_err() {
-                eprintln!("Error flushing data, disconnecting client...");
-                break;
+        }
+    } else {
+        None
+    };
+
+    let mut chat_transcript = ChatTranscript::open(client_config.chat_transcript_path.as_deref(), &session_security_info.summary()).unwrap_or_else(|e| {
+        warn!("Error opening chat transcript, chat not recorded: {}", e);
+        ChatTranscript::default()
+    });
+    let mut file_transfers = FileTransfers::new(client_config.download_directory.as_deref());
+    let mut remote_participants = RemoteParticipants::new(client_config.participants_window);
+    let (peer_command_sender, peer_command_receiver) = mpsc::channel();
+    live_settings::watch_live_settings(config_args, client_config.clone(), live_settings_sender, peer_command_sender);
+    if let Err(e) = sound_recorder.start() {
+        error!("Error starting microphone: {}", e);
+        return;
+    }
+
+    let live_settings = Arc::new(RwLock::new(live_settings));
+    let media_pipeline = MediaPipeline::start(camera, sound_recorder, audio_processor, CaptureClock::new(), live_settings.clone(), session_limits);
+    let mut dropped_frames = (0, 0);
+    let mut sent_media_state = MediaState::default();
+
+    'session: loop {
+        for live_settings_update in live_settings_receiver.try_iter() {
+            let mut live_settings = live_settings.write().unwrap();
+            let new_live_settings = live_settings_update.apply(&live_settings);
+            if new_live_settings != *live_settings {
+                info!("Live settings applied: {:?}", new_live_settings);
+                *live_settings = new_live_settings;
             }
-            if tls.conn.write_tls(&mut tls.sock).is_err() {
-                eprintln!("Error writing TLS, disconnecting client...");
-                break;
+        }
+        let audio_frame_accumulator_length = live_settings.read().unwrap().audio_frame_accumulator_length;
+        let mut sound_frame = Vec::new();
+        let mut sound_capture_timestamp_us = None;
+        for _ in 0..audio_frame_accumulator_length {
+            match media_pipeline.next_audio_chunk() {
+                Some(mut audio_chunk) => {
+                    sound_capture_timestamp_us.get_or_insert(audio_chunk.capture_timestamp_us);
+                    sound_frame.append(&mut audio_chunk.samples);
+                },
+                None => {
+                    error!("Audio capture stopped, disconnecting client...");
+                    break 'session;
+                }
             }
         }
-        //tls.conn.complete_io(&mut tls.sock).unwrap();
-        //std::thread::sleep(std::time::Duration::from_millis(1000 / FPS as u64));
-        if tls.conn.read_tls(&mut tls.sock).is_err() {
-            eprintln!("Error reading TLS for ACK, disconnecting client...");
+        if media_pipeline.is_video_stopped() {
+            error!("Video capture stopped, disconnecting client...");
             break;
         }
-        tls.conn.process_new_packets().unwrap();
-        let mut buf = [0u8; 3];
-        tls.conn.reader().read(&mut buf).unwrap();
-        if &buf != b"ACK" {
-            eprintln!("Warning: Invalid ACK, disconnecting client...");
+        // An empty image tells the server that no new frame was encoded since the previous packet
+        let (mut compressed_image, image_capture_timestamp_us) = match media_pipeline.newest_encoded_frame() {
+            Some(encoded_frame) => (encoded_frame.jpeg, encoded_frame.capture_timestamp_us),
+            None => (Vec::new(), 0),
+        };
+        if media_pipeline.dropped_frames() != dropped_frames {
+            dropped_frames = media_pipeline.dropped_frames();
+            debug!("Video frames dropped: {} before encoding, {} before sending", dropped_frames.0, dropped_frames.1);
+        }
+        let media_state = live_settings.read().unwrap().media_state();
+        if media_state.audio_muted {
+            sound_frame.clear();
+        }
+        if !media_state.video_enabled {
+            compressed_image.clear();
+        }
+        if let Some(preview) = preview.as_mut() {
+            preview.show(Some(compressed_image.as_slice()).filter(|jpeg| !jpeg.is_empty()), media_state);
         }
 
-        //std::thread::sleep(std::time::Duration::from_millis(1000 / FPS as u64));
-        /*println!("{:?}", tls.conn.read_tls(&mut tls.sock));
-        tls.conn.process_new_packets().unwrap();
-        let mut buf = [0u8; 25];
-        tls.conn.reader().read(&mut buf).unwrap();
-        println!("{:?}", buf);
-        println!("Wants read: {}", tls.conn.wants_read());
-        println!("Wants write: {}", tls.conn.wants_write());
-        println!("{:?}", tls.conn.read_tls(&mut tls.sock));*/
-        //tls.conn.complete_io(&mut tls.sock).unwrap();
+        let mut client_messages = Vec::with_capacity(2);
+        for peer_command in peer_command_receiver.try_iter() {
+            match peer_command {
+                PeerCommand::Chat(chat_message) => {
+                    chat_transcript.record(chat::LOCAL_SENDER, &chat_message);
+                    client_messages.push(ClientMessage::Chat(chat_message));
+                },
+                PeerCommand::SendFile(outgoing_file) => file_transfers.send(outgoing_file),
+            }
+        }
+        client_messages.extend(file_transfers.outgoing_messages().into_iter().map(ClientMessage::FileTransfer));
+        if media_state != sent_media_state {
+            info!("Sending {}", media_state);
+            client_messages.push(ClientMessage::MediaState(media_state));
+            sent_media_state = media_state;
+        }
+        let audio_video_packet = qkd_camera_common_lib::VideoAudioPacket {
+            compressed_image,
+            sound_frame,
+            sound_sample_rate,
+            sound_channels,
+            sound_capture_timestamp_us: sound_capture_timestamp_us.unwrap_or_default(),
+            image_capture_timestamp_us,
+        };
+        if !audio_video_packet.compressed_image.is_empty() {
+            trace!("Video capture offset relative to audio: {} us", audio_video_packet.capture_offset_us());
+        }
+        client_messages.push(ClientMessage::Media(audio_video_packet));
+        match session_security_info.rekey_if_due(tls.conn) {
+            Ok(true) => info!("{}", session_security_info.log_line("rekey")),
+            Ok(false) => {},
+            Err(e) => warn!("Error refreshing the session keys: {}", e),
+        }
+        for client_message in client_messages {
+            if let Err(e) = send_client_message(&mut tls, &client_message) {
+                error!("Error sending packet: {}, disconnecting client...", e);
+                break 'session;
+            }
+            // Chat, file transfer and participant messages from the server come before the answer
+            loop {
+                match qkd_camera_common_lib::read_message_up_to(&mut tls, MAX_SERVER_MESSAGE_SIZE) {
+                    Ok(ServerMessage::Ack) => break,
+                    Ok(ServerMessage::Chat(chat_message)) => match chat_message.validate() {
+                        Ok(()) => {
+                            chat::print_chat_message(chat::REMOTE_SENDER, &chat_message);
+                            chat_transcript.record(chat::REMOTE_SENDER, &chat_message);
+                        },
+                        Err(e) => warn!("Invalid chat message ignored: {}", e),
+                    },
+                    Ok(ServerMessage::FileTransfer(file_transfer_message)) => file_transfers.handle(file_transfer_message),
+                    Ok(ServerMessage::Participant(participant_id, participant_update)) => remote_participants.handle(participant_id, participant_update),
+                    Ok(ServerMessage::Rejected(rejection_reason)) => {
+                        warn!("Session ended by server: {}", rejection_reason);
+                        break 'session;
+                    },
+                    Err(e) => {
+                        error!("Error reading ACK: {}, disconnecting client...", e);
+                        break 'session;
+                    }
+                }
+            }
+        }
     }
 
-    sound_recorder.stop().unwrap();
+    media_pipeline.stop();
+    if let Some(preview) = preview {
+        preview.close();
+    }
+    remote_participants.close();
     conn.send_close_notify();
     let _ = conn.complete_io(&mut sock);
 }
 
-/// Ensure that audio is synchronized with video by reading audio chunks until capture is initialized
-fn init_audio_capture_sync(sound_recorder: &PvRecorder, max_read_loops: usize) -> Result<std::time::Duration, ()> {
-    // Read ellasped time factor meaning that audio capture is initialized
-    const READ_TIME_THRESHOLD: usize = 100;
-
-    let mut previous_time: u128 = 0;
-    let mut correctly_initialized = false;
-    let whole_sync_start = std::time::Instant::now();
-    for _ in 0..max_read_loops {
-        let read_start = std::time::Instant::now();
-        sound_recorder.read().unwrap();
-        let read_time = read_start.elapsed().as_micros();
-        if read_time * (READ_TIME_THRESHOLD as u128) < previous_time {
-            correctly_initialized = true;
-            break;
+/// Send a message announced by its size and number of chunks, then split in chunks of [PACKET_CHUNK_SIZE] bytes
+fn send_client_message(tls: &mut TlsStream<TcpStream>, client_message: &ClientMessage) -> Result<(), String> {
+    let packet_to_send = postcard::to_allocvec(client_message).map_err(|e| format!("cannot serialize packet: {}", e))?;
+    let packet_size: usize = packet_to_send.len();
+    let nb_chunk: usize = packet_size.div_ceil(PACKET_CHUNK_SIZE);
+    debug!("Packet size: {} bytes, {} chunks", packet_size, nb_chunk);
+    tls.write_all(&[packet_size.to_be_bytes(), nb_chunk.to_be_bytes(), usize::MAX.to_be_bytes()].concat())
+        .map_err(|e| format!("cannot write packet size: {}", e))?;
+    trace!("TLS wants read: {}, wants write: {}", tls.conn.wants_read(), tls.conn.wants_write());
+    tls.conn.write_tls(&mut tls.sock).map_err(|e| format!("cannot write TLS: {}", e))?;
+    tls.flush().map_err(|e| format!("cannot flush data: {}", e))?;
+
+    for packet_chunk in packet_to_send.chunks(PACKET_CHUNK_SIZE) {
+        tls.write_all(packet_chunk).map_err(|e| format!("cannot write packet chunk: {}", e))?;
+        tls.flush().map_err(|e| format!("cannot flush data: {}", e))?;
+        tls.conn.write_tls(&mut tls.sock).map_err(|e| format!("cannot write TLS: {}", e))?;
+    }
+    trace!("Packet sent, TLS wants read: {}, wants write: {}", tls.conn.wants_read(), tls.conn.wants_write());
+    Ok(())
+}
+
+/// TLS configuration of the client: the QKD one is only supported by the rustls fork, and the one without QKD by upstream
+/// rustls for its hybrid post-quantum key exchange
+enum ClientTlsConfig {
+    Qkd(rustls::ClientConfig),
+    Fallback(rustls_pq::ClientConfig),
+}
+
+/// Build the TLS configuration according to the security policy, and return the key exchange mechanism it will use
+fn build_tls_config(client_config: &JsonClientConfig) -> Result<(ClientTlsConfig, SecurityMode), String> {
+    if client_config.security_policy.allows_qkd() {
+        let kme_authentication_certificate_password = client_config.kme_authentication_certificate_password.resolve()?;
+        let qkd_config = rustls::ClientConfig::builder()
+            .with_root_certificates(web_pki_root_store())
+            .with_qkd(
+                &QkdClientConfig::new(
+                    client_config.kme_address.as_str(),
+                    client_config.kme_authentication_certificate_path.as_str(),
+                    kme_authentication_certificate_password.as_str(),
+                    client_config.target_sae_id,
+                    client_config.danger_accept_invalid_kme_cert
+                ));
+            /*.dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier {}))
+            .with_no_client_auth();*/
+        match qkd_config {
+            Ok(qkd_config) => return Ok((ClientTlsConfig::Qkd(qkd_config), SecurityMode::Qkd)),
+            Err(e) if client_config.security_policy == SecurityPolicy::QkdRequired => {
+                return Err(format!("QKD is required but unavailable: {:?}", e));
+            },
+            Err(e) => warn!("QKD unavailable ({:?}), falling back to non-QKD key exchange", e),
         }
-        previous_time = read_time;
     }
-    let whole_sync_duration = whole_sync_start.elapsed();
-    if correctly_initialized {
-        Ok(whole_sync_duration)
-    } else {
-        Err(())
+    build_fallback_tls_config(client_config)
+}
+
+/// Build the non-QKD TLS configuration, verifying the server certificate against the web PKI roots and the optional fallback root
+fn build_fallback_tls_config(client_config: &JsonClientConfig) -> Result<(ClientTlsConfig, SecurityMode), String> {
+    let mut root_store = rustls_pq::RootCertStore::empty();
+    root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(fallback_root_certificate_path) = client_config.fallback_root_certificate_path.as_ref() {
+        let fallback_root_certificate = std::fs::read(fallback_root_certificate_path)
+            .map_err(|e| format!("cannot read {}: {}", fallback_root_certificate_path, e))?;
+        root_store.add(CertificateDer::from(fallback_root_certificate))
+            .map_err(|e| format!("invalid certificate {}: {}", fallback_root_certificate_path, e))?;
     }
+    let (crypto_provider, security_mode) = security_policy::fallback_crypto_provider(client_config.security_policy)?;
+    let config = rustls_pq::ClientConfig::builder_with_provider(Arc::new(crypto_provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    Ok((ClientTlsConfig::Fallback(config), security_mode))
+}
+
+fn web_pki_root_store() -> rustls::RootCertStore {
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.extend(
+        webpki_roots::TLS_SERVER_ROOTS
+            .iter()
+            .cloned(),
+    );
+    root_store
+}
+
+/// Sign the keying material of this TLS connection with the SAE certificate, so that the server can trust `origin_sae_id`
+fn prove_sae_identity(conn: &TlsConnection, sae_credentials: &SaeCredentials) -> Result<SaeIdentityProof, String> {
+    let binding = conn.export_keying_material::<SAE_IDENTITY_BINDING_LENGTH>(SAE_IDENTITY_EXPORTER_LABEL)
+        .map_err(|e| format!("cannot export TLS keying material: {}", e))?;
+    sae_credentials.prove(&binding)
+}
+
+/// Connect to the server and complete the TLS handshake, during which a QKD configuration retrieves its key from the KME
+fn connect_to_server(client_config: &JsonClientConfig, config: ClientTlsConfig, key_log: Option<Arc<DangerousKeyLog>>) -> Result<(TlsConnection, TcpStream), String> {
+    let server_name: ServerName = client_config.target_sae_host.clone().try_into().map_err(|e| format!("invalid server name {}: {}", client_config.target_sae_host, e))?;
+    let mut conn = match config {
+        ClientTlsConfig::Qkd(mut config) => {
+            if let Some(key_log) = key_log {
+                config.key_log = key_log;
+            }
+            TlsConnection::Qkd(ClientConnection::new(Arc::new(config), server_name).map_err(|e| e.to_string())?.into())
+        },
+        ClientTlsConfig::Fallback(mut config) => {
+            if let Some(key_log) = key_log {
+                config.key_log = key_log;
+            }
+            TlsConnection::Fallback(rustls_pq::ClientConnection::new(Arc::new(config), server_name).map_err(|e| e.to_string())?.into())
+        },
+    };
+    let mut sock = TcpStream::connect(format!("{}:{}", client_config.target_sae_host, client_config.target_sae_port)).map_err(|e| e.to_string())?;
+    conn.complete_io(&mut sock).map_err(|e| format!("TLS handshake failed: {}", e))?;
+    Ok((conn, sock))
+}
+
+/// Check KME health, key availability and server reachability without starting a call
+fn diagnose(client_config: &JsonClientConfig) -> bool {
+    let kme_status = client_config.kme_authentication_certificate_password.resolve().and_then(|kme_authentication_certificate_password| {
+        kme_diagnostics::query_kme_status(
+            client_config.kme_address.as_str(),
+            client_config.kme_authentication_certificate_path.as_str(),
+            kme_authentication_certificate_password.as_str(),
+            client_config.target_sae_id,
+            client_config.danger_accept_invalid_kme_cert
+        )
+    }).and_then(|kme_status| {
+        if kme_status.stored_key_count == 0 {
+            Err(format!("no key available for SAE {}\n{}", client_config.target_sae_id, kme_status))
+        } else {
+            Ok(kme_status)
+        }
+    });
+    let kme_ok = kme_diagnostics::report_diagnostic(&format!("KME {} key status for SAE {}", client_config.kme_address, client_config.target_sae_id), kme_status);
+
+    let server_address = format!("{}:{}", client_config.target_sae_host, client_config.target_sae_port);
+    let server_reachability = kme_diagnostics::check_tcp_reachability(&server_address).map(|_| String::new());
+    let server_ok = kme_diagnostics::report_diagnostic(&format!("Server {} reachability", server_address), server_reachability);
+
+    kme_ok && server_ok
 }
 
 struct NoVerifier {}
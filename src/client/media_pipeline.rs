@@ -0,0 +1,370 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use image::{ImageBuffer, Rgb};
+use log::{debug, error, info, trace, warn};
+use qkd_camera_common_lib::{SessionLimits, VideoSource};
+use crate::audio_processing::AudioProcessor;
+use crate::audio_source::AudioSource;
+use crate::camera::{Camera, CameraFrame};
+use crate::capture_clock::{AudioTimestamper, CaptureClock};
+use crate::live_settings::LiveSettings;
+
+/// Captured frames waiting to be encoded, older ones are dropped when encoding can't keep up
+const RAW_VIDEO_QUEUE_CAPACITY: usize = 2;
+/// Encoded frames waiting to be sent, older ones are dropped when the network can't keep up
+const ENCODED_VIDEO_QUEUE_CAPACITY: usize = 2;
+/// Audio chunks waiting to be sent, capture blocks when it is full so that no sample is lost
+const AUDIO_QUEUE_CAPACITY: usize = 256;
+
+/// Bounded queue dropping its oldest item when full, stale video frames being useless
+pub(crate) struct DropOldestQueue<T> {
+    state: Mutex<QueueState<T>>,
+    not_empty: Condvar,
+    capacity: usize,
+    dropped_count: AtomicU64,
+}
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T> DropOldestQueue<T> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(QueueState { items: VecDeque::with_capacity(capacity), closed: false }),
+            not_empty: Condvar::new(),
+            capacity,
+            dropped_count: AtomicU64::new(0),
+        }
+    }
+
+    /// Add an item, dropping the oldest one if the queue is full. Returns false once the queue is closed.
+    pub(crate) fn push(&self, item: T) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        if state.items.len() >= self.capacity {
+            state.items.pop_front();
+            self.dropped_count.fetch_add(1, Ordering::Relaxed);
+        }
+        state.items.push_back(item);
+        self.not_empty.notify_one();
+        true
+    }
+
+    /// Wait for the oldest item, `None` once the queue is closed and empty
+    pub(crate) fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// Newest item without waiting, the older ones being dropped
+    pub(crate) fn pop_newest(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let newest_item = state.items.pop_back();
+        let dropped_count = state.items.len() as u64;
+        state.items.clear();
+        self.dropped_count.fetch_add(dropped_count, Ordering::Relaxed);
+        newest_item
+    }
+
+    /// Wake up the consumers, and refuse any new item
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    /// Whether the queue is closed and nothing is left to pop
+    pub(crate) fn is_finished(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.closed && state.items.is_empty()
+    }
+
+    pub(crate) fn dropped_count(&self) -> u64 {
+        self.dropped_count.load(Ordering::Relaxed)
+    }
+}
+
+/// Audio samples, interleaved when there are several channels, and the capture time of the first one
+pub(crate) struct AudioChunk {
+    pub(crate) samples: Vec<i16>,
+    pub(crate) capture_timestamp_us: u64,
+}
+
+/// JPEG image and the capture time of the camera frame it comes from
+pub(crate) struct EncodedFrame {
+    pub(crate) jpeg: Vec<u8>,
+    pub(crate) capture_timestamp_us: u64,
+}
+
+/// Camera capture, video encoding and audio capture threads, feeding the network loop through bounded queues.
+/// Audio and video are timestamped against the same [CaptureClock].
+pub(crate) struct MediaPipeline {
+    audio_receiver: mpsc::Receiver<AudioChunk>,
+    raw_video_queue: Arc<DropOldestQueue<(CameraFrame, u64)>>,
+    encoded_video_queue: Arc<DropOldestQueue<EncodedFrame>>,
+    /// Threads to wait for when stopping. The camera thread isn't part of them, as it may be blocked waiting for a frame.
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl MediaPipeline {
+    /// Start capturing, the camera settings being reapplied when they change in `live_settings`
+    pub(crate) fn start<C, A>(camera: C, audio_source: A, audio_processor: AudioProcessor, capture_clock: CaptureClock, live_settings: Arc<RwLock<LiveSettings>>, session_limits: SessionLimits) -> Self
+    where
+        C: Camera + Send + 'static,
+        A: AudioSource + Send + 'static,
+    {
+        let (audio_sender, audio_receiver) = mpsc::sync_channel(AUDIO_QUEUE_CAPACITY);
+        let raw_video_queue = Arc::new(DropOldestQueue::new(RAW_VIDEO_QUEUE_CAPACITY));
+        let encoded_video_queue = Arc::new(DropOldestQueue::new(ENCODED_VIDEO_QUEUE_CAPACITY));
+
+        let camera_raw_video_queue = raw_video_queue.clone();
+        let camera_live_settings = live_settings.clone();
+        std::thread::spawn(move || capture_video(camera, capture_clock, camera_raw_video_queue, camera_live_settings));
+
+        let encoder_raw_video_queue = raw_video_queue.clone();
+        let encoder_encoded_video_queue = encoded_video_queue.clone();
+        let encoder_thread = std::thread::spawn(move || encode_video(encoder_raw_video_queue, encoder_encoded_video_queue, live_settings, session_limits));
+
+        let audio_thread = std::thread::spawn(move || capture_audio(audio_source, audio_processor, capture_clock, audio_sender));
+
+        Self {
+            audio_receiver,
+            raw_video_queue,
+            encoded_video_queue,
+            threads: vec![encoder_thread, audio_thread],
+        }
+    }
+
+    /// Wait for the next audio chunk, `None` if audio capture stopped
+    pub(crate) fn next_audio_chunk(&self) -> Option<AudioChunk> {
+        self.audio_receiver.recv().ok()
+    }
+
+    /// Latest encoded frame not sent yet, if any
+    pub(crate) fn newest_encoded_frame(&self) -> Option<EncodedFrame> {
+        self.encoded_video_queue.pop_newest()
+    }
+
+    /// Whether video capture or encoding stopped, no frame being produced anymore
+    pub(crate) fn is_video_stopped(&self) -> bool {
+        self.encoded_video_queue.is_finished()
+    }
+
+    /// Frames dropped because encoding, respectively the network, couldn't keep up
+    pub(crate) fn dropped_frames(&self) -> (u64, u64) {
+        (self.raw_video_queue.dropped_count(), self.encoded_video_queue.dropped_count())
+    }
+
+    /// Stop the capture threads and wait for the audio source to be released
+    pub(crate) fn stop(self) {
+        let (raw_dropped_frames, encoded_dropped_frames) = self.dropped_frames();
+        info!("Video frames dropped during the call: {} before encoding, {} before sending", raw_dropped_frames, encoded_dropped_frames);
+        self.raw_video_queue.close();
+        self.encoded_video_queue.close();
+        drop(self.audio_receiver);
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn capture_video<C: Camera>(mut camera: C, capture_clock: CaptureClock, raw_video_queue: Arc<DropOldestQueue<(CameraFrame, u64)>>, live_settings: Arc<RwLock<LiveSettings>>) {
+    let camera_settings = |live_settings: &RwLock<LiveSettings>| {
+        let live_settings = live_settings.read().unwrap();
+        (live_settings.camera_format, live_settings.camera_fps)
+    };
+    let mut applied_camera_settings = camera_settings(&live_settings);
+    let mut applied_video_source = VideoSource::default();
+    loop {
+        let requested_video_source = live_settings.read().unwrap().video_source;
+        if requested_video_source != applied_video_source {
+            match camera.select_source(requested_video_source) {
+                Ok(()) => applied_video_source = requested_video_source,
+                Err(e) => {
+                    warn!("Cannot switch to {}: {}", requested_video_source, e);
+                    live_settings.write().unwrap().video_source = applied_video_source;
+                }
+            }
+        }
+        let requested_camera_settings = camera_settings(&live_settings);
+        if requested_camera_settings != applied_camera_settings {
+            let (camera_format, camera_fps) = requested_camera_settings;
+            match camera.reconfigure(camera_format, camera_fps) {
+                Ok(()) => applied_camera_settings = requested_camera_settings,
+                Err(e) => {
+                    warn!("Cannot change camera settings: {}, keeping the previous ones", e);
+                    let mut live_settings = live_settings.write().unwrap();
+                    (live_settings.camera_format, live_settings.camera_fps) = applied_camera_settings;
+                    drop(live_settings);
+                    if let Err(e) = camera.reconfigure(applied_camera_settings.0, applied_camera_settings.1) {
+                        error!("Error reopening camera: {}", e);
+                    }
+                }
+            }
+        }
+        match camera.get_frame() {
+            // Frames are timestamped when the camera delivers them
+            Ok(frame) => {
+                if !raw_video_queue.push((frame, capture_clock.now_us())) {
+                    return;
+                }
+            },
+            Err(e) => {
+                error!("Error getting frame: {}", e);
+                raw_video_queue.close();
+                return;
+            }
+        }
+    }
+}
+
+fn encode_video(raw_video_queue: Arc<DropOldestQueue<(CameraFrame, u64)>>, encoded_video_queue: Arc<DropOldestQueue<EncodedFrame>>, live_settings: Arc<RwLock<LiveSettings>>, session_limits: SessionLimits) {
+    while let Some((frame, capture_timestamp_us)) = raw_video_queue.pop() {
+        let frame_live_settings = live_settings.read().unwrap().clone();
+        // Frames captured while the video is off are never sent, nor encoded
+        if !frame_live_settings.video_enabled {
+            continue;
+        }
+        match compress_frame(frame, &frame_live_settings, &session_limits) {
+            Ok(jpeg) => {
+                trace!("Compressed image size: {} bytes", jpeg.len());
+                if !encoded_video_queue.push(EncodedFrame { jpeg, capture_timestamp_us }) {
+                    return;
+                }
+            },
+            Err(e) => warn!("Error compressing frame, skipped: {}", e),
+        }
+    }
+    encoded_video_queue.close();
+}
+
+fn capture_audio<A: AudioSource>(mut audio_source: A, mut audio_processor: AudioProcessor, capture_clock: CaptureClock, audio_sender: mpsc::SyncSender<AudioChunk>) {
+    let mut audio_timestamper = AudioTimestamper::new(audio_source.sample_rate());
+    loop {
+        let mut samples = match audio_source.read() {
+            Ok(samples) => samples,
+            Err(e) => {
+                error!("Error reading audio: {}", e);
+                break;
+            }
+        };
+        let capture_timestamp_us = audio_timestamper.timestamp_us(capture_clock.now_us(), samples.len() / audio_source.channels() as usize);
+        audio_processor.process(&mut samples);
+        let audio_chunk = AudioChunk { samples, capture_timestamp_us };
+        match audio_sender.try_send(audio_chunk) {
+            Ok(()) => {},
+            Err(mpsc::TrySendError::Full(audio_chunk)) => {
+                debug!("Audio queue full, waiting for the network");
+                if audio_sender.send(audio_chunk).is_err() {
+                    break;
+                }
+            },
+            Err(mpsc::TrySendError::Disconnected(_)) => break,
+        }
+    }
+    if let Err(e) = audio_source.stop() {
+        warn!("Error stopping audio capture: {}", e);
+    }
+}
+
+/// JPEG image to send, the camera JPEG frames being forwarded untouched unless they exceed the session limits
+fn compress_frame(frame: CameraFrame, live_settings: &LiveSettings, session_limits: &SessionLimits) -> Result<Vec<u8>, String> {
+    let image = match frame {
+        CameraFrame::Jpeg { jpeg, width, height } if jpeg_fits_session_limits(width, height, jpeg.len(), live_settings.camera_fps, session_limits) => return Ok(jpeg),
+        frame => fit_image_to_limits(frame.into_rgb()?, session_limits),
+    };
+    turbojpeg::compress_image(&image, live_settings.jpeg_quality, turbojpeg::Subsamp::Sub2x2)
+        .map(|compressed_image| compressed_image.to_vec())
+        .map_err(|e| format!("cannot compress image: {}", e))
+}
+
+/// Whether a camera JPEG frame can be sent as is, without exceeding the resolution and bitrate allowed by the server
+fn jpeg_fits_session_limits(width: u32, height: u32, jpeg_size: usize, camera_fps: u32, session_limits: &SessionLimits) -> bool {
+    let resolution_fits = session_limits.max_width.is_none_or(|max_width| width <= max_width)
+        && session_limits.max_height.is_none_or(|max_height| height <= max_height);
+    let bitrate_fits = session_limits.max_bitrate_kbps
+        .is_none_or(|max_bitrate_kbps| jpeg_size as u64 * 8 * camera_fps as u64 <= max_bitrate_kbps as u64 * 1000);
+    resolution_fits && bitrate_fits
+}
+
+/// Downscale the image, keeping its aspect ratio, if it exceeds the resolution allowed by the server
+fn fit_image_to_limits(image: ImageBuffer<Rgb<u8>, Vec<u8>>, session_limits: &SessionLimits) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let max_width = session_limits.max_width.unwrap_or(width);
+    let max_height = session_limits.max_height.unwrap_or(height);
+    if width <= max_width && height <= max_height {
+        return image;
+    }
+    let scale = f64::min(max_width as f64 / width as f64, max_height as f64 / height as f64);
+    let new_width = ((width as f64 * scale) as u32).max(1);
+    let new_height = ((height as f64 * scale) as u32).max(1);
+    image::imageops::resize(&image, new_width, new_height, image::imageops::FilterType::Triangle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn pushing_past_capacity_drops_the_oldest_item() {
+        let queue = DropOldestQueue::new(2);
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert_eq!(queue.dropped_count(), 0);
+        assert!(queue.push(3));
+        assert_eq!(queue.dropped_count(), 1);
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+    }
+
+    #[test]
+    fn popping_the_newest_item_drops_the_older_ones() {
+        let queue = DropOldestQueue::new(4);
+        for item in 1..=3 {
+            queue.push(item);
+        }
+        assert_eq!(queue.pop_newest(), Some(3));
+        assert_eq!(queue.dropped_count(), 2);
+        assert_eq!(queue.pop_newest(), None);
+        assert_eq!(queue.dropped_count(), 2);
+    }
+
+    #[test]
+    fn closing_wakes_up_the_consumer_and_refuses_new_items() {
+        let queue = Arc::new(DropOldestQueue::<u32>::new(2));
+        let consumer_queue = queue.clone();
+        let consumer = std::thread::spawn(move || consumer_queue.pop());
+        // Give the consumer time to wait on the empty queue
+        std::thread::sleep(Duration::from_millis(50));
+        queue.close();
+        assert_eq!(consumer.join().unwrap(), None);
+        assert!(!queue.push(1));
+        assert!(queue.is_finished());
+    }
+
+    #[test]
+    fn items_left_when_closing_can_still_be_popped() {
+        let queue = DropOldestQueue::new(2);
+        queue.push(1);
+        queue.close();
+        assert!(!queue.is_finished());
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_finished());
+    }
+}
@@ -0,0 +1,147 @@
+use image::{ImageBuffer, Rgb};
+use v4l::{Format, FourCC};
+
+pub(crate) const RGB3: FourCC = FourCC { repr: *b"RGB3" };
+pub(crate) const YUYV: FourCC = FourCC { repr: *b"YUYV" };
+pub(crate) const NV12: FourCC = FourCC { repr: *b"NV12" };
+pub(crate) const MJPG: FourCC = FourCC { repr: *b"MJPG" };
+
+/// Camera pixel formats that can be converted to RGB, by order of preference
+pub(crate) const SUPPORTED_PIXEL_FORMATS: [FourCC; 4] = [RGB3, YUYV, NV12, MJPG];
+/// Order of preference when the camera JPEG frames can be forwarded without transcoding them
+pub(crate) const PASSTHROUGH_PIXEL_FORMATS: [FourCC; 4] = [MJPG, RGB3, YUYV, NV12];
+
+/// Convert a frame captured with the given format to RGB
+pub(crate) fn convert_to_rgb(frame: &[u8], format: &Format) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, String> {
+    let (width, height) = (format.width as usize, format.height as usize);
+    // FourCC implements PartialEq by hand, so its constants can't be used as patterns
+    let rgb_image = match &format.fourcc.repr {
+        b"RGB3" => {
+            let stride = stride_or_default(format, width * 3);
+            check_frame_size(frame, format, stride * height)?;
+            frame.chunks(stride).take(height).flat_map(|row| &row[..width * 3]).copied().collect()
+        },
+        b"YUYV" => {
+            let stride = stride_or_default(format, width * 2);
+            check_frame_size(frame, format, stride * height)?;
+            let mut rgb_image = Vec::with_capacity(width * height * 3);
+            for row in frame.chunks(stride).take(height) {
+                for yuyv in row[..width * 2].chunks_exact(4) {
+                    rgb_image.extend_from_slice(&yuv_to_rgb(yuyv[0], yuyv[1], yuyv[3]));
+                    rgb_image.extend_from_slice(&yuv_to_rgb(yuyv[2], yuyv[1], yuyv[3]));
+                }
+            }
+            rgb_image
+        },
+        b"NV12" => {
+            let stride = stride_or_default(format, width);
+            let chroma_start = stride * height;
+            check_frame_size(frame, format, chroma_start + stride * height.div_ceil(2))?;
+            let mut rgb_image = Vec::with_capacity(width * height * 3);
+            for y in 0..height {
+                let chroma_row = chroma_start + (y / 2) * stride;
+                for x in 0..width {
+                    let chroma_index = chroma_row + (x / 2) * 2;
+                    rgb_image.extend_from_slice(&yuv_to_rgb(frame[y * stride + x], frame[chroma_index], frame[chroma_index + 1]));
+                }
+            }
+            rgb_image
+        },
+        b"MJPG" => return turbojpeg::decompress_image(frame).map_err(|e| format!("cannot decode MJPEG frame: {}", e)),
+        _ => return Err(format!("unsupported pixel format {}", format.fourcc)),
+    };
+    ImageBuffer::from_raw(format.width, format.height, rgb_image)
+        .ok_or_else(|| format!("cannot create {}x{} RGB image", format.width, format.height))
+}
+
+fn stride_or_default(format: &Format, default_stride: usize) -> usize {
+    (format.stride as usize).max(default_stride)
+}
+
+fn check_frame_size(frame: &[u8], format: &Format, expected_size: usize) -> Result<(), String> {
+    if frame.len() < expected_size {
+        return Err(format!("{} frame of {} bytes, expected {} bytes for {}x{}", format.fourcc, frame.len(), expected_size, format.width, format.height));
+    }
+    Ok(())
+}
+
+/// BT.601 limited range YUV to RGB conversion
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+    [clamp(c + 409 * e), clamp(c - 100 * d - 208 * e), clamp(c + 516 * d)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const BLACK: [u8; 3] = [0, 0, 0];
+    /// Padding at the end of the rows, which must not appear in the image
+    const PADDING: u8 = 0x55;
+
+    fn format_with_stride(width: u32, height: u32, fourcc: FourCC, stride: u32) -> Format {
+        let mut format = Format::new(width, height, fourcc);
+        format.stride = stride;
+        format
+    }
+
+    fn pixels(image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<[u8; 3]> {
+        image.pixels().map(|pixel| pixel.0).collect()
+    }
+
+    #[test]
+    fn limited_range_yuv_is_converted() {
+        assert_eq!(yuv_to_rgb(16, 128, 128), BLACK);
+        assert_eq!(yuv_to_rgb(235, 128, 128), WHITE);
+        assert_eq!(yuv_to_rgb(81, 90, 240), [255, 0, 0]);
+    }
+
+    #[test]
+    fn rgb_rows_are_copied_without_padding() {
+        let frame = [1, 2, 3, 4, 5, 6, PADDING, PADDING, 7, 8, 9, 10, 11, 12, PADDING, PADDING];
+        let image = convert_to_rgb(&frame, &format_with_stride(2, 2, RGB3, 8)).unwrap();
+        assert_eq!(pixels(&image), vec![[1, 2, 3], [4, 5, 6], [7, 8, 9], [10, 11, 12]]);
+    }
+
+    #[test]
+    fn yuyv_pixel_pairs_share_their_chroma() {
+        let frame = [235, 128, 16, 128, PADDING, PADDING, 16, 128, 235, 128, PADDING, PADDING];
+        let image = convert_to_rgb(&frame, &format_with_stride(2, 2, YUYV, 6)).unwrap();
+        assert_eq!(pixels(&image), vec![WHITE, BLACK, BLACK, WHITE]);
+    }
+
+    #[test]
+    fn nv12_chroma_plane_follows_the_luma_plane() {
+        let frame = [
+            235, 16, PADDING, PADDING,
+            16, 235, PADDING, PADDING,
+            128, 128, PADDING, PADDING,
+        ];
+        let image = convert_to_rgb(&frame, &format_with_stride(2, 2, NV12, 4)).unwrap();
+        assert_eq!(pixels(&image), vec![WHITE, BLACK, BLACK, WHITE]);
+    }
+
+    #[test]
+    fn default_stride_is_used_when_missing() {
+        let image = convert_to_rgb(&[235, 128, 16, 128], &Format::new(2, 1, YUYV)).unwrap();
+        assert_eq!(pixels(&image), vec![WHITE, BLACK]);
+    }
+
+    #[test]
+    fn short_frame_is_an_error() {
+        assert!(convert_to_rgb(&[0; 11], &Format::new(2, 2, RGB3)).is_err());
+        assert!(convert_to_rgb(&[0; 7], &Format::new(2, 2, YUYV)).is_err());
+        // The chroma plane is missing
+        assert!(convert_to_rgb(&[0; 4], &Format::new(2, 2, NV12)).is_err());
+        assert!(convert_to_rgb(&[0; 12], &format_with_stride(2, 2, RGB3, 8)).is_err());
+    }
+
+    #[test]
+    fn unsupported_pixel_format_is_an_error() {
+        assert!(convert_to_rgb(&[0; 16], &Format::new(2, 2, FourCC::new(b"GREY"))).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashSet;
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use image::{ImageBuffer, Rgb};
+use log::{error, warn};
+use qkd_camera_common_lib::MediaState;
+use qkd_camera_common_lib::media_indicators::{self, DEFAULT_PLACEHOLDER_SIZE};
+use show_image::{create_window, ImageInfo, ImageView, WindowProxy};
+use show_image::event::{ElementState, VirtualKeyCode, WindowEvent};
+use crate::live_settings::LiveSettingsUpdate;
+use crate::media_pipeline::DropOldestQueue;
+
+const PREVIEW_WINDOW_TITLE: &str = "Preview - M: mute, V: video on/off, S: camera/screen, hold space: push-to-talk";
+
+/// Window showing the sent video, with the keyboard shortcuts controlling what is sent
+pub(crate) struct Preview {
+    window: WindowProxy,
+    /// Newest frame to display, if any, along with the media state
+    frame_queue: Arc<DropOldestQueue<(Option<Vec<u8>>, MediaState)>>,
+    display_thread: JoinHandle<()>,
+    displayed_media_state: Option<MediaState>,
+}
+
+impl Preview {
+    /// Open the preview window, titled with the session security `summary`, its keyboard shortcuts being sent to `update_sender`
+    pub(crate) fn open(update_sender: mpsc::Sender<LiveSettingsUpdate>, summary: &str) -> Result<Self, String> {
+        let window = create_window(format!("{} - {}", summary, PREVIEW_WINDOW_TITLE), Default::default()).map_err(|e| format!("cannot create preview window: {}", e))?;
+        match window.event_channel() {
+            Ok(event_receiver) => {
+                std::thread::spawn(move || handle_shortcuts(event_receiver, update_sender));
+            },
+            Err(e) => warn!("Preview keyboard shortcuts unavailable: {}", e),
+        }
+        let frame_queue = Arc::new(DropOldestQueue::new(1));
+        let display_window = window.clone();
+        let display_frame_queue = frame_queue.clone();
+        let display_thread = std::thread::spawn(move || display_frames(display_window, display_frame_queue));
+        Ok(Self {
+            window,
+            frame_queue,
+            display_thread,
+            displayed_media_state: None,
+        })
+    }
+
+    /// Display the JPEG frame sent to the server, if any, or the placeholder when the video is off
+    pub(crate) fn show(&mut self, jpeg: Option<&[u8]>, media_state: MediaState) {
+        if jpeg.is_none() && self.displayed_media_state == Some(media_state) {
+            return;
+        }
+        self.displayed_media_state = Some(media_state);
+        self.frame_queue.push((jpeg.map(|jpeg| jpeg.to_vec()), media_state));
+    }
+
+    pub(crate) fn close(self) {
+        self.frame_queue.close();
+        let _ = self.display_thread.join();
+        let _ = self.window.run_function_wait(|window_handle| {
+            window_handle.destroy();
+        });
+    }
+}
+
+fn display_frames(window: WindowProxy, frame_queue: Arc<DropOldestQueue<(Option<Vec<u8>>, MediaState)>>) {
+    let mut last_image: Option<ImageBuffer<Rgb<u8>, Vec<u8>>> = None;
+    while let Some((jpeg, media_state)) = frame_queue.pop() {
+        if let Some(jpeg) = jpeg {
+            match turbojpeg::decompress_image(&jpeg) {
+                Ok(image) => last_image = Some(image),
+                Err(e) => warn!("Error decoding preview frame: {}", e),
+            }
+        }
+        let mut image = match last_image.as_ref() {
+            Some(image) if media_state.video_enabled => image.clone(),
+            Some(image) => media_indicators::placeholder_image(image.width(), image.height()),
+            None => media_indicators::placeholder_image(DEFAULT_PLACEHOLDER_SIZE.0, DEFAULT_PLACEHOLDER_SIZE.1),
+        };
+        if media_state.audio_muted {
+            media_indicators::draw_muted_indicator(&mut image);
+        }
+        let (width, height) = image.dimensions();
+        if let Err(e) = window.set_image("preview", ImageView::new(ImageInfo::rgb8(width, height), image.as_raw())) {
+            error!("Error displaying preview: {}", e);
+            return;
+        }
+    }
+}
+
+fn handle_shortcuts(event_receiver: mpsc::Receiver<WindowEvent>, update_sender: mpsc::Sender<LiveSettingsUpdate>) {
+    // Held keys repeat their pressed events, which must not toggle again
+    let mut held_keys = HashSet::new();
+    for event in event_receiver {
+        let WindowEvent::KeyboardInput(event) = event else {
+            continue;
+        };
+        let Some(key_code) = event.input.key_code else {
+            continue;
+        };
+        let update = match event.input.state {
+            ElementState::Pressed if !held_keys.insert(key_code) => continue,
+            ElementState::Pressed => match key_code {
+                VirtualKeyCode::M => LiveSettingsUpdate::ToggleAudioMuted,
+                VirtualKeyCode::V => LiveSettingsUpdate::ToggleVideo,
+                VirtualKeyCode::S => LiveSettingsUpdate::ToggleVideoSource,
+                VirtualKeyCode::Space => LiveSettingsUpdate::Talking(true),
+                _ => continue,
+            },
+            ElementState::Released => {
+                held_keys.remove(&key_code);
+                match key_code {
+                    VirtualKeyCode::Space => LiveSettingsUpdate::Talking(false),
+                    _ => continue,
+                }
+            },
+        };
+        if update_sender.send(update).is_err() {
+            return;
+        }
+    }
+}
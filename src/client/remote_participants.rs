@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use image::{ImageBuffer, Rgb};
+use log::{error, info, warn};
+use rodio::{OutputStream, OutputStreamHandle, Sink};
+use show_image::{create_window, ImageInfo, ImageView, WindowProxy};
+use qkd_camera_common_lib::{MediaState, ParticipantUpdate};
+use qkd_camera_common_lib::video_grid::{self, GridTile};
+
+type RgbImage = ImageBuffer<Rgb<u8>, Vec<u8>>;
+
+const PARTICIPANTS_WINDOW_TITLE: &str = "Conference participants";
+
+/// The other participants of a conference, whose media is forwarded by a relay server. Their sound is played on the
+/// default speaker, and their video displayed in a grid if `show_video` is set. Nothing is opened before the first
+/// participant joins, so that calls to a server that isn't a relay are unaffected.
+pub(crate) struct RemoteParticipants {
+    show_video: bool,
+    names: BTreeMap<u32, String>,
+    sinks: BTreeMap<u32, Sink>,
+    speaker: Option<(OutputStream, OutputStreamHandle)>,
+    speaker_unavailable: bool,
+    display: Option<(WindowProxy, mpsc::Sender<DisplayUpdate>, JoinHandle<()>)>,
+}
+
+/// Change of what the participants window shows
+enum DisplayUpdate {
+    Joined(u32),
+    Image(u32, Vec<u8>),
+    MediaState(u32, MediaState),
+    Left(u32),
+}
+
+impl RemoteParticipants {
+    pub(crate) fn new(show_video: bool) -> Self {
+        Self {
+            show_video,
+            names: BTreeMap::new(),
+            sinks: BTreeMap::new(),
+            speaker: None,
+            speaker_unavailable: false,
+            display: None,
+        }
+    }
+
+    pub(crate) fn handle(&mut self, participant_id: u32, participant_update: ParticipantUpdate) {
+        let display_update = match participant_update {
+            ParticipantUpdate::Joined(name) => {
+                info!("{} joined the conference", name);
+                self.names.insert(participant_id, name);
+                DisplayUpdate::Joined(participant_id)
+            },
+            ParticipantUpdate::Media(video_audio_packet) => {
+                if !video_audio_packet.sound_frame.is_empty() {
+                    match video_audio_packet.check_audio_format() {
+                        Ok(()) => self.play(participant_id, video_audio_packet.sound_channels, video_audio_packet.sound_sample_rate, video_audio_packet.sound_frame),
+                        Err(e) => warn!("Invalid audio format from participant {}: {}", participant_id, e),
+                    }
+                }
+                // The participant sends no image when no new frame was captured since its previous packet
+                if video_audio_packet.compressed_image.is_empty() {
+                    return;
+                }
+                DisplayUpdate::Image(participant_id, video_audio_packet.compressed_image)
+            },
+            ParticipantUpdate::MediaState(media_state) => {
+                info!("{} changed its media: {}", self.name(participant_id), media_state);
+                DisplayUpdate::MediaState(participant_id, media_state)
+            },
+            ParticipantUpdate::Left => {
+                info!("{} left the conference", self.name(participant_id));
+                self.names.remove(&participant_id);
+                self.sinks.remove(&participant_id);
+                DisplayUpdate::Left(participant_id)
+            },
+        };
+        if !self.show_video {
+            return;
+        }
+        if self.display.is_none() {
+            match create_window(PARTICIPANTS_WINDOW_TITLE, Default::default()) {
+                Ok(window) => {
+                    let (display_sender, display_receiver) = mpsc::channel();
+                    let display_window = window.clone();
+                    let display_thread = std::thread::spawn(move || display_grid(display_window, display_receiver));
+                    self.display = Some((window, display_sender, display_thread));
+                },
+                Err(e) => {
+                    error!("Error creating participants window: {}, video of the participants not shown", e);
+                    self.show_video = false;
+                    return;
+                }
+            }
+        }
+        if let Some((_, display_sender, _)) = self.display.as_ref() {
+            let _ = display_sender.send(display_update);
+        }
+    }
+
+    pub(crate) fn close(self) {
+        if let Some((window, display_sender, display_thread)) = self.display {
+            drop(display_sender);
+            let _ = display_thread.join();
+            let _ = window.run_function_wait(|window_handle| {
+                window_handle.destroy();
+            });
+        }
+    }
+
+    fn name(&self, participant_id: u32) -> String {
+        self.names.get(&participant_id).cloned().unwrap_or_else(|| format!("participant {}", participant_id))
+    }
+
+    /// Play the sound of a participant on its own sink, the speaker mixing all of them
+    fn play(&mut self, participant_id: u32, sound_channels: u16, sound_sample_rate: u32, sound_frame: Vec<i16>) {
+        if self.speaker_unavailable {
+            return;
+        }
+        if self.speaker.is_none() {
+            match OutputStream::try_default() {
+                Ok(speaker) => self.speaker = Some(speaker),
+                Err(e) => {
+                    error!("Error opening speaker: {}, sound of the participants not played", e);
+                    self.speaker_unavailable = true;
+                    return;
+                }
+            }
+        }
+        let (_, speaker_handle) = self.speaker.as_ref().unwrap();
+        let sink = match self.sinks.entry(participant_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match Sink::try_new(speaker_handle) {
+                Ok(sink) => entry.insert(sink),
+                Err(e) => {
+                    error!("Error playing sound of participant {}: {}", participant_id, e);
+                    return;
+                }
+            },
+        };
+        sink.append(rodio::buffer::SamplesBuffer::new(sound_channels, sound_sample_rate, sound_frame));
+    }
+}
+
+/// Display the participants in a grid, in the order they joined. Pending updates are applied together, only the newest
+/// image of each participant being decoded.
+fn display_grid(window: WindowProxy, display_receiver: mpsc::Receiver<DisplayUpdate>) {
+    let mut participants: BTreeMap<u32, (MediaState, Option<RgbImage>)> = BTreeMap::new();
+    while let Ok(display_update) = display_receiver.recv() {
+        let mut newest_jpegs = BTreeMap::new();
+        for display_update in std::iter::once(display_update).chain(display_receiver.try_iter()) {
+            match display_update {
+                DisplayUpdate::Joined(participant_id) => {
+                    participants.insert(participant_id, (MediaState::default(), None));
+                },
+                DisplayUpdate::Image(participant_id, jpeg) => {
+                    newest_jpegs.insert(participant_id, jpeg);
+                },
+                DisplayUpdate::MediaState(participant_id, media_state) => {
+                    if let Some((last_media_state, _)) = participants.get_mut(&participant_id) {
+                        *last_media_state = media_state;
+                    }
+                },
+                DisplayUpdate::Left(participant_id) => {
+                    participants.remove(&participant_id);
+                    newest_jpegs.remove(&participant_id);
+                },
+            }
+        }
+        for (participant_id, jpeg) in newest_jpegs {
+            let Some((_, last_image)) = participants.get_mut(&participant_id) else {
+                continue;
+            };
+            match turbojpeg::decompress_image(&jpeg) {
+                Ok(image) => *last_image = Some(image),
+                Err(e) => warn!("Error decoding image of participant {}: {}", participant_id, e),
+            }
+        }
+        let tiles: Vec<GridTile> = participants.values().map(|(media_state, image)| GridTile {
+            image: image.as_ref().filter(|_| media_state.video_enabled),
+            audio_muted: media_state.audio_muted,
+        }).collect();
+        let grid = video_grid::compose_grid(&tiles);
+        let (width, height) = grid.dimensions();
+        if let Err(e) = window.set_image("participants", ImageView::new(ImageInfo::rgb8(width, height), grid.as_raw())) {
+            error!("Error displaying participants: {}", e);
+            return;
+        }
+    }
+}
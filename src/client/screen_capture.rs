@@ -0,0 +1,200 @@
+use std::time::{Duration, Instant};
+use image::{ImageBuffer, Rgb};
+use log::{info, warn};
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::shm::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{ConnectionExt as _, ImageFormat, ImageOrder, Window};
+use x11rb::rust_connection::RustConnection;
+use crate::camera::{Camera, CameraFrame};
+use crate::json_client_config::{DEFAULT_CAMERA_FPS, JsonClientConfig};
+
+/// The X server sends 24 bits colors padded to 32 bits, in blue, green, red order
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Captures an X11 screen, or a part of it, through the MIT-SHM extension when the X server runs on the same machine,
+/// otherwise with plain GetImage requests. Frames are delivered at the camera frame rate.
+pub(crate) struct ScreenCapture {
+    connection: RustConnection,
+    root: Window,
+    x: i16,
+    y: i16,
+    width: u16,
+    height: u16,
+    /// The captured image is downscaled to fit in this format, keeping its aspect ratio
+    max_format: Option<(u32, u32)>,
+    shared_memory: Option<SharedMemorySegment>,
+    frame_interval: Duration,
+    next_frame_instant: Instant,
+}
+
+impl ScreenCapture {
+    fn capture(&self) -> Result<Vec<u8>, String> {
+        let image_size = self.width as usize * self.height as usize * BYTES_PER_PIXEL;
+        match self.shared_memory.as_ref() {
+            Some(shared_memory) => {
+                self.connection.shm_get_image(self.root, self.x, self.y, self.width, self.height, !0, ImageFormat::Z_PIXMAP.into(), shared_memory.segment, 0)
+                    .map_err(|e| format!("cannot request screen image: {}", e))?
+                    .reply()
+                    .map_err(|e| format!("cannot capture screen: {}", e))?;
+                Ok(shared_memory.data()[..image_size].to_vec())
+            },
+            None => {
+                let reply = self.connection.get_image(ImageFormat::Z_PIXMAP, self.root, self.x, self.y, self.width, self.height, !0)
+                    .map_err(|e| format!("cannot request screen image: {}", e))?
+                    .reply()
+                    .map_err(|e| format!("cannot capture screen: {}", e))?;
+                if reply.data.len() < image_size {
+                    return Err(format!("screen image of {} bytes, expected {}", reply.data.len(), image_size));
+                }
+                Ok(reply.data)
+            },
+        }
+    }
+}
+
+impl Camera for ScreenCapture {
+    fn new(client_config: &JsonClientConfig) -> Result<Self, String> {
+        let screen_share_config = client_config.screen_share.as_ref().ok_or("screen_share is not configured")?;
+        let (connection, screen_number) = x11rb::connect(screen_share_config.display.as_deref())
+            .map_err(|e| format!("cannot connect to X11 display: {}", e))?;
+        let setup = connection.setup();
+        let screen = &setup.roots[screen_number];
+        let bits_per_pixel = setup.pixmap_formats.iter()
+            .find(|format| format.depth == screen.root_depth)
+            .map(|format| format.bits_per_pixel);
+        if setup.image_byte_order != ImageOrder::LSB_FIRST || !matches!(screen.root_depth, 24 | 32) || bits_per_pixel != Some(32) {
+            return Err(format!("unsupported screen format: depth {}, {:?} bits per pixel", screen.root_depth, bits_per_pixel));
+        }
+        let (root, screen_width, screen_height) = (screen.root, screen.width_in_pixels as u32, screen.height_in_pixels as u32);
+        let (x, y, width, height) = match screen_share_config.region.as_ref() {
+            Some(region) => (region.x, region.y, region.width, region.height),
+            None => (0, 0, screen_width, screen_height),
+        };
+        if x + width > screen_width || y + height > screen_height {
+            return Err(format!("region {}x{} at ({}, {}) exceeds the {}x{} screen", width, height, x, y, screen_width, screen_height));
+        }
+        let shared_memory = match SharedMemorySegment::attach(&connection, width as usize * height as usize * BYTES_PER_PIXEL) {
+            Ok(shared_memory) => Some(shared_memory),
+            Err(e) => {
+                warn!("Cannot share memory with the X server: {}, screen capture will be slower", e);
+                None
+            },
+        };
+        info!("Screen share of {}x{} at ({}, {})", width, height, x, y);
+        let camera_fps = client_config.override_default_camera_fps.unwrap_or(DEFAULT_CAMERA_FPS);
+        Ok(Self {
+            connection,
+            root,
+            x: x as i16,
+            y: y as i16,
+            width: width as u16,
+            height: height as u16,
+            max_format: screen_share_config.max_format.as_ref().map(|format| (format.width, format.height)),
+            shared_memory,
+            frame_interval: Duration::from_secs(1) / camera_fps.max(1),
+            next_frame_instant: Instant::now(),
+        })
+    }
+
+    fn get_frame(&mut self) -> Result<CameraFrame, String> {
+        std::thread::sleep(self.next_frame_instant.saturating_duration_since(Instant::now()));
+        self.next_frame_instant = (self.next_frame_instant + self.frame_interval).max(Instant::now());
+        let pixels = self.capture()?;
+        let rgb_pixels = pixels.chunks_exact(BYTES_PER_PIXEL)
+            .take(self.width as usize * self.height as usize)
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0]])
+            .collect();
+        let image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_raw(self.width as u32, self.height as u32, rgb_pixels)
+            .ok_or("screen image smaller than its dimensions")?;
+        let Some((max_width, max_height)) = self.max_format else {
+            return Ok(CameraFrame::Rgb(image));
+        };
+        let (width, height) = image.dimensions();
+        if width <= max_width && height <= max_height {
+            return Ok(CameraFrame::Rgb(image));
+        }
+        let scale = f64::min(max_width as f64 / width as f64, max_height as f64 / height as f64);
+        let new_width = ((width as f64 * scale) as u32).max(1);
+        let new_height = ((height as f64 * scale) as u32).max(1);
+        Ok(CameraFrame::Rgb(image::imageops::resize(&image, new_width, new_height, image::imageops::FilterType::Triangle)))
+    }
+
+    /// Only the frame rate applies to the screen, the format being the camera one
+    fn reconfigure(&mut self, _camera_format: Option<(u32, u32)>, camera_fps: u32) -> Result<(), String> {
+        self.frame_interval = Duration::from_secs(1) / camera_fps.max(1);
+        Ok(())
+    }
+}
+
+impl Drop for ScreenCapture {
+    fn drop(&mut self) {
+        if let Some(shared_memory) = self.shared_memory.take() {
+            let _ = self.connection.shm_detach(shared_memory.segment);
+            let _ = self.connection.flush();
+        }
+    }
+}
+
+/// System V shared memory segment, attached by the X server which writes the captured images in it
+struct SharedMemorySegment {
+    segment: shm::Seg,
+    address: *mut u8,
+    size: usize,
+}
+
+// The segment is only accessed by the thread owning the screen capture
+unsafe impl Send for SharedMemorySegment {}
+
+impl SharedMemorySegment {
+    fn attach(connection: &RustConnection, size: usize) -> Result<Self, String> {
+        if connection.extension_information(shm::X11_EXTENSION_NAME).map_err(|e| format!("cannot query X extensions: {}", e))?.is_none() {
+            return Err("MIT-SHM extension unavailable".to_string());
+        }
+        // SAFETY: a new private segment is created then mapped, checking both calls
+        let shmid = unsafe { libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600) };
+        if shmid < 0 {
+            return Err(format!("cannot create shared memory: {}", std::io::Error::last_os_error()));
+        }
+        let address = unsafe { libc::shmat(shmid, std::ptr::null(), 0) };
+        let attached = if address as isize == -1 {
+            Err(format!("cannot map shared memory: {}", std::io::Error::last_os_error()))
+        } else {
+            connection.generate_id()
+                .map_err(|e| format!("cannot allocate shared memory segment ID: {}", e))
+                .and_then(|segment| {
+                    connection.shm_attach(segment, shmid as u32, false)
+                        .map_err(|e| format!("cannot attach shared memory: {}", e))?
+                        .check()
+                        .map_err(|e| format!("X server cannot attach shared memory: {}", e))?;
+                    Ok(segment)
+                })
+        };
+        // Once removed, the segment is freed as soon as both the client and the X server detach it, even if the client crashes
+        unsafe { libc::shmctl(shmid, libc::IPC_RMID, std::ptr::null_mut()) };
+        match attached {
+            Ok(segment) => Ok(Self {
+                segment,
+                address: address as *mut u8,
+                size,
+            }),
+            Err(e) => {
+                if address as isize != -1 {
+                    unsafe { libc::shmdt(address) };
+                }
+                Err(e)
+            },
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        // SAFETY: the segment is mapped for `size` bytes until dropped, and the X server only writes in it during a
+        // ShmGetImage request, whose reply has been received
+        unsafe { std::slice::from_raw_parts(self.address, self.size) }
+    }
+}
+
+impl Drop for SharedMemorySegment {
+    fn drop(&mut self) {
+        unsafe { libc::shmdt(self.address as *const libc::c_void) };
+    }
+}
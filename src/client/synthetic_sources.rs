@@ -0,0 +1,114 @@
+use std::f64::consts::PI;
+use image::{ImageBuffer, Rgb};
+use crate::audio_source::AudioSource;
+use crate::camera::{Camera, CameraFrame};
+use crate::capture_clock::CaptureClock;
+use crate::json_client_config::{DEFAULT_CAMERA_FPS, JsonClientConfig};
+
+/// The beep and the flash start at each whole second of the capture clock
+pub(crate) const SYNC_SIGNAL_PERIOD_US: u64 = 1_000_000;
+const BEEP_DURATION_US: u64 = 50_000;
+const BEEP_FREQUENCY: f64 = 1000.0;
+const BEEP_AMPLITUDE: f64 = 16000.0;
+const SYNTHETIC_SAMPLE_RATE: u32 = 16000;
+const SYNTHETIC_CHUNK_LENGTH: u64 = 512;
+const SYNTHETIC_CAMERA_FORMAT: (u32, u32) = (640, 480);
+
+/// Camera delivering black frames, the first one of each [SYNC_SIGNAL_PERIOD_US] being white
+pub(crate) struct SyntheticCamera {
+    capture_clock: CaptureClock,
+    width: u32,
+    height: u32,
+    fps: u32,
+    /// Index of the next frame, frames being due at `frame_index / fps` seconds of the capture clock
+    frame_index: u64,
+}
+
+impl SyntheticCamera {
+    pub(crate) fn with_clock(capture_clock: CaptureClock, camera_format: Option<(u32, u32)>, camera_fps: u32) -> Self {
+        let mut camera = Self {
+            capture_clock,
+            width: 0,
+            height: 0,
+            fps: 1,
+            frame_index: 0,
+        };
+        camera.configure(camera_format, camera_fps);
+        camera
+    }
+
+    fn configure(&mut self, camera_format: Option<(u32, u32)>, camera_fps: u32) {
+        (self.width, self.height) = camera_format.unwrap_or(SYNTHETIC_CAMERA_FORMAT);
+        self.fps = camera_fps.max(1);
+        self.frame_index = (self.capture_clock.now_us() * self.fps as u64).div_ceil(1_000_000);
+    }
+}
+
+impl Camera for SyntheticCamera {
+    fn new(client_config: &JsonClientConfig) -> Result<Self, String> {
+        let camera_format = client_config.override_default_format.as_ref().map(|format| (format.width, format.height));
+        let camera_fps = client_config.override_default_camera_fps.unwrap_or(DEFAULT_CAMERA_FPS);
+        Ok(Self::with_clock(CaptureClock::new(), camera_format, camera_fps))
+    }
+
+    fn get_frame(&mut self) -> Result<CameraFrame, String> {
+        let frame_timestamp_us = self.frame_index * 1_000_000 / self.fps as u64;
+        let flash = frame_timestamp_us % SYNC_SIGNAL_PERIOD_US < 1_000_000 / self.fps as u64;
+        self.frame_index += 1;
+        let luma = if flash { 255 } else { 0 };
+        let frame = ImageBuffer::from_pixel(self.width, self.height, Rgb([luma, luma, luma]));
+        std::thread::sleep(self.capture_clock.instant(frame_timestamp_us).saturating_duration_since(std::time::Instant::now()));
+        Ok(CameraFrame::Rgb(frame))
+    }
+
+    fn reconfigure(&mut self, camera_format: Option<(u32, u32)>, camera_fps: u32) -> Result<(), String> {
+        self.configure(camera_format, camera_fps);
+        Ok(())
+    }
+}
+
+/// Silence, with a 1 kHz beep at the start of each [SYNC_SIGNAL_PERIOD_US], delivered in real time
+pub(crate) struct SyntheticAudioSource {
+    capture_clock: CaptureClock,
+    /// Index of the next sample, sample `i` being captured at `i / SYNTHETIC_SAMPLE_RATE` seconds of the capture clock
+    sample_index: u64,
+}
+
+impl SyntheticAudioSource {
+    pub(crate) fn with_clock(capture_clock: CaptureClock) -> Self {
+        let first_chunk_index = (capture_clock.now_us() * SYNTHETIC_SAMPLE_RATE as u64).div_ceil(1_000_000 * SYNTHETIC_CHUNK_LENGTH);
+        Self {
+            capture_clock,
+            sample_index: first_chunk_index * SYNTHETIC_CHUNK_LENGTH,
+        }
+    }
+}
+
+impl AudioSource for SyntheticAudioSource {
+    fn sample_rate(&self) -> u32 {
+        SYNTHETIC_SAMPLE_RATE
+    }
+
+    fn read(&mut self) -> Result<Vec<i16>, String> {
+        let sample_rate = SYNTHETIC_SAMPLE_RATE as u64;
+        let beep_length = BEEP_DURATION_US * sample_rate / 1_000_000;
+        let period_length = SYNC_SIGNAL_PERIOD_US * sample_rate / 1_000_000;
+        let samples = (self.sample_index..self.sample_index + SYNTHETIC_CHUNK_LENGTH).map(|sample_index| {
+            let period_sample_index = sample_index % period_length;
+            if period_sample_index < beep_length {
+                (BEEP_AMPLITUDE * (2.0 * PI * BEEP_FREQUENCY * period_sample_index as f64 / sample_rate as f64).sin()) as i16
+            } else {
+                0
+            }
+        }).collect();
+        self.sample_index += SYNTHETIC_CHUNK_LENGTH;
+        // Like a microphone, a chunk is available once its last sample is captured
+        let chunk_end = self.capture_clock.instant(self.sample_index * 1_000_000 / sample_rate);
+        std::thread::sleep(chunk_end.saturating_duration_since(std::time::Instant::now()));
+        Ok(samples)
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
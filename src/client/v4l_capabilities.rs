@@ -0,0 +1,295 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use v4l::{Device, FourCC, Fraction};
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::framesize::FrameSizeEnum;
+use v4l::video::Capture;
+
+/// Pixel formats, frame sizes and frame rates supported by a V4L2 capture device
+#[derive(Debug, Clone)]
+pub(crate) struct CameraCapabilities {
+    pub(crate) formats: Vec<PixelFormatCapabilities>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PixelFormatCapabilities {
+    pub(crate) fourcc: FourCC,
+    pub(crate) description: String,
+    pub(crate) frame_sizes: Vec<FrameSizeCapabilities>,
+}
+
+/// A frame size, or a range of frame sizes, and the frame rates supported with it
+#[derive(Debug, Clone)]
+pub(crate) struct FrameSizeCapabilities {
+    pub(crate) size: FrameSizeRange,
+    pub(crate) frame_rates: FrameRates,
+}
+
+/// Discrete frame sizes are represented by a range with equal bounds
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameSizeRange {
+    pub(crate) min_width: u32,
+    pub(crate) max_width: u32,
+    pub(crate) step_width: u32,
+    pub(crate) min_height: u32,
+    pub(crate) max_height: u32,
+    pub(crate) step_height: u32,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum FrameRates {
+    Discrete(Vec<f64>),
+    Range { min_fps: f64, max_fps: f64 },
+}
+
+/// A capture mode supported by the device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct CameraMode {
+    pub(crate) fourcc: FourCC,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) fps: u32,
+}
+
+/// Query the capabilities of a V4L2 capture device.
+/// For stepwise frame sizes, the frame rates are the ones supported at the largest size.
+pub(crate) fn query_camera_capabilities<P: AsRef<Path>>(device_path: P) -> std::io::Result<CameraCapabilities> {
+    let device = Device::with_path(device_path)?;
+    let mut formats = Vec::new();
+    for format_description in device.enum_formats()? {
+        let mut frame_sizes = Vec::new();
+        for frame_size in device.enum_framesizes(format_description.fourcc)? {
+            let size = match frame_size.size {
+                FrameSizeEnum::Discrete(discrete) => FrameSizeRange {
+                    min_width: discrete.width,
+                    max_width: discrete.width,
+                    step_width: 1,
+                    min_height: discrete.height,
+                    max_height: discrete.height,
+                    step_height: 1,
+                },
+                FrameSizeEnum::Stepwise(stepwise) => FrameSizeRange {
+                    min_width: stepwise.min_width,
+                    max_width: stepwise.max_width,
+                    step_width: stepwise.step_width.max(1),
+                    min_height: stepwise.min_height,
+                    max_height: stepwise.max_height,
+                    step_height: stepwise.step_height.max(1),
+                },
+            };
+            let frame_rates = query_frame_rates(&device, format_description.fourcc, size.max_width, size.max_height)?;
+            frame_sizes.push(FrameSizeCapabilities { size, frame_rates });
+        }
+        formats.push(PixelFormatCapabilities {
+            fourcc: format_description.fourcc,
+            description: format_description.description,
+            frame_sizes,
+        });
+    }
+    Ok(CameraCapabilities { formats })
+}
+
+fn query_frame_rates(device: &Device, fourcc: FourCC, width: u32, height: u32) -> std::io::Result<FrameRates> {
+    let mut discrete_frame_rates = Vec::new();
+    for frame_interval in device.enum_frameintervals(fourcc, width, height)? {
+        match frame_interval.interval {
+            FrameIntervalEnum::Discrete(interval) => discrete_frame_rates.extend(frame_rate(interval)),
+            // The longest interval gives the lowest frame rate
+            FrameIntervalEnum::Stepwise(stepwise) => if let (Some(min_fps), Some(max_fps)) = (frame_rate(stepwise.max), frame_rate(stepwise.min)) {
+                return Ok(FrameRates::Range {
+                    min_fps: min_fps.min(max_fps),
+                    max_fps: min_fps.max(max_fps),
+                });
+            },
+        }
+    }
+    Ok(FrameRates::Discrete(discrete_frame_rates))
+}
+
+/// Frame rate of a frame interval, `None` for the invalid intervals reported by some drivers
+fn frame_rate(interval: Fraction) -> Option<f64> {
+    (interval.numerator != 0 && interval.denominator != 0).then(|| interval.denominator as f64 / interval.numerator as f64)
+}
+
+impl CameraCapabilities {
+    /// Supported mode nearest to the requested one, using one of the given pixel formats.
+    /// Resolutions are compared first, then frame rates, then the order of the pixel formats.
+    pub(crate) fn nearest_mode(&self, width: u32, height: u32, fps: u32, pixel_formats: &[FourCC]) -> Option<CameraMode> {
+        let mut nearest_mode: Option<((u64, u32, usize), CameraMode)> = None;
+        for format in self.formats.iter() {
+            let pixel_format_rank = match pixel_formats.iter().position(|pixel_format| *pixel_format == format.fourcc) {
+                Some(pixel_format_rank) => pixel_format_rank,
+                None => continue,
+            };
+            for frame_size in format.frame_sizes.iter() {
+                let (mode_width, mode_height) = frame_size.size.nearest(width, height);
+                let mode_fps = match frame_size.frame_rates.nearest(fps) {
+                    Some(mode_fps) => mode_fps,
+                    None => continue,
+                };
+                let distance = ((width.abs_diff(mode_width) + height.abs_diff(mode_height)) as u64, fps.abs_diff(mode_fps), pixel_format_rank);
+                if nearest_mode.is_none_or(|(nearest_distance, _)| distance < nearest_distance) {
+                    let mode = CameraMode {
+                        fourcc: format.fourcc,
+                        width: mode_width,
+                        height: mode_height,
+                        fps: mode_fps,
+                    };
+                    nearest_mode = Some((distance, mode));
+                }
+            }
+        }
+        nearest_mode.map(|(_, mode)| mode)
+    }
+}
+
+impl FrameSizeRange {
+    fn nearest(&self, width: u32, height: u32) -> (u32, u32) {
+        (
+            nearest_step(width, self.min_width, self.max_width, self.step_width),
+            nearest_step(height, self.min_height, self.max_height, self.step_height),
+        )
+    }
+}
+
+/// Value of `min + k * step`, not greater than `max`, nearest to `value`. A zero step is handled as 1, and a `max`
+/// lower than `min` as `min`.
+fn nearest_step(value: u32, min: u32, max: u32, step: u32) -> u32 {
+    let (value, min, max, step) = (value as u64, min as u64, max.max(min) as u64, step.max(1) as u64);
+    let value = value.clamp(min, max);
+    let nearest = min + (value - min + step / 2) / step * step;
+    let nearest = if nearest > max {
+        nearest - step
+    } else {
+        nearest
+    };
+    nearest as u32
+}
+
+impl FrameRates {
+    fn nearest(&self, fps: u32) -> Option<u32> {
+        match self {
+            FrameRates::Discrete(frame_rates) => frame_rates.iter()
+                .filter(|frame_rate| frame_rate.is_finite())
+                .min_by(|a, b| (*a - fps as f64).abs().total_cmp(&(*b - fps as f64).abs()))
+                .map(|nearest_fps| (nearest_fps.round() as u32).max(1)),
+            // Also rejects NaN bounds, on which clamp panics
+            FrameRates::Range { min_fps, max_fps } if !(min_fps.is_finite() && max_fps.is_finite() && min_fps <= max_fps) => None,
+            FrameRates::Range { min_fps, max_fps } => Some((fps as f64).clamp(*min_fps, *max_fps).round().max(1.0) as u32),
+        }
+    }
+}
+
+impl Display for CameraCapabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for format in self.formats.iter() {
+            writeln!(f, "{} ({}):", format.fourcc, format.description)?;
+            for frame_size in format.frame_sizes.iter() {
+                writeln!(f, "  {}: {}", frame_size.size, frame_size.frame_rates)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for FrameSizeRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.min_width == self.max_width && self.min_height == self.max_height {
+            write!(f, "{}x{}", self.min_width, self.min_height)
+        } else {
+            write!(f, "{}x{} to {}x{}, step {}x{}", self.min_width, self.min_height, self.max_width, self.max_height, self.step_width, self.step_height)
+        }
+    }
+}
+
+impl Display for FrameRates {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameRates::Discrete(frame_rates) => {
+                let frame_rates: Vec<String> = frame_rates.iter().map(|frame_rate| format!("{:.4}", frame_rate).trim_end_matches('0').trim_end_matches('.').to_string()).collect();
+                write!(f, "{} fps", frame_rates.join(", "))
+            },
+            FrameRates::Range { min_fps, max_fps } => write!(f, "{:.1} to {:.1} fps", min_fps, max_fps),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn discrete_size(width: u32, height: u32, frame_rates: Vec<f64>) -> FrameSizeCapabilities {
+        FrameSizeCapabilities {
+            size: FrameSizeRange {
+                min_width: width,
+                max_width: width,
+                step_width: 1,
+                min_height: height,
+                max_height: height,
+                step_height: 1,
+            },
+            frame_rates: FrameRates::Discrete(frame_rates),
+        }
+    }
+
+    fn test_capabilities() -> CameraCapabilities {
+        CameraCapabilities {
+            formats: vec![
+                PixelFormatCapabilities {
+                    fourcc: FourCC::new(b"YUYV"),
+                    description: "YUYV 4:2:2".to_string(),
+                    frame_sizes: vec![discrete_size(640, 480, vec![30.0, 15.0]), discrete_size(1280, 720, vec![10.0])],
+                },
+                PixelFormatCapabilities {
+                    fourcc: FourCC::new(b"MJPG"),
+                    description: "Motion-JPEG".to_string(),
+                    frame_sizes: vec![discrete_size(640, 480, vec![30.0]), discrete_size(1280, 720, vec![30.0])],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn nearest_step_rounds_to_the_grid() {
+        assert_eq!(nearest_step(99, 16, 1920, 8), 96);
+        assert_eq!(nearest_step(101, 16, 1920, 8), 104);
+        assert_eq!(nearest_step(1, 16, 1920, 8), 16);
+        assert_eq!(nearest_step(5000, 16, 1920, 8), 1920);
+        // 1916 is not on the grid of 16 + 8 * k, and greater values are out of range
+        assert_eq!(nearest_step(5000, 16, 1916, 8), 1912);
+    }
+
+    #[test]
+    fn nearest_step_handles_invalid_ranges() {
+        assert_eq!(nearest_step(100, 16, 1920, 0), 100);
+        assert_eq!(nearest_step(100, 640, 320, 8), 640);
+        assert_eq!(nearest_step(u32::MAX, 0, u32::MAX, u32::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn invalid_frame_rates_are_ignored() {
+        assert_eq!(frame_rate(Fraction::new(1, 30)), Some(30.0));
+        assert_eq!(frame_rate(Fraction::new(0, 30)), None);
+        assert_eq!(frame_rate(Fraction::new(1, 0)), None);
+        assert_eq!(FrameRates::Discrete(vec![f64::INFINITY, 25.0]).nearest(60), Some(25));
+        assert_eq!(FrameRates::Range { min_fps: f64::NAN, max_fps: 30.0 }.nearest(15), None);
+        assert_eq!(FrameRates::Range { min_fps: 30.0, max_fps: 5.0 }.nearest(15), None);
+        assert_eq!(FrameRates::Range { min_fps: 5.0, max_fps: 30.0 }.nearest(60), Some(30));
+    }
+
+    #[test]
+    fn nearest_mode_prefers_resolution_then_frame_rate_then_pixel_format() {
+        let capabilities = test_capabilities();
+        let pixel_formats = [FourCC::new(b"YUYV"), FourCC::new(b"MJPG")];
+        assert_eq!(capabilities.nearest_mode(1280, 720, 30, &pixel_formats), Some(CameraMode { fourcc: FourCC::new(b"MJPG"), width: 1280, height: 720, fps: 30 }));
+        assert_eq!(capabilities.nearest_mode(640, 480, 30, &pixel_formats), Some(CameraMode { fourcc: FourCC::new(b"YUYV"), width: 640, height: 480, fps: 30 }));
+        assert_eq!(capabilities.nearest_mode(1280, 720, 10, &pixel_formats), Some(CameraMode { fourcc: FourCC::new(b"YUYV"), width: 1280, height: 720, fps: 10 }));
+    }
+
+    #[test]
+    fn nearest_mode_only_uses_the_given_pixel_formats() {
+        let capabilities = test_capabilities();
+        assert_eq!(capabilities.nearest_mode(1280, 720, 10, &[FourCC::new(b"MJPG")]).map(|mode| mode.fps), Some(30));
+        assert_eq!(capabilities.nearest_mode(1280, 720, 10, &[FourCC::new(b"NV12")]), None);
+    }
+}
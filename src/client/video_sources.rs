@@ -0,0 +1,51 @@
+use log::info;
+use qkd_camera_common_lib::VideoSource;
+use crate::camera::{Camera, CameraFrame};
+use crate::json_client_config::JsonClientConfig;
+use crate::screen_capture::ScreenCapture;
+
+/// The camera and, when `screen_share` is configured, the screen, frames coming from the selected one
+pub(crate) struct VideoSources<C: Camera> {
+    camera: C,
+    screen_capture: Option<ScreenCapture>,
+    video_source: VideoSource,
+}
+
+impl<C: Camera> Camera for VideoSources<C> {
+    fn new(client_config: &JsonClientConfig) -> Result<Self, String> {
+        let camera = C::new(client_config)?;
+        let screen_capture = match client_config.screen_share {
+            Some(_) => Some(ScreenCapture::new(client_config).map_err(|e| format!("cannot open screen share: {}", e))?),
+            None => None,
+        };
+        Ok(Self {
+            camera,
+            screen_capture,
+            video_source: VideoSource::Camera,
+        })
+    }
+
+    fn get_frame(&mut self) -> Result<CameraFrame, String> {
+        match (self.video_source, self.screen_capture.as_mut()) {
+            (VideoSource::Screen, Some(screen_capture)) => screen_capture.get_frame(),
+            _ => self.camera.get_frame(),
+        }
+    }
+
+    fn reconfigure(&mut self, camera_format: Option<(u32, u32)>, camera_fps: u32) -> Result<(), String> {
+        self.camera.reconfigure(camera_format, camera_fps)?;
+        if let Some(screen_capture) = self.screen_capture.as_mut() {
+            screen_capture.reconfigure(camera_format, camera_fps)?;
+        }
+        Ok(())
+    }
+
+    fn select_source(&mut self, video_source: VideoSource) -> Result<(), String> {
+        if video_source == VideoSource::Screen && self.screen_capture.is_none() {
+            return Err("screen_share is not configured".to_string());
+        }
+        info!("Sending the {}", video_source);
+        self.video_source = video_source;
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// Audio device selected by its index in the device list, or by its name, eg `2` or `"USB Headset"`
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum AudioDeviceSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl AudioDeviceSelector {
+    /// Index of the selected device among `device_names`. A name matches either exactly,
+    /// or as a case insensitive part of a single device name.
+    pub fn find(&self, device_names: &[String]) -> Result<usize, String> {
+        let matching_device_indexes: Vec<usize> = match self {
+            AudioDeviceSelector::Index(device_index) => (*device_index..device_names.len()).take(1).collect(),
+            AudioDeviceSelector::Name(name) => match device_names.iter().position(|device_name| device_name == name) {
+                Some(device_index) => vec![device_index],
+                None => device_names.iter().enumerate()
+                    .filter(|(_, device_name)| device_name.to_lowercase().contains(&name.to_lowercase()))
+                    .map(|(device_index, _)| device_index)
+                    .collect(),
+            },
+        };
+        let available_devices: Vec<String> = device_names.iter().enumerate()
+            .map(|(device_index, device_name)| format!("{}: {}", device_index, device_name))
+            .collect();
+        match matching_device_indexes.as_slice() {
+            [device_index] => Ok(*device_index),
+            [] => Err(format!("audio device {} not found, available devices are [{}]", self, available_devices.join(", "))),
+            _ => Err(format!("audio device {} matches several devices, available devices are [{}]", self, available_devices.join(", "))),
+        }
+    }
+}
+
+impl std::fmt::Display for AudioDeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioDeviceSelector::Index(device_index) => write!(f, "#{}", device_index),
+            AudioDeviceSelector::Name(name) => write!(f, "\"{}\"", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_names() -> Vec<String> {
+        vec!["Built-in Microphone".to_string(), "USB Headset".to_string(), "USB Headset Mono".to_string()]
+    }
+
+    #[test]
+    fn index_selects_existing_device() {
+        assert_eq!(AudioDeviceSelector::Index(1).find(&device_names()), Ok(1));
+        assert!(AudioDeviceSelector::Index(3).find(&device_names()).is_err());
+    }
+
+    #[test]
+    fn exact_name_is_preferred_to_partial_matches() {
+        assert_eq!(AudioDeviceSelector::Name("USB Headset".to_string()).find(&device_names()), Ok(1));
+    }
+
+    #[test]
+    fn partial_name_matches_case_insensitively() {
+        assert_eq!(AudioDeviceSelector::Name("built-in".to_string()).find(&device_names()), Ok(0));
+        assert_eq!(AudioDeviceSelector::Name("mono".to_string()).find(&device_names()), Ok(2));
+    }
+
+    #[test]
+    fn ambiguous_or_unknown_name_is_an_error() {
+        let error = AudioDeviceSelector::Name("usb".to_string()).find(&device_names()).unwrap_err();
+        assert!(error.contains("several devices"), "{}", error);
+        let error = AudioDeviceSelector::Name("Speaker".to_string()).find(&device_names()).unwrap_err();
+        assert!(error.contains("not found"), "{}", error);
+    }
+}
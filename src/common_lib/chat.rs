@@ -0,0 +1,112 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+/// Longest chat message, in bytes
+pub const MAX_CHAT_MESSAGE_LENGTH: usize = 4096;
+
+/// Text message sent over the same TLS connection as audio and video, in both directions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub text: String,
+}
+
+impl ChatMessage {
+    pub fn new(text: &str) -> Result<Self, String> {
+        let chat_message = Self { text: text.trim().to_string() };
+        chat_message.validate()?;
+        Ok(chat_message)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.text.is_empty() {
+            return Err("empty chat message".to_string());
+        }
+        if self.text.len() > MAX_CHAT_MESSAGE_LENGTH {
+            return Err(format!("chat message too long: {} bytes, at most {}", self.text.len(), MAX_CHAT_MESSAGE_LENGTH));
+        }
+        Ok(())
+    }
+
+    /// Text without control characters, so that a received message can't inject terminal escape sequences
+    pub fn printable_text(&self) -> String {
+        self.text.chars().map(|character| if character.is_control() { ' ' } else { character }).collect()
+    }
+}
+
+/// Sender of the messages typed on this side, in transcripts
+pub const LOCAL_SENDER: &str = "local";
+/// Sender of the messages received from the other side
+pub const REMOTE_SENDER: &str = "remote";
+
+/// Print a chat message in the terminal, prefixed by its sender
+pub fn print_chat_message(sender: &str, chat_message: &ChatMessage) {
+    println!("[{}] {}", sender, chat_message.printable_text());
+}
+
+/// Chat messages of a session appended to a file, one per line with its Unix timestamp and sender.
+/// The default transcript records nothing.
+#[derive(Default)]
+pub struct ChatTranscript {
+    file: Option<File>,
+}
+
+impl ChatTranscript {
+    /// Transcript appended to `path`, or recording nothing when missing
+    pub fn open(path: Option<&str>, session_description: &str) -> Result<Self, String> {
+        let file = match path {
+            Some(path) => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)
+                    .map_err(|e| format!("cannot open chat transcript {}: {}", path, e))?;
+                writeln!(file, "# {} session with {}", unix_timestamp(), session_description)
+                    .map_err(|e| format!("cannot write chat transcript {}: {}", path, e))?;
+                Some(file)
+            },
+            None => None,
+        };
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, sender: &str, chat_message: &ChatMessage) {
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = writeln!(file, "{} [{}] {}", unix_timestamp(), sender, chat_message.printable_text()) {
+                log::warn!("Error writing chat transcript, no longer recorded: {}", e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_is_trimmed() {
+        assert_eq!(ChatMessage::new("  hello\n").unwrap().text, "hello");
+    }
+
+    #[test]
+    fn empty_message_is_invalid() {
+        assert!(ChatMessage::new(" \n").is_err());
+        assert!(ChatMessage { text: String::new() }.validate().is_err());
+    }
+
+    #[test]
+    fn length_is_limited_in_bytes() {
+        assert!(ChatMessage { text: "a".repeat(MAX_CHAT_MESSAGE_LENGTH) }.validate().is_ok());
+        assert!(ChatMessage { text: "a".repeat(MAX_CHAT_MESSAGE_LENGTH + 1) }.validate().is_err());
+        assert!(ChatMessage { text: "é".repeat(MAX_CHAT_MESSAGE_LENGTH / 2 + 1) }.validate().is_err());
+    }
+
+    #[test]
+    fn control_characters_are_not_printed() {
+        let chat_message = ChatMessage { text: "\u{1b}[2Jhello\tworld".to_string() };
+        assert_eq!(chat_message.printable_text(), " [2Jhello world");
+    }
+}
@@ -0,0 +1,273 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use serde_path_to_error::Segment;
+use crate::config_validation::{self, ConfigValidator, ValidateConfig};
+
+/// Arguments shared by the subcommands that need a configuration file
+#[derive(Debug, clap::Args)]
+pub struct ConfigArgs {
+    /// Path of the configuration file, in JSON, TOML (.toml) or YAML (.yaml, .yml) format
+    pub config: PathBuf,
+    /// Override a configuration field, eg `--set target_sae_port=14443` or `--set override_default_format.width=640`
+    #[arg(short = 's', long = "set", value_name = "FIELD=VALUE")]
+    pub overrides: Vec<String>,
+}
+
+impl ConfigArgs {
+    /// Load the configuration, overridden by the environment variables starting with `env_prefix` then by the command line
+    pub fn load<T: DeserializeOwned + JsonSchema + ValidateConfig>(&self, env_prefix: &str) -> Result<T, String> {
+        let mut overrides = environment_overrides(env_prefix);
+        overrides.extend(self.overrides.iter().cloned());
+        load_config(&self.config, &overrides)
+    }
+}
+
+/// Load a configuration file, applying `field=value` overrides before deserializing and validating it.
+/// All the problems found are reported at once, one per line, with the JSON path of the faulty field.
+pub fn load_config<T: DeserializeOwned + JsonSchema + ValidateConfig>(config_path: &Path, overrides: &[String]) -> Result<T, String> {
+    let mut config_value = parse_config_file(config_path)?;
+    let root_schema = serde_json::to_value(schemars::schema_for!(T)).unwrap();
+    for config_override in overrides {
+        apply_override(&mut config_value, &root_schema, config_override)?;
+    }
+
+    let mut validator = ConfigValidator::default();
+    config_validation::remove_unknown_fields(&mut validator, &root_schema, &mut config_value);
+    // Fields removed because of a deserialization error, as the segments of their path
+    let mut removed_fields: Vec<Vec<String>> = Vec::new();
+    loop {
+        match serde_path_to_error::deserialize::<_, T>(config_value.clone()) {
+            Ok(config) => {
+                config.validate(&mut validator);
+                return validator.into_result().map(|_| config);
+            },
+            Err(e) => {
+                let error_segments: Vec<String> = e.path().iter().map(Segment::to_string).collect();
+                // A removed required field is then reported as missing by its parent, which is already reported
+                if removed_fields.iter().any(|removed_field| removed_field.len() > error_segments.len() && removed_field.starts_with(&error_segments)) {
+                    return Err(validator.report());
+                }
+                let json_path = match e.path().to_string().as_str() {
+                    "." => "$".to_string(),
+                    path => format!("$.{}", path),
+                };
+                validator.problem(&json_path, e.inner());
+                // Without the faulty field, the next deserialization reports the problems of the other fields
+                match remove_field(&mut config_value, e.path()) {
+                    Some(removed_field) => removed_fields.push(removed_field),
+                    None => return Err(validator.report()),
+                }
+            }
+        }
+    }
+}
+
+/// JSON schema of a configuration type, pretty printed
+pub fn json_schema<T: JsonSchema>() -> String {
+    serde_json::to_string_pretty(&schemars::schema_for!(T)).unwrap()
+}
+
+/// Write a subcommand output to the given file, or to the standard output if missing
+pub fn write_output(output: Option<PathBuf>, content: &str) -> Result<(), String> {
+    match output {
+        Some(output) => std::fs::write(&output, content).map_err(|e| format!("cannot write {}: {}", output.display(), e)),
+        None => {
+            println!("{}", content);
+            Ok(())
+        }
+    }
+}
+
+/// Parse a configuration file as JSON, TOML or YAML depending on its extension
+fn parse_config_file(config_path: &Path) -> Result<Value, String> {
+    let config_str = std::fs::read_to_string(config_path)
+        .map_err(|e| format!("$: cannot read file: {}", e))?;
+    match config_path.extension().and_then(OsStr::to_str) {
+        Some("toml") => toml::from_str(&config_str).map_err(|e| format!("$: invalid TOML: {}", e)),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&config_str).map_err(|e| format!("$: invalid YAML: {}", e)),
+        _ => serde_json::from_str(&config_str).map_err(|e| format!("$: invalid JSON: {}", e)),
+    }
+}
+
+/// Overrides built from the environment variables starting with `env_prefix`, eg `QKD_CLIENT_TARGET_SAE_PORT=14443`
+/// overrides `target_sae_port`. Nested fields are separated by `__`, eg `QKD_CLIENT_OVERRIDE_DEFAULT_FORMAT__WIDTH`.
+pub fn environment_overrides(env_prefix: &str) -> Vec<String> {
+    let mut overrides: Vec<String> = std::env::vars()
+        .filter_map(|(variable_name, variable_value)| {
+            let field_path = variable_name.strip_prefix(env_prefix)?.to_lowercase().replace("__", ".");
+            Some(format!("{}={}", field_path, variable_value))
+        })
+        .collect();
+    overrides.sort();
+    overrides
+}
+
+/// Set the field at the dot separated path to the value. The value is kept as a string, unless the JSON schema says
+/// the field doesn't accept one: it is then parsed as JSON, eg for numbers and booleans.
+pub fn apply_override(config_value: &mut Value, root_schema: &Value, config_override: &str) -> Result<(), String> {
+    let (field_path, raw_value) = config_override.split_once('=')
+        .ok_or_else(|| format!("invalid override {}, expected FIELD=VALUE", config_override))?;
+    // The value itself is not logged, it may be a secret
+    log::debug!("Overriding configuration field {}", field_path);
+    let value = if config_validation::field_accepts_string(root_schema, field_path) {
+        Value::String(raw_value.to_string())
+    } else {
+        serde_json::from_str(raw_value).unwrap_or_else(|_| Value::String(raw_value.to_string()))
+    };
+
+    let mut field_names = field_path.split('.').peekable();
+    let mut current_value = config_value;
+    while let Some(field_name) = field_names.next() {
+        if current_value.is_null() {
+            *current_value = Value::Object(Map::new());
+        }
+        let current_object = current_value.as_object_mut()
+            .ok_or_else(|| format!("cannot override {}: parent of {} is not an object", field_path, field_name))?;
+        if field_names.peek().is_none() {
+            current_object.insert(field_name.to_string(), value);
+            return Ok(());
+        }
+        current_value = current_object.entry(field_name).or_insert(Value::Null);
+    }
+    Err(format!("invalid override {}: empty field name", config_override))
+}
+
+/// Remove the object field containing the value at `path`, a faulty list item removing the whole list so that the
+/// indexes of the other items still match the configuration file. Returns the path segments of the removed field.
+fn remove_field(config_value: &mut Value, path: &serde_path_to_error::Path) -> Option<Vec<String>> {
+    let segments: Vec<&Segment> = path.iter().collect();
+    let field_index = segments.iter().rposition(|segment| matches!(segment, Segment::Map { .. }))?;
+    let mut current_value = config_value;
+    for segment in &segments[..field_index] {
+        current_value = match segment {
+            Segment::Seq { index } => current_value.get_mut(*index)?,
+            Segment::Map { key } | Segment::Enum { variant: key } => current_value.get_mut(key.as_str())?,
+            Segment::Unknown => return None,
+        };
+    }
+    let Segment::Map { key } = segments[field_index] else {
+        return None;
+    };
+    current_value.as_object_mut()?.remove(key)?;
+    Some(segments[..=field_index].iter().map(|segment| segment.to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use super::*;
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    #[serde(deny_unknown_fields)]
+    struct TestConfig {
+        port: u16,
+        width: Option<u32>,
+        height: Option<u32>,
+    }
+
+    impl ValidateConfig for TestConfig {
+        fn validate(&self, validator: &mut ConfigValidator) {
+            validator.check_range("$.port", Some(self.port), 1024, u16::MAX);
+            validator.check_range("$.width", self.width, 1, 4096);
+            validator.check_range("$.height", self.height, 1, 4096);
+        }
+    }
+
+    fn load_test_config(config_name: &str, config_str: &str) -> Result<TestConfig, String> {
+        let config_path = std::env::temp_dir().join(format!("qkd_config_loader_test_{}_{}.json", std::process::id(), config_name));
+        std::fs::write(&config_path, config_str).unwrap();
+        let config = load_config(&config_path, &[]);
+        std::fs::remove_file(&config_path).unwrap();
+        config
+    }
+
+    #[test]
+    fn valid_config_is_loaded() {
+        let config = load_test_config("valid", r#"{"port": 14443, "width": 640}"#).unwrap();
+        assert_eq!(config.port, 14443);
+        assert_eq!(config.width, Some(640));
+        assert_eq!(config.height, None);
+    }
+
+    #[test]
+    fn type_errors_are_reported_with_validation_problems() {
+        let problems = load_test_config("invalid", r#"{"port": 80, "width": "wide", "height": 10000, "depth": 3}"#).unwrap_err();
+        let problems: Vec<&str> = problems.lines().collect();
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert_eq!(problems[0], "$.depth: unknown field");
+        assert!(problems[1].starts_with("$.width: invalid type: string \"wide\""), "{}", problems[1]);
+        assert_eq!(problems[2], "$.port: 80 is out of range, expected between 1024 and 65535");
+        assert_eq!(problems[3], "$.height: 10000 is out of range, expected between 1 and 4096");
+    }
+
+    #[test]
+    fn removed_required_field_is_not_reported_missing() {
+        let problems = load_test_config("required", r#"{"port": "https", "width": "wide"}"#).unwrap_err();
+        let problems: Vec<&str> = problems.lines().collect();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("$.port: invalid type: string \"https\""), "{}", problems[0]);
+        assert!(problems[1].starts_with("$.width: invalid type: string \"wide\""), "{}", problems[1]);
+    }
+
+    #[derive(Deserialize, JsonSchema)]
+    #[allow(dead_code)]
+    struct OverrideTestConfig {
+        password: String,
+        name: Option<String>,
+        port: u16,
+        enabled: bool,
+        limits: Option<OverrideTestLimits>,
+    }
+
+    #[derive(Deserialize, JsonSchema)]
+    #[allow(dead_code)]
+    struct OverrideTestLimits {
+        max_width: Option<u32>,
+    }
+
+    fn override_test_config(config_overrides: &[&str]) -> Result<Value, String> {
+        let root_schema = serde_json::to_value(schemars::schema_for!(OverrideTestConfig)).unwrap();
+        let mut config_value = serde_json::json!({"password": "secret", "port": 14443, "enabled": false});
+        for config_override in config_overrides {
+            apply_override(&mut config_value, &root_schema, config_override)?;
+        }
+        Ok(config_value)
+    }
+
+    #[test]
+    fn override_values_are_strings_unless_the_schema_expects_another_type() {
+        let config_value = override_test_config(&["password=123456", "name=true", "port=8443", "enabled=true"]).unwrap();
+        assert_eq!(config_value["password"], "123456");
+        assert_eq!(config_value["name"], "true");
+        assert_eq!(config_value["port"], 8443);
+        assert_eq!(config_value["enabled"], true);
+    }
+
+    #[test]
+    fn override_creates_missing_parent_objects() {
+        let config_value = override_test_config(&["limits.max_width=1280"]).unwrap();
+        assert_eq!(config_value["limits"]["max_width"], 1280);
+    }
+
+    #[test]
+    fn invalid_override_is_an_error() {
+        assert!(override_test_config(&["port"]).is_err());
+        assert!(override_test_config(&["port.number=1"]).is_err());
+    }
+
+    #[test]
+    fn unparsable_override_is_kept_as_string_to_be_reported() {
+        let config_value = override_test_config(&["port=https"]).unwrap();
+        assert_eq!(config_value["port"], "https");
+    }
+
+    #[test]
+    fn unwritable_output_is_an_error() {
+        let output = std::env::temp_dir().join(format!("qkd_config_loader_test_{}_missing", std::process::id())).join("config.json");
+        let e = write_output(Some(output.clone()), "{}").unwrap_err();
+        assert!(e.contains(&output.display().to_string()));
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt::Display;
+use std::path::Path;
+use serde_json::Value;
+
+/// Semantic checks run on a configuration once it has been deserialized
+pub trait ValidateConfig {
+    fn validate(&self, validator: &mut ConfigValidator);
+}
+
+/// Collect every configuration problem, so they can be reported at once
+#[derive(Debug, Default)]
+pub struct ConfigValidator {
+    problems: Vec<String>,
+}
+
+impl ConfigValidator {
+    /// Record a problem for the field at `json_path`, eg `$.target_sae_port`
+    pub fn problem(&mut self, json_path: &str, message: impl Display) {
+        self.problems.push(format!("{}: {}", json_path, message));
+    }
+
+    pub fn check(&mut self, condition: bool, json_path: &str, message: impl Display) {
+        if !condition {
+            self.problem(json_path, message);
+        }
+    }
+
+    /// Check that an optional value is in the inclusive range `min..=max`
+    pub fn check_range<T: PartialOrd + Display>(&mut self, json_path: &str, value: Option<T>, min: T, max: T) {
+        if let Some(value) = value {
+            if value < min || value > max {
+                self.problem(json_path, format!("{} is out of range, expected between {} and {}", value, min, max));
+            }
+        }
+    }
+
+    pub fn check_file_exists(&mut self, json_path: &str, path: &str) {
+        if !Path::new(path).exists() {
+            self.problem(json_path, format!("{} does not exist", path));
+        }
+    }
+
+    /// All the recorded problems, one per line
+    pub fn report(self) -> String {
+        self.problems.join("\n")
+    }
+
+    pub fn into_result(self) -> Result<(), String> {
+        if self.problems.is_empty() {
+            Ok(())
+        } else {
+            Err(self.report())
+        }
+    }
+}
+
+/// Report and remove every field of `value` not described by the JSON schema, for objects not accepting additional properties.
+/// Removing them allows deserializing the remaining fields, so that their own problems are reported too.
+pub fn remove_unknown_fields(validator: &mut ConfigValidator, root_schema: &Value, value: &mut Value) {
+    collect_unknown_fields(validator, root_schema, root_schema, value, "$");
+}
+
+fn collect_unknown_fields(validator: &mut ConfigValidator, root_schema: &Value, schema: &Value, value: &mut Value, json_path: &str) {
+    let schemas = resolve_subschemas(root_schema, schema);
+    match value {
+        Value::Object(fields) => {
+            let object_schemas: Vec<&Value> = schemas.iter().copied().filter(|schema| schema.get("properties").is_some()).collect();
+            if object_schemas.is_empty() {
+                return;
+            }
+            let denies_unknown_fields = object_schemas.iter()
+                .all(|schema| schema.get("additionalProperties") == Some(&Value::Bool(false)));
+            let mut unknown_field_names = Vec::new();
+            for (field_name, field_value) in fields.iter_mut() {
+                let field_path = format!("{}.{}", json_path, field_name);
+                let field_schemas: Vec<&Value> = object_schemas.iter()
+                    .filter_map(|schema| schema["properties"].get(field_name))
+                    .collect();
+                if field_schemas.is_empty() {
+                    if denies_unknown_fields {
+                        validator.problem(&field_path, "unknown field");
+                        unknown_field_names.push(field_name.clone());
+                    }
+                    continue;
+                }
+                for field_schema in field_schemas {
+                    collect_unknown_fields(validator, root_schema, field_schema, field_value, &field_path);
+                }
+            }
+            for unknown_field_name in unknown_field_names {
+                fields.remove(&unknown_field_name);
+            }
+        },
+        Value::Array(items) => {
+            for items_schema in schemas.iter().filter_map(|schema| schema.get("items")) {
+                for (item_index, item) in items.iter_mut().enumerate() {
+                    collect_unknown_fields(validator, root_schema, items_schema, item, &format!("{}[{}]", json_path, item_index));
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+/// Whether the field at the dot separated path accepts a string according to the JSON schema, fields missing from the
+/// schema being considered as accepting one
+pub fn field_accepts_string(root_schema: &Value, field_path: &str) -> bool {
+    let mut field_schemas = vec![root_schema];
+    for field_name in field_path.split('.') {
+        field_schemas = field_schemas.into_iter()
+            .flat_map(|schema| resolve_subschemas(root_schema, schema))
+            .filter_map(|schema| schema.get("properties").and_then(|properties| properties.get(field_name)))
+            .collect();
+    }
+    if field_schemas.is_empty() {
+        return true;
+    }
+    field_schemas.into_iter()
+        .flat_map(|field_schema| resolve_subschemas(root_schema, field_schema))
+        .any(|schema| match schema.get("type") {
+            Some(Value::String(schema_type)) => schema_type == "string",
+            Some(Value::Array(schema_types)) => schema_types.iter().any(|schema_type| schema_type == "string"),
+            _ => false,
+        })
+}
+
+/// Follow `$ref` and flatten `allOf`, `anyOf` and `oneOf` into the list of schemas that may describe a value
+fn resolve_subschemas<'a>(root_schema: &'a Value, schema: &'a Value) -> Vec<&'a Value> {
+    let schema = match schema.get("$ref").and_then(Value::as_str).and_then(|reference| reference.strip_prefix("#/definitions/")) {
+        Some(definition_name) => match root_schema["definitions"].get(definition_name) {
+            Some(definition) => definition,
+            None => return Vec::new(),
+        },
+        None => schema,
+    };
+    let mut schemas = vec![schema];
+    for combinator in ["allOf", "anyOf", "oneOf"] {
+        if let Some(subschemas) = schema.get(combinator).and_then(Value::as_array) {
+            for subschema in subschemas {
+                schemas.extend(resolve_subschemas(root_schema, subschema));
+            }
+        }
+    }
+    schemas
+}
+
+#[cfg(test)]
+mod tests {
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+    use serde_json::json;
+    use super::*;
+
+    #[derive(Deserialize, JsonSchema)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct TestConfig {
+        port: u16,
+        limits: Option<TestLimits>,
+        #[serde(default)]
+        per_sae_limits: Vec<TestLimits>,
+    }
+
+    #[derive(Deserialize, JsonSchema)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct TestLimits {
+        max_width: Option<u32>,
+    }
+
+    fn remove_unknown_test_config_fields(value: &mut Value) -> Vec<String> {
+        let root_schema = serde_json::to_value(schemars::schema_for!(TestConfig)).unwrap();
+        let mut validator = ConfigValidator::default();
+        remove_unknown_fields(&mut validator, &root_schema, value);
+        validator.problems
+    }
+
+    #[test]
+    fn known_fields_are_kept() {
+        let mut value = json!({"port": 14443, "limits": {"max_width": 640}, "per_sae_limits": [{"max_width": 320}]});
+        let expected_value = value.clone();
+        assert!(remove_unknown_test_config_fields(&mut value).is_empty());
+        assert_eq!(value, expected_value);
+    }
+
+    #[test]
+    fn unknown_fields_are_reported_and_removed() {
+        let mut value = json!({"port": 14443, "prot": 1, "limits": {"max_witdh": 640}, "per_sae_limits": [{}, {"max_height": 480}]});
+        let problems = remove_unknown_test_config_fields(&mut value);
+        assert_eq!(problems, vec![
+            "$.limits.max_witdh: unknown field",
+            "$.per_sae_limits[1].max_height: unknown field",
+            "$.prot: unknown field",
+        ]);
+        assert_eq!(value, json!({"port": 14443, "limits": {}, "per_sae_limits": [{}, {}]}));
+    }
+
+    #[test]
+    fn range_is_inclusive() {
+        let mut validator = ConfigValidator::default();
+        validator.check_range("$.min", Some(2), 2, 16);
+        validator.check_range("$.max", Some(16), 2, 16);
+        validator.check_range("$.missing", None, 2, 16);
+        assert!(validator.into_result().is_ok());
+    }
+
+    #[test]
+    fn out_of_range_values_are_reported() {
+        let mut validator = ConfigValidator::default();
+        validator.check_range("$.too_low", Some(1), 2, 16);
+        validator.check_range("$.too_high", Some(17.5), 2.0, 16.0);
+        assert_eq!(validator.into_result().unwrap_err(), "$.too_low: 1 is out of range, expected between 2 and 16\n\
+                                                         $.too_high: 17.5 is out of range, expected between 2 and 16");
+    }
+}
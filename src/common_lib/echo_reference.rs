@@ -0,0 +1,28 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use serde::{Deserialize, Serialize};
+
+/// Where the server sends the sound it plays and the client listens for it, when both run on the same machine
+pub const DEFAULT_ECHO_REFERENCE_ADDRESS: &str = "127.0.0.1:14600";
+/// Largest UDP payload, bigger packets can't be received
+pub const MAX_ECHO_REFERENCE_PACKET_SIZE: usize = 65507;
+
+/// Block of sound played by the server, sent over UDP to the client next to it so that it can cancel its echo
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EchoReferencePacket {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Interleaved samples when there are several channels
+    pub samples: Vec<i16>,
+}
+
+/// Resolve an echo reference address, which must be a loopback one: the played sound is sent unencrypted
+pub fn resolve_loopback_address(echo_reference_address: &str) -> Result<SocketAddr, String> {
+    let socket_address = echo_reference_address.to_socket_addrs()
+        .map_err(|e| format!("{} is not a valid address: {}", echo_reference_address, e))?
+        .next()
+        .ok_or_else(|| format!("{} resolves to nothing", echo_reference_address))?;
+    if !socket_address.ip().is_loopback() {
+        return Err(format!("{} is not a loopback address, the played sound would leave the machine unencrypted", echo_reference_address));
+    }
+    Ok(socket_address)
+}
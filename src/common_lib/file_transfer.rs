@@ -0,0 +1,482 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Largest file chunk, at most one being sent with each media packet so that transfers don't delay the sound.
+/// Small enough for a chunk to fit in a server message, see [crate::MAX_CONTROL_MESSAGE_SIZE].
+pub const FILE_CHUNK_SIZE: usize = 32 * 1024;
+/// Progress is logged each time a transfer crosses a multiple of this percentage
+const PROGRESS_REPORT_STEP_PERCENT: u64 = 10;
+
+/// File transfer message, carried over the same TLS connection as audio and video, in both directions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileTransferMessage {
+    /// The sender proposes a file, identified by `transfer_id` in the following messages
+    Offer { transfer_id: u64, file_name: String, size: u64, sha256: [u8; 32] },
+    /// The receiver asks for the file from `offset`, the bytes before having been received during a previous call
+    Accept { transfer_id: u64, offset: u64 },
+    Chunk { transfer_id: u64, offset: u64, data: Vec<u8> },
+    /// The receiver got the whole file, with the offered hash
+    Completed { transfer_id: u64 },
+    /// The receiver gave up the transfer
+    Failed { transfer_id: u64, reason: String },
+    /// The sender gave up the transfer
+    Cancelled { transfer_id: u64, reason: String },
+}
+
+/// File to send, hashed before being offered
+pub struct OutgoingFile {
+    path: PathBuf,
+    file_name: String,
+    size: u64,
+    sha256: [u8; 32],
+}
+
+impl OutgoingFile {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file_name = path.file_name().and_then(|file_name| file_name.to_str())
+            .ok_or_else(|| format!("{} has no valid file name", path.display()))?
+            .to_string();
+        let metadata = std::fs::metadata(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        if !metadata.is_file() {
+            return Err(format!("{} is not a file", path.display()));
+        }
+        let sha256 = file_sha256(path).map_err(|e| format!("cannot hash {}: {}", path.display(), e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file_name,
+            size: metadata.len(),
+            sha256,
+        })
+    }
+}
+
+/// File transfers of a session in both directions. Received files are written in the download directory, as
+/// `<name>.<hash>.part` until complete, so that a transfer interrupted by the end of the call resumes when the same
+/// file is sent again.
+pub struct FileTransfers {
+    /// Incoming files are refused when missing
+    download_directory: Option<PathBuf>,
+    next_transfer_id: u64,
+    outgoing: HashMap<u64, OutgoingTransfer>,
+    incoming: HashMap<u64, IncomingTransfer>,
+    /// Messages other than chunks, sent before them
+    pending_messages: VecDeque<FileTransferMessage>,
+}
+
+struct OutgoingTransfer {
+    file: OutgoingFile,
+    /// Opened once the receiver accepted the transfer
+    reader: Option<File>,
+    offset: u64,
+    progress: Progress,
+}
+
+struct IncomingTransfer {
+    file_name: String,
+    size: u64,
+    sha256: [u8; 32],
+    partial_path: PathBuf,
+    writer: File,
+    offset: u64,
+    progress: Progress,
+}
+
+impl FileTransfers {
+    pub fn new(download_directory: Option<&str>) -> Self {
+        Self {
+            download_directory: download_directory.map(PathBuf::from),
+            next_transfer_id: 0,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            pending_messages: VecDeque::new(),
+        }
+    }
+
+    /// Offer a file to the peer, it's sent once accepted
+    pub fn send(&mut self, file: OutgoingFile) {
+        let transfer_id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+        info!("Offering {} ({} bytes)", file.file_name, file.size);
+        self.pending_messages.push_back(FileTransferMessage::Offer {
+            transfer_id,
+            file_name: file.file_name.clone(),
+            size: file.size,
+            sha256: file.sha256,
+        });
+        self.outgoing.insert(transfer_id, OutgoingTransfer {
+            progress: Progress::new(format!("Sending {}", file.file_name), file.size),
+            file,
+            reader: None,
+            offset: 0,
+        });
+    }
+
+    pub fn handle(&mut self, message: FileTransferMessage) {
+        match message {
+            FileTransferMessage::Offer { transfer_id, file_name, size, sha256 } => {
+                let reply = match self.accept_offer(transfer_id, &file_name, size, sha256) {
+                    Ok(Some(offset)) => FileTransferMessage::Accept { transfer_id, offset },
+                    Ok(None) => self.finish_incoming(transfer_id),
+                    Err(reason) => {
+                        warn!("Refusing {}: {}", file_name, reason);
+                        FileTransferMessage::Failed { transfer_id, reason }
+                    },
+                };
+                self.pending_messages.push_back(reply);
+            },
+            FileTransferMessage::Accept { transfer_id, offset } => self.start_sending(transfer_id, offset),
+            FileTransferMessage::Chunk { transfer_id, offset, data } => {
+                if let Err(reason) = self.write_chunk(transfer_id, offset, &data) {
+                    warn!("Error receiving file: {}", reason);
+                    self.incoming.remove(&transfer_id);
+                    self.pending_messages.push_back(FileTransferMessage::Failed { transfer_id, reason });
+                    return;
+                }
+                if self.incoming.get(&transfer_id).is_some_and(|transfer| transfer.offset == transfer.size) {
+                    let reply = self.finish_incoming(transfer_id);
+                    self.pending_messages.push_back(reply);
+                }
+            },
+            FileTransferMessage::Completed { transfer_id } => {
+                if let Some(transfer) = self.outgoing.remove(&transfer_id) {
+                    info!("{} sent", transfer.file.file_name);
+                }
+            },
+            FileTransferMessage::Failed { transfer_id, reason } => {
+                if let Some(transfer) = self.outgoing.remove(&transfer_id) {
+                    warn!("Peer didn't receive {}: {}", transfer.file.file_name, reason);
+                }
+            },
+            FileTransferMessage::Cancelled { transfer_id, reason } => {
+                if let Some(transfer) = self.incoming.remove(&transfer_id) {
+                    warn!("Peer cancelled sending {}: {}", transfer.file_name, reason);
+                }
+            },
+        }
+    }
+
+    /// Messages to send with the next media packet, with at most one chunk
+    pub fn outgoing_messages(&mut self) -> Vec<FileTransferMessage> {
+        let mut messages: Vec<FileTransferMessage> = self.pending_messages.drain(..).collect();
+        let Some((&transfer_id, transfer)) = self.outgoing.iter_mut()
+            .filter(|(_, transfer)| transfer.reader.is_some() && transfer.offset < transfer.file.size)
+            .min_by_key(|(transfer_id, _)| **transfer_id) else {
+            return messages;
+        };
+        let chunk_length = (transfer.file.size - transfer.offset).min(FILE_CHUNK_SIZE as u64) as usize;
+        let mut data = vec![0u8; chunk_length];
+        if let Err(e) = transfer.reader.as_mut().unwrap().read_exact(&mut data) {
+            let reason = format!("cannot read {}: {}", transfer.file.path.display(), e);
+            warn!("Error sending file: {}", reason);
+            self.outgoing.remove(&transfer_id);
+            messages.push(FileTransferMessage::Cancelled { transfer_id, reason });
+            return messages;
+        }
+        messages.push(FileTransferMessage::Chunk { transfer_id, offset: transfer.offset, data });
+        transfer.offset += chunk_length as u64;
+        transfer.progress.update(transfer.offset);
+        messages
+    }
+
+    /// Open the partial file of an offered file, returning the offset to resume from, or `None` if it's already complete
+    fn accept_offer(&mut self, transfer_id: u64, file_name: &str, size: u64, sha256: [u8; 32]) -> Result<Option<u64>, String> {
+        let download_directory = self.download_directory.as_ref().ok_or("peer doesn't accept files")?;
+        // Only the last component is kept, so that the sender can't write outside the download directory
+        let file_name = Path::new(file_name).file_name().and_then(|file_name| file_name.to_str())
+            .ok_or_else(|| format!("invalid file name {}", file_name))?
+            .to_string();
+        let partial_path = download_directory.join(format!("{}.{}.part", file_name, hex_prefix(&sha256)));
+        if self.incoming.values().any(|transfer| transfer.partial_path == partial_path) {
+            return Err("already being received".to_string());
+        }
+        let writer = OpenOptions::new().create(true).append(true).open(&partial_path)
+            .map_err(|e| format!("cannot create {}: {}", partial_path.display(), e))?;
+        let mut offset = writer.metadata().map_err(|e| format!("cannot read {}: {}", partial_path.display(), e))?.len();
+        if offset > size {
+            writer.set_len(0).map_err(|e| format!("cannot truncate {}: {}", partial_path.display(), e))?;
+            offset = 0;
+        }
+        if offset > 0 {
+            info!("Resuming reception of {} at {} of {} bytes", file_name, offset, size);
+        } else {
+            info!("Receiving {} ({} bytes)", file_name, size);
+        }
+        let mut progress = Progress::new(format!("Receiving {}", file_name), size);
+        progress.update(offset);
+        self.incoming.insert(transfer_id, IncomingTransfer {
+            file_name,
+            size,
+            sha256,
+            partial_path,
+            writer,
+            offset,
+            progress,
+        });
+        Ok((offset < size).then_some(offset))
+    }
+
+    fn start_sending(&mut self, transfer_id: u64, offset: u64) {
+        let Some(transfer) = self.outgoing.get_mut(&transfer_id) else {
+            debug!("Accepted unknown file transfer {}", transfer_id);
+            return;
+        };
+        let reader = File::open(&transfer.file.path).and_then(|mut reader| {
+            reader.seek(SeekFrom::Start(offset))?;
+            Ok(reader)
+        });
+        match reader {
+            Ok(reader) if offset <= transfer.file.size => {
+                if offset > 0 {
+                    info!("Resuming sending {} at {} of {} bytes", transfer.file.file_name, offset, transfer.file.size);
+                }
+                transfer.reader = Some(reader);
+                transfer.offset = offset;
+                transfer.progress.update(offset);
+            },
+            Ok(_) => {
+                let reason = format!("offset {} beyond the end of {}", offset, transfer.file.file_name);
+                warn!("Error sending file: {}", reason);
+                self.outgoing.remove(&transfer_id);
+                self.pending_messages.push_back(FileTransferMessage::Cancelled { transfer_id, reason });
+            },
+            Err(e) => {
+                let reason = format!("cannot read {}: {}", transfer.file.path.display(), e);
+                warn!("Error sending file: {}", reason);
+                self.outgoing.remove(&transfer_id);
+                self.pending_messages.push_back(FileTransferMessage::Cancelled { transfer_id, reason });
+            },
+        }
+    }
+
+    fn write_chunk(&mut self, transfer_id: u64, offset: u64, data: &[u8]) -> Result<(), String> {
+        let transfer = self.incoming.get_mut(&transfer_id).ok_or_else(|| format!("unknown file transfer {}", transfer_id))?;
+        if offset != transfer.offset {
+            return Err(format!("{}: expected chunk at {}, got {}", transfer.file_name, transfer.offset, offset));
+        }
+        if offset + data.len() as u64 > transfer.size {
+            return Err(format!("{}: chunk beyond the offered size", transfer.file_name));
+        }
+        transfer.writer.write_all(data).map_err(|e| format!("cannot write {}: {}", transfer.partial_path.display(), e))?;
+        transfer.offset += data.len() as u64;
+        transfer.progress.update(transfer.offset);
+        Ok(())
+    }
+
+    /// Check the hash of a fully received file and move it out of its partial file
+    fn finish_incoming(&mut self, transfer_id: u64) -> FileTransferMessage {
+        let Some(transfer) = self.incoming.remove(&transfer_id) else {
+            return FileTransferMessage::Failed { transfer_id, reason: "unknown file transfer".to_string() };
+        };
+        drop(transfer.writer);
+        match file_sha256(&transfer.partial_path) {
+            Ok(sha256) if sha256 == transfer.sha256 => {},
+            Ok(_) => {
+                let _ = std::fs::remove_file(&transfer.partial_path);
+                warn!("{} received with a wrong hash, deleted", transfer.file_name);
+                return FileTransferMessage::Failed { transfer_id, reason: "hash mismatch".to_string() };
+            },
+            Err(e) => {
+                let reason = format!("cannot hash {}: {}", transfer.partial_path.display(), e);
+                warn!("Error receiving file: {}", reason);
+                return FileTransferMessage::Failed { transfer_id, reason };
+            },
+        }
+        let destination = available_path(transfer.partial_path.with_file_name(&transfer.file_name));
+        if let Err(e) = std::fs::rename(&transfer.partial_path, &destination) {
+            let reason = format!("cannot move {} to {}: {}", transfer.partial_path.display(), destination.display(), e);
+            warn!("Error receiving file: {}", reason);
+            return FileTransferMessage::Failed { transfer_id, reason };
+        }
+        info!("{} received, saved as {}", transfer.file_name, destination.display());
+        FileTransferMessage::Completed { transfer_id }
+    }
+}
+
+impl Drop for FileTransfers {
+    fn drop(&mut self) {
+        for transfer in self.outgoing.values() {
+            warn!("Sending {} interrupted at {} of {} bytes, send it again during the next call to resume",
+                transfer.file.file_name, transfer.offset, transfer.file.size);
+        }
+        for transfer in self.incoming.values() {
+            warn!("Receiving {} interrupted at {} of {} bytes, kept in {}",
+                transfer.file_name, transfer.offset, transfer.size, transfer.partial_path.display());
+        }
+    }
+}
+
+/// Logs the progress of a transfer
+struct Progress {
+    description: String,
+    size: u64,
+    reported_percent: u64,
+}
+
+impl Progress {
+    fn new(description: String, size: u64) -> Self {
+        Self {
+            description,
+            size,
+            reported_percent: 0,
+        }
+    }
+
+    fn update(&mut self, offset: u64) {
+        let percent = (offset * 100).checked_div(self.size).unwrap_or(100);
+        if percent >= self.reported_percent + PROGRESS_REPORT_STEP_PERCENT {
+            self.reported_percent = percent - percent % PROGRESS_REPORT_STEP_PERCENT;
+            info!("{}: {}%", self.description, self.reported_percent);
+        }
+    }
+}
+
+fn file_sha256(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// First bytes of a hash in hexadecimal, distinguishing partial files of different files with the same name
+fn hex_prefix(sha256: &[u8; 32]) -> String {
+    sha256[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `path`, or `path` with a number appended to its stem if a file already exists there
+fn available_path(path: PathBuf) -> PathBuf {
+    if !path.exists() {
+        return path;
+    }
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+    (1..).map(|number| path.with_file_name(format!("{} ({}){}", stem, number, extension)))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory for a test, removed when dropped
+    struct TestDirectory(PathBuf);
+
+    impl TestDirectory {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("qkd_file_transfer_{}_{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn as_str(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Content spanning several chunks, the last one partial
+    fn test_content() -> Vec<u8> {
+        (0..(FILE_CHUNK_SIZE * 2 + 100)).map(|byte_index| (byte_index % 251) as u8).collect()
+    }
+
+    /// Deliver the messages of each side to the other until both have nothing more to send, returning those
+    /// received by the sender
+    fn exchange(sender: &mut FileTransfers, receiver: &mut FileTransfers) -> Vec<FileTransferMessage> {
+        let mut replies = Vec::new();
+        loop {
+            let sent_messages = sender.outgoing_messages();
+            let received_replies = receiver.outgoing_messages();
+            if sent_messages.is_empty() && received_replies.is_empty() {
+                return replies;
+            }
+            for message in sent_messages {
+                receiver.handle(message);
+            }
+            for reply in received_replies {
+                replies.push(reply.clone());
+                sender.handle(reply);
+            }
+        }
+    }
+
+    fn send_test_file(sent_directory: &TestDirectory, download_directory: &TestDirectory, content: &[u8]) -> Vec<FileTransferMessage> {
+        let sent_path = sent_directory.0.join("video.bin");
+        std::fs::write(&sent_path, content).unwrap();
+        let mut sender = FileTransfers::new(None);
+        let mut receiver = FileTransfers::new(Some(download_directory.as_str()));
+        sender.send(OutgoingFile::open(&sent_path).unwrap());
+        exchange(&mut sender, &mut receiver)
+    }
+
+    fn partial_path(download_directory: &TestDirectory, content: &[u8]) -> PathBuf {
+        let sha256: [u8; 32] = Sha256::digest(content).into();
+        download_directory.0.join(format!("video.bin.{}.part", hex_prefix(&sha256)))
+    }
+
+    #[test]
+    fn file_is_received() {
+        let sent_directory = TestDirectory::new("received_sent");
+        let download_directory = TestDirectory::new("received_download");
+        let content = test_content();
+        let replies = send_test_file(&sent_directory, &download_directory, &content);
+        assert!(matches!(replies.as_slice(), [FileTransferMessage::Accept { offset: 0, .. }, FileTransferMessage::Completed { .. }]), "{:?}", replies);
+        assert_eq!(std::fs::read(download_directory.0.join("video.bin")).unwrap(), content);
+        assert!(!partial_path(&download_directory, &content).exists());
+    }
+
+    #[test]
+    fn interrupted_reception_is_resumed() {
+        let sent_directory = TestDirectory::new("resumed_sent");
+        let download_directory = TestDirectory::new("resumed_download");
+        let content = test_content();
+        let received_length = FILE_CHUNK_SIZE + 10;
+        std::fs::write(partial_path(&download_directory, &content), &content[..received_length]).unwrap();
+        let replies = send_test_file(&sent_directory, &download_directory, &content);
+        assert!(matches!(replies.first(), Some(FileTransferMessage::Accept { offset, .. }) if *offset == received_length as u64), "{:?}", replies);
+        assert!(matches!(replies.last(), Some(FileTransferMessage::Completed { .. })), "{:?}", replies);
+        assert_eq!(std::fs::read(download_directory.0.join("video.bin")).unwrap(), content);
+    }
+
+    #[test]
+    fn file_with_wrong_hash_is_deleted() {
+        let sent_directory = TestDirectory::new("mismatch_sent");
+        let download_directory = TestDirectory::new("mismatch_download");
+        let content = test_content();
+        // Bytes received during a previous call that don't match the file sent again
+        std::fs::write(partial_path(&download_directory, &content), vec![0u8; 100]).unwrap();
+        let replies = send_test_file(&sent_directory, &download_directory, &content);
+        assert!(matches!(replies.last(), Some(FileTransferMessage::Failed { reason, .. }) if reason == "hash mismatch"), "{:?}", replies);
+        assert!(!download_directory.0.join("video.bin").exists());
+        assert!(!partial_path(&download_directory, &content).exists());
+    }
+
+    #[test]
+    fn offered_file_name_cannot_leave_the_download_directory() {
+        let download_directory = TestDirectory::new("traversal_download");
+        let mut receiver = FileTransfers::new(Some(download_directory.as_str()));
+        receiver.handle(FileTransferMessage::Offer { transfer_id: 0, file_name: "../../escaped.txt".to_string(), size: 10, sha256: [0; 32] });
+        receiver.handle(FileTransferMessage::Offer { transfer_id: 1, file_name: "..".to_string(), size: 10, sha256: [1; 32] });
+        let replies = receiver.outgoing_messages();
+        assert!(matches!(replies.as_slice(), [FileTransferMessage::Accept { transfer_id: 0, offset: 0 }, FileTransferMessage::Failed { transfer_id: 1, .. }]), "{:?}", replies);
+        assert_eq!(receiver.incoming[&0].partial_path.parent(), Some(download_directory.0.as_path()));
+        assert!(receiver.incoming[&0].partial_path.file_name().unwrap().to_str().unwrap().starts_with("escaped.txt."));
+    }
+
+    #[test]
+    fn files_are_refused_without_download_directory() {
+        let mut receiver = FileTransfers::new(None);
+        receiver.handle(FileTransferMessage::Offer { transfer_id: 0, file_name: "video.bin".to_string(), size: 10, sha256: [0; 32] });
+        assert!(matches!(receiver.outgoing_messages().as_slice(), [FileTransferMessage::Failed { transfer_id: 0, .. }]));
+    }
+}
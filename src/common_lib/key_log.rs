@@ -0,0 +1,43 @@
+use std::sync::Arc;
+
+/// Writer of the TLS secrets to $SSLKEYLOGFILE, installed in the QKD and the fallback TLS configurations
+#[derive(Debug)]
+pub struct DangerousKeyLog(rustls::KeyLogFile);
+
+impl rustls::KeyLog for DangerousKeyLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        rustls::KeyLog::log(&self.0, label, client_random, secret);
+    }
+}
+
+impl rustls_pq::KeyLog for DangerousKeyLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        rustls::KeyLog::log(&self.0, label, client_random, secret);
+    }
+}
+
+/// Key logger to install when `danger_enable_key_log` is set in the configuration.
+/// Returns `None` if key logging is disabled, or compiled out with the `key-log` feature turned off.
+pub fn dangerous_key_log(danger_enable_key_log: bool) -> Option<Arc<DangerousKeyLog>> {
+    if !danger_enable_key_log {
+        return None;
+    }
+    enable_key_log()
+}
+
+#[cfg(feature = "key-log")]
+fn enable_key_log() -> Option<Arc<DangerousKeyLog>> {
+    // Printed directly rather than logged, so that the warning can't be hidden by the log level
+    eprintln!("################################################################################");
+    eprintln!("# WARNING: TLS key logging is enabled (danger_enable_key_log)                  #");
+    eprintln!("# Session secrets derived from QKD keys will be written to $SSLKEYLOGFILE,     #");
+    eprintln!("# anyone reading this file can decrypt the call. Never use this in production. #");
+    eprintln!("################################################################################");
+    Some(Arc::new(DangerousKeyLog(rustls::KeyLogFile::new())))
+}
+
+#[cfg(not(feature = "key-log"))]
+fn enable_key_log() -> Option<Arc<DangerousKeyLog>> {
+    log::warn!("danger_enable_key_log is set but key logging was compiled out of this build, ignoring it");
+    None
+}
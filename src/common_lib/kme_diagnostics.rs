@@ -0,0 +1,90 @@
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use serde::Deserialize;
+
+/// Timeout for each network request made during diagnostics
+const DIAGNOSTIC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answer of the ETSI GS QKD 014 `status` endpoint
+#[derive(Debug, Deserialize)]
+pub struct KmeStatus {
+    #[serde(rename = "source_KME_ID")]
+    pub source_kme_id: serde_json::Value,
+    #[serde(rename = "target_KME_ID")]
+    pub target_kme_id: serde_json::Value,
+    #[serde(rename = "master_SAE_ID")]
+    pub master_sae_id: serde_json::Value,
+    #[serde(rename = "slave_SAE_ID")]
+    pub slave_sae_id: serde_json::Value,
+    pub key_size: u64,
+    pub stored_key_count: u64,
+    pub max_key_count: u64,
+    pub max_key_per_request: u64,
+}
+
+impl std::fmt::Display for KmeStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  source KME ID: {}", self.source_kme_id)?;
+        writeln!(f, "  target KME ID: {}", self.target_kme_id)?;
+        writeln!(f, "  master SAE ID: {}", self.master_sae_id)?;
+        writeln!(f, "  slave SAE ID: {}", self.slave_sae_id)?;
+        writeln!(f, "  key size: {} bits", self.key_size)?;
+        writeln!(f, "  stored key count: {}", self.stored_key_count)?;
+        writeln!(f, "  max key count: {}", self.max_key_count)?;
+        write!(f, "  max key per request: {}", self.max_key_per_request)
+    }
+}
+
+/// Authenticate to the KME with the PFX certificate and query the key status for the link with `target_sae_id`
+pub fn query_kme_status(kme_address: &str, certificate_path: &str, certificate_password: &str, target_sae_id: i64, danger_accept_invalid_kme_cert: bool) -> Result<KmeStatus, String> {
+    let certificate = std::fs::read(certificate_path)
+        .map_err(|e| format!("cannot read KME authentication certificate {}: {}", certificate_path, e))?;
+    let identity = reqwest::Identity::from_pkcs12_der(&certificate, certificate_password)
+        .map_err(|e| format!("cannot load KME authentication certificate {}: {}", certificate_path, e))?;
+    let client = reqwest::blocking::Client::builder()
+        .identity(identity)
+        .danger_accept_invalid_certs(danger_accept_invalid_kme_cert)
+        .timeout(DIAGNOSTIC_TIMEOUT)
+        .build()
+        .map_err(|e| format!("cannot create HTTPS client: {}", e))?;
+    let status_url = format!("https://{}/api/v1/keys/{}/status", kme_address, target_sae_id);
+    log::debug!("Querying KME key status at {}", status_url);
+    let response = client.get(&status_url).send()
+        .map_err(|e| format!("cannot reach KME at {}: {}", kme_address, e))?;
+    if !response.status().is_success() {
+        return Err(format!("KME answered {} for {}", response.status(), status_url));
+    }
+    response.json().map_err(|e| format!("invalid status answer from KME: {}", e))
+}
+
+/// Check that a TCP connection can be opened to `address`
+pub fn check_tcp_reachability(address: &str) -> Result<(), String> {
+    let socket_addresses = address.to_socket_addrs()
+        .map_err(|e| format!("cannot resolve {}: {}", address, e))?;
+    let mut last_error = format!("no address found for {}", address);
+    for socket_address in socket_addresses {
+        match TcpStream::connect_timeout(&socket_address, DIAGNOSTIC_TIMEOUT) {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = format!("cannot connect to {}: {}", socket_address, e),
+        }
+    }
+    Err(last_error)
+}
+
+/// Print the result of a diagnostic step, and return whether it succeeded
+pub fn report_diagnostic<T: std::fmt::Display>(step_name: &str, result: Result<T, String>) -> bool {
+    match result {
+        Ok(details) => {
+            println!("[OK]   {}", step_name);
+            let details = details.to_string();
+            if !details.is_empty() {
+                println!("{}", details);
+            }
+            true
+        },
+        Err(e) => {
+            println!("[FAIL] {}: {}", step_name, e);
+            false
+        }
+    }
+}
@@ -1,11 +1,222 @@
+use std::io::{Read, Write};
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use crate::chat::ChatMessage;
+use crate::file_transfer::FileTransferMessage;
+use crate::sae_identity::SaeIdentityProof;
 
-#[derive(Serialize, Deserialize)]
+pub mod audio_device;
+pub mod chat;
+pub mod config_loader;
+pub mod config_validation;
+pub mod echo_reference;
+pub mod file_transfer;
+pub mod key_log;
+pub mod kme_diagnostics;
+pub mod logging;
+pub mod media_indicators;
+pub mod resampler;
+pub mod sae_identity;
+pub mod secret_source;
+pub mod security_policy;
+pub mod session_security;
+pub mod tls_connection;
+pub mod video_grid;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VideoAudioPacket {
+    /// JPEG image, empty when the client has no new frame since the previous packet
     pub compressed_image: Vec<u8>,
+    /// Interleaved samples when there are several channels, empty when the client is muted
     pub sound_frame: Vec<i16>,
     pub sound_sample_rate: u32,
+    pub sound_channels: u16,
+    /// Capture time of the first sound sample, in microseconds on the client capture clock
+    pub sound_capture_timestamp_us: u64,
+    /// Capture time of the image, in microseconds on the same clock
+    pub image_capture_timestamp_us: u64,
+}
+
+impl VideoAudioPacket {
+    /// How long after the first sound sample the image was captured, negative if before
+    pub fn capture_offset_us(&self) -> i64 {
+        self.image_capture_timestamp_us as i64 - self.sound_capture_timestamp_us as i64
+    }
+
+    /// Check that the sound can be played: 1 or 2 channels, a sample rate, and whole frames
+    pub fn check_audio_format(&self) -> Result<(), String> {
+        if !(1..=2).contains(&self.sound_channels) || self.sound_sample_rate == 0 || !self.sound_frame.len().is_multiple_of(self.sound_channels as usize) {
+            return Err(format!("{} samples, {} channels at {} Hz", self.sound_frame.len(), self.sound_channels, self.sound_sample_rate));
+        }
+        Ok(())
+    }
+}
+
+/// What the client sends, announced with a [ClientMessage::MediaState] each time it changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaState {
+    pub audio_muted: bool,
+    pub video_enabled: bool,
+    pub video_source: VideoSource,
+}
+
+impl Default for MediaState {
+    fn default() -> Self {
+        Self {
+            audio_muted: false,
+            video_enabled: true,
+            video_source: VideoSource::Camera,
+        }
+    }
+}
+
+impl std::fmt::Display for MediaState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "audio {}, video {} ({})", if self.audio_muted { "muted" } else { "on" }, if self.video_enabled { "on" } else { "off" }, self.video_source)
+    }
+}
+
+/// Where the sent video comes from, the client switching between them during a call
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VideoSource {
+    #[default]
+    Camera,
+    Screen,
+}
+
+impl std::fmt::Display for VideoSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VideoSource::Camera => write!(f, "camera"),
+            VideoSource::Screen => write!(f, "screen share"),
+        }
+    }
+}
+
+/// Message sent by the client once the session is accepted, each one being answered by a [ServerMessage]
+#[derive(Serialize, Deserialize)]
+pub enum ClientMessage {
+    Media(VideoAudioPacket),
+    MediaState(MediaState),
+    Chat(ChatMessage),
+    FileTransfer(FileTransferMessage),
+}
+
+/// First message sent by the client once the QKD TLS handshake is complete
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionRequest {
+    /// SAE ID of the calling client, checked by the server against the one of its SAE certificate
+    pub origin_sae_id: i64,
+    /// SAE ID the client requested the QKD key for, expected to be the server's
+    pub target_sae_id: i64,
+    /// Signature of the TLS connection with the SAE certificate of the client, if it has one
+    pub sae_identity_proof: Option<SaeIdentityProof>,
+}
+
+/// Server answer to a [SessionRequest]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SessionResponse {
+    Accepted(SessionLimits),
+    Rejected(RejectionReason),
+}
+
+/// Limits enforced by the server for the whole session, `None` meaning unlimited
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionLimits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_bitrate_kbps: Option<u32>,
+    pub max_session_duration_secs: Option<u64>,
+}
+
+/// Message sent back by the server after each received packet, chat, file transfer and participant messages preceding the [ServerMessage::Ack]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Ack,
+    Rejected(RejectionReason),
+    Chat(ChatMessage),
+    FileTransfer(FileTransferMessage),
+    /// Sent by a relay server, about the conference participant with this ID
+    Participant(u32, ParticipantUpdate),
+}
+
+/// What a relay server forwards about another participant of the conference
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ParticipantUpdate {
+    /// Sent when the participant joins, or when joining a conference for the participants already there
+    Joined(String),
+    Media(VideoAudioPacket),
+    MediaState(MediaState),
+    Left,
+}
+
+/// Why the server refused or ended a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectionReason {
+    SaeIdNotAllowed,
+    SaeIdBlocked,
+    /// The SAE ID announced in the [SessionRequest] isn't the one of the caller SAE certificate, or its proof is invalid
+    SaeIdMismatch,
+    ResolutionTooHigh,
+    BitrateTooHigh,
+    SessionDurationExceeded,
+    DeclinedByCallee,
+    CallNotAnswered,
+    SecurityPolicyNotMet,
+    ConferenceFull,
+}
+
+impl std::fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectionReason::SaeIdNotAllowed => f.write_str("SAE ID is not in the server allow list"),
+            RejectionReason::SaeIdBlocked => f.write_str("SAE ID is blocked by the server"),
+            RejectionReason::SaeIdMismatch => f.write_str("SAE ID doesn't match the SAE certificate"),
+            RejectionReason::ResolutionTooHigh => f.write_str("video resolution exceeds the server limit"),
+            RejectionReason::BitrateTooHigh => f.write_str("bitrate exceeds the server limit"),
+            RejectionReason::SessionDurationExceeded => f.write_str("maximum session duration reached"),
+            RejectionReason::DeclinedByCallee => f.write_str("call declined by callee"),
+            RejectionReason::CallNotAnswered => f.write_str("call not answered"),
+            RejectionReason::SecurityPolicyNotMet => f.write_str("key exchange mechanism not allowed by the server security policy"),
+            RejectionReason::ConferenceFull => f.write_str("conference is full"),
+        }
+    }
 }
 
 /// Size of sent packet chunks, in order to avoid sending too big packets that could overflow the server's buffer
-pub const PACKET_CHUNK_SIZE: usize = 8192;
\ No newline at end of file
+pub const PACKET_CHUNK_SIZE: usize = 8192;
+
+/// Maximum size of a control message, bigger announced sizes are considered as a protocol error
+pub const MAX_CONTROL_MESSAGE_SIZE: usize = 65536;
+
+/// Maximum size of a [ServerMessage] read by the client, media forwarded by a relay server being bigger than control messages
+pub const MAX_SERVER_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Send a control message, prefixed by its size as a big endian u32
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> std::io::Result<()> {
+    let serialized_message = postcard::to_allocvec(message)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    writer.write_all(&(serialized_message.len() as u32).to_be_bytes())?;
+    writer.write_all(&serialized_message)?;
+    log::trace!("Control message sent: {} bytes", serialized_message.len());
+    writer.flush()
+}
+
+/// Receive a control message sent by [write_message]
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> std::io::Result<T> {
+    read_message_up_to(reader, MAX_CONTROL_MESSAGE_SIZE)
+}
+
+/// Receive a message sent by [write_message], of at most `max_message_size` bytes
+pub fn read_message_up_to<R: Read, T: DeserializeOwned>(reader: &mut R, max_message_size: usize) -> std::io::Result<T> {
+    let mut message_size_buf = [0u8; 4];
+    reader.read_exact(&mut message_size_buf)?;
+    let message_size = u32::from_be_bytes(message_size_buf) as usize;
+    if message_size > max_message_size {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Control message too big: {} bytes", message_size)));
+    }
+    let mut message_buf = vec![0u8; message_size];
+    reader.read_exact(&mut message_buf)?;
+    log::trace!("Control message received: {} bytes", message_size);
+    postcard::from_bytes(&message_buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
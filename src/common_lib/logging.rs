@@ -0,0 +1,309 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{LevelFilter, Log, Metadata, Record};
+use simplelog::{ColorChoice, CombinedLogger, Config, SharedLogger, TermLogger, TerminalMode, WriteLogger};
+
+/// Logging options shared by both binaries
+#[derive(Debug, Clone, clap::Args)]
+pub struct LoggingArgs {
+    /// Log level: off, error, warn, info, debug or trace, optionally followed by per-module levels,
+    /// eg `info,qkd_camera_common_lib=debug,rustls=warn`
+    #[arg(long, global = true, default_value = "info")]
+    pub log_level: LogLevels,
+    /// Also write the logs to this file, rotated when it exceeds `--log-file-max-size-mb`
+    #[arg(long, global = true)]
+    pub log_file: Option<PathBuf>,
+    #[arg(long, global = true, default_value_t = 10)]
+    pub log_file_max_size_mb: u64,
+    /// How many rotated log files to keep, besides the current one
+    #[arg(long, global = true, default_value_t = 5)]
+    pub log_file_count: usize,
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with timestamp_ms, level, target and message fields
+    Json,
+}
+
+/// A default log level, and levels for specific modules
+#[derive(Debug, Clone)]
+pub struct LogLevels {
+    default_level: LevelFilter,
+    module_levels: Vec<(String, LevelFilter)>,
+}
+
+impl LogLevels {
+    /// Level of the most specific module matching the log target
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.module_levels.iter()
+            .filter(|(module, _)| target == module || target.strip_prefix(module.as_str()).is_some_and(|rest| rest.starts_with("::")))
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default_level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.module_levels.iter().map(|(_, level)| *level).fold(self.default_level, Ord::max)
+    }
+}
+
+impl FromStr for LogLevels {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut log_levels = LogLevels {
+            default_level: LevelFilter::Info,
+            module_levels: Vec::new(),
+        };
+        for directive in s.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            let parse_level = |level: &str| LevelFilter::from_str(level).map_err(|_| format!("invalid log level {}", level));
+            match directive.split_once('=') {
+                Some((module, level)) => log_levels.module_levels.push((module.trim().to_string(), parse_level(level.trim())?)),
+                None => log_levels.default_level = parse_level(directive)?,
+            }
+        }
+        Ok(log_levels)
+    }
+}
+
+/// Initialize the terminal logger, and the rotating file logger if requested
+pub fn init_logger(logging_args: &LoggingArgs) -> Result<(), String> {
+    let max_level = logging_args.log_level.max_level();
+    let config = Config::default();
+    let mut loggers: Vec<Box<dyn SharedLogger>> = Vec::new();
+    match logging_args.log_format {
+        LogFormat::Text => loggers.push(TermLogger::new(max_level, config.clone(), TerminalMode::Mixed, ColorChoice::Auto)),
+        LogFormat::Json => loggers.push(JsonLogger::new(max_level, std::io::stderr())),
+    }
+    if let Some(log_file) = logging_args.log_file.as_ref() {
+        let rotating_file = RotatingFile::open(log_file, logging_args.log_file_max_size_mb * 1024 * 1024, logging_args.log_file_count)
+            .map_err(|e| format!("cannot open log file {}: {}", log_file.display(), e))?;
+        match logging_args.log_format {
+            LogFormat::Text => loggers.push(WriteLogger::new(max_level, config, rotating_file)),
+            LogFormat::Json => loggers.push(JsonLogger::new(max_level, rotating_file)),
+        }
+    }
+    let logger = ModuleLevelLogger {
+        log_levels: logging_args.log_level.clone(),
+        inner: CombinedLogger::new(loggers),
+    };
+    log::set_max_level(max_level);
+    log::set_boxed_logger(Box::new(logger)).map_err(|e| e.to_string())
+}
+
+/// Filter the records according to the level of their module before passing them to the simplelog loggers
+struct ModuleLevelLogger {
+    log_levels: LogLevels,
+    inner: Box<CombinedLogger>,
+}
+
+impl Log for ModuleLevelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.log_levels.level_for(metadata.target()) && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Write each record as a JSON object on its own line
+struct JsonLogger<W: Write + Send + 'static> {
+    level: LevelFilter,
+    output: Mutex<W>,
+}
+
+impl<W: Write + Send + 'static> JsonLogger<W> {
+    fn new(level: LevelFilter, output: W) -> Box<Self> {
+        Box::new(Self {
+            level,
+            output: Mutex::new(output),
+        })
+    }
+}
+
+impl<W: Write + Send + 'static> Log for JsonLogger<W> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_millis()).unwrap_or_default();
+        let json_record = serde_json::json!({
+            "timestamp_ms": timestamp_ms as u64,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        let mut output = self.output.lock().unwrap();
+        let _ = writeln!(output, "{}", json_record);
+    }
+
+    fn flush(&self) {
+        let _ = self.output.lock().unwrap().flush();
+    }
+}
+
+impl<W: Write + Send + 'static> SharedLogger for JsonLogger<W> {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
+/// Log file renamed to `<path>.1`, `<path>.2`... once it exceeds `max_size` bytes
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    file_count: usize,
+    file: File,
+    size: u64,
+    /// Records are written in several parts, only rotate between lines
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, file_count: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            file_count,
+            file,
+            size,
+            at_line_start: true,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut rotated_path = self.path.clone().into_os_string();
+        rotated_path.push(format!(".{}", index));
+        PathBuf::from(rotated_path)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.file_count == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for index in (1..self.file_count).rev() {
+                let _ = std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.at_line_start && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory in the temporary directory, removed before the test if left by a previous run
+    fn test_directory(test_name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("qkd_logging_test_{}_{}", std::process::id(), test_name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn default_level_and_module_overrides_are_parsed() {
+        let log_levels = LogLevels::from_str("warn, rustls=error ,qkd_camera_common_lib=debug,qkd_camera_common_lib::logging=trace").unwrap();
+        assert_eq!(log_levels.level_for("server"), LevelFilter::Warn);
+        assert_eq!(log_levels.level_for("rustls::conn"), LevelFilter::Error);
+        assert_eq!(log_levels.level_for("qkd_camera_common_lib::chat"), LevelFilter::Debug);
+        assert_eq!(log_levels.level_for("qkd_camera_common_lib::logging"), LevelFilter::Trace);
+        assert_eq!(log_levels.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn module_prefix_only_matches_whole_module_names() {
+        let log_levels = LogLevels::from_str("info,rustls=off").unwrap();
+        assert_eq!(log_levels.level_for("rustls"), LevelFilter::Off);
+        assert_eq!(log_levels.level_for("rustls_pq"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn invalid_level_is_an_error() {
+        assert!(LogLevels::from_str("verbose").unwrap_err().contains("verbose"));
+        assert!(LogLevels::from_str("info,rustls=loud").is_err());
+    }
+
+    #[test]
+    fn file_is_rotated_between_lines_once_full() {
+        let directory = test_directory("rotation");
+        let path = directory.join("test.log");
+        let mut rotating_file = RotatingFile::open(&path, 10, 2).unwrap();
+        rotating_file.write_all(b"first ").unwrap();
+        // Not rotated in the middle of a line, even beyond the maximum size
+        rotating_file.write_all(b"line\n").unwrap();
+        rotating_file.write_all(b"second\n").unwrap();
+        rotating_file.write_all(b"third\n").unwrap();
+        rotating_file.write_all(b"fourth\n").unwrap();
+        rotating_file.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(std::fs::read_to_string(rotating_file.rotated_path(1)).unwrap(), "third\n");
+        assert_eq!(std::fs::read_to_string(rotating_file.rotated_path(2)).unwrap(), "second\n");
+        // Only file_count rotated files are kept
+        assert!(!rotating_file.rotated_path(3).exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn reopened_file_keeps_its_size() {
+        let directory = test_directory("reopen");
+        let path = directory.join("test.log");
+        std::fs::write(&path, "previous run\n").unwrap();
+        let mut rotating_file = RotatingFile::open(&path, 16, 1).unwrap();
+        rotating_file.write_all(b"new run\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new run\n");
+        assert_eq!(std::fs::read_to_string(rotating_file.rotated_path(1)).unwrap(), "previous run\n");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
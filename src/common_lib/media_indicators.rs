@@ -0,0 +1,53 @@
+use image::{ImageBuffer, Rgb};
+
+/// Size of the displayed image when no frame was received yet
+pub const DEFAULT_PLACEHOLDER_SIZE: (u32, u32) = (640, 480);
+
+const PLACEHOLDER_BACKGROUND: Rgb<u8> = Rgb([48, 48, 48]);
+const PLACEHOLDER_CAMERA: Rgb<u8> = Rgb([160, 160, 160]);
+const INDICATOR_RED: Rgb<u8> = Rgb([200, 0, 0]);
+const INDICATOR_WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// Image displayed instead of the video when the camera is off: a crossed out camera on a dark background
+pub fn placeholder_image(width: u32, height: u32) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let mut image = ImageBuffer::from_pixel(width, height, PLACEHOLDER_BACKGROUND);
+    let unit = (width.min(height) / 16).max(1);
+    let (center_x, center_y) = (width / 2, height / 2);
+    // Camera body, and its lens as a triangle on the right
+    fill_rectangle(&mut image, center_x.saturating_sub(3 * unit), center_y.saturating_sub(2 * unit), 5 * unit, 4 * unit, PLACEHOLDER_CAMERA);
+    for lens_column in 0..2 * unit {
+        let lens_half_height = unit + lens_column / 2;
+        fill_rectangle(&mut image, center_x + 2 * unit + lens_column, center_y.saturating_sub(lens_half_height), 1, 2 * lens_half_height, PLACEHOLDER_CAMERA);
+    }
+    draw_diagonal(&mut image, center_x.saturating_sub(4 * unit), center_y.saturating_sub(4 * unit), 8 * unit, unit / 2, INDICATOR_RED);
+    image
+}
+
+/// Draw a crossed out red square in the top left corner of the image, shown while the sound is muted
+pub fn draw_muted_indicator(image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) {
+    let size = (image.height() / 10).max(8);
+    let margin = size / 4;
+    fill_rectangle(image, margin, margin, size, size, INDICATOR_RED);
+    let cross_margin = size / 4;
+    let thickness = (size / 12).max(1);
+    draw_diagonal(image, margin + cross_margin, margin + cross_margin, size - 2 * cross_margin, thickness, INDICATOR_WHITE);
+    for offset in 0..size - 2 * cross_margin {
+        fill_rectangle(image, margin + size - cross_margin - 1 - offset, margin + cross_margin + offset, thickness, thickness, INDICATOR_WHITE);
+    }
+}
+
+/// Fill a rectangle, clipped to the image
+fn fill_rectangle(image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, x: u32, y: u32, width: u32, height: u32, color: Rgb<u8>) {
+    for pixel_y in y..(y + height).min(image.height()) {
+        for pixel_x in x..(x + width).min(image.width()) {
+            image.put_pixel(pixel_x, pixel_y, color);
+        }
+    }
+}
+
+/// Draw a line going down and right from (x, y), `length` pixels wide and high
+fn draw_diagonal(image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, x: u32, y: u32, length: u32, thickness: u32, color: Rgb<u8>) {
+    for offset in 0..length {
+        fill_rectangle(image, x + offset, y + offset, thickness.max(1), thickness.max(1), color);
+    }
+}
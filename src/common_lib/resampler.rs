@@ -0,0 +1,103 @@
+/// Converts interleaved audio from one sample rate to another by linear interpolation.
+/// The last frame and the interpolation position are kept between calls, so that chunk boundaries aren't audible.
+pub struct Resampler {
+    channels: usize,
+    input_sample_rate: u32,
+    output_sample_rate: u32,
+    /// Last frame of the previous chunk, interpolated with the first frame of the next one
+    previous_frame: Option<Vec<i16>>,
+    /// Position of the next output frame, in input frames from the previous frame, or from the first frame if none
+    position: f64,
+}
+
+impl Resampler {
+    pub fn new(channels: u16, input_sample_rate: u32, output_sample_rate: u32) -> Self {
+        Self {
+            channels: channels as usize,
+            input_sample_rate,
+            output_sample_rate,
+            previous_frame: None,
+            position: 0.0,
+        }
+    }
+
+    /// Whether this resampler converts audio of this format, otherwise a new one has to be created
+    pub fn accepts(&self, channels: u16, input_sample_rate: u32) -> bool {
+        self.channels == channels as usize && self.input_sample_rate == input_sample_rate
+    }
+
+    /// Resample interleaved samples, whose length must be a multiple of the channel count
+    pub fn resample(&mut self, samples: Vec<i16>) -> Vec<i16> {
+        if self.input_sample_rate == self.output_sample_rate || samples.is_empty() {
+            return samples;
+        }
+        let mut input = self.previous_frame.take().unwrap_or_default();
+        input.extend_from_slice(&samples);
+        let input_frames = input.len() / self.channels;
+        let step = self.input_sample_rate as f64 / self.output_sample_rate as f64;
+        let mut output = Vec::with_capacity(((input_frames as f64 / step) as usize + 1) * self.channels);
+        while self.position + 1.0 < input_frames as f64 {
+            let frame_index = self.position as usize;
+            let fraction = self.position - frame_index as f64;
+            for channel in 0..self.channels {
+                let current = input[frame_index * self.channels + channel] as f64;
+                let next = input[(frame_index + 1) * self.channels + channel] as f64;
+                output.push((current + (next - current) * fraction).round() as i16);
+            }
+            self.position += step;
+        }
+        self.position -= (input_frames - 1) as f64;
+        self.previous_frame = Some(input.split_off((input_frames - 1) * self.channels));
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_sample_rate_is_unchanged() {
+        let mut resampler = Resampler::new(2, 48000, 48000);
+        assert_eq!(resampler.resample(vec![1, 2, 3, 4]), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn upsampling_interpolates_between_frames() {
+        let mut resampler = Resampler::new(1, 24000, 48000);
+        assert_eq!(resampler.resample(vec![0, 100, 200]), vec![0, 50, 100, 150]);
+    }
+
+    #[test]
+    fn channels_are_interpolated_separately() {
+        let mut resampler = Resampler::new(2, 24000, 48000);
+        assert_eq!(resampler.resample(vec![0, 1000, 100, 900]), vec![0, 1000, 50, 950]);
+    }
+
+    #[test]
+    fn chunks_give_the_same_output_as_a_whole_signal() {
+        let samples: Vec<i16> = (0..480).map(|sample_index| ((sample_index as f64 / 10.0).sin() * 10000.0) as i16).collect();
+        let mut whole_resampler = Resampler::new(1, 44100, 48000);
+        let whole_output = whole_resampler.resample(samples.clone());
+        let mut chunk_resampler = Resampler::new(1, 44100, 48000);
+        let chunked_output: Vec<i16> = samples.chunks(100).flat_map(|chunk| chunk_resampler.resample(chunk.to_vec())).collect();
+        // The interpolation positions may only differ by floating point rounding
+        assert_eq!(chunked_output.len(), whole_output.len());
+        assert!(chunked_output.iter().zip(&whole_output).all(|(chunked_sample, whole_sample)| chunked_sample.abs_diff(*whole_sample) <= 1));
+    }
+
+    #[test]
+    fn output_length_follows_the_sample_rate_ratio() {
+        let mut resampler = Resampler::new(1, 48000, 16000);
+        let output_length: usize = (0..10).map(|_| resampler.resample(vec![0; 480]).len()).sum();
+        assert!((1598..=1600).contains(&output_length), "{}", output_length);
+    }
+
+    #[test]
+    fn accepts_only_its_input_format() {
+        let resampler = Resampler::new(2, 44100, 48000);
+        assert!(resampler.accepts(2, 44100));
+        assert!(!resampler.accepts(1, 44100));
+        assert!(!resampler.accepts(2, 48000));
+    }
+}
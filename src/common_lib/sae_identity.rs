@@ -0,0 +1,284 @@
+use std::sync::Arc;
+use rustls::sign::SigningKey;
+use rustls::SignatureScheme;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Label of the TLS keying material signed by the caller, binding its SAE certificate to the connection
+pub const SAE_IDENTITY_EXPORTER_LABEL: &[u8] = b"EXPORTER-qkd-camera-sae-identity";
+/// Length of the keying material signed by the caller
+pub const SAE_IDENTITY_BINDING_LENGTH: usize = 32;
+
+/// Signature schemes the caller may sign its proof with, in order of preference
+const SAE_IDENTITY_SIGNATURE_SCHEMES: [SignatureScheme; 5] = [
+    SignatureScheme::ED25519,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PSS_SHA512,
+];
+
+/// Proof that the caller holds the private key of its SAE certificate, sent in the session request. The signed keying
+/// material is exported from the TLS connection, so that a proof can't be replayed on another connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SaeIdentityProof {
+    /// DER encoded SAE certificate
+    pub certificate: Vec<u8>,
+    /// TLS code point of the signature scheme
+    pub signature_scheme: u16,
+    pub signature: Vec<u8>,
+}
+
+/// Identity of a SAE certificate, shown to the user answering a call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateIdentity {
+    /// Distinguished name, eg `CN=sae1, O=Example`
+    pub subject: String,
+    /// Lowercase hexadecimal SHA-256 of the DER certificate
+    pub sha256_fingerprint: String,
+}
+
+impl std::fmt::Display for CertificateIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (SHA-256 {})", self.subject, self.sha256_fingerprint)
+    }
+}
+
+/// SAE certificate and private key proving the identity of the caller
+pub struct SaeCredentials {
+    certificate: Vec<u8>,
+    signing_key: Arc<dyn SigningKey>,
+}
+
+impl SaeCredentials {
+    /// Load a DER certificate and its PKCS#8 private key
+    pub fn from_der_files(certificate_path: &str, private_key_path: &str) -> Result<Self, String> {
+        let certificate = std::fs::read(certificate_path).map_err(|e| format!("cannot read {}: {}", certificate_path, e))?;
+        let private_key = std::fs::read(private_key_path).map_err(|e| format!("cannot read {}: {}", private_key_path, e))?;
+        let signing_key = rustls::crypto::ring::sign::any_supported_type(&PrivateKeyDer::from(PrivatePkcs8KeyDer::from(private_key)))
+            .map_err(|e| format!("unsupported private key {}: {}", private_key_path, e))?;
+        Ok(Self {
+            certificate,
+            signing_key,
+        })
+    }
+
+    /// Sign the keying material exported from the TLS connection
+    pub fn prove(&self, binding: &[u8]) -> Result<SaeIdentityProof, String> {
+        let signer = self.signing_key.choose_scheme(&SAE_IDENTITY_SIGNATURE_SCHEMES)
+            .ok_or("no supported signature scheme for the SAE private key")?;
+        let signature = signer.sign(binding).map_err(|e| format!("cannot sign SAE identity proof: {}", e))?;
+        Ok(SaeIdentityProof {
+            certificate: self.certificate.clone(),
+            signature_scheme: signer.scheme().get_u16(),
+            signature,
+        })
+    }
+}
+
+impl SaeIdentityProof {
+    /// Check that the keying material exported from the TLS connection was signed with the key of the certificate
+    pub fn verify(&self, binding: &[u8]) -> Result<CertificateIdentity, String> {
+        let signature_scheme = SignatureScheme::from(self.signature_scheme);
+        if !SAE_IDENTITY_SIGNATURE_SCHEMES.contains(&signature_scheme) {
+            return Err(format!("unsupported signature scheme {:?}", signature_scheme));
+        }
+        let certificate = CertificateDer::from(self.certificate.as_slice());
+        let end_entity_certificate = webpki::EndEntityCert::try_from(&certificate).map_err(|e| format!("invalid SAE certificate: {:?}", e))?;
+        let verification_algorithms = rustls::crypto::ring::default_provider().signature_verification_algorithms.mapping.iter()
+            .find(|(scheme, _)| *scheme == signature_scheme)
+            .map(|(_, verification_algorithms)| *verification_algorithms)
+            .unwrap_or_default();
+        if !verification_algorithms.iter().any(|verification_algorithm| end_entity_certificate.verify_signature(*verification_algorithm, binding, &self.signature).is_ok()) {
+            return Err("invalid SAE identity signature".to_string());
+        }
+        Ok(CertificateIdentity {
+            subject: distinguished_name(end_entity_certificate.subject()),
+            sha256_fingerprint: sha256_fingerprint(&self.certificate),
+        })
+    }
+}
+
+/// Lowercase hexadecimal SHA-256 of a DER certificate
+pub fn sha256_fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Fingerprint written with any case and with or without colons, as output by `openssl x509 -fingerprint -sha256`
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.chars().filter(|character| *character != ':').collect::<String>().to_lowercase()
+}
+
+/// Format the content of a DER distinguished name, keeping its usual attributes
+fn distinguished_name(name: &[u8]) -> String {
+    let mut attributes = Vec::new();
+    let mut relative_names = name;
+    while let Some((0x31, relative_name, remaining)) = read_der(relative_names) {
+        relative_names = remaining;
+        let mut attribute_values = relative_name;
+        while let Some((0x30, attribute, remaining)) = read_der(attribute_values) {
+            attribute_values = remaining;
+            let Some((0x06, object_identifier, value)) = read_der(attribute) else {
+                continue;
+            };
+            let attribute_name = match object_identifier {
+                [0x55, 0x04, 0x03] => "CN",
+                [0x55, 0x04, 0x06] => "C",
+                [0x55, 0x04, 0x07] => "L",
+                [0x55, 0x04, 0x08] => "ST",
+                [0x55, 0x04, 0x0A] => "O",
+                [0x55, 0x04, 0x0B] => "OU",
+                _ => continue,
+            };
+            if let Some((_, value, _)) = read_der(value) {
+                attributes.push(format!("{}={}", attribute_name, String::from_utf8_lossy(value)));
+            }
+        }
+    }
+    if attributes.is_empty() {
+        return "empty subject".to_string();
+    }
+    attributes.join(", ")
+}
+
+/// Split the first DER element of `input` into its tag, its content and the remaining input
+fn read_der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first_length_byte, input) = input.split_first()?;
+    let (length, input) = if first_length_byte < 0x80 {
+        (first_length_byte as usize, input)
+    } else {
+        let length_size = (first_length_byte & 0x7F) as usize;
+        if length_size == 0 || length_size > std::mem::size_of::<usize>() || input.len() < length_size {
+            return None;
+        }
+        let length = input[..length_size].iter().fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        (length, &input[length_size..])
+    };
+    if input.len() < length {
+        return None;
+    }
+    Some((tag, &input[..length], &input[length..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ed25519 private key of RFC 8410, section 10.3
+    const ED25519_PKCS8_KEY: [u8; 48] = [
+        0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+        0xd4, 0xee, 0x72, 0xdb, 0xf9, 0x13, 0x58, 0x4a, 0xd5, 0xb6, 0xd8, 0xf1, 0xf7, 0x69, 0xf8, 0xad,
+        0x3a, 0xfe, 0x7c, 0x28, 0xcb, 0xf1, 0xd4, 0xfb, 0xe0, 0x97, 0xa8, 0x8f, 0x44, 0x75, 0x58, 0x42,
+    ];
+    /// Ed25519 public key of RFC 8410, section 10.1
+    const ED25519_PUBLIC_KEY: [u8; 32] = [
+        0x19, 0xbf, 0x44, 0x09, 0x69, 0x84, 0xcd, 0xfe, 0x85, 0x41, 0xba, 0xc1, 0x67, 0xdc, 0x3b, 0x96,
+        0xc8, 0x50, 0x86, 0xaa, 0x30, 0xb6, 0xb6, 0xcb, 0x0c, 0x5c, 0x38, 0xad, 0x70, 0x31, 0x66, 0xe1,
+    ];
+    const ED25519_ALGORITHM_IDENTIFIER: [u8; 7] = [0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70];
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let length = match content.len() {
+            length @ 0..=0x7F => vec![length as u8],
+            length @ 0x80..=0xFF => vec![0x81, length as u8],
+            length => vec![0x82, (length >> 8) as u8, length as u8],
+        };
+        [&[tag], length.as_slice(), content].concat()
+    }
+
+    /// DER attribute with a UTF8String value
+    fn attribute(object_identifier: &[u8], value: &str) -> Vec<u8> {
+        let attribute = [der(0x06, object_identifier), der(0x0C, value.as_bytes())].concat();
+        der(0x31, &der(0x30, &attribute))
+    }
+
+    /// Certificate of the RFC 8410 Ed25519 key, its own signature isn't checked by the proof
+    fn sae_certificate(common_name: &str) -> Vec<u8> {
+        let name = der(0x30, &[attribute(&[0x55, 0x04, 0x0A], "Example"), attribute(&[0x55, 0x04, 0x03], common_name)].concat());
+        let validity = der(0x30, &[der(0x17, b"250101000000Z"), der(0x17, b"491231235959Z")].concat());
+        let subject_public_key_info = der(0x30, &[ED25519_ALGORITHM_IDENTIFIER.as_slice(), &der(0x03, &[&[0x00], ED25519_PUBLIC_KEY.as_slice()].concat())].concat());
+        let to_be_signed = der(0x30, &[
+            der(0xA0, &der(0x02, &[0x02])),
+            der(0x02, &[0x01]),
+            ED25519_ALGORITHM_IDENTIFIER.to_vec(),
+            name.clone(),
+            validity,
+            name,
+            subject_public_key_info,
+        ].concat());
+        der(0x30, &[to_be_signed, ED25519_ALGORITHM_IDENTIFIER.to_vec(), der(0x03, &[0; 65])].concat())
+    }
+
+    fn sae_credentials(common_name: &str) -> SaeCredentials {
+        SaeCredentials {
+            certificate: sae_certificate(common_name),
+            signing_key: rustls::crypto::ring::sign::any_supported_type(&PrivateKeyDer::from(PrivatePkcs8KeyDer::from(ED25519_PKCS8_KEY.to_vec()))).unwrap(),
+        }
+    }
+
+    #[test]
+    fn proof_is_verified_against_the_signed_keying_material() {
+        let binding = [7; SAE_IDENTITY_BINDING_LENGTH];
+        let proof = sae_credentials("sae1").prove(&binding).unwrap();
+        assert_eq!(SignatureScheme::from(proof.signature_scheme), SignatureScheme::ED25519);
+        let certificate_identity = proof.verify(&binding).unwrap();
+        assert_eq!(certificate_identity.subject, "O=Example, CN=sae1");
+        assert_eq!(certificate_identity.sha256_fingerprint, sha256_fingerprint(&sae_certificate("sae1")));
+        // Replayed on another connection
+        assert!(proof.verify(&[8; SAE_IDENTITY_BINDING_LENGTH]).unwrap_err().contains("invalid SAE identity signature"));
+    }
+
+    #[test]
+    fn proof_with_another_certificate_is_rejected() {
+        let binding = [7; SAE_IDENTITY_BINDING_LENGTH];
+        let mut proof = sae_credentials("sae1").prove(&binding).unwrap();
+        // Same public key, so the signature stays valid, but another fingerprint
+        proof.certificate = sae_certificate("sae2");
+        assert_ne!(proof.verify(&binding).unwrap().sha256_fingerprint, sha256_fingerprint(&sae_certificate("sae1")));
+        proof.signature[0] ^= 1;
+        assert!(proof.verify(&binding).is_err());
+    }
+
+    #[test]
+    fn distinguished_name_keeps_the_usual_attributes() {
+        let name = [
+            attribute(&[0x55, 0x04, 0x06], "FR"),
+            attribute(&[0x55, 0x04, 0x0A], "Example"),
+            // Email address, not shown
+            attribute(&[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x09, 0x01], "sae@example.com"),
+            attribute(&[0x55, 0x04, 0x03], "sae1"),
+        ].concat();
+        assert_eq!(distinguished_name(&name), "C=FR, O=Example, CN=sae1");
+        assert_eq!(distinguished_name(&[]), "empty subject");
+    }
+
+    #[test]
+    fn truncated_der_is_not_read() {
+        assert_eq!(read_der(&[0x30, 0x03, 0x01, 0x02]), None);
+        assert_eq!(read_der(&[0x30, 0x82, 0x01]), None);
+        assert_eq!(read_der(&[0x30, 0x81, 0x02, 0x01, 0x02, 0x03]), Some((0x30, &[0x01, 0x02][..], &[0x03][..])));
+    }
+
+    #[test]
+    fn fingerprint_is_normalized() {
+        assert_eq!(normalize_fingerprint("AB:cd:01"), "abcd01");
+        assert_eq!(sha256_fingerprint(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[test]
+    fn proof_with_unsupported_scheme_or_invalid_certificate_is_rejected() {
+        let proof = SaeIdentityProof {
+            certificate: vec![0x30, 0x00],
+            signature_scheme: SignatureScheme::RSA_PKCS1_SHA1.get_u16(),
+            signature: Vec::new(),
+        };
+        assert!(proof.verify(&[0; SAE_IDENTITY_BINDING_LENGTH]).unwrap_err().contains("unsupported signature scheme"));
+        let proof = SaeIdentityProof {
+            signature_scheme: SignatureScheme::ED25519.get_u16(),
+            ..proof
+        };
+        assert!(proof.verify(&[0; SAE_IDENTITY_BINDING_LENGTH]).unwrap_err().contains("invalid SAE certificate"));
+    }
+}
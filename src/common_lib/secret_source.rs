@@ -0,0 +1,36 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// A secret given either directly in the configuration, or read from a file or an environment variable,
+/// eg `"password"`, `{"file": "/run/secrets/kme_password"}` or `{"env": "KME_PASSWORD"}`
+#[derive(Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum SecretSource {
+    Value(String),
+    File { file: String },
+    Env { env: String },
+}
+
+impl SecretSource {
+    pub fn resolve(&self) -> Result<String, String> {
+        match self {
+            SecretSource::Value(secret) => Ok(secret.clone()),
+            SecretSource::File { file } => std::fs::read_to_string(file)
+                .map(|secret| secret.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|e| format!("cannot read secret file {}: {}", file, e)),
+            SecretSource::Env { env } => std::env::var(env)
+                .map_err(|e| format!("cannot read secret environment variable {}: {}", env, e)),
+        }
+    }
+}
+
+/// Never print the secret itself
+impl std::fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretSource::Value(_) => f.write_str("Value(<redacted>)"),
+            SecretSource::File { file } => f.debug_struct("File").field("file", file).finish(),
+            SecretSource::Env { env } => f.debug_struct("Env").field("env", env).finish(),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use rustls_pq::crypto::CryptoProvider;
+use rustls_pq::NamedGroup;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Hybrid post-quantum key exchange groups, preferred when QKD is not used
+const HYBRID_POST_QUANTUM_GROUPS: [NamedGroup; 1] = [NamedGroup::X25519MLKEM768];
+
+/// Which key exchange mechanisms are acceptable for a session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityPolicy {
+    /// Only QKD keys are accepted, the call fails if the KME can't provide them
+    #[default]
+    QkdRequired,
+    /// QKD keys are used when available, otherwise falls back to a hybrid post-quantum key exchange, never to a classical one
+    QkdPreferred,
+    /// QKD is never used
+    ClassicalOnly,
+}
+
+/// Key exchange mechanism actually used for a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SecurityMode {
+    Qkd,
+    HybridPostQuantum,
+    Classical,
+}
+
+impl SecurityPolicy {
+    pub fn allows_qkd(&self) -> bool {
+        *self != SecurityPolicy::ClassicalOnly
+    }
+
+    pub fn allows(&self, security_mode: SecurityMode) -> bool {
+        match self {
+            SecurityPolicy::QkdRequired => security_mode == SecurityMode::Qkd,
+            SecurityPolicy::QkdPreferred => security_mode != SecurityMode::Classical,
+            SecurityPolicy::ClassicalOnly => security_mode != SecurityMode::Qkd,
+        }
+    }
+}
+
+impl std::fmt::Display for SecurityMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecurityMode::Qkd => f.write_str("QKD"),
+            SecurityMode::HybridPostQuantum => f.write_str("hybrid post-quantum"),
+            SecurityMode::Classical => f.write_str("classical"),
+        }
+    }
+}
+
+/// Crypto provider of the upstream rustls used when QKD is not available, the QKD fork having no post-quantum key
+/// exchange. When the security policy doesn't allow classical key exchange, only the hybrid post-quantum groups are kept
+/// so that the peer can't negotiate a classical one. The returned mode is the weakest one the provider can negotiate.
+pub fn fallback_crypto_provider(security_policy: SecurityPolicy) -> Result<(CryptoProvider, SecurityMode), String> {
+    let mut provider = rustls_pq::crypto::aws_lc_rs::default_provider();
+    if security_policy.allows(SecurityMode::Classical) {
+        return Ok((provider, SecurityMode::Classical));
+    }
+    provider.kx_groups.retain(|kx_group| is_hybrid_post_quantum(kx_group.name()));
+    if provider.kx_groups.is_empty() {
+        return Err(format!("no hybrid post-quantum key exchange available, and security policy {:?} doesn't allow classical ECDHE", security_policy));
+    }
+    Ok((provider, SecurityMode::HybridPostQuantum))
+}
+
+pub fn is_hybrid_post_quantum(named_group: NamedGroup) -> bool {
+    HYBRID_POST_QUANTUM_GROUPS.contains(&named_group)
+}
+
+/// Key exchange mechanism of a connection made without QKD, according to the group it negotiated
+pub fn fallback_security_mode(negotiated_group: Option<NamedGroup>) -> SecurityMode {
+    match negotiated_group {
+        Some(negotiated_group) if is_hybrid_post_quantum(negotiated_group) => SecurityMode::HybridPostQuantum,
+        _ => SecurityMode::Classical,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qkd_preferred_falls_back_to_hybrid_post_quantum_only() {
+        let (provider, security_mode) = fallback_crypto_provider(SecurityPolicy::QkdPreferred).unwrap();
+        assert_eq!(security_mode, SecurityMode::HybridPostQuantum);
+        let kx_group_names: Vec<NamedGroup> = provider.kx_groups.iter().map(|kx_group| kx_group.name()).collect();
+        assert_eq!(kx_group_names, vec![NamedGroup::X25519MLKEM768]);
+    }
+
+    #[test]
+    fn classical_only_keeps_every_group() {
+        let (provider, security_mode) = fallback_crypto_provider(SecurityPolicy::ClassicalOnly).unwrap();
+        assert_eq!(security_mode, SecurityMode::Classical);
+        assert!(provider.kx_groups.iter().any(|kx_group| kx_group.name() == NamedGroup::X25519));
+        // Still preferred, the peer choosing which one is used
+        assert_eq!(provider.kx_groups[0].name(), NamedGroup::X25519MLKEM768);
+    }
+
+    #[test]
+    fn fallback_security_mode_follows_the_negotiated_group() {
+        assert_eq!(fallback_security_mode(Some(NamedGroup::X25519MLKEM768)), SecurityMode::HybridPostQuantum);
+        assert_eq!(fallback_security_mode(Some(NamedGroup::X25519)), SecurityMode::Classical);
+        assert_eq!(fallback_security_mode(None), SecurityMode::Classical);
+    }
+
+    #[test]
+    fn policies_allow_their_security_modes() {
+        assert!(SecurityPolicy::QkdRequired.allows(SecurityMode::Qkd));
+        assert!(!SecurityPolicy::QkdRequired.allows(SecurityMode::HybridPostQuantum));
+        assert!(SecurityPolicy::QkdPreferred.allows(SecurityMode::HybridPostQuantum));
+        assert!(!SecurityPolicy::QkdPreferred.allows(SecurityMode::Classical));
+        assert!(SecurityPolicy::ClassicalOnly.allows(SecurityMode::Classical));
+        assert!(!SecurityPolicy::ClassicalOnly.allows(SecurityMode::Qkd));
+    }
+}
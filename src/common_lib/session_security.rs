@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::security_policy::SecurityMode;
+use crate::tls_connection::TlsConnection;
+
+/// Time after which the traffic keys of a session are refreshed
+pub const REKEY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Label of the keying material identifying the QKD key of a session (RFC 5705)
+const QKD_KEY_ID_EXPORTER_LABEL: &[u8] = b"EXPORTER-qkd-camera-key-id";
+const QKD_KEY_ID_LENGTH: usize = 8;
+
+/// Security properties of a session, displayed to the user and logged at session start and on every re-key
+#[derive(Debug, Clone)]
+pub struct SessionSecurityInfo {
+    pub security_mode: SecurityMode,
+    /// Identifier of the QKD key used for the session, derived from the session secrets so that both sides display
+    /// the same one. The KME key ID itself isn't exposed by the QKD fork of rustls.
+    pub qkd_key_id: Option<String>,
+    pub kme_address: String,
+    pub origin_sae_id: i64,
+    pub target_sae_id: i64,
+    pub tls_version: String,
+    pub cipher_suite: String,
+    pub rekey_count: u32,
+    key_established_at: Instant,
+    next_rekey_at: Instant,
+}
+
+impl SessionSecurityInfo {
+    /// Gather security properties once the TLS handshake is complete
+    pub fn new(conn: &TlsConnection, kme_address: &str, origin_sae_id: i64, target_sae_id: i64) -> Self {
+        let security_mode = conn.security_mode();
+        let qkd_key_id = match security_mode {
+            SecurityMode::Qkd => conn.export_keying_material::<QKD_KEY_ID_LENGTH>(QKD_KEY_ID_EXPORTER_LABEL).ok()
+                .map(|key_id| key_id.iter().map(|byte| format!("{:02x}", byte)).collect()),
+            _ => None,
+        };
+        Self {
+            security_mode,
+            qkd_key_id,
+            kme_address: kme_address.to_string(),
+            origin_sae_id,
+            target_sae_id,
+            tls_version: conn.protocol_version(),
+            cipher_suite: conn.cipher_suite(),
+            rekey_count: 0,
+            key_established_at: Instant::now(),
+            next_rekey_at: Instant::now() + REKEY_INTERVAL,
+        }
+    }
+
+    /// Time elapsed since the current session key was established
+    pub fn key_age(&self) -> Duration {
+        self.key_established_at.elapsed()
+    }
+
+    /// Refresh the traffic keys of `conn` once [REKEY_INTERVAL] has elapsed since the last attempt, and return whether
+    /// it was done. Connections using QKD keep their keys, the QKD fork not supporting key updates.
+    pub fn rekey_if_due(&mut self, conn: &mut TlsConnection) -> Result<bool, String> {
+        if !conn.supports_key_update() || Instant::now() < self.next_rekey_at {
+            return Ok(false);
+        }
+        self.next_rekey_at = Instant::now() + REKEY_INTERVAL;
+        conn.refresh_traffic_keys()?;
+        self.rekey_count += 1;
+        self.key_established_at = Instant::now();
+        Ok(true)
+    }
+
+    /// Short description, suitable for a window title
+    pub fn summary(&self) -> String {
+        format!("{} key exchange, {} {}, SAE {} -> {}",
+                self.security_mode, self.tls_version, self.cipher_suite, self.origin_sae_id, self.target_sae_id)
+    }
+
+    /// Structured log line, `event` being for example "session_start" or "rekey"
+    pub fn log_line(&self, event: &str) -> String {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        format!("session_security event={} timestamp={} security_mode=\"{}\" qkd_key_id={} kme_address={} origin_sae_id={} target_sae_id={} tls_version={} cipher_suite={} key_age_secs={} rekey_count={}",
+                event, timestamp, self.security_mode, self.qkd_key_id.as_deref().unwrap_or("none"), self.kme_address,
+                self.origin_sae_id, self.target_sae_id, self.tls_version, self.cipher_suite, self.key_age().as_secs(), self.rekey_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::security_policy::SecurityPolicy;
+    use crate::tls_connection::tests::fallback_connections;
+    use super::*;
+
+    #[test]
+    fn fallback_session_has_no_qkd_key_id() {
+        let (client_conn, _client_sock, _server_conn, _server_sock) = fallback_connections(SecurityPolicy::QkdPreferred, SecurityPolicy::QkdPreferred);
+        let session_security_info = SessionSecurityInfo::new(&client_conn, "kme:8080", 1, 2);
+        assert_eq!(session_security_info.security_mode, SecurityMode::HybridPostQuantum);
+        assert_eq!(session_security_info.qkd_key_id, None);
+        assert!(session_security_info.log_line("session_start").contains("event=session_start"));
+        assert!(session_security_info.log_line("session_start").contains("qkd_key_id=none"));
+    }
+
+    #[test]
+    fn rekey_happens_once_due() {
+        let (mut client_conn, _client_sock, _server_conn, _server_sock) = fallback_connections(SecurityPolicy::QkdPreferred, SecurityPolicy::QkdPreferred);
+        let mut session_security_info = SessionSecurityInfo::new(&client_conn, "kme:8080", 1, 2);
+        assert!(!session_security_info.rekey_if_due(&mut client_conn).unwrap());
+        session_security_info.next_rekey_at = Instant::now();
+        assert!(session_security_info.rekey_if_due(&mut client_conn).unwrap());
+        assert_eq!(session_security_info.rekey_count, 1);
+        assert!(session_security_info.log_line("rekey").contains("rekey_count=1"));
+        assert!(!session_security_info.rekey_if_due(&mut client_conn).unwrap());
+    }
+}
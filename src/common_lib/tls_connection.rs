@@ -0,0 +1,262 @@
+use std::io::{Read, Write};
+use crate::security_policy::{self, SecurityMode};
+
+/// TLS connection of a session. QKD keys are only supported by the rustls fork, whose key exchange groups are all
+/// classical, so the connections without QKD are made by upstream rustls and its hybrid post-quantum groups.
+pub enum TlsConnection {
+    Qkd(rustls::Connection),
+    Fallback(rustls_pq::Connection),
+}
+
+impl TlsConnection {
+    pub fn read_tls(&mut self, reader: &mut dyn Read) -> std::io::Result<usize> {
+        match self {
+            TlsConnection::Qkd(conn) => conn.read_tls(reader),
+            TlsConnection::Fallback(conn) => conn.read_tls(reader),
+        }
+    }
+
+    pub fn write_tls(&mut self, writer: &mut dyn Write) -> std::io::Result<usize> {
+        match self {
+            TlsConnection::Qkd(conn) => conn.write_tls(writer),
+            TlsConnection::Fallback(conn) => conn.write_tls(writer),
+        }
+    }
+
+    /// Process the received TLS records, and return the number of plaintext bytes available to read
+    pub fn process_new_packets(&mut self) -> Result<usize, String> {
+        match self {
+            TlsConnection::Qkd(conn) => conn.process_new_packets().map(|io_state| io_state.plaintext_bytes_to_read()).map_err(|e| e.to_string()),
+            TlsConnection::Fallback(conn) => conn.process_new_packets().map(|io_state| io_state.plaintext_bytes_to_read()).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Plaintext received from the peer
+    pub fn reader(&mut self) -> Box<dyn Read + '_> {
+        match self {
+            TlsConnection::Qkd(conn) => Box::new(conn.reader()),
+            TlsConnection::Fallback(conn) => Box::new(conn.reader()),
+        }
+    }
+
+    /// Plaintext to send to the peer, with [TlsConnection::write_tls]
+    pub fn writer(&mut self) -> Box<dyn Write + '_> {
+        match self {
+            TlsConnection::Qkd(conn) => Box::new(conn.writer()),
+            TlsConnection::Fallback(conn) => Box::new(conn.writer()),
+        }
+    }
+
+    pub fn wants_read(&self) -> bool {
+        match self {
+            TlsConnection::Qkd(conn) => conn.wants_read(),
+            TlsConnection::Fallback(conn) => conn.wants_read(),
+        }
+    }
+
+    pub fn wants_write(&self) -> bool {
+        match self {
+            TlsConnection::Qkd(conn) => conn.wants_write(),
+            TlsConnection::Fallback(conn) => conn.wants_write(),
+        }
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        match self {
+            TlsConnection::Qkd(conn) => conn.is_handshaking(),
+            TlsConnection::Fallback(conn) => conn.is_handshaking(),
+        }
+    }
+
+    pub fn send_close_notify(&mut self) {
+        match self {
+            TlsConnection::Qkd(conn) => conn.send_close_notify(),
+            TlsConnection::Fallback(conn) => conn.send_close_notify(),
+        }
+    }
+
+    pub fn complete_io<T: Read + Write>(&mut self, io: &mut T) -> std::io::Result<(usize, usize)> {
+        match self {
+            TlsConnection::Qkd(conn) => conn.complete_io(io),
+            TlsConnection::Fallback(conn) => conn.complete_io(io),
+        }
+    }
+
+    /// Whether [TlsConnection::refresh_traffic_keys] is available, the QKD fork not sending TLS 1.3 key updates
+    pub fn supports_key_update(&self) -> bool {
+        matches!(self, TlsConnection::Fallback(_))
+    }
+
+    /// Queue a TLS 1.3 key update, sent with the next [TlsConnection::write_tls], the peer updating its keys in return
+    pub fn refresh_traffic_keys(&mut self) -> Result<(), String> {
+        match self {
+            TlsConnection::Qkd(_) => Err("the QKD fork of rustls cannot update traffic keys".to_string()),
+            TlsConnection::Fallback(conn) => conn.refresh_traffic_keys().map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Keying material derived from the session secrets, identical on both sides of the connection (RFC 5705)
+    pub fn export_keying_material<const LENGTH: usize>(&self, label: &[u8]) -> Result<[u8; LENGTH], String> {
+        match self {
+            TlsConnection::Qkd(conn) => conn.export_keying_material([0u8; LENGTH], label, None).map_err(|e| e.to_string()),
+            TlsConnection::Fallback(conn) => conn.export_keying_material([0u8; LENGTH], label, None).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Key exchange mechanism of the connection once the handshake is complete
+    pub fn security_mode(&self) -> SecurityMode {
+        match self {
+            TlsConnection::Qkd(_) => SecurityMode::Qkd,
+            TlsConnection::Fallback(conn) => security_policy::fallback_security_mode(conn.negotiated_key_exchange_group().map(|kx_group| kx_group.name())),
+        }
+    }
+
+    pub fn protocol_version(&self) -> String {
+        let protocol_version = match self {
+            TlsConnection::Qkd(conn) => conn.protocol_version().map(|protocol_version| format!("{:?}", protocol_version)),
+            TlsConnection::Fallback(conn) => conn.protocol_version().map(|protocol_version| format!("{:?}", protocol_version)),
+        };
+        protocol_version.unwrap_or_else(|| "unknown".to_string())
+    }
+
+    pub fn cipher_suite(&self) -> String {
+        let cipher_suite = match self {
+            TlsConnection::Qkd(conn) => conn.negotiated_cipher_suite().map(|cipher_suite| format!("{:?}", cipher_suite.suite())),
+            TlsConnection::Fallback(conn) => conn.negotiated_cipher_suite().map(|cipher_suite| format!("{:?}", cipher_suite.suite())),
+        };
+        cipher_suite.unwrap_or_else(|| "unknown".to_string())
+    }
+}
+
+/// Plaintext stream over a [TlsConnection] and its socket, doing the TLS I/O as needed like [rustls::Stream]
+pub struct TlsStream<'a, T: Read + Write> {
+    pub conn: &'a mut TlsConnection,
+    pub sock: &'a mut T,
+}
+
+impl<'a, T: Read + Write> TlsStream<'a, T> {
+    pub fn new(conn: &'a mut TlsConnection, sock: &'a mut T) -> Self {
+        Self {
+            conn,
+            sock,
+        }
+    }
+
+    fn complete_prior_io(&mut self) -> std::io::Result<()> {
+        if self.conn.is_handshaking() {
+            self.conn.complete_io(self.sock)?;
+        }
+        if self.conn.wants_write() {
+            self.conn.complete_io(self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read + Write> Read for TlsStream<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.complete_prior_io()?;
+        while self.conn.wants_read() {
+            if self.conn.complete_io(self.sock)?.0 == 0 {
+                break;
+            }
+        }
+        self.conn.reader().read(buf)
+    }
+}
+
+impl<T: Read + Write> Write for TlsStream<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.complete_prior_io()?;
+        let written_size = self.conn.writer().write(buf)?;
+        // Sent with the next write or flush if the socket isn't ready
+        let _ = self.conn.complete_io(self.sock);
+        Ok(written_size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.complete_prior_io()?;
+        self.conn.writer().flush()?;
+        if self.conn.wants_write() {
+            self.conn.complete_io(self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+    use crate::security_policy::SecurityPolicy;
+    use super::*;
+
+    /// Self-signed P-256 certificate for `localhost`, and its key
+    fn localhost_certificate() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        (CertificateDer::from(certificate.serialize_der().unwrap()), PrivatePkcs8KeyDer::from(certificate.serialize_private_key_der()).into())
+    }
+
+    /// Connect an upstream rustls client and server with the fallback providers of their security policies
+    pub(crate) fn fallback_connections(client_security_policy: SecurityPolicy, server_security_policy: SecurityPolicy) -> (TlsConnection, TcpStream, TlsConnection, TcpStream) {
+        let (certificate, private_key) = localhost_certificate();
+        let (server_provider, _) = security_policy::fallback_crypto_provider(server_security_policy).unwrap();
+        let server_config = rustls_pq::ServerConfig::builder_with_provider(Arc::new(server_provider))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.clone()], private_key).unwrap();
+        let mut root_store = rustls_pq::RootCertStore::empty();
+        root_store.add(certificate).unwrap();
+        let (client_provider, _) = security_policy::fallback_crypto_provider(client_security_policy).unwrap();
+        let client_config = rustls_pq::ClientConfig::builder_with_provider(Arc::new(client_provider))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client_sock = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server_sock, _) = listener.accept().unwrap();
+        let server_thread = std::thread::spawn(move || {
+            let mut server_conn = TlsConnection::Fallback(rustls_pq::ServerConnection::new(Arc::new(server_config)).unwrap().into());
+            while server_conn.is_handshaking() {
+                server_conn.complete_io(&mut server_sock).unwrap();
+            }
+            (server_conn, server_sock)
+        });
+        let client_conn = rustls_pq::ClientConnection::new(Arc::new(client_config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut client_conn = TlsConnection::Fallback(client_conn.into());
+        while client_conn.is_handshaking() {
+            client_conn.complete_io(&mut client_sock).unwrap();
+        }
+        let (server_conn, server_sock) = server_thread.join().unwrap();
+        (client_conn, client_sock, server_conn, server_sock)
+    }
+
+    #[test]
+    fn fallback_connection_negotiates_hybrid_post_quantum_key_exchange() {
+        let (client_conn, _client_sock, server_conn, _server_sock) = fallback_connections(SecurityPolicy::QkdPreferred, SecurityPolicy::ClassicalOnly);
+        assert_eq!(client_conn.security_mode(), SecurityMode::HybridPostQuantum);
+        assert_eq!(server_conn.security_mode(), SecurityMode::HybridPostQuantum);
+        assert_eq!(client_conn.protocol_version(), "TLSv1_3");
+        assert_eq!(client_conn.export_keying_material::<32>(b"test").unwrap(), server_conn.export_keying_material::<32>(b"test").unwrap());
+    }
+
+    #[test]
+    fn messages_are_exchanged_through_tls_streams() {
+        let (mut client_conn, mut client_sock, mut server_conn, mut server_sock) = fallback_connections(SecurityPolicy::ClassicalOnly, SecurityPolicy::ClassicalOnly);
+        crate::write_message(&mut TlsStream::new(&mut client_conn, &mut client_sock), &"hello".to_string()).unwrap();
+        let message: String = crate::read_message(&mut TlsStream::new(&mut server_conn, &mut server_sock)).unwrap();
+        assert_eq!(message, "hello");
+    }
+
+    #[test]
+    fn messages_are_exchanged_after_a_key_update() {
+        let (mut client_conn, mut client_sock, mut server_conn, mut server_sock) = fallback_connections(SecurityPolicy::QkdPreferred, SecurityPolicy::QkdPreferred);
+        assert!(client_conn.supports_key_update());
+        client_conn.refresh_traffic_keys().unwrap();
+        crate::write_message(&mut TlsStream::new(&mut client_conn, &mut client_sock), &"after key update".to_string()).unwrap();
+        let message: String = crate::read_message(&mut TlsStream::new(&mut server_conn, &mut server_sock)).unwrap();
+        assert_eq!(message, "after key update");
+    }
+}
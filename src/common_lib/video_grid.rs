@@ -0,0 +1,113 @@
+use image::{ImageBuffer, Rgb};
+use image::imageops::{self, FilterType};
+use crate::media_indicators::{self, DEFAULT_PLACEHOLDER_SIZE};
+
+/// Size of each participant tile of the grid
+pub const GRID_TILE_SIZE: (u32, u32) = (480, 360);
+
+/// What a tile of the grid shows for a conference participant
+pub struct GridTile<'a> {
+    /// Newest image of the participant, a placeholder is shown if missing
+    pub image: Option<&'a ImageBuffer<Rgb<u8>, Vec<u8>>>,
+    pub audio_muted: bool,
+}
+
+/// Arrange the tiles in a grid with as many columns as rows, or one more. Each image is scaled to fit its tile,
+/// keeping its aspect ratio. Without tiles, a single placeholder is returned.
+pub fn compose_grid(tiles: &[GridTile]) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    if tiles.is_empty() {
+        return media_indicators::placeholder_image(DEFAULT_PLACEHOLDER_SIZE.0, DEFAULT_PLACEHOLDER_SIZE.1);
+    }
+    let (tile_width, tile_height) = GRID_TILE_SIZE;
+    let columns = (1..).find(|columns| columns * columns >= tiles.len()).unwrap() as u32;
+    let rows = (tiles.len() as u32).div_ceil(columns);
+    let mut grid = ImageBuffer::new(columns * tile_width, rows * tile_height);
+    for (tile_index, tile) in tiles.iter().enumerate() {
+        let mut tile_image = match tile.image {
+            Some(image) => fit_in_tile(image),
+            None => media_indicators::placeholder_image(tile_width, tile_height),
+        };
+        if tile.audio_muted {
+            media_indicators::draw_muted_indicator(&mut tile_image);
+        }
+        let x = (tile_index as u32 % columns) * tile_width;
+        let y = (tile_index as u32 / columns) * tile_height;
+        imageops::replace(&mut grid, &tile_image, x as i64, y as i64);
+    }
+    grid
+}
+
+/// Scale the image to fit in a tile, centered on a black background
+fn fit_in_tile(image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let (tile_width, tile_height) = GRID_TILE_SIZE;
+    let (width, height) = image.dimensions();
+    let scale = f64::min(tile_width as f64 / width.max(1) as f64, tile_height as f64 / height.max(1) as f64);
+    let scaled_width = ((width as f64 * scale) as u32).clamp(1, tile_width);
+    let scaled_height = ((height as f64 * scale) as u32).clamp(1, tile_height);
+    let scaled_image = imageops::resize(image, scaled_width, scaled_height, FilterType::Triangle);
+    let mut tile_image = ImageBuffer::new(tile_width, tile_height);
+    imageops::replace(&mut tile_image, &scaled_image, ((tile_width - scaled_width) / 2) as i64, ((tile_height - scaled_height) / 2) as i64);
+    tile_image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+
+    fn grid_of(tile_count: usize) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let tiles: Vec<GridTile> = (0..tile_count).map(|_| GridTile { image: None, audio_muted: false }).collect();
+        compose_grid(&tiles)
+    }
+
+    #[test]
+    fn grid_has_as_many_columns_as_rows_or_one_more() {
+        let (tile_width, tile_height) = GRID_TILE_SIZE;
+        assert_eq!(grid_of(0).dimensions(), DEFAULT_PLACEHOLDER_SIZE);
+        assert_eq!(grid_of(1).dimensions(), (tile_width, tile_height));
+        assert_eq!(grid_of(2).dimensions(), (2 * tile_width, tile_height));
+        assert_eq!(grid_of(3).dimensions(), (2 * tile_width, 2 * tile_height));
+        assert_eq!(grid_of(4).dimensions(), (2 * tile_width, 2 * tile_height));
+        assert_eq!(grid_of(5).dimensions(), (3 * tile_width, 2 * tile_height));
+        assert_eq!(grid_of(7).dimensions(), (3 * tile_width, 3 * tile_height));
+    }
+
+    #[test]
+    fn tiles_are_placed_in_reading_order() {
+        let (tile_width, tile_height) = GRID_TILE_SIZE;
+        let image = ImageBuffer::from_pixel(tile_width, tile_height, RED);
+        let tiles = [
+            GridTile { image: None, audio_muted: false },
+            GridTile { image: None, audio_muted: false },
+            GridTile { image: Some(&image), audio_muted: false },
+        ];
+        let grid = compose_grid(&tiles);
+        assert_eq!(*grid.get_pixel(tile_width / 2, tile_height + tile_height / 2), RED);
+        assert_ne!(*grid.get_pixel(tile_width + tile_width / 2, tile_height / 2), RED);
+        // The last row isn't full
+        assert_eq!(*grid.get_pixel(tile_width + tile_width / 2, tile_height + tile_height / 2), Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn image_keeps_its_aspect_ratio() {
+        let (tile_width, tile_height) = GRID_TILE_SIZE;
+        // Twice as wide as the tile ratio: scaled to the tile width, with black bars above and below
+        let image = ImageBuffer::from_pixel(tile_width * 2, tile_height, RED);
+        let grid = compose_grid(&[GridTile { image: Some(&image), audio_muted: false }]);
+        assert_eq!(grid.dimensions(), GRID_TILE_SIZE);
+        assert_eq!(*grid.get_pixel(tile_width / 2, tile_height / 8), Rgb([0, 0, 0]));
+        assert_eq!(*grid.get_pixel(tile_width / 2, tile_height / 2), RED);
+        assert_eq!(*grid.get_pixel(tile_width / 2, tile_height * 7 / 8), Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn muted_tile_shows_the_indicator() {
+        let (tile_width, tile_height) = GRID_TILE_SIZE;
+        let image = ImageBuffer::from_pixel(tile_width, tile_height, Rgb([0, 0, 255]));
+        let muted_grid = compose_grid(&[GridTile { image: Some(&image), audio_muted: true }]);
+        let unmuted_grid = compose_grid(&[GridTile { image: Some(&image), audio_muted: false }]);
+        assert_ne!(muted_grid, unmuted_grid);
+        assert_eq!(*unmuted_grid.get_pixel(tile_width / 2, tile_height / 2), *muted_grid.get_pixel(tile_width / 2, tile_height / 2));
+    }
+}
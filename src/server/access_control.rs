@@ -0,0 +1,197 @@
+use std::time::{Duration, Instant};
+use qkd_camera_common_lib::{RejectionReason, SessionLimits};
+use qkd_camera_common_lib::sae_identity::{self, CertificateIdentity};
+use crate::json_server_config::{JsonAccessControlConfig, JsonSaeLimitsConfig};
+
+/// Duration over which the received bitrate is averaged before comparing it to the limit
+const BITRATE_MEASUREMENT_WINDOW: Duration = Duration::from_secs(5);
+
+/// Decide whether the calling SAE is allowed, and which limits apply to its session. Only the SAE ID authenticated by
+/// its SAE certificate is trusted: a caller without one is refused by an allow list and gets the default limits.
+pub(crate) fn check_caller(access_control: Option<&JsonAccessControlConfig>, authenticated_sae_id: Option<i64>) -> Result<SessionLimits, RejectionReason> {
+    let access_control = match access_control {
+        Some(access_control) => access_control,
+        None => return Ok(SessionLimits::default()),
+    };
+    let origin_sae_id = match authenticated_sae_id {
+        Some(origin_sae_id) => origin_sae_id,
+        None if access_control.allowed_sae_ids.is_some() => return Err(RejectionReason::SaeIdNotAllowed),
+        None => return Ok(access_control.default_limits.as_ref().map(session_limits_from_config).unwrap_or_default()),
+    };
+    if access_control.blocked_sae_ids.contains(&origin_sae_id) {
+        return Err(RejectionReason::SaeIdBlocked);
+    }
+    if let Some(allowed_sae_ids) = access_control.allowed_sae_ids.as_ref() {
+        if !allowed_sae_ids.contains(&origin_sae_id) {
+            return Err(RejectionReason::SaeIdNotAllowed);
+        }
+    }
+    let limits = access_control.per_sae_limits.iter()
+        .find(|per_sae_limits| per_sae_limits.sae_id == origin_sae_id)
+        .map(|per_sae_limits| per_sae_limits.limits())
+        .or_else(|| access_control.default_limits.clone());
+    Ok(limits.as_ref().map(session_limits_from_config).unwrap_or_default())
+}
+
+/// SAE ID of the certificate the caller proved to hold, if it is listed in the access control
+pub(crate) fn certificate_sae_id(access_control: Option<&JsonAccessControlConfig>, certificate: &CertificateIdentity) -> Option<i64> {
+    access_control?.sae_certificates.iter()
+        .find(|sae_certificate| sae_identity::normalize_fingerprint(&sae_certificate.sha256_fingerprint) == certificate.sha256_fingerprint)
+        .map(|sae_certificate| sae_certificate.sae_id)
+}
+
+fn session_limits_from_config(limits: &JsonSaeLimitsConfig) -> SessionLimits {
+    SessionLimits {
+        max_width: limits.max_width,
+        max_height: limits.max_height,
+        max_bitrate_kbps: limits.max_bitrate_kbps,
+        max_session_duration_secs: limits.max_session_duration_secs,
+    }
+}
+
+/// Enforce the limits of an accepted session on each received packet
+pub(crate) struct SessionLimitsEnforcer {
+    limits: SessionLimits,
+    session_start: Instant,
+    bitrate_window_start: Instant,
+    bitrate_window_bytes: usize,
+}
+
+impl SessionLimitsEnforcer {
+    pub(crate) fn new(limits: SessionLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            limits,
+            session_start: now,
+            bitrate_window_start: now,
+            bitrate_window_bytes: 0,
+        }
+    }
+
+    /// Check session duration and bitrate, to be called with the size of each received packet
+    pub(crate) fn check_packet(&mut self, packet_size: usize) -> Result<(), RejectionReason> {
+        if let Some(max_session_duration_secs) = self.limits.max_session_duration_secs {
+            if self.session_start.elapsed() > Duration::from_secs(max_session_duration_secs) {
+                return Err(RejectionReason::SessionDurationExceeded);
+            }
+        }
+        self.bitrate_window_bytes = self.bitrate_window_bytes.saturating_add(packet_size);
+        let window_duration = self.bitrate_window_start.elapsed();
+        if window_duration >= BITRATE_MEASUREMENT_WINDOW {
+            let bitrate_kbps = (self.bitrate_window_bytes as f64 * 8.0 / 1000.0) / window_duration.as_secs_f64();
+            self.bitrate_window_start = Instant::now();
+            self.bitrate_window_bytes = 0;
+            if let Some(max_bitrate_kbps) = self.limits.max_bitrate_kbps {
+                if bitrate_kbps > max_bitrate_kbps as f64 {
+                    return Err(RejectionReason::BitrateTooHigh);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the resolution of a received image
+    pub(crate) fn check_resolution(&self, width: usize, height: usize) -> Result<(), RejectionReason> {
+        let width_too_high = self.limits.max_width.is_some_and(|max_width| width > max_width as usize);
+        let height_too_high = self.limits.max_height.is_some_and(|max_height| height > max_height as usize);
+        if width_too_high || height_too_high {
+            return Err(RejectionReason::ResolutionTooHigh);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json_server_config::{JsonPerSaeLimitsConfig, JsonSaeCertificateConfig};
+    use super::*;
+
+    fn access_control(allowed_sae_ids: Option<Vec<i64>>, blocked_sae_ids: Vec<i64>) -> JsonAccessControlConfig {
+        JsonAccessControlConfig {
+            allowed_sae_ids,
+            blocked_sae_ids,
+            default_limits: Some(JsonSaeLimitsConfig { max_width: Some(640), ..Default::default() }),
+            per_sae_limits: vec![JsonPerSaeLimitsConfig {
+                sae_id: 2,
+                max_width: Some(1920),
+                max_height: None,
+                max_bitrate_kbps: None,
+                max_session_duration_secs: None,
+            }],
+            sae_certificates: vec![JsonSaeCertificateConfig {
+                sae_id: 2,
+                sha256_fingerprint: "AB:CD:EF".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn caller_limits_are_the_per_sae_or_default_ones() {
+        let access_control = access_control(None, Vec::new());
+        assert_eq!(check_caller(Some(&access_control), Some(2)).unwrap().max_width, Some(1920));
+        assert_eq!(check_caller(Some(&access_control), Some(3)).unwrap().max_width, Some(640));
+        assert_eq!(check_caller(Some(&access_control), None).unwrap().max_width, Some(640));
+        assert_eq!(check_caller(None, Some(2)).unwrap().max_width, None);
+    }
+
+    #[test]
+    fn blocked_and_not_allowed_callers_are_rejected() {
+        let access_control = access_control(Some(vec![1, 2]), vec![2]);
+        assert!(check_caller(Some(&access_control), Some(1)).is_ok());
+        assert_eq!(check_caller(Some(&access_control), Some(2)).unwrap_err(), RejectionReason::SaeIdBlocked);
+        assert_eq!(check_caller(Some(&access_control), Some(3)).unwrap_err(), RejectionReason::SaeIdNotAllowed);
+        // Without a known SAE certificate, the caller can't be on the allow list
+        assert_eq!(check_caller(Some(&access_control), None).unwrap_err(), RejectionReason::SaeIdNotAllowed);
+    }
+
+    #[test]
+    fn certificate_sae_id_is_looked_up_by_fingerprint() {
+        let access_control = access_control(None, Vec::new());
+        let certificate = |sha256_fingerprint: &str| CertificateIdentity {
+            subject: "CN=sae2".to_string(),
+            sha256_fingerprint: sha256_fingerprint.to_string(),
+        };
+        assert_eq!(certificate_sae_id(Some(&access_control), &certificate("abcdef")), Some(2));
+        assert_eq!(certificate_sae_id(Some(&access_control), &certificate("abcd00")), None);
+        assert_eq!(certificate_sae_id(None, &certificate("abcdef")), None);
+    }
+
+    #[test]
+    fn resolution_is_limited_in_each_dimension() {
+        let enforcer = SessionLimitsEnforcer::new(SessionLimits { max_width: Some(640), max_height: Some(480), ..Default::default() });
+        assert!(enforcer.check_resolution(640, 480).is_ok());
+        assert_eq!(enforcer.check_resolution(641, 480).unwrap_err(), RejectionReason::ResolutionTooHigh);
+        assert_eq!(enforcer.check_resolution(640, 481).unwrap_err(), RejectionReason::ResolutionTooHigh);
+        assert!(SessionLimitsEnforcer::new(SessionLimits::default()).check_resolution(7680, 4320).is_ok());
+    }
+
+    #[test]
+    fn session_duration_is_limited() {
+        let mut enforcer = SessionLimitsEnforcer::new(SessionLimits { max_session_duration_secs: Some(60), ..Default::default() });
+        assert!(enforcer.check_packet(0).is_ok());
+        enforcer.session_start -= Duration::from_secs(61);
+        assert_eq!(enforcer.check_packet(0).unwrap_err(), RejectionReason::SessionDurationExceeded);
+    }
+
+    #[test]
+    fn bitrate_is_averaged_over_the_measurement_window() {
+        let mut enforcer = SessionLimitsEnforcer::new(SessionLimits { max_bitrate_kbps: Some(1000), ..Default::default() });
+        // Far above the limit, but only measured once the window is over
+        assert!(enforcer.check_packet(10_000_000).is_ok());
+        enforcer.bitrate_window_start -= BITRATE_MEASUREMENT_WINDOW;
+        assert_eq!(enforcer.check_packet(0).unwrap_err(), RejectionReason::BitrateTooHigh);
+        // 600 kB over 5 s is 960 kbps
+        assert!(enforcer.check_packet(600_000).is_ok());
+        enforcer.bitrate_window_start -= BITRATE_MEASUREMENT_WINDOW;
+        assert!(enforcer.check_packet(0).is_ok());
+    }
+
+    #[test]
+    fn huge_packet_sizes_do_not_overflow_the_bitrate_window() {
+        let mut enforcer = SessionLimitsEnforcer::new(SessionLimits { max_bitrate_kbps: Some(1000), ..Default::default() });
+        assert!(enforcer.check_packet(usize::MAX).is_ok());
+        assert!(enforcer.check_packet(usize::MAX).is_ok());
+        enforcer.bitrate_window_start -= BITRATE_MEASUREMENT_WINDOW;
+        assert_eq!(enforcer.check_packet(0).unwrap_err(), RejectionReason::BitrateTooHigh);
+    }
+}
@@ -0,0 +1,30 @@
+use log::info;
+use rodio::{DeviceTrait, OutputStream, OutputStreamHandle};
+use rodio::cpal::traits::HostTrait;
+use qkd_camera_common_lib::audio_device::AudioDeviceSelector;
+
+/// Names of the speakers, in the order of their indexes
+pub(crate) fn speaker_names() -> Result<Vec<String>, String> {
+    let output_devices = rodio::cpal::default_host().output_devices().map_err(|e| format!("cannot list speakers: {}", e))?;
+    Ok(output_devices.map(|output_device| output_device.name().unwrap_or_else(|_| "unknown".to_string())).collect())
+}
+
+/// Open the selected speaker, or the default one. The sample rate it plays at is returned with its output stream.
+pub(crate) fn open_speaker(audio_output_device: Option<&AudioDeviceSelector>) -> Result<(OutputStream, OutputStreamHandle, u32), String> {
+    let output_device = match audio_output_device {
+        Some(audio_output_device) => {
+            let device_index = audio_output_device.find(&speaker_names()?)?;
+            rodio::cpal::default_host().output_devices()
+                .map_err(|e| format!("cannot list speakers: {}", e))?
+                .nth(device_index)
+                .ok_or_else(|| format!("speaker {} was disconnected", audio_output_device))?
+        },
+        None => rodio::cpal::default_host().default_output_device().ok_or("no default speaker")?,
+    };
+    let device_name = output_device.name().unwrap_or_else(|_| "unknown".to_string());
+    let output_config = output_device.default_output_config().map_err(|e| format!("cannot query speaker {} format: {}", device_name, e))?;
+    let (output_stream, output_stream_handle) = OutputStream::try_from_device(&output_device)
+        .map_err(|e| format!("cannot open speaker {}: {}", device_name, e))?;
+    info!("Playing on speaker {} ({} Hz)", device_name, output_config.sample_rate().0);
+    Ok((output_stream, output_stream_handle, output_config.sample_rate().0))
+}
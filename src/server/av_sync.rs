@@ -0,0 +1,89 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+use image::{ImageBuffer, Rgb};
+use log::{debug, error};
+use qkd_camera_common_lib::MediaState;
+use qkd_camera_common_lib::media_indicators::{self, DEFAULT_PLACEHOLDER_SIZE};
+use rodio::source::EmptyCallback;
+use show_image::{ImageInfo, ImageView, WindowProxy};
+
+/// Images due later than this are displayed right away, the audio playback being too far behind to wait for it
+const MAX_VIDEO_DELAY: Duration = Duration::from_secs(1);
+
+/// Maps the client capture clock to the local playback time, using the last sound that started playing
+pub(crate) struct PlaybackClock {
+    /// Client capture time of the sound, and when it started playing
+    anchor: Mutex<Option<(u64, Instant)>>,
+}
+
+impl PlaybackClock {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self { anchor: Mutex::new(None) })
+    }
+
+    /// Source to append to the sink right before the sound captured at `capture_timestamp_us`, recording when it starts playing
+    pub(crate) fn marker(self: &Arc<Self>, capture_timestamp_us: u64) -> EmptyCallback<i16> {
+        let playback_clock = self.clone();
+        EmptyCallback::new(Box::new(move || {
+            *playback_clock.anchor.lock().unwrap() = Some((capture_timestamp_us, Instant::now()));
+        }))
+    }
+
+    /// When something captured at `capture_timestamp_us` plays, `None` before any sound is played
+    pub(crate) fn playback_instant(&self, capture_timestamp_us: u64) -> Option<Instant> {
+        let (anchor_timestamp_us, anchor_instant) = (*self.anchor.lock().unwrap())?;
+        if capture_timestamp_us >= anchor_timestamp_us {
+            anchor_instant.checked_add(Duration::from_micros(capture_timestamp_us - anchor_timestamp_us))
+        } else {
+            anchor_instant.checked_sub(Duration::from_micros(anchor_timestamp_us - capture_timestamp_us))
+        }
+    }
+}
+
+/// Change of what the server window shows
+pub(crate) enum DisplayUpdate {
+    /// Image captured at this time on the client capture clock
+    Image(u64, ImageBuffer<Rgb<u8>, Vec<u8>>),
+    MediaState(MediaState),
+}
+
+/// Display the received images, each one when the sound captured at the same time plays.
+/// A placeholder is shown while the remote camera is off, and a muted indicator while its sound is muted.
+pub(crate) fn display_images(window: WindowProxy, display_receiver: Receiver<DisplayUpdate>, playback_clock: Arc<PlaybackClock>) {
+    let mut media_state = MediaState::default();
+    let mut image_size = DEFAULT_PLACEHOLDER_SIZE;
+    let mut last_image = None;
+    for display_update in display_receiver {
+        match display_update {
+            DisplayUpdate::Image(capture_timestamp_us, image) => {
+                if !media_state.video_enabled {
+                    continue;
+                }
+                if let Some(display_instant) = playback_clock.playback_instant(capture_timestamp_us) {
+                    let display_delay = display_instant.saturating_duration_since(Instant::now());
+                    if display_delay > MAX_VIDEO_DELAY {
+                        debug!("Image due in {} ms, displayed right away", display_delay.as_millis());
+                    } else {
+                        std::thread::sleep(display_delay);
+                    }
+                }
+                image_size = image.dimensions();
+                last_image = Some(image);
+            },
+            DisplayUpdate::MediaState(new_media_state) => media_state = new_media_state,
+        }
+        let mut image = match last_image.as_ref() {
+            Some(image) if media_state.video_enabled => image.clone(),
+            _ => media_indicators::placeholder_image(image_size.0, image_size.1),
+        };
+        if media_state.audio_muted {
+            media_indicators::draw_muted_indicator(&mut image);
+        }
+        let (width, height) = image.dimensions();
+        if let Err(e) = window.set_image("image-001", ImageView::new(ImageInfo::rgb8(width, height), image.as_raw())) {
+            error!("Error displaying image: {}", e);
+            return;
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use qkd_camera_common_lib::config_loader::ConfigArgs;
+use qkd_camera_common_lib::logging::LoggingArgs;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "QKD video call server, displays and plays the stream received from the client")]
+pub(crate) struct Cli {
+    #[command(flatten)]
+    pub(crate) logging_args: LoggingArgs,
+    #[command(subcommand)]
+    pub(crate) command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Wait for incoming calls
+    Run {
+        #[command(flatten)]
+        config_args: ConfigArgs,
+        /// Validate the configuration without listening
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check KME health, key availability and binding address availability
+    Diagnose {
+        #[command(flatten)]
+        config_args: ConfigArgs,
+        /// SAE ID of a remote client to check key availability for, the first SAE of the access control if missing
+        #[arg(long)]
+        remote_sae_id: Option<i64>,
+    },
+    /// List playback devices
+    ListDevices,
+    /// List speakers, same as list-devices
+    ListAudioDevices,
+    /// Write a configuration template
+    GenConfig {
+        /// Output file, standard output if missing
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Write the JSON schema of the configuration
+    Schema {
+        /// Output file, standard output if missing
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
@@ -0,0 +1,125 @@
+use std::io::Read;
+use std::net::TcpStream;
+use log::{debug, error, info, trace};
+use qkd_camera_common_lib::{ClientMessage, PACKET_CHUNK_SIZE, RejectionReason, ServerMessage};
+use qkd_camera_common_lib::tls_connection::TlsConnection;
+use crate::MAX_CLIENT_MESSAGE_SIZE;
+
+/// Read the next message of the client, along with its size. `Ok(None)` is returned for a message that cannot be
+/// deserialized, and `Err` once the session must end.
+pub(crate) fn read_client_message(conn: &mut TlsConnection, stream: &mut TcpStream) -> Result<Option<(ClientMessage, usize)>, ()> {
+    const USIZE_SIZE: usize = std::mem::size_of::<usize>();
+    const PACKET_ANNOUNCE_SIZE: usize = USIZE_SIZE * 3; // packet size + nb chunks
+
+    let mut packet_size_and_nb_chunks_buf = [0u8; PACKET_ANNOUNCE_SIZE];
+    let received_plaintext_size = match conn.read_tls(stream) {
+        Ok(size) => size,
+        Err(e) => {
+            error!("Error reading TLS: {}", e);
+            return Err(());
+        }
+    };
+    let plaintext_bytes_to_read = match conn.process_new_packets() {
+        Ok(plaintext_bytes_to_read) => plaintext_bytes_to_read,
+        Err(e) => {
+            error!("Error processing TLS packets: {}", e);
+            return Err(());
+        }
+    };
+    trace!("TLS wants read: {}, wants write: {}", conn.wants_read(), conn.wants_write());
+
+    if plaintext_bytes_to_read < PACKET_ANNOUNCE_SIZE {
+        info!("Client disconnected");
+        return Err(());
+    }
+
+    let mut read_vec = vec![0u8; received_plaintext_size];
+    if let Err(e) = conn.reader().read_exact(&mut read_vec[..PACKET_ANNOUNCE_SIZE]) {
+        error!("Error reading packet announce: {}", e);
+        return Err(());
+    }
+
+    packet_size_and_nb_chunks_buf.clone_from_slice(&read_vec[..PACKET_ANNOUNCE_SIZE]);
+
+    let packet_size = usize::from_be_bytes(packet_size_and_nb_chunks_buf[..USIZE_SIZE].try_into().unwrap());
+    let nb_chunks = usize::from_be_bytes(packet_size_and_nb_chunks_buf[USIZE_SIZE..(USIZE_SIZE * 2)].try_into().unwrap());
+    let control_bytes = usize::from_be_bytes(packet_size_and_nb_chunks_buf[(USIZE_SIZE * 2)..PACKET_ANNOUNCE_SIZE].try_into().unwrap());
+    if control_bytes != usize::MAX {
+        error!("Invalid Control bytes not MAX: {}, disconnecting client...", control_bytes);
+        return Err(());
+    }
+    if packet_size > MAX_CLIENT_MESSAGE_SIZE {
+        error!("Announced packet of {} bytes is too big, disconnecting client...", packet_size);
+        return Err(());
+    }
+    if nb_chunks != packet_size.div_ceil(PACKET_CHUNK_SIZE) {
+        error!("Announced {} chunks for a packet of {} bytes, disconnecting client...", nb_chunks, packet_size);
+        return Err(());
+    }
+    debug!("Expecting packet of {} bytes: {} chunks", packet_size, nb_chunks);
+
+    let mut read_vec = Vec::with_capacity(packet_size);
+    let mut packet_size_remaining = packet_size;
+    for _ in 0..nb_chunks {
+        let expected_chunk_size = std::cmp::min(packet_size_remaining, PACKET_CHUNK_SIZE);
+        let mut chunk_vec = match read_stream_data(conn, stream, expected_chunk_size) {
+            Ok(vec) => vec,
+            Err(_) => {
+                info!("Client disconnected");
+                return Err(());
+            }
+        };
+        packet_size_remaining -= expected_chunk_size;
+        read_vec.append(&mut chunk_vec);
+    }
+
+    match postcard::from_bytes(&read_vec) {
+        Ok(client_message) => Ok(Some((client_message, packet_size))),
+        Err(e) => {
+            error!("Error deserializing packet: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Answer a client message with `server_message`, preceded by the chat, file transfer and participant messages for the client
+pub(crate) fn write_server_messages(conn: &mut TlsConnection, stream: &mut TcpStream, peer_messages: Vec<ServerMessage>, server_message: ServerMessage) -> std::io::Result<()> {
+    for peer_message in peer_messages {
+        qkd_camera_common_lib::write_message(&mut conn.writer(), &peer_message)?;
+    }
+    qkd_camera_common_lib::write_message(&mut conn.writer(), &server_message)?;
+    conn.write_tls(stream)?;
+    Ok(())
+}
+
+/// Answer a client message with the reason its session ends, preceded by the pending peer messages, and close the connection
+pub(crate) fn reject_client(conn: &mut TlsConnection, stream: &mut TcpStream, peer_messages: Vec<ServerMessage>, rejection_reason: RejectionReason) {
+    if write_server_messages(conn, stream, peer_messages, ServerMessage::Rejected(rejection_reason)).is_err() {
+        error!("Error writing TLS rejection");
+    }
+    conn.send_close_notify();
+    let _ = conn.write_tls(stream);
+}
+
+fn read_stream_data(conn: &mut TlsConnection, stream: &mut TcpStream, size_to_read: usize) -> Result<Vec<u8>, ()> {
+    let plaintext_bytes_to_read = conn.process_new_packets().map_err(|e| error!("Error processing TLS packets: {}", e))?;
+    if plaintext_bytes_to_read < size_to_read {
+        trace!("Trying to read {} bytes, TLS wants read: {}", size_to_read, conn.wants_read());
+        while let Ok(size_read) = conn.read_tls(stream) {
+            let plaintext_bytes_to_read = conn.process_new_packets().map_err(|e| error!("Error processing TLS packets: {}", e))?;
+            trace!("Read {} TLS bytes, {} plaintext bytes available", size_read, plaintext_bytes_to_read);
+            if plaintext_bytes_to_read >= size_to_read {
+                break;
+            }
+            if size_read == 0 {
+                debug!("EOF while reading chunk");
+                return Err(());
+            }
+        }
+    }
+
+    let mut read_vec = vec![0u8; size_to_read];
+    conn.reader().read_exact(&mut read_vec).map_err(|e| error!("Error reading chunk: {}", e))?;
+    trace!("Chunk read: {} bytes", size_to_read);
+    Ok(read_vec)
+}
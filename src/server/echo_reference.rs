@@ -0,0 +1,114 @@
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::time::Duration;
+use log::{error, info, trace};
+use qkd_camera_common_lib::echo_reference::{self, EchoReferencePacket};
+use rodio::Source;
+
+/// Duration of the played sound sent in each packet
+const ECHO_REFERENCE_BLOCK_DURATION_MS: u32 = 10;
+
+/// Sends the sound played by the speaker to the client running next to the server, which uses it to cancel the echo
+/// picked up by its microphone. The sound is sent when rodio mixes it, slightly ahead of the speaker playing it.
+pub(crate) struct EchoReferenceSender {
+    packet_sender: mpsc::Sender<EchoReferencePacket>,
+}
+
+impl EchoReferenceSender {
+    pub(crate) fn start(echo_reference_address: &str) -> Result<Self, String> {
+        let echo_reference_address = echo_reference::resolve_loopback_address(echo_reference_address)?;
+        let binding_address = if echo_reference_address.is_ipv4() { "127.0.0.1:0" } else { "[::1]:0" };
+        let socket = UdpSocket::bind(binding_address).map_err(|e| format!("cannot create echo reference socket: {}", e))?;
+        socket.connect(echo_reference_address).map_err(|e| format!("cannot send echo reference to {}: {}", echo_reference_address, e))?;
+        info!("Sending played sound to {} for echo cancellation", echo_reference_address);
+
+        let (packet_sender, packet_receiver) = mpsc::channel::<EchoReferencePacket>();
+        std::thread::spawn(move || {
+            for packet in packet_receiver {
+                let serialized_packet = match postcard::to_allocvec(&packet) {
+                    Ok(serialized_packet) => serialized_packet,
+                    Err(e) => {
+                        error!("Error serializing echo reference: {}", e);
+                        return;
+                    }
+                };
+                // Nothing listens when the local client doesn't cancel echo, which isn't an error
+                if let Err(e) = socket.send(&serialized_packet) {
+                    trace!("Echo reference not sent: {}", e);
+                }
+            }
+        });
+        Ok(Self { packet_sender })
+    }
+
+    /// Source playing `source` unchanged, sending its samples as they are played
+    pub(crate) fn tee<S: Source<Item = i16>>(&self, source: S) -> EchoReferenceSource<S> {
+        let block_length = (source.sample_rate() * ECHO_REFERENCE_BLOCK_DURATION_MS / 1000).max(1) as usize * source.channels() as usize;
+        EchoReferenceSource {
+            source,
+            block: Vec::with_capacity(block_length),
+            block_length,
+            packet_sender: self.packet_sender.clone(),
+        }
+    }
+}
+
+pub(crate) struct EchoReferenceSource<S> {
+    source: S,
+    /// Samples played since the last packet
+    block: Vec<i16>,
+    block_length: usize,
+    packet_sender: mpsc::Sender<EchoReferencePacket>,
+}
+
+impl<S: Source<Item = i16>> EchoReferenceSource<S> {
+    fn send_block(&mut self) {
+        if self.block.is_empty() {
+            return;
+        }
+        let packet = EchoReferencePacket {
+            channels: self.source.channels(),
+            sample_rate: self.source.sample_rate(),
+            samples: std::mem::replace(&mut self.block, Vec::with_capacity(self.block_length)),
+        };
+        let _ = self.packet_sender.send(packet);
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for EchoReferenceSource<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        match self.source.next() {
+            Some(sample) => {
+                self.block.push(sample);
+                if self.block.len() >= self.block_length {
+                    self.send_block();
+                }
+                Some(sample)
+            },
+            None => {
+                self.send_block();
+                None
+            }
+        }
+    }
+}
+
+impl<S: Source<Item = i16>> Source for EchoReferenceSource<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
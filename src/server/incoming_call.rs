@@ -0,0 +1,142 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use image::{ImageBuffer, Rgb};
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use show_image::{create_window, ImageInfo, ImageView};
+use show_image::event::{VirtualKeyCode, WindowEvent};
+use qkd_camera_common_lib::sae_identity::CertificateIdentity;
+use crate::terminal_input;
+
+pub(crate) const DEFAULT_INCOMING_CALL_TIMEOUT_SECS: u64 = 30;
+const PROMPT_WINDOW_WIDTH: u32 = 480;
+const PROMPT_WINDOW_HEIGHT: u32 = 120;
+
+/// How the server asks whether an incoming call should be accepted
+#[derive(Debug, Clone, Copy, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IncomingCallPromptMode {
+    #[default]
+    Terminal,
+    Window,
+    AutoAccept,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CallDecision {
+    Accepted,
+    Declined,
+    NotAnswered,
+}
+
+/// What the server knows about the caller once the TLS handshake is complete
+pub(crate) struct CallerIdentity {
+    pub(crate) sae_id: i64,
+    pub(crate) peer_address: Option<SocketAddr>,
+    /// Whether `sae_id` is the one of the caller SAE certificate, rather than only announced by the caller
+    pub(crate) authenticated: bool,
+    /// SAE certificate the caller proved to hold, even if it isn't listed in the access control
+    pub(crate) certificate: Option<CertificateIdentity>,
+}
+
+impl CallerIdentity {
+    pub(crate) fn new(sae_id: i64, authenticated_sae_id: Option<i64>, certificate: Option<CertificateIdentity>, peer_address: Option<SocketAddr>) -> Self {
+        Self {
+            sae_id,
+            peer_address,
+            authenticated: authenticated_sae_id == Some(sae_id),
+            certificate,
+        }
+    }
+
+    fn description(&self) -> String {
+        let peer_address = self.peer_address.map(|address| address.to_string()).unwrap_or_else(|| "unknown address".to_string());
+        let authentication = match (self.authenticated, self.certificate.as_ref()) {
+            (true, Some(certificate)) => format!("authenticated by certificate {}", certificate),
+            (false, Some(certificate)) => format!("not authenticated, unknown certificate {}", certificate),
+            (_, None) => "not authenticated, no SAE certificate".to_string(),
+        };
+        format!("SAE {} ({}), {}", self.sae_id, peer_address, authentication)
+    }
+}
+
+/// Ask the local user whether to accept the call, the call is considered as not answered after `timeout`
+pub(crate) fn prompt_incoming_call(prompt_mode: IncomingCallPromptMode, caller: &CallerIdentity, timeout: Duration) -> CallDecision {
+    info!("Incoming call from {}", caller.description());
+    match prompt_mode {
+        IncomingCallPromptMode::AutoAccept => CallDecision::Accepted,
+        IncomingCallPromptMode::Terminal => prompt_terminal(timeout),
+        IncomingCallPromptMode::Window => prompt_window(caller, timeout),
+    }
+}
+
+fn prompt_terminal(timeout: Duration) -> CallDecision {
+    terminal_input::discard_typed_lines();
+    println!("Accept call? [y/N] (waiting {} s)", timeout.as_secs());
+    match terminal_input::next_line(timeout) {
+        Ok(answer) if answer.trim().eq_ignore_ascii_case("y") => CallDecision::Accepted,
+        Ok(_) => CallDecision::Declined,
+        Err(_) => CallDecision::NotAnswered,
+    }
+}
+
+fn prompt_window(caller: &CallerIdentity, timeout: Duration) -> CallDecision {
+    let title = format!("Incoming call from {} - press Y to accept, N to reject", caller.description());
+    let window = match create_window(title, Default::default()) {
+        Ok(window) => window,
+        Err(e) => {
+            warn!("Error creating incoming call window: {}, falling back to terminal", e);
+            return prompt_terminal(timeout);
+        }
+    };
+    let placeholder_image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_pixel(PROMPT_WINDOW_WIDTH, PROMPT_WINDOW_HEIGHT, Rgb([0, 96, 0]));
+    let image = ImageView::new(ImageInfo::rgb8(PROMPT_WINDOW_WIDTH, PROMPT_WINDOW_HEIGHT), placeholder_image.as_raw());
+    let _ = window.set_image("incoming-call", image);
+
+    let mut decision = CallDecision::NotAnswered;
+    if let Ok(event_receiver) = window.event_channel() {
+        let prompt_start = std::time::Instant::now();
+        while let Some(remaining_time) = timeout.checked_sub(prompt_start.elapsed()) {
+            let event = match event_receiver.recv_timeout(remaining_time) {
+                Ok(event) => event,
+                Err(_) => break,
+            };
+            if let WindowEvent::KeyboardInput(event) = event {
+                if !event.input.state.is_pressed() {
+                    continue;
+                }
+                match event.input.key_code {
+                    Some(VirtualKeyCode::Y) => decision = CallDecision::Accepted,
+                    Some(VirtualKeyCode::N) | Some(VirtualKeyCode::Escape) => decision = CallDecision::Declined,
+                    _ => continue,
+                }
+                break;
+            }
+        }
+    }
+    let _ = window.run_function_wait(|window_handle| {
+        window_handle.destroy();
+    });
+    decision
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificate() -> CertificateIdentity {
+        CertificateIdentity {
+            subject: "CN=sae1".to_string(),
+            sha256_fingerprint: "abcdef".to_string(),
+        }
+    }
+
+    #[test]
+    fn description_shows_the_caller_certificate() {
+        let peer_address = Some(SocketAddr::from(([192, 168, 0, 2], 50000)));
+        assert_eq!(CallerIdentity::new(1, Some(1), Some(certificate()), peer_address).description(), "SAE 1 (192.168.0.2:50000), authenticated by certificate CN=sae1 (SHA-256 abcdef)");
+        assert_eq!(CallerIdentity::new(1, None, Some(certificate()), None).description(), "SAE 1 (unknown address), not authenticated, unknown certificate CN=sae1 (SHA-256 abcdef)");
+        assert_eq!(CallerIdentity::new(1, None, None, None).description(), "SAE 1 (unknown address), not authenticated, no SAE certificate");
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashSet;
+use std::net::ToSocketAddrs;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use qkd_camera_common_lib::audio_device::AudioDeviceSelector;
+use qkd_camera_common_lib::config_validation::{ConfigValidator, ValidateConfig};
+use qkd_camera_common_lib::echo_reference;
+use qkd_camera_common_lib::sae_identity;
+use qkd_camera_common_lib::secret_source::SecretSource;
+use qkd_camera_common_lib::security_policy::SecurityPolicy;
+use crate::audio_output;
+use crate::incoming_call::{DEFAULT_INCOMING_CALL_TIMEOUT_SECS, IncomingCallPromptMode};
+use crate::relay::MAX_PARTICIPANTS_RANGE;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonServerConfig {
+    pub(crate) kme_address: String,
+    pub(crate) kme_authentication_certificate_path: String,
+    pub(crate) kme_authentication_certificate_password: SecretSource,
+    pub(crate) binding_address: String,
+    pub(crate) danger_accept_invalid_kme_cert: bool,
+    /// Write TLS secrets to $SSLKEYLOGFILE, for debugging only
+    #[serde(default)]
+    pub(crate) danger_enable_key_log: bool,
+    #[serde(default)]
+    pub(crate) security_policy: SecurityPolicy,
+    /// DER certificate and PKCS#8 private key presented by the server, a throwaway certificate is generated if missing
+    pub(crate) fallback_certificate_path: Option<String>,
+    pub(crate) fallback_private_key_path: Option<String>,
+    pub(crate) access_control: Option<JsonAccessControlConfig>,
+    #[serde(default)]
+    pub(crate) incoming_call_prompt: IncomingCallPromptMode,
+    pub(crate) override_default_incoming_call_timeout_secs: Option<u64>,
+    /// Speaker, by index or name as printed by `list-audio-devices`, the default one if missing
+    pub(crate) audio_output_device: Option<AudioDeviceSelector>,
+    /// Where to send the played sound, for the client running on the same machine to cancel its echo
+    pub(crate) echo_reference_address: Option<String>,
+    /// File the chat messages are appended to, not recorded if missing
+    pub(crate) chat_transcript_path: Option<String>,
+    /// Directory the files sent by the client are written to, they are refused if missing
+    pub(crate) download_directory: Option<String>,
+    /// Run as a conference relay, forwarding the media of each client to the others, instead of taking one call at a time
+    pub(crate) relay: Option<JsonRelayConfig>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonRelayConfig {
+    pub(crate) override_default_max_participants: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonAccessControlConfig {
+    /// If set, only these SAE IDs are allowed to call
+    pub(crate) allowed_sae_ids: Option<Vec<i64>>,
+    #[serde(default)]
+    pub(crate) blocked_sae_ids: Vec<i64>,
+    /// Limits applied to SAEs without a specific entry in `per_sae_limits`
+    pub(crate) default_limits: Option<JsonSaeLimitsConfig>,
+    #[serde(default)]
+    pub(crate) per_sae_limits: Vec<JsonPerSaeLimitsConfig>,
+    /// SAE certificates authenticating the callers, a caller without a known certificate has no trusted SAE ID
+    #[serde(default)]
+    pub(crate) sae_certificates: Vec<JsonSaeCertificateConfig>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonSaeCertificateConfig {
+    pub(crate) sae_id: i64,
+    /// SHA-256 fingerprint of the DER certificate, hexadecimal with or without colons
+    pub(crate) sha256_fingerprint: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonSaeLimitsConfig {
+    pub(crate) max_width: Option<u32>,
+    pub(crate) max_height: Option<u32>,
+    pub(crate) max_bitrate_kbps: Option<u32>,
+    pub(crate) max_session_duration_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonPerSaeLimitsConfig {
+    pub(crate) sae_id: i64,
+    pub(crate) max_width: Option<u32>,
+    pub(crate) max_height: Option<u32>,
+    pub(crate) max_bitrate_kbps: Option<u32>,
+    pub(crate) max_session_duration_secs: Option<u64>,
+}
+
+impl JsonPerSaeLimitsConfig {
+    pub(crate) fn limits(&self) -> JsonSaeLimitsConfig {
+        JsonSaeLimitsConfig {
+            max_width: self.max_width,
+            max_height: self.max_height,
+            max_bitrate_kbps: self.max_bitrate_kbps,
+            max_session_duration_secs: self.max_session_duration_secs,
+        }
+    }
+}
+
+impl ValidateConfig for JsonServerConfig {
+    fn validate(&self, validator: &mut ConfigValidator) {
+        validator.check(!self.kme_address.is_empty(), "$.kme_address", "must not be empty");
+        validator.check_file_exists("$.kme_authentication_certificate_path", &self.kme_authentication_certificate_path);
+        if let Err(e) = self.kme_authentication_certificate_password.resolve() {
+            validator.problem("$.kme_authentication_certificate_password", e);
+        }
+        validator.check(self.binding_address.to_socket_addrs().is_ok(), "$.binding_address", format!("{} is not a valid address", self.binding_address));
+        match (self.fallback_certificate_path.as_ref(), self.fallback_private_key_path.as_ref()) {
+            (Some(fallback_certificate_path), Some(fallback_private_key_path)) => {
+                validator.check_file_exists("$.fallback_certificate_path", fallback_certificate_path);
+                validator.check_file_exists("$.fallback_private_key_path", fallback_private_key_path);
+            },
+            (None, None) => {},
+            (Some(_), None) => validator.problem("$.fallback_private_key_path", "must be set along with fallback_certificate_path"),
+            (None, Some(_)) => validator.problem("$.fallback_certificate_path", "must be set along with fallback_private_key_path"),
+        }
+        if let Some(access_control) = self.access_control.as_ref() {
+            if let Some(allowed_sae_ids) = access_control.allowed_sae_ids.as_ref() {
+                for (blocked_sae_id_index, blocked_sae_id) in access_control.blocked_sae_ids.iter().enumerate() {
+                    validator.check(!allowed_sae_ids.contains(blocked_sae_id), &format!("$.access_control.blocked_sae_ids[{}]", blocked_sae_id_index), format!("SAE {} is also in allowed_sae_ids", blocked_sae_id));
+                }
+            }
+            if let Some(default_limits) = access_control.default_limits.as_ref() {
+                default_limits.validate("$.access_control.default_limits", validator);
+            }
+            let mut limited_sae_ids = HashSet::new();
+            for (per_sae_limits_index, per_sae_limits) in access_control.per_sae_limits.iter().enumerate() {
+                let json_path = format!("$.access_control.per_sae_limits[{}]", per_sae_limits_index);
+                validator.check(limited_sae_ids.insert(per_sae_limits.sae_id), &format!("{}.sae_id", json_path), format!("duplicate limits for SAE {}", per_sae_limits.sae_id));
+                per_sae_limits.limits().validate(&json_path, validator);
+            }
+            let mut sae_certificate_fingerprints = HashSet::new();
+            for (sae_certificate_index, sae_certificate) in access_control.sae_certificates.iter().enumerate() {
+                let json_path = format!("$.access_control.sae_certificates[{}].sha256_fingerprint", sae_certificate_index);
+                let sha256_fingerprint = sae_identity::normalize_fingerprint(&sae_certificate.sha256_fingerprint);
+                validator.check(sha256_fingerprint.len() == 64 && sha256_fingerprint.chars().all(|character| character.is_ascii_hexdigit()), &json_path, "must be 32 hexadecimal bytes");
+                validator.check(sae_certificate_fingerprints.insert(sha256_fingerprint), &json_path, format!("certificate of SAE {} already given", sae_certificate.sae_id));
+            }
+        }
+        validator.check_range("$.override_default_incoming_call_timeout_secs", self.override_default_incoming_call_timeout_secs, 1, 3600);
+        if let Some(audio_output_device) = self.audio_output_device.as_ref() {
+            if let Err(e) = audio_output::speaker_names().and_then(|speaker_names| audio_output_device.find(&speaker_names)) {
+                validator.problem("$.audio_output_device", e);
+            }
+        }
+        if let Some(echo_reference_address) = self.echo_reference_address.as_ref() {
+            if let Err(e) = echo_reference::resolve_loopback_address(echo_reference_address) {
+                validator.problem("$.echo_reference_address", e);
+            }
+        }
+        if let Some(relay) = self.relay.as_ref() {
+            validator.check_range("$.relay.override_default_max_participants", relay.override_default_max_participants, *MAX_PARTICIPANTS_RANGE.start(), *MAX_PARTICIPANTS_RANGE.end());
+            validator.check(self.echo_reference_address.is_none(), "$.echo_reference_address", "not supported by the relay");
+        }
+    }
+}
+
+impl JsonSaeLimitsConfig {
+    fn validate(&self, json_path: &str, validator: &mut ConfigValidator) {
+        validator.check_range(&format!("{}.max_width", json_path), self.max_width, 1, 7680);
+        validator.check_range(&format!("{}.max_height", json_path), self.max_height, 1, 4320);
+        validator.check_range(&format!("{}.max_bitrate_kbps", json_path), self.max_bitrate_kbps, 1, u32::MAX);
+        validator.check_range(&format!("{}.max_session_duration_secs", json_path), self.max_session_duration_secs, 1, u64::MAX);
+    }
+}
+
+/// Configuration template written by the `gen-config` subcommand
+pub(crate) fn config_template() -> serde_json::Value {
+    serde_json::json!({
+        "kme_address": "localhost:14000",
+        "kme_authentication_certificate_path": "data/sae3.pfx",
+        "kme_authentication_certificate_password": "",
+        "binding_address": "0.0.0.0:14443",
+        "danger_accept_invalid_kme_cert": false,
+        "security_policy": "qkd_required",
+        "access_control": {
+            "allowed_sae_ids": [1],
+            "blocked_sae_ids": [],
+            "default_limits": {
+                "max_width": 1280,
+                "max_height": 720
+            },
+            "per_sae_limits": [],
+            "sae_certificates": []
+        },
+        "incoming_call_prompt": "terminal",
+        "override_default_incoming_call_timeout_secs": DEFAULT_INCOMING_CALL_TIMEOUT_SECS
+    })
+}
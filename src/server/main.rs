@@ -1,193 +1,500 @@
-use std::io::{Read, Write};
+mod json_server_config;
+mod access_control;
+mod incoming_call;
+mod cli;
+mod av_sync;
+mod audio_output;
+mod echo_reference;
+mod peer_exchange;
+mod terminal_input;
+mod client_stream;
+mod relay;
+mod tls_acceptor;
+
+use std::collections::HashSet;
 use std::net::TcpStream;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use clap::Parser;
+use log::{error, info, trace, warn};
 use image::{ImageBuffer, Rgb};
 use rodio::Sink;
-use rustls::server::Acceptor;
-use rustls::{ServerConfig, ServerConnection};
+use rustls::ServerConfig;
 use rustls::qkd_config::{QkdInitialServerConfig};
-use rustls::server::qkd::QkdServerConfig;
 use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-use serde::Deserialize;
-use show_image::{create_window, ImageInfo, ImageView};
-use qkd_camera_common_lib::PACKET_CHUNK_SIZE;
+use show_image::create_window;
+use show_image::event::{ElementState, VirtualKeyCode, WindowEvent};
+use qkd_camera_common_lib::{config_loader, key_log, kme_diagnostics, logging};
+use qkd_camera_common_lib::audio_device::AudioDeviceSelector;
+use qkd_camera_common_lib::config_loader::ConfigArgs;
+use qkd_camera_common_lib::resampler::Resampler;
+use qkd_camera_common_lib::sae_identity::{CertificateIdentity, SAE_IDENTITY_BINDING_LENGTH, SAE_IDENTITY_EXPORTER_LABEL};
+use qkd_camera_common_lib::security_policy::{self, SecurityPolicy};
+use qkd_camera_common_lib::session_security::SessionSecurityInfo;
+use qkd_camera_common_lib::tls_connection::{TlsConnection, TlsStream};
+use qkd_camera_common_lib::{ClientMessage, RejectionReason, ServerMessage, SessionLimits, SessionRequest, SessionResponse};
+use crate::access_control::SessionLimitsEnforcer;
+use crate::av_sync::{DisplayUpdate, PlaybackClock};
+use crate::echo_reference::EchoReferenceSender;
+use crate::cli::Command;
+use crate::incoming_call::{CallDecision, CallerIdentity};
+use crate::json_server_config::JsonServerConfig;
+use crate::peer_exchange::PeerExchange;
+use crate::relay::Relay;
+use crate::tls_acceptor::ServerTlsConfig;
 
 const MAX_ACCEPTABLE_IMAGE_SIZE: usize = 10_000_000;
+/// Largest client message announced, leaving room for the sound and the other fields of a media packet
+const MAX_CLIENT_MESSAGE_SIZE: usize = MAX_ACCEPTABLE_IMAGE_SIZE + 1024 * 1024;
+/// Longest a caller may take to complete the TLS handshake and send its session request, calls being accepted one at a time
+const SESSION_SETUP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// Prefix of the environment variables overriding configuration fields
+const CONFIG_ENV_PREFIX: &str = "QKD_SERVER_";
 
-#[derive(Debug, Deserialize)]
-struct JsonServerConfig {
-    kme_address: String,
-    kme_authentication_certificate_path: String,
-    kme_authentication_certificate_password: String,
-    binding_address: String,
-    danger_accept_invalid_kme_cert: bool,
+#[show_image::main]
+fn main() {
+    let cli = cli::Cli::parse();
+    if let Err(e) = logging::init_logger(&cli.logging_args) {
+        eprintln!("Error initializing logger: {}", e);
+        std::process::exit(1);
+    }
+
+    match cli.command {
+        Command::Run { config_args, dry_run } => {
+            let json_server_config = load_config_or_exit(&config_args);
+            if dry_run {
+                println!("Configuration {} is valid:\n{:#?}", config_args.config.display(), json_server_config);
+                return;
+            }
+            run(json_server_config);
+        },
+        Command::Diagnose { config_args, remote_sae_id } => {
+            let json_server_config = load_config_or_exit(&config_args);
+            std::process::exit(if diagnose(&json_server_config, remote_sae_id) { 0 } else { 1 });
+        },
+        Command::ListDevices | Command::ListAudioDevices => list_audio_devices(),
+        Command::GenConfig { output } => {
+            let config_template = serde_json::to_string_pretty(&json_server_config::config_template()).unwrap();
+            write_output_or_exit(output, &config_template);
+        },
+        Command::Schema { output } => {
+            write_output_or_exit(output, &config_loader::json_schema::<JsonServerConfig>());
+        }
+    }
 }
 
+fn load_config_or_exit(config_args: &ConfigArgs) -> JsonServerConfig {
+    match config_args.load(CONFIG_ENV_PREFIX) {
+        Ok(json_server_config) => json_server_config,
+        Err(e) => {
+            error!("Invalid configuration {}:\n{}", config_args.config.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
 
-#[show_image::main]
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <server_config.json>", args[0]);
+fn write_output_or_exit(output: Option<PathBuf>, content: &str) {
+    if let Err(e) = config_loader::write_output(output, content) {
+        error!("Error writing output: {}", e);
         std::process::exit(1);
     }
+}
 
-    let json_server_config_str = std::fs::read_to_string(&args[1]).unwrap();
-    let json_server_config: JsonServerConfig = serde_json::from_str(&json_server_config_str).unwrap();
+/// Print the available audio playback devices, with the index used to select them in the configuration
+fn list_audio_devices() {
+    println!("Speakers:");
+    match audio_output::speaker_names() {
+        Ok(speaker_names) => {
+            for (speaker_index, speaker_name) in speaker_names.iter().enumerate() {
+                println!("  {}: {}", speaker_index, speaker_name);
+            }
+        },
+        Err(e) => error!("Error listing speakers: {}", e),
+    }
+}
 
-    let server_config = TestPki::new().server_config(&json_server_config);
+fn run(json_server_config: JsonServerConfig) {
+    let test_pki = match (json_server_config.fallback_certificate_path.as_ref(), json_server_config.fallback_private_key_path.as_ref()) {
+        (Some(certificate_path), Some(private_key_path)) => match TestPki::from_der_files(certificate_path, private_key_path) {
+            Ok(test_pki) => test_pki,
+            Err(e) => {
+                error!("Error loading server certificate: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => TestPki::new(),
+    };
+    let server_config = match test_pki.server_config(&json_server_config) {
+        Ok(server_config) => server_config,
+        Err(e) => {
+            error!("Error creating TLS configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    info!("Server key exchange: {} (security policy {:?})", server_config, json_server_config.security_policy);
+
+    let echo_reference_sender = match json_server_config.echo_reference_address.as_deref().map(EchoReferenceSender::start).transpose() {
+        Ok(echo_reference_sender) => echo_reference_sender,
+        Err(e) => {
+            error!("Error starting echo reference: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let listener = std::net::TcpListener::bind(json_server_config.binding_address).unwrap();
-    for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
-        let mut acceptor = Acceptor::default();
+    let (relay, _relay_output_stream) = match json_server_config.relay.as_ref() {
+        Some(relay_config) => match Relay::start(json_server_config.audio_output_device.as_ref(), relay_config.override_default_max_participants.unwrap_or(relay::DEFAULT_MAX_PARTICIPANTS)) {
+            Ok((relay, relay_output_stream)) => (Some(relay), Some(relay_output_stream)),
+            Err(e) => {
+                error!("Error starting relay: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => (None, None),
+    };
 
-        let accepted = loop {
-            acceptor.read_tls(&mut stream).unwrap();
-            if let Some(accepted) = acceptor.accept().unwrap() {
-                break accepted;
+    let listener = std::net::TcpListener::bind(&json_server_config.binding_address).unwrap();
+    for stream in listener.incoming() {
+        // A failing caller must not stop the server, all the more in relay mode where other participants are connected
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_read_timeout(Some(SESSION_SETUP_TIMEOUT)) {
+            error!("Error setting session setup timeout: {}", e);
+            continue;
+        }
+        let mut conn = match tls_acceptor::accept_connection(&mut stream, &server_config) {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Error establishing TLS connection: {}", e);
+                continue;
             }
         };
 
-        let conn = accepted.into_qkd_connection(server_config.clone()).unwrap();
-        let mut conn = conn.complete_qkd_ack(&mut stream.try_clone().unwrap(), &mut stream.try_clone().unwrap());
-        //let mut conn = accepted.into_connection(server_config.clone()).unwrap();
-        conn.complete_io(&mut stream).unwrap();
+        let (session_limits, session_security_info) = match accept_session(&mut conn, &mut stream, &json_server_config, relay.as_deref()) {
+            Some(session_limits_and_security_info) => session_limits_and_security_info,
+            None => continue,
+        };
 
-        manage_stream(conn, stream);
+        let peer_exchange = PeerExchange::start(json_server_config.chat_transcript_path.as_deref(), json_server_config.download_directory.as_deref(), &session_security_info.summary(), relay.is_none());
+        match relay.as_ref() {
+            // The relay keeps accepting calls while the participants are connected
+            Some(relay) => match relay.join(format!("SAE {}", session_security_info.origin_sae_id)) {
+                Ok(participant) => {
+                    std::thread::spawn(move || relay::manage_participant(conn, stream, session_limits, session_security_info, participant, peer_exchange));
+                },
+                Err(e) => error!("Error joining conference: {}", e),
+            },
+            None => manage_stream(conn, stream, session_limits, session_security_info, json_server_config.audio_output_device.as_ref(), echo_reference_sender.as_ref(), peer_exchange),
+        }
     }
 }
 
-fn manage_stream(mut conn: ServerConnection, mut stream: TcpStream) {
+/// Check the KME with an authenticated key status request for the remote SAE, and that the binding address is available
+fn diagnose(json_server_config: &JsonServerConfig, remote_sae_id: Option<i64>) -> bool {
+    let kme_ok = match remote_sae_id.or_else(|| configured_remote_sae_id(json_server_config)) {
+        Some(remote_sae_id) => {
+            let kme_status = json_server_config.kme_authentication_certificate_password.resolve().and_then(|kme_authentication_certificate_password| {
+                kme_diagnostics::query_kme_status(
+                    json_server_config.kme_address.as_str(),
+                    json_server_config.kme_authentication_certificate_path.as_str(),
+                    kme_authentication_certificate_password.as_str(),
+                    remote_sae_id,
+                    json_server_config.danger_accept_invalid_kme_cert
+                )
+            });
+            kme_diagnostics::report_diagnostic(&format!("KME {} key status for SAE {}", json_server_config.kme_address, remote_sae_id), kme_status)
+        },
+        None => {
+            let no_remote_sae: Result<String, String> = Err("no remote SAE, pass --remote-sae-id or list the callers in access_control".to_string());
+            kme_diagnostics::report_diagnostic(&format!("KME {} key status", json_server_config.kme_address), no_remote_sae)
+        }
+    };
 
-    let window = create_window("image", Default::default()).unwrap();
-    let (_stream, audio_output_stream_handle) = rodio::OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&audio_output_stream_handle).unwrap();
+    let binding = std::net::TcpListener::bind(&json_server_config.binding_address)
+        .map(|_| String::new())
+        .map_err(|e| e.to_string());
+    let binding_ok = kme_diagnostics::report_diagnostic(&format!("Binding address {} availability", json_server_config.binding_address), binding);
 
-    loop {
-        const USIZE_SIZE: usize = std::mem::size_of::<usize>();
-        const PACKET_ANNOUNCE_SIZE: usize = USIZE_SIZE * 3; // packet size + nb chunks
+    kme_ok && binding_ok
+}
 
-        let mut packet_size_and_nb_chunks_buf = [0u8; PACKET_ANNOUNCE_SIZE];
-        let received_plaintext_size = match conn.read_tls(&mut stream) {
-            Ok(size) => size,
-            Err(e) => {
-                eprintln!("Error reading TLS: {}", e);
-                break;
-            }
-        };
-        let packet_process_result = conn.process_new_packets().unwrap();
+/// First caller SAE of the access control, whose keys the KME can be asked about
+fn configured_remote_sae_id(json_server_config: &JsonServerConfig) -> Option<i64> {
+    let access_control = json_server_config.access_control.as_ref()?;
+    access_control.allowed_sae_ids.iter().flatten().copied()
+        .chain(access_control.sae_certificates.iter().map(|sae_certificate| sae_certificate.sae_id))
+        .chain(access_control.per_sae_limits.iter().map(|per_sae_limits| per_sae_limits.sae_id))
+        .next()
+}
 
-        if packet_process_result.plaintext_bytes_to_read() < PACKET_ANNOUNCE_SIZE {
-            println!("Client disconnected");
-            break;
+/// Read the client's session request, check it against the access control list and ask the local user to answer the call.
+/// Rejected callers receive the reason before the connection is closed, a full conference being rejected without asking.
+fn accept_session(conn: &mut TlsConnection, stream: &mut TcpStream, json_server_config: &JsonServerConfig, relay: Option<&Relay>) -> Option<(SessionLimits, SessionSecurityInfo)> {
+    let peer_address = stream.peer_addr().ok();
+    let mut tls = TlsStream::new(conn, stream);
+    let session_request: SessionRequest = match qkd_camera_common_lib::read_message(&mut tls) {
+        Ok(session_request) => session_request,
+        Err(e) => {
+            error!("Error reading session request: {}", e);
+            return None;
+        }
+    };
+    // The media are then read without timeout, the caller only sending them once the call is answered
+    if let Err(e) = tls.sock.set_read_timeout(None) {
+        error!("Error removing session setup timeout: {}", e);
+        return None;
+    }
+    let caller_certificate = verify_sae_identity_proof(tls.conn, &session_request);
+    let caller_sae_id = caller_certificate.as_ref().ok().and_then(Option::as_ref).and_then(|caller_certificate| access_control::certificate_sae_id(json_server_config.access_control.as_ref(), caller_certificate));
+    let security_mode = tls.conn.security_mode();
+    info!("Session key exchange with SAE {}: {}", session_request.origin_sae_id, security_mode);
+    let security_check = if json_server_config.security_policy.allows(security_mode) {
+        Ok(())
+    } else {
+        Err(RejectionReason::SecurityPolicyNotMet)
+    };
+
+    let identity_check = match (&caller_certificate, caller_sae_id) {
+        (Err(e), _) => {
+            warn!("Invalid SAE certificate proof from SAE {}: {}", session_request.origin_sae_id, e);
+            Err(RejectionReason::SaeIdMismatch)
+        },
+        (Ok(_), Some(caller_sae_id)) if caller_sae_id != session_request.origin_sae_id => {
+            warn!("SAE {} announced itself as SAE {}", caller_sae_id, session_request.origin_sae_id);
+            Err(RejectionReason::SaeIdMismatch)
+        },
+        _ => Ok(()),
+    };
+
+    let conference_check = match relay {
+        Some(relay) if relay.is_full() => Err(RejectionReason::ConferenceFull),
+        _ => Ok(()),
+    };
+
+    let session_check = security_check.and(identity_check).and(conference_check).and_then(|_| access_control::check_caller(json_server_config.access_control.as_ref(), caller_sae_id)).and_then(|session_limits| {
+        let caller = CallerIdentity::new(session_request.origin_sae_id, caller_sae_id, caller_certificate.clone().ok().flatten(), peer_address);
+        let incoming_call_timeout = std::time::Duration::from_secs(json_server_config.override_default_incoming_call_timeout_secs.unwrap_or(incoming_call::DEFAULT_INCOMING_CALL_TIMEOUT_SECS));
+        match incoming_call::prompt_incoming_call(json_server_config.incoming_call_prompt, &caller, incoming_call_timeout) {
+            CallDecision::Accepted => Ok(session_limits),
+            CallDecision::Declined => Err(RejectionReason::DeclinedByCallee),
+            CallDecision::NotAnswered => Err(RejectionReason::CallNotAnswered),
         }
+    });
+    match session_check {
+        Ok(session_limits) => {
+            info!("Accepting call from SAE {}", session_request.origin_sae_id);
+            if let Err(e) = qkd_camera_common_lib::write_message(&mut tls, &SessionResponse::Accepted(session_limits.clone())) {
+                error!("Error sending session response: {}", e);
+                return None;
+            }
+            let session_security_info = SessionSecurityInfo::new(tls.conn, &json_server_config.kme_address, session_request.origin_sae_id, session_request.target_sae_id);
+            info!("{}", session_security_info.log_line("session_start"));
+            Some((session_limits, session_security_info))
+        },
+        Err(rejection_reason) => {
+            warn!("Rejecting call from SAE {}: {}", session_request.origin_sae_id, rejection_reason);
+            let _ = qkd_camera_common_lib::write_message(&mut tls, &SessionResponse::Rejected(rejection_reason));
+            tls.conn.send_close_notify();
+            let _ = tls.conn.complete_io(tls.sock);
+            None
+        }
+    }
+}
 
-        let mut read_vec = vec![0u8; received_plaintext_size];
-        conn.reader().read(&mut read_vec).unwrap();
+/// Check the signature of this TLS connection with the caller SAE certificate, callers without a certificate have none
+fn verify_sae_identity_proof(conn: &TlsConnection, session_request: &SessionRequest) -> Result<Option<CertificateIdentity>, String> {
+    let Some(sae_identity_proof) = session_request.sae_identity_proof.as_ref() else {
+        return Ok(None);
+    };
+    let binding = conn.export_keying_material::<SAE_IDENTITY_BINDING_LENGTH>(SAE_IDENTITY_EXPORTER_LABEL)
+        .map_err(|e| format!("cannot export TLS keying material: {}", e))?;
+    sae_identity_proof.verify(&binding).map(Some)
+}
 
-        packet_size_and_nb_chunks_buf.clone_from_slice(&read_vec[..PACKET_ANNOUNCE_SIZE]);
+fn manage_stream(mut conn: TlsConnection, mut stream: TcpStream, session_limits: SessionLimits, mut session_security_info: SessionSecurityInfo, audio_output_device: Option<&AudioDeviceSelector>, echo_reference_sender: Option<&EchoReferenceSender>, mut peer_exchange: PeerExchange) {
+    let mut session_limits_enforcer = SessionLimitsEnforcer::new(session_limits);
+
+    let window = create_window(session_security_info.summary(), Default::default()).unwrap();
+    let (_stream, audio_output_stream_handle, output_sample_rate) = match audio_output::open_speaker(audio_output_device) {
+        Ok(output_stream) => output_stream,
+        Err(e) => {
+            error!("Error opening speaker: {}, disconnecting client...", e);
+            conn.send_close_notify();
+            let _ = conn.write_tls(&mut stream);
+            return;
+        }
+    };
+    let sink = Arc::new(Sink::try_new(&audio_output_stream_handle).unwrap());
+    let playback_clock = PlaybackClock::new();
+    let mut resampler: Option<Resampler> = None;
+    let (display_sender, display_receiver) = mpsc::channel();
+    let display_window = window.clone();
+    let display_playback_clock = playback_clock.clone();
+    let display_thread = std::thread::spawn(move || av_sync::display_images(display_window, display_receiver, display_playback_clock));
+    match window.event_channel() {
+        Ok(event_receiver) => {
+            let shortcut_sink = sink.clone();
+            std::thread::spawn(move || handle_window_shortcuts(event_receiver, shortcut_sink));
+        },
+        Err(e) => warn!("Keyboard shortcuts unavailable: {}", e),
+    }
 
-        let packet_size = usize::from_be_bytes(packet_size_and_nb_chunks_buf[..USIZE_SIZE].try_into().unwrap());
-        let nb_chunks = usize::from_be_bytes(packet_size_and_nb_chunks_buf[USIZE_SIZE..(USIZE_SIZE * 2)].try_into().unwrap());
-        let control_bytes = usize::from_be_bytes(packet_size_and_nb_chunks_buf[(USIZE_SIZE * 2)..PACKET_ANNOUNCE_SIZE].try_into().unwrap());
-        if control_bytes != usize::MAX {
-            eprintln!("Invalid Control bytes not MAX: {}, disconnecting client...", control_bytes);
+    loop {
+        let (client_message, packet_size) = match client_stream::read_client_message(&mut conn, &mut stream) {
+            Ok(Some(client_message_and_size)) => client_message_and_size,
+            Ok(None) => continue,
+            Err(()) => break,
+        };
+        match session_security_info.rekey_if_due(&mut conn) {
+            Ok(true) => info!("{}", session_security_info.log_line("rekey")),
+            Ok(false) => {},
+            Err(e) => warn!("Error refreshing the session keys: {}", e),
+        }
+        // Every message counts towards the session duration and bitrate, not only the media
+        if let Err(rejection_reason) = session_limits_enforcer.check_packet(packet_size) {
+            warn!("Ending session: {}", rejection_reason);
+            client_stream::reject_client(&mut conn, &mut stream, peer_exchange.outgoing_messages(), rejection_reason);
             break;
         }
-        //println!("Expecting {} bytes: {} chunks", packet_size, nb_chunks);
-
-        let mut read_vec = Vec::with_capacity(packet_size);
-        let mut packet_size_remaining = packet_size;
-        for _ in 0..nb_chunks {
-            let expected_chunk_size = std::cmp::min(packet_size_remaining, PACKET_CHUNK_SIZE);
-            let mut chunk_vec = match read_stream_data(&mut conn, &mut stream, expected_chunk_size) {
-                Ok(vec) => vec,
-                Err(_) => {
-                    println!("Client disconnected");
+        let mut video_audio_packet = match client_message {
+            ClientMessage::Media(video_audio_packet) => video_audio_packet,
+            ClientMessage::MediaState(media_state) => {
+                info!("Remote participant changed its media: {}", media_state);
+                if display_sender.send(DisplayUpdate::MediaState(media_state)).is_err() {
+                    error!("Image display stopped, disconnecting client...");
+                    break;
+                }
+                if client_stream::write_server_messages(&mut conn, &mut stream, peer_exchange.outgoing_messages(), ServerMessage::Ack).is_err() {
+                    error!("Error writing TLS ACK, disconnecting client...");
                     break;
                 }
-            };
-            packet_size_remaining -= expected_chunk_size;
-            read_vec.append(&mut chunk_vec);
-        }
-
-        let video_audio_packet: qkd_camera_common_lib::VideoAudioPacket = match postcard::from_bytes(&read_vec) {
-            Ok(packet) => packet,
-            Err(e) => {
-                eprintln!("Error deserializing packet: {}", e);
                 continue;
-            }
+            },
+            ClientMessage::Chat(chat_message) => {
+                peer_exchange.handle_chat(chat_message);
+                if client_stream::write_server_messages(&mut conn, &mut stream, peer_exchange.outgoing_messages(), ServerMessage::Ack).is_err() {
+                    error!("Error writing TLS ACK, disconnecting client...");
+                    break;
+                }
+                continue;
+            },
+            ClientMessage::FileTransfer(file_transfer_message) => {
+                peer_exchange.handle_file_transfer(file_transfer_message);
+                if client_stream::write_server_messages(&mut conn, &mut stream, peer_exchange.outgoing_messages(), ServerMessage::Ack).is_err() {
+                    error!("Error writing TLS ACK, disconnecting client...");
+                    break;
+                }
+                continue;
+            },
         };
 
-        let audio_buffer = rodio::buffer::SamplesBuffer::new(1, video_audio_packet.sound_sample_rate, video_audio_packet.sound_frame);
-        sink.append(audio_buffer);
-
-        conn.writer().write(b"ACK").unwrap();
-        conn.writer().flush().unwrap();
-        if conn.write_tls(&mut stream).is_err() {
-            eprintln!("Error writing TLS ACK, disconnecting client...");
+        if let Err(e) = video_audio_packet.check_audio_format() {
+            error!("Invalid audio format: {}, disconnecting client...", e);
             break;
         }
-        //println!("{:?}", conn.complete_io(&mut stream));
+        let sound_channels = video_audio_packet.sound_channels;
+        let sound_sample_rate = video_audio_packet.sound_sample_rate;
+        if !resampler.as_ref().is_some_and(|resampler| resampler.accepts(sound_channels, sound_sample_rate)) {
+            info!("Playing {} channels at {} Hz on a {} Hz speaker", sound_channels, sound_sample_rate, output_sample_rate);
+            resampler = Some(Resampler::new(sound_channels, sound_sample_rate, output_sample_rate));
+        }
+        // The client sends no sound while muted
+        if !video_audio_packet.sound_frame.is_empty() {
+            let sound_frame = resampler.as_mut().unwrap().resample(std::mem::take(&mut video_audio_packet.sound_frame));
+            let audio_buffer = rodio::buffer::SamplesBuffer::new(sound_channels, output_sample_rate, sound_frame);
+            sink.append(playback_clock.marker(video_audio_packet.sound_capture_timestamp_us));
+            match echo_reference_sender {
+                Some(echo_reference_sender) => sink.append(echo_reference_sender.tee(audio_buffer)),
+                None => sink.append(audio_buffer),
+            }
+        }
 
         let compressed_image_data = video_audio_packet.compressed_image.as_slice();
-        let image_header = match turbojpeg::read_header(compressed_image_data) {
+        let image_header = turbojpeg::read_header(compressed_image_data);
+
+        let resolution_check = match image_header.as_ref() {
+            Ok(header) => session_limits_enforcer.check_resolution(header.width, header.height),
+            Err(_) => Ok(()),
+        };
+        if let Err(rejection_reason) = resolution_check {
+            warn!("Ending session: {}", rejection_reason);
+            client_stream::reject_client(&mut conn, &mut stream, peer_exchange.outgoing_messages(), rejection_reason);
+            break;
+        }
+        if client_stream::write_server_messages(&mut conn, &mut stream, peer_exchange.outgoing_messages(), ServerMessage::Ack).is_err() {
+            error!("Error writing TLS ACK, disconnecting client...");
+            break;
+        }
+
+        // The client sends no image when no new frame was captured since the previous packet
+        if compressed_image_data.is_empty() {
+            continue;
+        }
+        trace!("Video capture offset relative to audio: {} us", video_audio_packet.capture_offset_us());
+        let image_header = match image_header {
             Ok(header) => header,
             Err(e) => {
-                eprintln!("Error reading image header: {}", e);
+                error!("Error reading image header: {}", e);
                 continue;
             }
         };
         let image_allocated_space = image_header.width * image_header.height * image_header.colorspace as usize;
 
         if image_allocated_space > MAX_ACCEPTABLE_IMAGE_SIZE {
-            eprintln!("Image too big: {} bytes", image_allocated_space);
+            error!("Image too big: {} bytes", image_allocated_space);
             continue;
         }
 
         let decompressed_image: ImageBuffer<Rgb<u8>, Vec<u8>> = match turbojpeg::decompress_image(compressed_image_data) {
             Ok(image) => image,
             Err(e) => {
-                eprintln!("Error decompressing image: {}", e);
+                error!("Error decompressing image: {}", e);
                 continue;
             }
         };
-        let (width, height) = decompressed_image.dimensions();
-        let image = ImageView::new(ImageInfo::rgb8(width, height), decompressed_image.as_raw());
-        window.set_image("image-001", image).unwrap();
-
+        // Displayed along with the sound captured at the same time
+        if display_sender.send(DisplayUpdate::Image(video_audio_packet.image_capture_timestamp_us, decompressed_image)).is_err() {
+            error!("Image display stopped, disconnecting client...");
+            break;
+        }
     }
+    drop(peer_exchange);
+    drop(display_sender);
+    let _ = display_thread.join();
     sink.sleep_until_end();
     let _ = window.run_function_wait(|window_handle| {
         window_handle.destroy();
     });
 }
 
-fn read_stream_data(conn: &mut ServerConnection, stream: &mut TcpStream, size_to_read: usize) -> Result<Vec<u8>, ()> {
-    let last_connection_state = conn.process_new_packets().unwrap();
-    if last_connection_state.plaintext_bytes_to_read() < size_to_read {
-        //println!("Trying to read {} bytes... {}", size_to_read, conn.wants_read());
-        while let Ok(size_read) = conn.read_tls(stream) {
-            let t = conn.process_new_packets().unwrap();
-            //println!("process result: {:?}", t);
-            if t.plaintext_bytes_to_read() >= size_to_read {
-                //println!("Enough bytes read");
-                break;
-            }
-            if size_read == 0 {
-                println!("EOF");
-                return Err(());
-            }
-            //println!("Read {} bytes", size_read);
+/// Mute or unmute the speaker with the M key of the call window
+fn handle_window_shortcuts(event_receiver: mpsc::Receiver<WindowEvent>, sink: Arc<Sink>) {
+    // Held keys repeat their pressed events, which must not toggle again
+    let mut held_keys = HashSet::new();
+    for event in event_receiver {
+        let WindowEvent::KeyboardInput(event) = event else {
+            continue;
+        };
+        let Some(key_code) = event.input.key_code else {
+            continue;
+        };
+        match event.input.state {
+            ElementState::Pressed if !held_keys.insert(key_code) => {},
+            ElementState::Pressed if key_code == VirtualKeyCode::M => {
+                let speaker_muted = sink.volume() > 0.0;
+                sink.set_volume(if speaker_muted { 0.0 } else { 1.0 });
+                info!("Speaker {}", if speaker_muted { "muted" } else { "unmuted" });
+            },
+            ElementState::Pressed => {},
+            ElementState::Released => {
+                held_keys.remove(&key_code);
+            },
         }
     }
-
-    let mut read_vec = vec![0u8; size_to_read];
-    //let _ = conn.reader().read_exact(&mut read_vec).unwrap();
-    let _ = conn.reader().read(&mut read_vec).unwrap();
-    //println!("Vec read {} bytes", read_vec.len());
-    Ok(read_vec)
 }
 
 struct TestPki {
@@ -232,19 +539,65 @@ impl TestPki {
         }
     }
 
-    fn server_config(self, json_config: &JsonServerConfig) -> Arc<QkdServerConfig> {
-        let server_config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_qkd_and_single_cert(vec![self.server_cert_der], self.server_key_der, &QkdInitialServerConfig::new(
-                json_config.kme_address.as_str(),
-                json_config.kme_authentication_certificate_path.as_str(),
-                json_config.kme_authentication_certificate_password.as_str(),
-                json_config.danger_accept_invalid_kme_cert
-            )).unwrap();
-        //.with_single_cert(vec![self.server_cert_der], self.server_key_der).unwrap();
+    /// Load the server certificate and its PKCS#8 private key from DER files
+    fn from_der_files(certificate_path: &str, private_key_path: &str) -> Result<Self, String> {
+        let server_cert_der = CertificateDer::from(std::fs::read(certificate_path).map_err(|e| format!("cannot read {}: {}", certificate_path, e))?);
+        let server_key_der = PrivatePkcs8KeyDer::from(std::fs::read(private_key_path).map_err(|e| format!("cannot read {}: {}", private_key_path, e))?).into();
+        Ok(Self {
+            server_cert_der,
+            server_key_der,
+        })
+    }
 
-        //server_config.set_key_log(Arc::new(rustls::KeyLogFile::new()));
+    fn server_config(self, json_config: &JsonServerConfig) -> Result<ServerTlsConfig, String> {
+        let key_log = key_log::dangerous_key_log(json_config.danger_enable_key_log);
+        let qkd = if json_config.security_policy.allows_qkd() {
+            let kme_authentication_certificate_password = json_config.kme_authentication_certificate_password.resolve()?;
+            let server_config = ServerConfig::builder()
+                .with_no_client_auth()
+                .with_qkd_and_single_cert(vec![self.server_cert_der.clone()], self.server_key_der.clone_key(), &QkdInitialServerConfig::new(
+                    json_config.kme_address.as_str(),
+                    json_config.kme_authentication_certificate_path.as_str(),
+                    kme_authentication_certificate_password.as_str(),
+                    json_config.danger_accept_invalid_kme_cert
+                ));
+            //.with_single_cert(vec![self.server_cert_der], self.server_key_der).unwrap();
+
+            match server_config {
+                Ok(mut server_config) => {
+                    if let Some(key_log) = key_log.clone() {
+                        server_config.set_key_log(key_log);
+                    }
+                    Some(Arc::new(server_config))
+                },
+                Err(e) if json_config.security_policy == SecurityPolicy::QkdRequired => {
+                    return Err(format!("QKD is required but unavailable: {:?}", e));
+                },
+                Err(e) => {
+                    warn!("QKD unavailable ({:?}), falling back to non-QKD key exchange", e);
+                    None
+                },
+            }
+        } else {
+            None
+        };
 
-        Arc::new(server_config)
+        // Callers without QKD complete the handshake even when the security policy rejects them, to be told why
+        let (crypto_provider, fallback_security_mode) = security_policy::fallback_crypto_provider(json_config.security_policy)
+            .map_err(|e| format!("cannot fall back to non-QKD key exchange: {}", e))?;
+        let mut fallback_server_config = rustls_pq::ServerConfig::builder_with_provider(Arc::new(crypto_provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_single_cert(vec![self.server_cert_der], self.server_key_der)
+            .map_err(|e| format!("invalid server certificate: {}", e))?;
+        if let Some(key_log) = key_log {
+            fallback_server_config.key_log = key_log;
+        }
+        Ok(ServerTlsConfig {
+            qkd,
+            fallback: Arc::new(fallback_server_config),
+            fallback_security_mode,
+        })
     }
-}
\ No newline at end of file
+}
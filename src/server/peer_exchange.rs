@@ -0,0 +1,119 @@
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::{info, warn};
+use qkd_camera_common_lib::ServerMessage;
+use qkd_camera_common_lib::chat::{self, ChatMessage, ChatTranscript};
+use qkd_camera_common_lib::file_transfer::{FileTransferMessage, FileTransfers, OutgoingFile};
+use crate::terminal_input;
+
+/// How often the terminal input thread checks whether the session ended
+const TERMINAL_INPUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const SEND_FILE_COMMAND: &str = "send-file ";
+
+/// Something typed in the terminal for the client
+enum PeerCommand {
+    Chat(ChatMessage),
+    SendFile(OutgoingFile),
+}
+
+/// Chat messages and file transfers exchanged with the client during a session, besides the media
+pub(crate) struct PeerExchange {
+    command_receiver: mpsc::Receiver<PeerCommand>,
+    terminal_input_stopped: Arc<AtomicBool>,
+    chat_transcript: ChatTranscript,
+    file_transfers: FileTransfers,
+}
+
+impl PeerExchange {
+    /// Start reading the terminal if `read_terminal` is set: each line is sent as a chat message, except `send-file <path>`
+    /// which sends a file. The relay doesn't read it, its sessions running at the same time.
+    pub(crate) fn start(chat_transcript_path: Option<&str>, download_directory: Option<&str>, session_description: &str, read_terminal: bool) -> Self {
+        let chat_transcript = ChatTranscript::open(chat_transcript_path, session_description).unwrap_or_else(|e| {
+            warn!("Error opening chat transcript, chat not recorded: {}", e);
+            ChatTranscript::default()
+        });
+        let (command_sender, command_receiver) = mpsc::channel();
+        let terminal_input_stopped = Arc::new(AtomicBool::new(false));
+        let thread_terminal_input_stopped = terminal_input_stopped.clone();
+        if read_terminal {
+            std::thread::spawn(move || read_terminal_input(command_sender, thread_terminal_input_stopped));
+            info!("Type a line in the terminal to send it as a chat message, or {}<path> to send a file", SEND_FILE_COMMAND);
+        }
+        Self {
+            command_receiver,
+            terminal_input_stopped,
+            chat_transcript,
+            file_transfers: FileTransfers::new(download_directory),
+        }
+    }
+
+    pub(crate) fn handle_chat(&mut self, chat_message: ChatMessage) {
+        match chat_message.validate() {
+            Ok(()) => {
+                chat::print_chat_message(chat::REMOTE_SENDER, &chat_message);
+                self.chat_transcript.record(chat::REMOTE_SENDER, &chat_message);
+            },
+            Err(e) => warn!("Invalid chat message ignored: {}", e),
+        }
+    }
+
+    pub(crate) fn handle_file_transfer(&mut self, file_transfer_message: FileTransferMessage) {
+        self.file_transfers.handle(file_transfer_message);
+    }
+
+    /// Messages to send before the next answer to the client
+    pub(crate) fn outgoing_messages(&mut self) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        for command in self.command_receiver.try_iter() {
+            match command {
+                PeerCommand::Chat(chat_message) => {
+                    self.chat_transcript.record(chat::LOCAL_SENDER, &chat_message);
+                    messages.push(ServerMessage::Chat(chat_message));
+                },
+                PeerCommand::SendFile(outgoing_file) => self.file_transfers.send(outgoing_file),
+            }
+        }
+        messages.extend(self.file_transfers.outgoing_messages().into_iter().map(ServerMessage::FileTransfer));
+        messages
+    }
+}
+
+impl Drop for PeerExchange {
+    fn drop(&mut self) {
+        self.terminal_input_stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+fn read_terminal_input(command_sender: mpsc::Sender<PeerCommand>, terminal_input_stopped: Arc<AtomicBool>) {
+    while !terminal_input_stopped.load(Ordering::Relaxed) {
+        let line = match terminal_input::next_line(TERMINAL_INPUT_POLL_INTERVAL) {
+            Ok(line) => line,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let command = match line.trim_start().strip_prefix(SEND_FILE_COMMAND) {
+            Some(path) => match OutgoingFile::open(Path::new(path.trim())) {
+                Ok(outgoing_file) => PeerCommand::SendFile(outgoing_file),
+                Err(e) => {
+                    warn!("File not sent: {}", e);
+                    continue;
+                }
+            },
+            None => match ChatMessage::new(&line) {
+                Ok(chat_message) => PeerCommand::Chat(chat_message),
+                Err(e) => {
+                    warn!("Chat message not sent: {}", e);
+                    continue;
+                }
+            },
+        };
+        if command_sender.send(command).is_err() {
+            return;
+        }
+    }
+}
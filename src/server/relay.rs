@@ -0,0 +1,297 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::net::TcpStream;
+use std::ops::RangeInclusive;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use image::{ImageBuffer, Rgb};
+use log::{debug, error, info, warn};
+use rodio::{OutputStream, OutputStreamHandle, Sink};
+use show_image::{create_window, ImageInfo, ImageView, WindowProxy};
+use qkd_camera_common_lib::{ClientMessage, MAX_SERVER_MESSAGE_SIZE, MediaState, ParticipantUpdate, ServerMessage, SessionLimits, VideoAudioPacket};
+use qkd_camera_common_lib::audio_device::AudioDeviceSelector;
+use qkd_camera_common_lib::resampler::Resampler;
+use qkd_camera_common_lib::session_security::SessionSecurityInfo;
+use qkd_camera_common_lib::tls_connection::TlsConnection;
+use qkd_camera_common_lib::video_grid::{self, GridTile};
+use crate::access_control::SessionLimitsEnforcer;
+use crate::peer_exchange::PeerExchange;
+use crate::{audio_output, client_stream, MAX_ACCEPTABLE_IMAGE_SIZE};
+
+type RgbImage = ImageBuffer<Rgb<u8>, Vec<u8>>;
+
+pub(crate) const DEFAULT_MAX_PARTICIPANTS: usize = 4;
+pub(crate) const MAX_PARTICIPANTS_RANGE: RangeInclusive<usize> = 2..=16;
+/// Media waiting to be forwarded to a participant is dropped beyond this count, oldest first, so that a slow
+/// connection doesn't make the server memory grow
+const MAX_PENDING_MEDIA: usize = 16;
+
+/// Conference relay: the media of each participant is played and displayed in a grid locally, and forwarded to the
+/// other participants before the answers to their own messages
+pub(crate) struct Relay {
+    participants: Mutex<BTreeMap<u32, ParticipantState>>,
+    next_participant_id: AtomicU32,
+    max_participants: usize,
+    display_sender: mpsc::Sender<GridUpdate>,
+    audio_output_stream_handle: OutputStreamHandle,
+    output_sample_rate: u32,
+}
+
+struct ParticipantState {
+    name: String,
+    media_state: MediaState,
+    /// Updates about the other participants, not yet sent to this one
+    pending_updates: VecDeque<ServerMessage>,
+}
+
+/// Change of what the grid window shows
+enum GridUpdate {
+    Joined(u32),
+    Image(u32, RgbImage),
+    MediaState(u32, MediaState),
+    Left(u32),
+}
+
+impl Relay {
+    /// Open the speaker and the grid window. The returned output stream must be kept as long as the relay runs.
+    pub(crate) fn start(audio_output_device: Option<&AudioDeviceSelector>, max_participants: usize) -> Result<(Arc<Self>, OutputStream), String> {
+        let (output_stream, audio_output_stream_handle, output_sample_rate) = audio_output::open_speaker(audio_output_device)?;
+        let window = create_window("Conference relay", Default::default()).map_err(|e| format!("cannot open window: {}", e))?;
+        let (display_sender, display_receiver) = mpsc::channel();
+        std::thread::spawn(move || display_grid(window, display_receiver));
+        info!("Relaying a conference of up to {} participants", max_participants);
+        let relay = Self {
+            participants: Mutex::new(BTreeMap::new()),
+            next_participant_id: AtomicU32::new(0),
+            max_participants,
+            display_sender,
+            audio_output_stream_handle,
+            output_sample_rate,
+        };
+        Ok((Arc::new(relay), output_stream))
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.participants.lock().unwrap().len() >= self.max_participants
+    }
+
+    /// Add a participant, telling the others it joined and it who is already there
+    pub(crate) fn join(self: &Arc<Self>, name: String) -> Result<RelayParticipant, String> {
+        let sink = Sink::try_new(&self.audio_output_stream_handle).map_err(|e| format!("cannot play sound: {}", e))?;
+        let participant_id = self.next_participant_id.fetch_add(1, Ordering::Relaxed);
+        let mut participants = self.participants.lock().unwrap();
+        let mut pending_updates = VecDeque::new();
+        for (other_participant_id, other_participant) in participants.iter_mut() {
+            other_participant.pending_updates.push_back(ServerMessage::Participant(participant_id, ParticipantUpdate::Joined(name.clone())));
+            pending_updates.push_back(ServerMessage::Participant(*other_participant_id, ParticipantUpdate::Joined(other_participant.name.clone())));
+            pending_updates.push_back(ServerMessage::Participant(*other_participant_id, ParticipantUpdate::MediaState(other_participant.media_state)));
+        }
+        info!("{} joined the conference, {} participants", name, participants.len() + 1);
+        participants.insert(participant_id, ParticipantState {
+            name: name.clone(),
+            media_state: MediaState::default(),
+            pending_updates,
+        });
+        let _ = self.display_sender.send(GridUpdate::Joined(participant_id));
+        Ok(RelayParticipant {
+            participant_id,
+            name,
+            relay: self.clone(),
+            sink,
+            resampler: None,
+        })
+    }
+
+    /// Queue an update about a participant for all the others. Updates too big for the other clients to read are dropped.
+    fn forward(&self, participant_id: u32, update: ParticipantUpdate) {
+        let message = ServerMessage::Participant(participant_id, update);
+        match postcard::serialize_with_flavor(&message, postcard::ser_flavors::Size::default()) {
+            Ok(message_size) if message_size <= MAX_SERVER_MESSAGE_SIZE => {},
+            Ok(message_size) => {
+                warn!("Update of participant {} not forwarded, its {} bytes exceed the {} bytes clients accept", participant_id, message_size, MAX_SERVER_MESSAGE_SIZE);
+                return;
+            },
+            Err(e) => {
+                error!("Error serializing update of participant {}: {}", participant_id, e);
+                return;
+            }
+        }
+        let mut participants = self.participants.lock().unwrap();
+        for (_, receiver) in participants.iter_mut().filter(|(receiver_id, _)| **receiver_id != participant_id) {
+            let is_media = |message: &ServerMessage| matches!(message, ServerMessage::Participant(_, ParticipantUpdate::Media(_)));
+            if is_media(&message) && receiver.pending_updates.iter().filter(|message| is_media(message)).count() >= MAX_PENDING_MEDIA {
+                if let Some(oldest_media_index) = receiver.pending_updates.iter().position(is_media) {
+                    receiver.pending_updates.remove(oldest_media_index);
+                    debug!("Media dropped for {}, its connection is too slow", receiver.name);
+                }
+            }
+            receiver.pending_updates.push_back(message.clone());
+        }
+    }
+}
+
+/// A client taking part in the conference, which leaves it when dropped
+pub(crate) struct RelayParticipant {
+    participant_id: u32,
+    name: String,
+    relay: Arc<Relay>,
+    sink: Sink,
+    resampler: Option<Resampler>,
+}
+
+impl RelayParticipant {
+    /// Play and display the media locally, then forward it to the other participants. Images that cannot be displayed
+    /// are not forwarded.
+    fn publish_media(&mut self, mut video_audio_packet: VideoAudioPacket) {
+        let sound_channels = video_audio_packet.sound_channels;
+        let sound_sample_rate = video_audio_packet.sound_sample_rate;
+        let output_sample_rate = self.relay.output_sample_rate;
+        // The client sends no sound while muted
+        if !video_audio_packet.sound_frame.is_empty() {
+            if !self.resampler.as_ref().is_some_and(|resampler| resampler.accepts(sound_channels, sound_sample_rate)) {
+                info!("Playing {} channels at {} Hz from {} on a {} Hz speaker", sound_channels, sound_sample_rate, self.name, output_sample_rate);
+                self.resampler = Some(Resampler::new(sound_channels, sound_sample_rate, output_sample_rate));
+            }
+            let sound_frame = self.resampler.as_mut().unwrap().resample(video_audio_packet.sound_frame.clone());
+            self.sink.append(rodio::buffer::SamplesBuffer::new(sound_channels, output_sample_rate, sound_frame));
+        }
+        // The client sends no image when no new frame was captured since the previous packet
+        if !video_audio_packet.compressed_image.is_empty() {
+            match decompress_image(&video_audio_packet.compressed_image) {
+                Ok(image) => {
+                    let _ = self.relay.display_sender.send(GridUpdate::Image(self.participant_id, image));
+                },
+                Err(e) => {
+                    error!("Error decompressing image from {}: {}", self.name, e);
+                    video_audio_packet.compressed_image.clear();
+                },
+            }
+        }
+        self.relay.forward(self.participant_id, ParticipantUpdate::Media(video_audio_packet));
+    }
+
+    fn publish_media_state(&mut self, media_state: MediaState) {
+        info!("{} changed its media: {}", self.name, media_state);
+        if let Some(participant) = self.relay.participants.lock().unwrap().get_mut(&self.participant_id) {
+            participant.media_state = media_state;
+        }
+        let _ = self.relay.display_sender.send(GridUpdate::MediaState(self.participant_id, media_state));
+        self.relay.forward(self.participant_id, ParticipantUpdate::MediaState(media_state));
+    }
+
+    /// Updates about the other participants to send before the next answer to the client
+    fn pending_updates(&mut self) -> Vec<ServerMessage> {
+        match self.relay.participants.lock().unwrap().get_mut(&self.participant_id) {
+            Some(participant) => participant.pending_updates.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for RelayParticipant {
+    fn drop(&mut self) {
+        let remaining_participants = {
+            let mut participants = self.relay.participants.lock().unwrap();
+            participants.remove(&self.participant_id);
+            participants.len()
+        };
+        info!("{} left the conference, {} participants", self.name, remaining_participants);
+        self.relay.forward(self.participant_id, ParticipantUpdate::Left);
+        let _ = self.relay.display_sender.send(GridUpdate::Left(self.participant_id));
+    }
+}
+
+/// Forward the media of a participant until it disconnects or exceeds its session limits
+pub(crate) fn manage_participant(mut conn: TlsConnection, mut stream: TcpStream, session_limits: SessionLimits, mut session_security_info: SessionSecurityInfo, mut participant: RelayParticipant, mut peer_exchange: PeerExchange) {
+    let mut session_limits_enforcer = SessionLimitsEnforcer::new(session_limits);
+    loop {
+        let (client_message, packet_size) = match client_stream::read_client_message(&mut conn, &mut stream) {
+            Ok(Some(client_message_and_size)) => client_message_and_size,
+            Ok(None) => continue,
+            Err(()) => break,
+        };
+        match session_security_info.rekey_if_due(&mut conn) {
+            Ok(true) => info!("{}", session_security_info.log_line("rekey")),
+            Ok(false) => {},
+            Err(e) => warn!("Error refreshing the session keys: {}", e),
+        }
+        // Every message counts towards the session duration and bitrate, not only the media
+        let limits_check = session_limits_enforcer.check_packet(packet_size).and_then(|_| match &client_message {
+            ClientMessage::Media(video_audio_packet) => match turbojpeg::read_header(&video_audio_packet.compressed_image) {
+                Ok(header) => session_limits_enforcer.check_resolution(header.width, header.height),
+                Err(_) => Ok(()),
+            },
+            _ => Ok(()),
+        });
+        if let Err(rejection_reason) = limits_check {
+            warn!("Ending session of {}: {}", participant.name, rejection_reason);
+            client_stream::reject_client(&mut conn, &mut stream, peer_exchange.outgoing_messages(), rejection_reason);
+            break;
+        }
+        match client_message {
+            ClientMessage::Media(video_audio_packet) => {
+                if let Err(e) = video_audio_packet.check_audio_format() {
+                    error!("Invalid audio format: {}, disconnecting client...", e);
+                    break;
+                }
+                participant.publish_media(video_audio_packet);
+            },
+            ClientMessage::MediaState(media_state) => participant.publish_media_state(media_state),
+            ClientMessage::Chat(chat_message) => peer_exchange.handle_chat(chat_message),
+            ClientMessage::FileTransfer(file_transfer_message) => peer_exchange.handle_file_transfer(file_transfer_message),
+        }
+
+        let mut peer_messages = peer_exchange.outgoing_messages();
+        peer_messages.extend(participant.pending_updates());
+        if client_stream::write_server_messages(&mut conn, &mut stream, peer_messages, ServerMessage::Ack).is_err() {
+            error!("Error writing TLS ACK, disconnecting client...");
+            break;
+        }
+    }
+}
+
+fn decompress_image(compressed_image: &[u8]) -> Result<RgbImage, String> {
+    let image_header = turbojpeg::read_header(compressed_image).map_err(|e| format!("cannot read image header: {}", e))?;
+    let image_allocated_space = image_header.width * image_header.height * image_header.colorspace as usize;
+    if image_allocated_space > MAX_ACCEPTABLE_IMAGE_SIZE {
+        return Err(format!("image too big: {} bytes", image_allocated_space));
+    }
+    turbojpeg::decompress_image(compressed_image).map_err(|e| e.to_string())
+}
+
+/// Display the participants in a grid, in the order they joined. Pending updates are applied together, so that the
+/// grid is composed once for all of them.
+fn display_grid(window: WindowProxy, display_receiver: mpsc::Receiver<GridUpdate>) {
+    let mut participants: BTreeMap<u32, (MediaState, Option<RgbImage>)> = BTreeMap::new();
+    while let Ok(grid_update) = display_receiver.recv() {
+        for grid_update in std::iter::once(grid_update).chain(display_receiver.try_iter()) {
+            match grid_update {
+                GridUpdate::Joined(participant_id) => {
+                    participants.insert(participant_id, (MediaState::default(), None));
+                },
+                GridUpdate::Image(participant_id, image) => {
+                    if let Some((_, last_image)) = participants.get_mut(&participant_id) {
+                        *last_image = Some(image);
+                    }
+                },
+                GridUpdate::MediaState(participant_id, media_state) => {
+                    if let Some((last_media_state, _)) = participants.get_mut(&participant_id) {
+                        *last_media_state = media_state;
+                    }
+                },
+                GridUpdate::Left(participant_id) => {
+                    participants.remove(&participant_id);
+                },
+            }
+        }
+        let tiles: Vec<GridTile> = participants.values().map(|(media_state, image)| GridTile {
+            image: image.as_ref().filter(|_| media_state.video_enabled),
+            audio_muted: media_state.audio_muted,
+        }).collect();
+        let grid = video_grid::compose_grid(&tiles);
+        let (width, height) = grid.dimensions();
+        if let Err(e) = window.set_image("image-001", ImageView::new(ImageInfo::rgb8(width, height), grid.as_raw())) {
+            error!("Error displaying image: {}", e);
+            return;
+        }
+    }
+}
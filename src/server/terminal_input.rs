@@ -0,0 +1,36 @@
+use std::io::BufRead;
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// Lines typed in the terminal, read by a single thread for the whole process, so that a call prompt that timed out
+/// doesn't keep waiting for a line and swallow the next chat message
+static TERMINAL_LINES: OnceLock<Mutex<mpsc::Receiver<String>>> = OnceLock::new();
+
+fn terminal_lines() -> &'static Mutex<mpsc::Receiver<String>> {
+    TERMINAL_LINES.get_or_init(|| {
+        let (line_sender, line_receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => {
+                        if line_sender.send(line).is_err() {
+                            return;
+                        }
+                    },
+                    Err(_) => return,
+                }
+            }
+        });
+        Mutex::new(line_receiver)
+    })
+}
+
+/// Forget the lines typed before, which weren't meant as an answer to what is asked now
+pub(crate) fn discard_typed_lines() {
+    terminal_lines().lock().unwrap().try_iter().for_each(drop);
+}
+
+/// Wait for the next line typed in the terminal, at most `timeout`. Fails with `Disconnected` once the standard input is closed.
+pub(crate) fn next_line(timeout: Duration) -> Result<String, mpsc::RecvTimeoutError> {
+    terminal_lines().lock().unwrap().recv_timeout(timeout)
+}
@@ -0,0 +1,85 @@
+use std::io::Read;
+use std::net::TcpStream;
+use std::sync::Arc;
+use rustls::server::qkd::QkdServerConfig;
+use qkd_camera_common_lib::security_policy::{self, SecurityMode};
+use qkd_camera_common_lib::tls_connection::TlsConnection;
+
+/// Size of the reads while waiting for the client hello
+const CLIENT_HELLO_READ_SIZE: usize = 4096;
+
+/// TLS configurations chosen according to the security policy: the QKD one if available, and the one of the callers
+/// without QKD, along with the weakest key exchange it accepts
+pub(crate) struct ServerTlsConfig {
+    pub(crate) qkd: Option<Arc<QkdServerConfig>>,
+    pub(crate) fallback: Arc<rustls_pq::ServerConfig>,
+    pub(crate) fallback_security_mode: SecurityMode,
+}
+
+impl std::fmt::Display for ServerTlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.qkd {
+            Some(_) => write!(f, "{}, or at least {} without QKD", SecurityMode::Qkd, self.fallback_security_mode),
+            None => write!(f, "at least {}", self.fallback_security_mode),
+        }
+    }
+}
+
+/// Read the client hello and complete the TLS handshake. The QKD fork handles it, unless the client offers a hybrid
+/// post-quantum key exchange, which only upstream rustls supports, or QKD is unavailable.
+pub(crate) fn accept_connection(stream: &mut TcpStream, server_tls_config: &ServerTlsConfig) -> Result<TlsConnection, String> {
+    let mut acceptor = rustls_pq::server::Acceptor::default();
+    // Given again to the QKD fork if the client asks for a QKD key
+    let mut client_hello = Vec::new();
+    let accepted = loop {
+        let mut read_buf = [0u8; CLIENT_HELLO_READ_SIZE];
+        let read_size = stream.read(&mut read_buf).map_err(|e| format!("cannot read TLS: {}", e))?;
+        if read_size == 0 {
+            return Err("client disconnected before the TLS handshake".to_string());
+        }
+        client_hello.extend_from_slice(&read_buf[..read_size]);
+        let mut received_tls = &read_buf[..read_size];
+        while !received_tls.is_empty() {
+            acceptor.read_tls(&mut received_tls).map_err(|e| format!("cannot read TLS: {}", e))?;
+        }
+        match acceptor.accept() {
+            Ok(Some(accepted)) => break accepted,
+            Ok(None) => {},
+            Err((e, mut alert)) => {
+                let _ = alert.write_all(stream);
+                return Err(format!("invalid TLS client hello: {}", e));
+            }
+        }
+    };
+
+    let offers_hybrid_post_quantum = accepted.client_hello().named_groups()
+        .is_some_and(|named_groups| named_groups.iter().any(|named_group| security_policy::is_hybrid_post_quantum(*named_group)));
+    if let (Some(qkd_server_config), false) = (server_tls_config.qkd.as_ref(), offers_hybrid_post_quantum) {
+        return accept_qkd_connection(stream, qkd_server_config, &client_hello);
+    }
+    let mut conn = match accepted.into_connection(server_tls_config.fallback.clone()) {
+        Ok(conn) => conn,
+        Err((e, mut alert)) => {
+            let _ = alert.write_all(stream);
+            return Err(format!("cannot establish TLS connection: {}", e));
+        }
+    };
+    conn.complete_io(stream).map_err(|e| format!("TLS handshake failed: {}", e))?;
+    Ok(TlsConnection::Fallback(conn.into()))
+}
+
+fn accept_qkd_connection(stream: &mut TcpStream, qkd_server_config: &Arc<QkdServerConfig>, mut client_hello: &[u8]) -> Result<TlsConnection, String> {
+    let mut acceptor = rustls::server::Acceptor::default();
+    while !client_hello.is_empty() {
+        acceptor.read_tls(&mut client_hello).map_err(|e| format!("cannot read TLS: {}", e))?;
+    }
+    let accepted = acceptor.accept()
+        .map_err(|e| format!("invalid TLS client hello: {:?}", e))?
+        .ok_or("incomplete TLS client hello")?;
+    let conn = accepted.into_qkd_connection(qkd_server_config.clone()).map_err(|e| format!("cannot establish QKD connection: {:?}", e))?;
+    let mut qkd_ack_reader = stream.try_clone().map_err(|e| format!("cannot clone TCP stream: {}", e))?;
+    let mut qkd_ack_writer = stream.try_clone().map_err(|e| format!("cannot clone TCP stream: {}", e))?;
+    let mut conn = conn.complete_qkd_ack(&mut qkd_ack_reader, &mut qkd_ack_writer);
+    conn.complete_io(stream).map_err(|e| format!("TLS handshake failed: {}", e))?;
+    Ok(TlsConnection::Qkd(conn.into()))
+}